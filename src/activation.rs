@@ -0,0 +1,122 @@
+// Writing the files that let dbus-daemon start a service when its name
+// is first called, so the name lives in one place in the code:
+//
+//     let service = ServiceFile::new(BusName::from_static("com.example.Frob"),
+//                                    "/usr/bin/frobd --bus")
+//         .systemd_service("frobd.service");
+//     try!(service.write_to(&Path::new("/usr/share/dbus-1/services")));
+//     try!(File::create(&Path::new("/usr/lib/systemd/user/frobd.service"))
+//              .write_str(service.systemd_unit("Frobnication service").as_slice()));
+
+use std::io::{File, IoResult};
+
+use names::BusName;
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct ServiceFile {
+    name: String,
+    // The command line, as dbus-daemon will split it
+    exec: String,
+    user: Option<String>,
+    systemd_service: Option<String>
+}
+
+impl ServiceFile {
+    pub fn new(name: BusName, exec: &str) -> ServiceFile {
+        ServiceFile {
+            name: String::from_str(name.as_str()),
+            exec: String::from_str(exec),
+            user: None,
+            systemd_service: None
+        }
+    }
+
+    // The user to run as.  System bus services must set this.
+    pub fn user(mut self, user: &str) -> ServiceFile {
+        self.user = Some(String::from_str(user));
+        self
+    }
+
+    // Has dbus-daemon ask systemd to start `unit` instead of running
+    // Exec itself.
+    pub fn systemd_service(mut self, unit: &str) -> ServiceFile {
+        self.systemd_service = Some(String::from_str(unit));
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_slice()
+    }
+
+    // dbus-daemon only finds the file if it's named after the service.
+    pub fn file_name(&self) -> String {
+        format!("{}.service", self.name)
+    }
+
+    // The .service file's contents.
+    pub fn render(&self) -> String {
+        let mut out = String::from_str("[D-BUS Service]\n");
+        out.push_str(format!("Name={}\n", self.name).as_slice());
+        out.push_str(format!("Exec={}\n", self.exec).as_slice());
+        for user in self.user.iter() {
+            out.push_str(format!("User={}\n", user).as_slice());
+        }
+        for unit in self.systemd_service.iter() {
+            out.push_str(format!("SystemdService={}\n", unit).as_slice());
+        }
+        out
+    }
+
+    // Writes the .service file into `dir`, returning its path.
+    pub fn write_to(&self, dir: &Path) -> IoResult<Path> {
+        let path = dir.join(self.file_name());
+        let mut file = try!(File::create(&path));
+        try!(file.write_str(self.render().as_slice()));
+        Ok(path)
+    }
+
+    // A systemd unit of Type=dbus for the same service, which systemd
+    // considers started once the name is taken.
+    pub fn systemd_unit(&self, description: &str) -> String {
+        let mut out = String::from_str("[Unit]\n");
+        out.push_str(format!("Description={}\n\n", description).as_slice());
+        out.push_str("[Service]\nType=dbus\n");
+        out.push_str(format!("BusName={}\n", self.name).as_slice());
+        out.push_str(format!("ExecStart={}\n", self.exec).as_slice());
+        for user in self.user.iter() {
+            out.push_str(format!("User={}\n", user).as_slice());
+        }
+        out
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{File, TempDir};
+
+    use super::*;
+    use names::BusName;
+
+    #[test]
+    fn test_service_file() {
+        let service = ServiceFile::new(BusName::from_static("com.example.Frob"),
+                                       "/usr/bin/frobd --bus")
+            .user("frob")
+            .systemd_service("frobd.service");
+        assert_eq!(service.render().as_slice(),
+                   "[D-BUS Service]\n\
+                    Name=com.example.Frob\n\
+                    Exec=/usr/bin/frobd --bus\n\
+                    User=frob\n\
+                    SystemdService=frobd.service\n");
+        assert!(service.systemd_unit("Frobnication").as_slice()
+                       .contains("Type=dbus\nBusName=com.example.Frob\n"));
+
+        let dir = TempDir::new("dbus-activation").unwrap();
+        let path = service.write_to(dir.path()).unwrap();
+        assert_eq!(path.filename_str(), Some("com.example.Frob.service"));
+        assert_eq!(File::open(&path).read_to_string().unwrap(), service.render());
+    }
+}
@@ -0,0 +1,472 @@
+// Server addresses, as found in DBUS_SESSION_BUS_ADDRESS: one or more
+// "transport:key=value,key=value" entries separated by semicolons.
+
+use std::fmt;
+use std::str;
+
+use super::{DBusResult, DBusError, StandardError};
+
+
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum TcpFamily {
+    Ipv4,
+    Ipv6
+}
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct TcpAddress {
+    pub host: String,
+    pub port: u16,
+    pub family: Option<TcpFamily>
+}
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum Transport {
+    UnixPath(Path),
+    // The socket name, without the leading NUL
+    UnixAbstract(Vec<u8>),
+    Tcp(TcpAddress),
+    // Like Tcp, but a nonce from the file must be sent before auth
+    NonceTcp(TcpAddress, Path),
+    // The optional scope
+    Autolaunch(Option<String>),
+    // The environment variable launchd publishes the socket path in
+    Launchd(String),
+    // A program to run, speaking D-Bus on its stdin and stdout, and
+    // its argv from argv0 on
+    UnixExec(Path, Vec<String>),
+    // A transport we don't know, with its raw (unescaped) parameters
+    OtherTransport(String, Vec<(String, Vec<u8>)>)
+}
+
+#[deriving(Clone, PartialEq)]
+pub struct BusAddress {
+    pub transport: Transport,
+    pub guid: Option<String>
+}
+
+
+fn bad_address(message: &str) -> DBusError {
+    DBusError::standard(StandardError::BadAddress, message)
+}
+
+// Bytes an address value may hold as they are; the rest must be
+// written as %xx.
+fn is_optionally_escaped(b: u8) -> bool {
+    let c = b as char;
+    b < 0x80 && (c.is_alphanumeric() || "-_/.\\*".contains_char(c))
+}
+
+// Escapes a value for use in an address, e.g. "/tmp/my bus" as
+// "/tmp/my%20bus".
+pub fn escape_value(value: &[u8]) -> String {
+    let mut out = String::with_capacity(value.len());
+    for &b in value.iter() {
+        if is_optionally_escaped(b) {
+            out.push(b as char);
+        } else {
+            out.push_str(format!("%{:02x}", b).as_slice());
+        }
+    }
+    out
+}
+
+// The bytes an address value stands for.  Any byte may be escaped,
+// not only those that must be.
+pub fn unescape_value(value: &str) -> DBusResult<Vec<u8>> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0u;
+    while i < bytes.len() {
+        if bytes[i] == '%' as u8 {
+            if i + 3 > bytes.len() {
+                return Err(bad_address("truncated escape in address"));
+            }
+            // from the bytes: a multibyte char after the % must fail,
+            // not be sliced through
+            match ((bytes[i + 1] as char).to_digit(16), (bytes[i + 2] as char).to_digit(16)) {
+                (Some(hi), Some(lo)) => out.push((hi * 16 + lo) as u8),
+                _ => return Err(bad_address("invalid escape in address"))
+            }
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(out)
+}
+
+// The parameters of one address entry, unescaped.
+struct Params {
+    entries: Vec<(String, Vec<u8>)>
+}
+
+impl Params {
+    fn parse(params: &str) -> DBusResult<Params> {
+        let mut entries: Vec<(String, Vec<u8>)> = Vec::new();
+        if params.is_empty() {
+            return Ok(Params { entries: entries });
+        }
+        for param in params.split(',') {
+            let (key, value) = match param.find('=') {
+                Some(pos) => (param.slice_to(pos), param.slice_from(pos + 1)),
+                None => return Err(bad_address("address parameter has no value"))
+            };
+            if key.is_empty() {
+                return Err(bad_address("address parameter has no name"));
+            }
+            if entries.iter().any(|&(ref k, _)| k.as_slice() == key) {
+                return Err(bad_address(format!("duplicate address parameter: {}",
+                                               key).as_slice()));
+            }
+            entries.push((String::from_str(key), try!(unescape_value(value))));
+        }
+        Ok(Params { entries: entries })
+    }
+
+    fn get(&self, key: &str) -> Option<&[u8]> {
+        self.entries.iter()
+            .find(|&&(ref k, _)| k.as_slice() == key)
+            .map(|&(_, ref v)| v.as_slice())
+    }
+
+    fn get_str(&self, key: &str) -> DBusResult<Option<String>> {
+        match self.get(key) {
+            Some(value) => match str::from_utf8(value) {
+                Some(value) => Ok(Some(String::from_str(value))),
+                None => Err(bad_address(format!("{} is not valid UTF-8", key).as_slice()))
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn tcp(&self) -> DBusResult<TcpAddress> {
+        let host = try!(self.get_str("host")).unwrap_or(String::from_str("localhost"));
+        let port = match try!(self.get_str("port")) {
+            Some(port) => match from_str::<u16>(port.as_slice()) {
+                Some(port) => port,
+                None => return Err(bad_address("invalid tcp port"))
+            },
+            None => return Err(bad_address("tcp address has no port"))
+        };
+        let family = match try!(self.get_str("family")) {
+            Some(ref family) if family.as_slice() == "ipv4" => Some(Ipv4),
+            Some(ref family) if family.as_slice() == "ipv6" => Some(Ipv6),
+            Some(_) => return Err(bad_address("unknown tcp family")),
+            None => None
+        };
+        Ok(TcpAddress {
+            host: host,
+            port: port,
+            family: family
+        })
+    }
+}
+
+impl BusAddress {
+    // Building addresses, rather than parsing them:
+    //
+    //     let address = BusAddress::unix_path(&runtime_dir.join("frob socket"));
+    //     let server = try!(DBusServer::listen(address.to_string().as_slice()));
+
+    pub fn unix_path(path: &Path) -> BusAddress {
+        BusAddress::new(UnixPath(path.clone()))
+    }
+
+    // A socket in the abstract namespace (Linux only), `name` without
+    // the leading NUL.
+    pub fn abstract_(name: &[u8]) -> BusAddress {
+        BusAddress::new(UnixAbstract(name.to_vec()))
+    }
+
+    pub fn tcp(host: &str, port: u16) -> BusAddress {
+        BusAddress::new(Tcp(TcpAddress {
+            host: String::from_str(host),
+            port: port,
+            family: None
+        }))
+    }
+
+    fn new(transport: Transport) -> BusAddress {
+        BusAddress {
+            transport: transport,
+            guid: None
+        }
+    }
+
+    // Insists on the server with this guid.
+    pub fn with_guid(mut self, guid: &str) -> BusAddress {
+        self.guid = Some(String::from_str(guid));
+        self
+    }
+
+    // Parses a single address entry.
+    pub fn parse(address: &str) -> DBusResult<BusAddress> {
+        let (transport, params) = match address.find(':') {
+            Some(pos) => (address.slice_to(pos), address.slice_from(pos + 1)),
+            None => return Err(bad_address("address has no transport name"))
+        };
+        if transport.is_empty() {
+            return Err(bad_address("address has no transport name"));
+        }
+        let params = try!(Params::parse(params));
+
+        let transport = match transport {
+            "unix" => {
+                match (params.get("path"), params.get("abstract")) {
+                    (Some(path), None) => UnixPath(Path::new(path)),
+                    (None, Some(name)) => UnixAbstract(name.to_vec()),
+                    (Some(_), Some(_)) => {
+                        return Err(bad_address("unix address has both path and abstract"));
+                    },
+                    (None, None) => {
+                        return Err(bad_address("unix address has no path or abstract name"));
+                    }
+                }
+            },
+            "tcp" => Tcp(try!(params.tcp())),
+            "nonce-tcp" => {
+                let noncefile = match params.get("noncefile") {
+                    Some(noncefile) => Path::new(noncefile),
+                    None => return Err(bad_address("nonce-tcp address has no noncefile"))
+                };
+                NonceTcp(try!(params.tcp()), noncefile)
+            },
+            "autolaunch" => Autolaunch(try!(params.get_str("scope"))),
+            "launchd" => {
+                match try!(params.get_str("env")) {
+                    Some(env) => Launchd(env),
+                    None => return Err(bad_address("launchd address has no env"))
+                }
+            },
+            "unixexec" => {
+                let path = match params.get("path") {
+                    Some(path) => Path::new(path),
+                    None => return Err(bad_address("unixexec address has no path"))
+                };
+                let argv0 = match try!(params.get_str("argv0")) {
+                    Some(argv0) => argv0,
+                    None => String::from_utf8_lossy(path.as_vec()).into_string()
+                };
+                let mut argv = vec![argv0];
+                loop {
+                    match try!(params.get_str(format!("argv{}", argv.len()).as_slice())) {
+                        Some(arg) => argv.push(arg),
+                        None => break
+                    }
+                }
+                UnixExec(path, argv)
+            },
+            other => {
+                // the guid is kept apart, as it is for every transport
+                let others = params.entries.iter()
+                    .filter(|&&(ref key, _)| key.as_slice() != "guid")
+                    .map(|entry| entry.clone())
+                    .collect();
+                OtherTransport(String::from_str(other), others)
+            }
+        };
+
+        Ok(BusAddress {
+            transport: transport,
+            guid: try!(params.get_str("guid"))
+        })
+    }
+
+    // The address as a string open() accepts, escaped as need be.
+    pub fn to_address(&self) -> String {
+        let mut params: Vec<(String, Vec<u8>)> = Vec::new();
+        let param = |key: &str, value: &[u8]| (String::from_str(key), value.to_vec());
+        let name = match self.transport {
+            UnixPath(ref path) => {
+                params.push(param("path", path.as_vec()));
+                "unix"
+            },
+            UnixAbstract(ref name) => {
+                params.push(param("abstract", name.as_slice()));
+                "unix"
+            },
+            Tcp(ref tcp) => {
+                push_tcp(&mut params, tcp);
+                "tcp"
+            },
+            NonceTcp(ref tcp, ref noncefile) => {
+                push_tcp(&mut params, tcp);
+                params.push(param("noncefile", noncefile.as_vec()));
+                "nonce-tcp"
+            },
+            Autolaunch(ref scope) => {
+                for scope in scope.iter() {
+                    params.push(param("scope", scope.as_bytes()));
+                }
+                "autolaunch"
+            },
+            Launchd(ref env) => {
+                params.push(param("env", env.as_bytes()));
+                "launchd"
+            },
+            UnixExec(ref path, ref argv) => {
+                params.push(param("path", path.as_vec()));
+                for (i, arg) in argv.iter().enumerate() {
+                    params.push(param(format!("argv{}", i).as_slice(), arg.as_bytes()));
+                }
+                "unixexec"
+            },
+            OtherTransport(ref name, ref others) => {
+                params.push_all(others.as_slice());
+                name.as_slice()
+            }
+        };
+        for guid in self.guid.iter() {
+            params.push(param("guid", guid.as_bytes()));
+        }
+        let params: Vec<String> = params.iter().map(|&(ref key, ref value)| {
+            format!("{}={}", key, escape_value(value.as_slice()))
+        }).collect();
+        format!("{}:{}", name, params.connect(","))
+    }
+}
+
+// Shows the address as to_address() writes it.
+impl fmt::Show for BusAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_address())
+    }
+}
+
+fn push_tcp(params: &mut Vec<(String, Vec<u8>)>, tcp: &TcpAddress) {
+    params.push((String::from_str("host"), tcp.host.as_bytes().to_vec()));
+    params.push((String::from_str("port"), tcp.port.to_string().into_bytes()));
+    match tcp.family {
+        Some(Ipv4) => params.push((String::from_str("family"), b"ipv4".to_vec())),
+        Some(Ipv6) => params.push((String::from_str("family"), b"ipv6".to_vec())),
+        None => ()
+    }
+}
+
+// Parses a semicolon-separated list of addresses, in order of preference.
+pub fn parse_addresses(addresses: &str) -> DBusResult<Vec<BusAddress>> {
+    let mut out = Vec::new();
+    for address in addresses.split(';') {
+        if !address.is_empty() {
+            out.push(try!(BusAddress::parse(address)));
+        }
+    }
+    if out.is_empty() {
+        return Err(bad_address("empty address"));
+    }
+    Ok(out)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_unix() {
+        let address = BusAddress::parse("unix:path=/tmp/dbus%20test,guid=0123").unwrap();
+        assert_eq!(address.transport, UnixPath(Path::new("/tmp/dbus test")));
+        assert_eq!(address.guid, Some(String::from_str("0123")));
+
+        let address = BusAddress::parse("unix:abstract=/tmp/dbus-XyZ").unwrap();
+        assert_eq!(address.transport, UnixAbstract(b"/tmp/dbus-XyZ".to_vec()));
+
+        assert!(BusAddress::parse("unix:").is_err());
+        assert!(BusAddress::parse("unix:path=/a,abstract=b").is_err());
+        assert!(BusAddress::parse("unix:path=/a,path=/b").is_err());
+        assert!(BusAddress::parse("unix:path=%zz").is_err());
+        assert!(BusAddress::parse("path=/a").is_err());
+    }
+
+    #[test]
+    fn test_parse_unixexec() {
+        let address = BusAddress::parse("unixexec:path=/usr/bin/nsenter,argv1=-t,argv2=42,\
+                                         argv3=dbus-stdio%20bridge").unwrap();
+        assert_eq!(address.transport,
+                   UnixExec(Path::new("/usr/bin/nsenter"),
+                            vec![String::from_str("/usr/bin/nsenter"), String::from_str("-t"),
+                                 String::from_str("42"), String::from_str("dbus-stdio bridge")]));
+
+        let address = BusAddress::parse("unixexec:path=/bin/sh,argv0=sh").unwrap();
+        assert_eq!(address.transport,
+                   UnixExec(Path::new("/bin/sh"), vec![String::from_str("sh")]));
+        assert!(BusAddress::parse("unixexec:argv1=x").is_err());
+    }
+
+    #[test]
+    fn test_parse_tcp() {
+        let address = BusAddress::parse("tcp:host=10.0.0.1,port=1234,family=ipv4").unwrap();
+        assert_eq!(address.transport, Tcp(TcpAddress {
+            host: String::from_str("10.0.0.1"),
+            port: 1234,
+            family: Some(Ipv4)
+        }));
+        assert!(BusAddress::parse("tcp:host=a").is_err());
+        assert!(BusAddress::parse("tcp:host=a,port=70000").is_err());
+
+        let address = BusAddress::parse("nonce-tcp:port=1,noncefile=/tmp/n").unwrap();
+        match address.transport {
+            NonceTcp(ref tcp, ref noncefile) => {
+                assert_eq!(tcp.host.as_slice(), "localhost");
+                assert_eq!(*noncefile, Path::new("/tmp/n"));
+            },
+            _ => fail!("expected a nonce-tcp address")
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape_value(b"/tmp/dbus-XyZ_1.sock").as_slice(), "/tmp/dbus-XyZ_1.sock");
+        assert_eq!(escape_value(b"/tmp/my bus,1;2=%").as_slice(),
+                   "/tmp/my%20bus%2c1%3b2%3d%25");
+        assert_eq!(escape_value(b"\xff\x00").as_slice(), "%ff%00");
+        assert_eq!(unescape_value("%2Ftmp%2fa").unwrap(), b"/tmp/a".to_vec());
+        let odd = b"/tmp/\xe2\x98\x83 :=,;%";
+        assert_eq!(unescape_value(escape_value(odd).as_slice()).unwrap(), odd.to_vec());
+        assert!(unescape_value("%4").is_err());
+        assert!(unescape_value("%aé").is_err());
+        assert!(unescape_value("%éa").is_err());
+    }
+
+    #[test]
+    fn test_to_address() {
+        for address in ["unix:path=/tmp/dbus%20test,guid=0123",
+                        "unix:abstract=/tmp/dbus-XyZ",
+                        "tcp:host=10.0.0.1,port=1234,family=ipv4",
+                        "nonce-tcp:host=localhost,port=1,noncefile=/tmp/n",
+                        "autolaunch:",
+                        "autolaunch:scope=*user",
+                        "launchd:env=DBUS_LAUNCHD_SESSION_BUS_SOCKET",
+                        "unixexec:path=/bin/sh,argv0=sh,argv1=-c",
+                        "frob:where=over%3dthere",
+                        "frob:where=x,guid=0123"].iter() {
+            let parsed = BusAddress::parse(*address).unwrap();
+            assert_eq!(parsed.to_address().as_slice(), *address);
+            assert_eq!(BusAddress::parse(parsed.to_address().as_slice()).unwrap(), parsed);
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        assert_eq!(BusAddress::unix_path(&Path::new("/run/frob socket")).to_string().as_slice(),
+                   "unix:path=/run/frob%20socket");
+        let address = BusAddress::abstract_(b"/tmp/dbus-XyZ").with_guid("0123");
+        assert_eq!(address.to_string().as_slice(), "unix:abstract=/tmp/dbus-XyZ,guid=0123");
+        let address = BusAddress::tcp("::1", 4000);
+        assert_eq!(address.to_string().as_slice(), "tcp:host=%3a%3a1,port=4000");
+        assert_eq!(BusAddress::parse(address.to_string().as_slice()).unwrap(), address);
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let addresses = parse_addresses("unix:abstract=/tmp/a;autolaunch:;\
+                                         launchd:env=DBUS_LAUNCHD_SESSION_BUS_SOCKET").unwrap();
+        assert_eq!(addresses.len(), 3);
+        assert_eq!(addresses[1].transport, Autolaunch(None));
+        assert_eq!(addresses[2].transport,
+                   Launchd(String::from_str("DBUS_LAUNCHD_SESSION_BUS_SOCKET")));
+        assert!(parse_addresses("").is_err());
+    }
+}
@@ -0,0 +1,239 @@
+// Decodes a message's arguments into one flat, reusable vector instead
+// of a tree of boxes and Vecs, so a dict-heavy message costs a couple
+// of allocations rather than several per entry.  Strings still borrow
+// from the receive buffer, as with MessageRef.  The native
+// DBusConnection decodes into one with with_next_decoded(), freeing
+// each message's values wholesale once they've been handled.
+
+use std::mem;
+
+use marshal::{Decoder, ParseError, InvalidArrayLength, TooDeep, UnexpectedEof};
+use marshal::{MAX_ARRAY_LEN, MAX_DEPTH};
+use message_ref::{MessageRef, ValueRef, read_value_ref};
+use value::{DBusType, parse_signature, parse_single_type};
+
+
+// One value in the arena.  Containers are followed by their contents
+// and hold the index just past them.
+#[deriving(Clone, PartialEq, Show)]
+pub enum ArenaNode<'a> {
+    // A basic value, or a whole byte array
+    Leaf(ValueRef<'a>),
+    ArrayNode(uint),
+    StructNode(uint),
+    DictEntryNode(uint),
+    VariantNode(uint)
+}
+
+impl<'a> ArenaNode<'a> {
+    // The index after this node and everything inside it.
+    fn end(&self, index: uint) -> uint {
+        match *self {
+            Leaf(_) => index + 1,
+            ArrayNode(end) | StructNode(end) | DictEntryNode(end) | VariantNode(end) => end
+        }
+    }
+}
+
+
+pub struct DecodeArena<'a> {
+    nodes: Vec<ArenaNode<'a>>,
+    // Indices of the top-level arguments
+    args: Vec<uint>
+}
+
+impl<'a> DecodeArena<'a> {
+    pub fn new() -> DecodeArena<'a> {
+        DecodeArena {
+            nodes: Vec::new(),
+            args: Vec::new()
+        }
+    }
+
+    // Empties the arena for a message in another buffer, keeping the
+    // memory.  This is where the savings come from: a monitor can
+    // decode every message into the same arena.
+    pub fn recycle<'b>(mut self) -> DecodeArena<'b> {
+        self.nodes.clear();
+        self.args.clear();
+        // both are empty, so no borrow outlives its buffer
+        unsafe { mem::transmute(self) }
+    }
+
+    // Decodes the body of `msg`, replacing anything decoded before.
+    pub fn decode(&mut self, msg: &MessageRef<'a>) -> Result<(), ParseError> {
+        self.nodes.clear();
+        self.args.clear();
+        let mut dec = Decoder::with_endianness(msg.body_bytes(), msg.endianness());
+        for ty in try!(parse_signature(msg.signature())).iter() {
+            self.args.push(self.nodes.len());
+            try!(self.decode_value(&mut dec, ty, 0));
+        }
+        if dec.remaining() != 0 {
+            return Err(InvalidArrayLength(dec.position()));
+        }
+        Ok(())
+    }
+
+    fn decode_value(&mut self, dec: &mut Decoder<'a>, ty: &DBusType,
+                    depth: uint) -> Result<(), ParseError> {
+        let is_leaf = match *ty {
+            DBusType::Array(ref elem_type) => **elem_type == DBusType::Byte,
+            _ => ty.is_basic()
+        };
+        if is_leaf {
+            self.nodes.push(Leaf(try!(read_value_ref(dec, ty))));
+            return Ok(());
+        }
+        if depth == MAX_DEPTH {
+            return Err(TooDeep(dec.position()));
+        }
+        let index = self.nodes.len();
+        // a placeholder until we know where the container ends
+        self.nodes.push(Leaf(ValueRef::Byte(0)));
+        let node = match *ty {
+            DBusType::Array(ref elem_type) => {
+                try!(dec.align(4));
+                let len_offset = dec.position();
+                let len = try!(dec.read_u32()) as uint;
+                if len > MAX_ARRAY_LEN {
+                    return Err(InvalidArrayLength(len_offset));
+                }
+                try!(dec.align(elem_type.alignment()));
+                if len > dec.remaining() {
+                    return Err(UnexpectedEof(dec.position() + dec.remaining()));
+                }
+                let end = dec.position() + len;
+                while dec.position() < end {
+                    try!(self.decode_value(dec, &**elem_type, depth + 1));
+                }
+                if dec.position() != end {
+                    return Err(InvalidArrayLength(len_offset));
+                }
+                ArrayNode(self.nodes.len())
+            },
+            DBusType::Struct(ref field_types) => {
+                try!(dec.align(8));
+                for field_type in field_types.iter() {
+                    try!(self.decode_value(dec, field_type, depth + 1));
+                }
+                StructNode(self.nodes.len())
+            },
+            DBusType::DictEntry(ref key_type, ref value_type) => {
+                try!(dec.align(8));
+                try!(self.decode_value(dec, &**key_type, depth + 1));
+                try!(self.decode_value(dec, &**value_type, depth + 1));
+                DictEntryNode(self.nodes.len())
+            },
+            DBusType::Variant => {
+                let sig = try!(dec.read_signature_ref());
+                let inner_type = try!(parse_single_type(sig));
+                try!(self.decode_value(dec, &inner_type, depth + 1));
+                VariantNode(self.nodes.len())
+            },
+            _ => unreachable!()
+        };
+        *self.nodes.get_mut(index) = node;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn arg_count(&self) -> uint {
+        self.args.len()
+    }
+
+    pub fn arg<'r>(&'r self, index: uint) -> Option<ArenaValue<'r, 'a>> {
+        self.args.as_slice().get(index).map(|i| ArenaValue { arena: self, index: *i })
+    }
+}
+
+
+// A value in a DecodeArena.
+pub struct ArenaValue<'r, 'a: 'r> {
+    arena: &'r DecodeArena<'a>,
+    index: uint
+}
+
+impl<'r, 'a> ArenaValue<'r, 'a> {
+    #[inline]
+    pub fn node(&self) -> &'r ArenaNode<'a> {
+        &self.arena.nodes[self.index]
+    }
+
+    pub fn as_leaf(&self) -> Option<&'r ValueRef<'a>> {
+        match *self.node() {
+            Leaf(ref value) => Some(value),
+            _ => None
+        }
+    }
+
+    // The elements, fields, key and value, or inner value of a
+    // container; nothing for a leaf.
+    pub fn children(&self) -> ArenaChildren<'r, 'a> {
+        let node = self.node();
+        ArenaChildren {
+            arena: self.arena,
+            next: self.index + 1,
+            end: match *node {
+                Leaf(_) => self.index + 1,
+                _ => node.end(self.index)
+            }
+        }
+    }
+}
+
+pub struct ArenaChildren<'r, 'a: 'r> {
+    arena: &'r DecodeArena<'a>,
+    next: uint,
+    end: uint
+}
+
+impl<'r, 'a> Iterator<ArenaValue<'r, 'a>> for ArenaChildren<'r, 'a> {
+    fn next(&mut self) -> Option<ArenaValue<'r, 'a>> {
+        if self.next >= self.end {
+            return None;
+        }
+        let index = self.next;
+        self.next = self.arena.nodes[index].end(index);
+        Some(ArenaValue { arena: self.arena, index: index })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::DBusMessage;
+    use message_ref::{MessageRef, ValueRef};
+    use names::{InterfaceName, MemberName};
+    use value::{DBusType, Value, Str, Int32, Variant};
+
+    #[test]
+    fn test_arena_decode() {
+        let dict = Value::dict(DBusType::Str, DBusType::Variant, vec![
+            (Str(String::from_str("a")), Variant(box Int32(1))),
+            (Str(String::from_str("b")), Variant(box Str(String::from_str("x"))))
+        ]);
+        let mut msg = DBusMessage::signal("/a", InterfaceName::from_static("a.b"),
+                                          MemberName::from_static("C"))
+            .with_args(vec![dict, Int32(9)]);
+        msg.set_serial(1);
+        let buf = msg.marshal();
+        let view = MessageRef::parse(buf.as_slice()).unwrap();
+
+        let mut arena = DecodeArena::new();
+        arena.decode(&view).unwrap();
+        assert_eq!(arena.arg_count(), 2);
+
+        let entries: Vec<ArenaValue> = arena.arg(0).unwrap().children().collect();
+        assert_eq!(entries.len(), 2);
+        let kv: Vec<ArenaValue> = entries[1].children().collect();
+        assert_eq!(kv[0].as_leaf(), Some(&ValueRef::Str("b")));
+        let inner = kv[1].children().next().unwrap();
+        assert_eq!(inner.as_leaf(), Some(&ValueRef::Str("x")));
+        assert_eq!(arena.arg(1).unwrap().as_leaf(), Some(&ValueRef::Int32(9)));
+
+        let arena = arena.recycle();
+        assert_eq!(arena.arg_count(), 0);
+    }
+}
@@ -0,0 +1,252 @@
+// Gathers a connection's options in one place, instead of opening it
+// and then calling setters:
+//
+//     let conn = try!(ConnectionBuilder::session()
+//         .private(true)
+//         .max_message_size(1 << 20)
+//         .connect());
+//
+// or ConnectionBuilder::bus(bus_type) where the bus is configurable.
+
+use env::Environment;
+#[cfg(feature = "native")]
+use native::auth::AuthMechanism;
+use super::{DBusConnection, DBusResult};
+#[cfg(not(feature = "native"))]
+use super::{DBusError, StandardError};
+#[cfg(not(feature = "native"))]
+use super::{get_dbus_session_address_in, get_dbus_starter_address_in};
+use super::get_dbus_system_address_in;
+#[cfg(feature = "native")]
+use env::ProcessEnvironment;
+
+
+// Which bus to connect to.  Session, System and Starter are found the
+// usual way, so DBUS_SESSION_BUS_ADDRESS, DBUS_SYSTEM_BUS_ADDRESS and
+// DBUS_STARTER_ADDRESS override where they are.
+pub type BusType = self::BusType::BusType;
+pub mod BusType {
+    use address::BusAddress;
+
+    #[deriving(Clone, PartialEq, Show)]
+    pub enum BusType {
+        Session,
+        System,
+        // The bus that activated this process; see is_bus_activated()
+        Starter,
+        // A bus at a known address
+        Custom(BusAddress)
+    }
+}
+
+enum Target {
+    Address(Vec<u8>),
+    SessionBus,
+    SystemBus,
+    StarterBus
+}
+
+pub struct ConnectionBuilder {
+    target: Target,
+    private: bool,
+    exit_on_disconnect: bool,
+    register: bool,
+    max_message_size: Option<uint>,
+    max_received_size: Option<uint>,
+    max_message_unix_fds: Option<uint>,
+    max_received_unix_fds: Option<uint>,
+    // Where to find the bus, if not the process environment
+    env: Option<Box<Environment + Send>>,
+    #[cfg(feature = "native")]
+    mechanisms: Option<Vec<Box<AuthMechanism>>>
+}
+
+impl ConnectionBuilder {
+    fn new(target: Target, register: bool) -> ConnectionBuilder {
+        ConnectionBuilder {
+            target: target,
+            private: false,
+            exit_on_disconnect: false,
+            register: register,
+            max_message_size: None,
+            max_received_size: None,
+            max_message_unix_fds: None,
+            max_received_unix_fds: None,
+            env: None,
+            #[cfg(feature = "native")]
+            mechanisms: None
+        }
+    }
+
+    // A peer or bus at `address`.  Doesn't register unless asked to.
+    pub fn address(address: &[u8]) -> ConnectionBuilder {
+        ConnectionBuilder::new(Address(address.to_vec()), false)
+    }
+
+    // The bus `bus` names, registering once connected.
+    pub fn bus(bus: BusType) -> ConnectionBuilder {
+        let target = match bus {
+            BusType::Session => SessionBus,
+            BusType::System => SystemBus,
+            BusType::Starter => StarterBus,
+            BusType::Custom(address) => Address(address.to_address().into_bytes())
+        };
+        ConnectionBuilder::new(target, true)
+    }
+
+    pub fn session() -> ConnectionBuilder {
+        ConnectionBuilder::bus(BusType::Session)
+    }
+
+    pub fn system() -> ConnectionBuilder {
+        ConnectionBuilder::bus(BusType::System)
+    }
+
+    pub fn starter() -> ConnectionBuilder {
+        ConnectionBuilder::bus(BusType::Starter)
+    }
+
+    // A connection of our own rather than the process's shared one.
+    pub fn private(mut self, private: bool) -> ConnectionBuilder {
+        self.private = private;
+        self
+    }
+
+    // Whether losing the connection exits the process.  Off by default.
+    pub fn exit_on_disconnect(mut self, exit: bool) -> ConnectionBuilder {
+        self.exit_on_disconnect = exit;
+        self
+    }
+
+    // Whether to say Hello to the bus once connected.  libdbus always
+    // does for session and system bus connections.
+    pub fn register(mut self, register: bool) -> ConnectionBuilder {
+        self.register = register;
+        self
+    }
+
+    pub fn max_message_size(mut self, size: uint) -> ConnectionBuilder {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    pub fn max_received_size(mut self, size: uint) -> ConnectionBuilder {
+        self.max_received_size = Some(size);
+        self
+    }
+
+    pub fn max_message_unix_fds(mut self, n: uint) -> ConnectionBuilder {
+        self.max_message_unix_fds = Some(n);
+        self
+    }
+
+    pub fn max_received_unix_fds(mut self, n: uint) -> ConnectionBuilder {
+        self.max_received_unix_fds = Some(n);
+        self
+    }
+
+    // Looks for the session, system or starter bus in `env` rather than
+    // the process environment.
+    pub fn environment<E: Environment + Send>(mut self, env: E) -> ConnectionBuilder {
+        self.env = Some(box env as Box<Environment + Send>);
+        self
+    }
+
+    // Offers only these mechanisms, in order, in place of the defaults.
+    // Leave out Anonymous to refuse unauthenticated connections.
+    #[cfg(feature = "native")]
+    pub fn auth_mechanisms(mut self, mechanisms: Vec<Box<AuthMechanism>>) -> ConnectionBuilder {
+        self.mechanisms = Some(mechanisms);
+        self
+    }
+
+    pub fn connect(mut self) -> DBusResult<DBusConnection> {
+        let mut conn = try!(self.open());
+        for size in self.max_message_size.iter() {
+            conn.set_max_message_size(*size);
+        }
+        for size in self.max_received_size.iter() {
+            conn.set_max_received_size(*size);
+        }
+        for n in self.max_message_unix_fds.iter() {
+            conn.set_max_message_unix_fds(*n);
+        }
+        for n in self.max_received_unix_fds.iter() {
+            conn.set_max_received_unix_fds(*n);
+        }
+        Ok(conn)
+    }
+
+    // libdbus only looks at the process environment, so with another
+    // we find the bus ourselves and open it by address.
+    #[cfg(not(feature = "native"))]
+    fn resolve(&self) -> DBusResult<Option<Vec<u8>>> {
+        let env: &Environment = match self.env {
+            Some(ref env) => &**env,
+            None => return Ok(None)
+        };
+        let address = match self.target {
+            Address(_) => return Ok(None),
+            SessionBus => get_dbus_session_address_in(env),
+            SystemBus => Some(get_dbus_system_address_in(env)),
+            // DBUS_STARTER_BUS_TYPE alone isn't enough here
+            StarterBus => get_dbus_starter_address_in(env)
+        };
+        match address {
+            Some(address) => Ok(Some(address.into_bytes())),
+            None => Err(DBusError::standard(StandardError::BadAddress,
+                                            "the environment doesn't say where the bus is"))
+        }
+    }
+
+    #[cfg(not(feature = "native"))]
+    fn open(&mut self) -> DBusResult<DBusConnection> {
+        match try!(self.resolve()) {
+            Some(address) => self.target = Address(address),
+            None => ()
+        }
+        let mut conn = try!(match self.target {
+            SessionBus if self.private => DBusConnection::session_private(),
+            SessionBus => DBusConnection::session(),
+            SystemBus if self.private => DBusConnection::system_private(),
+            SystemBus => DBusConnection::system(),
+            StarterBus if self.private => DBusConnection::starter_private(),
+            StarterBus => DBusConnection::starter(),
+            Address(ref address) if self.private => {
+                DBusConnection::open_private(address.as_slice())
+            },
+            Address(ref address) => DBusConnection::open(address.as_slice())
+        });
+        match self.target {
+            Address(_) if self.register => try!(conn.bus_register()),
+            _ => ()
+        }
+        conn.set_exit_on_disconnect(self.exit_on_disconnect);
+        Ok(conn)
+    }
+
+    // Native connections are always private and never exit the process.
+    #[cfg(feature = "native")]
+    fn open(&mut self) -> DBusResult<DBusConnection> {
+        let process_env = ProcessEnvironment;
+        let env: &Environment = match self.env {
+            Some(ref env) => &**env,
+            None => &process_env
+        };
+        let address = match self.target {
+            Address(ref address) => address.clone(),
+            SessionBus => try!(DBusConnection::session_address_in(env)).into_bytes(),
+            SystemBus => get_dbus_system_address_in(env).into_bytes(),
+            StarterBus => try!(DBusConnection::starter_address_in(env)).into_bytes()
+        };
+        let mut conn = try!(match self.mechanisms.take() {
+            Some(mechanisms) => DBusConnection::open_with_mechanisms(address.as_slice(),
+                                                                     mechanisms),
+            None => DBusConnection::open(address.as_slice())
+        });
+        if self.register {
+            try!(conn.bus_register());
+        }
+        Ok(conn)
+    }
+}
@@ -0,0 +1,176 @@
+// Where timeouts get their time from.  Timers are channels, as with
+// std::io::Timer, so they can be waited on with select!.  Tests use a
+// ManualClock, whose time only moves when told to, so timeouts happen
+// instantly and in a known order:
+//
+//     let clock = ManualClock::new();
+//     let keepalive = Keepalive::start_with_clock(sender, None, interval, timeout,
+//                                                   on_failure, clock.clone());
+//     clock.advance(interval);
+//
+// A timer stops once its receiver is dropped.
+
+use std::io::timer::sleep;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::precise_time_ns;
+
+
+pub trait Clock: Send {
+    // Time since some fixed point, the same for every call.
+    fn now(&self) -> Duration;
+    // Receives once, when `after` has passed.
+    fn oneshot(&mut self, after: Duration) -> Receiver<()>;
+    // Receives each time another `interval` has passed.
+    fn periodic(&mut self, interval: Duration) -> Receiver<()>;
+}
+
+
+// The real time, with a task sleeping behind each timer.
+#[deriving(Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        Duration::nanoseconds(precise_time_ns() as i64)
+    }
+
+    fn oneshot(&mut self, after: Duration) -> Receiver<()> {
+        let (tx, rx) = channel();
+        spawn(proc() {
+            sleep(after);
+            let _ = tx.send_opt(());
+        });
+        rx
+    }
+
+    fn periodic(&mut self, interval: Duration) -> Receiver<()> {
+        let (tx, rx) = channel();
+        spawn(proc() {
+            loop {
+                sleep(interval);
+                if tx.send_opt(()).is_err() {
+                    return;
+                }
+            }
+        });
+        rx
+    }
+}
+
+
+struct ManualTimer {
+    deadline: Duration,
+    // None for oneshots
+    interval: Option<Duration>,
+    tx: Sender<()>
+}
+
+struct ManualState {
+    now: Duration,
+    timers: Vec<ManualTimer>
+}
+
+// A clock that starts at zero and stands still between calls to
+// advance().  Clones share the same time.
+#[deriving(Clone)]
+pub struct ManualClock {
+    state: Arc<Mutex<ManualState>>
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            state: Arc::new(Mutex::new(ManualState {
+                now: Duration::zero(),
+                timers: Vec::new()
+            }))
+        }
+    }
+
+    // Moves time on by `by`, firing every timer that comes due on the
+    // way, periodic ones once for each interval that passes.
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.state.lock();
+        let now = state.now + by;
+        state.now = now;
+        let mut live = Vec::new();
+        for mut timer in mem::replace(&mut state.timers, Vec::new()).into_iter() {
+            let mut alive = true;
+            while alive && timer.deadline <= now {
+                alive = timer.tx.send_opt(()).is_ok();
+                match timer.interval {
+                    Some(interval) => timer.deadline = timer.deadline + interval,
+                    None => alive = false
+                }
+            }
+            if alive {
+                live.push(timer);
+            }
+        }
+        state.timers = live;
+    }
+
+    // Timers that haven't fired yet, or are periodic and still wanted.
+    pub fn pending(&self) -> uint {
+        self.state.lock().timers.len()
+    }
+
+    fn add(&self, after: Duration, interval: Option<Duration>) -> Receiver<()> {
+        let (tx, rx) = channel();
+        let mut state = self.state.lock();
+        let deadline = state.now + after;
+        state.timers.push(ManualTimer {
+            deadline: deadline,
+            interval: interval,
+            tx: tx
+        });
+        rx
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.state.lock().now
+    }
+
+    fn oneshot(&mut self, after: Duration) -> Receiver<()> {
+        self.add(after, None)
+    }
+
+    fn periodic(&mut self, interval: Duration) -> Receiver<()> {
+        self.add(interval, Some(interval))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let mut clock = ManualClock::new();
+        let once = clock.oneshot(Duration::seconds(5));
+        let ticks = clock.periodic(Duration::seconds(2));
+        assert_eq!(clock.pending(), 2);
+
+        clock.advance(Duration::seconds(4));
+        assert_eq!(clock.now(), Duration::seconds(4));
+        assert!(once.try_recv().is_err());
+        assert!(ticks.try_recv().is_ok());
+        assert!(ticks.try_recv().is_ok());
+        assert!(ticks.try_recv().is_err());
+
+        clock.advance(Duration::seconds(1));
+        assert!(once.try_recv().is_ok());
+        assert_eq!(clock.pending(), 1);
+
+        drop(ticks);
+        clock.advance(Duration::seconds(2));
+        assert_eq!(clock.pending(), 0);
+    }
+}
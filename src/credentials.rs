@@ -0,0 +1,127 @@
+// What the bus knows about the process behind a connection, from
+// org.freedesktop.DBus.GetConnectionCredentials.
+
+use value::{Value, Array, DictEntry, Byte, UInt32, Str};
+use super::{DBusResult, DBusError, StandardError};
+
+
+#[deriving(Clone, PartialEq, Show, Default)]
+pub struct Credentials {
+    pub unix_user_id: Option<u32>,
+    pub unix_group_ids: Option<Vec<u32>>,
+    pub process_id: Option<u32>,
+    // The security label as the kernel gave it, without the trailing NUL
+    pub linux_security_label: Option<Vec<u8>>,
+    pub windows_sid: Option<String>
+}
+
+impl Credentials {
+    // Decodes the a{sv} reply.  Keys we don't know are skipped, since
+    // the bus is free to add more; known keys of the wrong type are an
+    // error.
+    pub fn from_value(value: &Value) -> DBusResult<Credentials> {
+        let entries = match *value {
+            Array(_, ref entries) => entries,
+            _ => return Err(malformed("expected a dictionary"))
+        };
+        let mut creds: Credentials = Default::default();
+        for entry in entries.iter() {
+            let (key, value) = match *entry {
+                DictEntry(ref key, ref value) => match key.as_str() {
+                    Some(key) => (key, value.inner()),
+                    None => return Err(malformed("expected string keys"))
+                },
+                _ => return Err(malformed("expected a{sv} entries"))
+            };
+            match key {
+                "UnixUserID" => creds.unix_user_id = Some(try!(expect_u32(key, value))),
+                "ProcessID" => creds.process_id = Some(try!(expect_u32(key, value))),
+                "UnixGroupIDs" => {
+                    let ids = match *value {
+                        Array(_, ref ids) => ids,
+                        _ => return Err(wrong_type(key))
+                    };
+                    let mut out = Vec::with_capacity(ids.len());
+                    for id in ids.iter() {
+                        out.push(try!(expect_u32(key, id)));
+                    }
+                    creds.unix_group_ids = Some(out);
+                },
+                "LinuxSecurityLabel" => {
+                    let bytes = match *value {
+                        Array(_, ref bytes) => bytes,
+                        _ => return Err(wrong_type(key))
+                    };
+                    let mut label = Vec::with_capacity(bytes.len());
+                    for b in bytes.iter() {
+                        match *b {
+                            Byte(b) => label.push(b),
+                            _ => return Err(wrong_type(key))
+                        }
+                    }
+                    if label.last() == Some(&0) {
+                        label.pop();
+                    }
+                    creds.linux_security_label = Some(label);
+                },
+                "WindowsSID" => match *value {
+                    Str(ref sid) => creds.windows_sid = Some(sid.clone()),
+                    _ => return Err(wrong_type(key))
+                },
+                _ => ()
+            }
+        }
+        Ok(creds)
+    }
+}
+
+
+fn malformed(why: &str) -> DBusError {
+    DBusError::standard(StandardError::InconsistentMessage,
+                        format!("malformed credentials: {}", why).as_slice())
+}
+
+fn wrong_type(key: &str) -> DBusError {
+    malformed(format!("{} has the wrong type", key).as_slice())
+}
+
+fn expect_u32(key: &str, value: &Value) -> DBusResult<u32> {
+    match *value {
+        UInt32(v) => Ok(v),
+        _ => Err(wrong_type(key))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use value::{DBusType, Value, Array, Byte, Str, UInt32, Variant};
+
+    fn entry(key: &str, value: Value) -> (Value, Value) {
+        (Str(String::from_str(key)), Variant(box value))
+    }
+
+    #[test]
+    fn test_credentials() {
+        let label = "unconfined\0".bytes().map(|b| Byte(b)).collect();
+        let dict = Value::dict(DBusType::Str, DBusType::Variant, vec![
+            entry("UnixUserID", UInt32(1000)),
+            entry("ProcessID", UInt32(42)),
+            entry("UnixGroupIDs", Array(DBusType::UInt32, vec![UInt32(1000), UInt32(27)])),
+            entry("LinuxSecurityLabel", Array(DBusType::Byte, label)),
+            entry("SomethingNew", Str(String::from_str("ignored")))
+        ]);
+        let creds = Credentials::from_value(&dict).unwrap();
+        assert_eq!(creds.unix_user_id, Some(1000));
+        assert_eq!(creds.process_id, Some(42));
+        assert_eq!(creds.unix_group_ids, Some(vec![1000, 27]));
+        assert_eq!(creds.linux_security_label, Some(b"unconfined".to_vec()));
+        assert_eq!(creds.windows_sid, None);
+
+        let bad = Value::dict(DBusType::Str, DBusType::Variant, vec![
+            entry("ProcessID", Str(String::from_str("42")))
+        ]);
+        assert!(Credentials::from_value(&bad).is_err());
+    }
+}
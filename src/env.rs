@@ -0,0 +1,57 @@
+// Where bus discovery reads its environment variables from.  Normally
+// that's the process environment, but tests and embedders that run
+// with a sanitised one can pass a map instead:
+//
+//     let mut env = HashMap::new();
+//     env.insert(String::from_str("DBUS_SESSION_BUS_ADDRESS"),
+//                test_bus.address());
+//     let conn = try!(ConnectionBuilder::session().environment(env).connect());
+
+use std::collections::HashMap;
+use std::os;
+
+
+pub trait Environment {
+    fn var(&self, name: &str) -> Option<String>;
+}
+
+// The process environment.
+pub struct ProcessEnvironment;
+
+impl Environment for ProcessEnvironment {
+    fn var(&self, name: &str) -> Option<String> {
+        os::getenv(name)
+    }
+}
+
+// Exactly these variables, and no others.
+impl Environment for HashMap<String, String> {
+    fn var(&self, name: &str) -> Option<String> {
+        self.find_equiv(&name).map(|value| value.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use super::super::{get_dbus_session_address_in, get_dbus_starter_address_in};
+    use super::super::is_bus_activated_in;
+
+    #[test]
+    fn test_map_environment() {
+        let mut env = HashMap::new();
+        env.insert(String::from_str("DBUS_SESSION_BUS_ADDRESS"),
+                   String::from_str("unix:path=/tmp/frob"));
+        assert_eq!(get_dbus_session_address_in(&env),
+                   Some(String::from_str("unix:path=/tmp/frob")));
+        assert_eq!(get_dbus_starter_address_in(&env), None);
+        assert!(!is_bus_activated_in(&env));
+
+        // nothing else to go on
+        let empty: HashMap<String, String> = HashMap::new();
+        assert_eq!(get_dbus_session_address_in(&empty), None);
+    }
+}
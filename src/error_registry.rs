@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::{DBusError, DBusResult};
+
+
+// The outcome of running a DBusError through an ErrorRegistry.
+pub enum MappedError<E> {
+    Mapped(E),
+    Unmapped(DBusError)
+}
+
+impl<E> MappedError<E> {
+    pub fn mapped(&self) -> Option<&E> {
+        match *self {
+            Mapped(ref err) => Some(err),
+            Unmapped(_) => None
+        }
+    }
+}
+
+impl<E: fmt::Show> fmt::Show for MappedError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Mapped(ref err) => err.fmt(f),
+            Unmapped(ref err) => err.fmt(f)
+        }
+    }
+}
+
+
+// Maps an application's own D-Bus error names (com.example.Error.Busy)
+// onto its Rust error type.  Names without a mapping are passed through
+// untouched as MappedError::Unmapped.  The native DBusConnection's
+// call_mapped() applies one to a call's error reply.
+pub struct ErrorRegistry<E> {
+    mappings: HashMap<String, fn(&DBusError) -> E>
+}
+
+impl<E> ErrorRegistry<E> {
+    pub fn new() -> ErrorRegistry<E> {
+        ErrorRegistry {
+            mappings: HashMap::new()
+        }
+    }
+
+    // Replaces any mapping previously registered for `name`.
+    pub fn register(&mut self, name: &str, mapper: fn(&DBusError) -> E) {
+        self.mappings.insert(String::from_str(name), mapper);
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.mappings.contains_key_equiv(&name)
+    }
+
+    pub fn map(&self, err: DBusError) -> MappedError<E> {
+        let mapped = match self.mappings.find_equiv(&err.name()) {
+            Some(mapper) => Some((*mapper)(&err)),
+            None => None
+        };
+        match mapped {
+            Some(mapped) => Mapped(mapped),
+            None => Unmapped(err)
+        }
+    }
+
+    pub fn map_result<T>(&self, result: DBusResult<T>) -> Result<T, MappedError<E>> {
+        match result {
+            Ok(value) => Ok(value),
+            Err(err) => Err(self.map(err))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::{DBusError, StandardError};
+
+    #[deriving(PartialEq, Show)]
+    enum FrobError {
+        Busy(String),
+        Jammed
+    }
+
+    fn map_busy(err: &DBusError) -> FrobError {
+        Busy(String::from_str(err.message()))
+    }
+
+    fn map_jammed(_: &DBusError) -> FrobError {
+        Jammed
+    }
+
+    #[test]
+    fn test_error_registry() {
+        let mut registry = ErrorRegistry::new();
+        registry.register("org.yasashiisyndicate.Frobulator.Error.Busy", map_busy);
+        registry.register("org.yasashiisyndicate.Frobulator.Error.Jammed", map_jammed);
+
+        let busy = DBusError::new("org.yasashiisyndicate.Frobulator.Error.Busy", "later");
+        assert_eq!(registry.map(busy).mapped(), Some(&Busy(String::from_str("later"))));
+
+        let denied = DBusError::standard(StandardError::AccessDenied, "no");
+        match registry.map(denied) {
+            Unmapped(err) => assert!(err.is_standard(StandardError::AccessDenied)),
+            Mapped(err) => fail!("unexpected mapping: {}", err)
+        }
+    }
+}
@@ -0,0 +1,97 @@
+// Raw bindings to libdbus.  Everything unsafe about talking to the C
+// library is declared here; libdbus.rs builds the safe wrappers on top.
+
+use libc::{c_char, c_int, c_long, c_uint, c_ulong, c_void};
+
+
+// dbus_bool_t from dbus-types.h
+#[allow(non_camel_case_types)]
+pub type dbus_bool_t = u32;
+
+// DBusConnection from dbus-connection.h.  Its layout is private to
+// libdbus, so we only ever hold pointers to it.
+pub enum CDBusConnection {}
+
+// DBusMessage from dbus-message.h, likewise opaque.
+pub enum CDBusMessage {}
+
+// Type codes from dbus-protocol.h, for dbus_message_append_args() and
+// dbus_message_get_args()
+pub const DBUS_TYPE_INVALID: c_int = 0;
+pub const DBUS_TYPE_STRING: c_int = 115; // 's'
+pub const DBUS_TYPE_UINT32: c_int = 117; // 'u'
+
+// Lets libdbus pick the timeout for a blocking call
+pub const DBUS_TIMEOUT_USE_DEFAULT: c_int = -1;
+
+// DBusBusType from dbus-shared.h
+pub const DBUS_BUS_SESSION: c_int = 0;
+pub const DBUS_BUS_SYSTEM: c_int = 1;
+pub const DBUS_BUS_STARTER: c_int = 2;
+
+#[link(name = "dbus-1")]
+extern {
+    pub fn dbus_connection_open(address: *const c_char,
+                                error: *mut CDBusError
+                               ) -> *mut CDBusConnection;
+    pub fn dbus_connection_open_private(address: *const c_char,
+                                        error: *mut CDBusError
+                                       ) -> *mut CDBusConnection;
+    pub fn dbus_connection_ref(connection: *mut CDBusConnection) -> *mut CDBusConnection;
+    pub fn dbus_connection_unref(connection: *mut CDBusConnection);
+    pub fn dbus_connection_close(connection: *mut CDBusConnection);
+    pub fn dbus_connection_get_server_id(connection: *mut CDBusConnection) -> *mut c_char;
+    pub fn dbus_connection_set_exit_on_disconnect(connection: *mut CDBusConnection,
+                                                  exit_on_disconnect: dbus_bool_t);
+    pub fn dbus_connection_dispatch(connection: *mut CDBusConnection) -> c_int;
+    pub fn dbus_connection_set_max_message_size(connection: *mut CDBusConnection, size: c_long);
+    pub fn dbus_connection_get_max_message_size(connection: *mut CDBusConnection) -> c_long;
+    pub fn dbus_connection_set_max_received_size(connection: *mut CDBusConnection, size: c_long);
+    pub fn dbus_connection_get_max_received_size(connection: *mut CDBusConnection) -> c_long;
+    pub fn dbus_connection_set_max_message_unix_fds(connection: *mut CDBusConnection, n: c_long);
+    pub fn dbus_connection_get_max_message_unix_fds(connection: *mut CDBusConnection) -> c_long;
+    pub fn dbus_connection_set_max_received_unix_fds(connection: *mut CDBusConnection, n: c_long);
+    pub fn dbus_connection_get_max_received_unix_fds(connection: *mut CDBusConnection) -> c_long;
+
+    pub fn dbus_bus_get(bus_type: c_int, error: *mut CDBusError) -> *mut CDBusConnection;
+    pub fn dbus_bus_get_private(bus_type: c_int,
+                                error: *mut CDBusError) -> *mut CDBusConnection;
+    pub fn dbus_bus_register(connection: *mut CDBusConnection,
+                             error: *mut CDBusError) -> dbus_bool_t;
+    pub fn dbus_bus_get_unix_user(connection: *mut CDBusConnection, name: *const c_char,
+                                  error: *mut CDBusError) -> c_ulong;
+    pub fn dbus_bus_request_name(connection: *mut CDBusConnection, name: *const c_char,
+                                 flags: c_uint, error: *mut CDBusError) -> c_int;
+    pub fn dbus_connection_send_with_reply_and_block(connection: *mut CDBusConnection,
+                                                     message: *mut CDBusMessage,
+                                                     timeout_milliseconds: c_int,
+                                                     error: *mut CDBusError
+                                                    ) -> *mut CDBusMessage;
+
+    pub fn dbus_message_new_method_call(destination: *const c_char, path: *const c_char,
+                                        interface: *const c_char,
+                                        method: *const c_char) -> *mut CDBusMessage;
+    pub fn dbus_message_unref(message: *mut CDBusMessage);
+    // Each argument is a type code followed by a pointer to the value,
+    // ending with DBUS_TYPE_INVALID.
+    pub fn dbus_message_append_args(message: *mut CDBusMessage,
+                                    first_arg_type: c_int, ...) -> dbus_bool_t;
+    pub fn dbus_message_get_args(message: *mut CDBusMessage, error: *mut CDBusError,
+                                 first_arg_type: c_int, ...) -> dbus_bool_t;
+
+    pub fn dbus_threads_init_default() -> dbus_bool_t;
+    pub fn dbus_error_is_set(error: *const CDBusError) -> dbus_bool_t;
+    pub fn dbus_error_init(error: *mut CDBusError);
+    pub fn dbus_error_free(error: *mut CDBusError);
+    pub fn dbus_free(memory: *mut c_void);
+}
+
+
+// The DBusError struct from dbus-errors.h, only ever filled in by libdbus.
+#[repr(C)]
+pub struct CDBusError {
+    pub name: *const c_char,
+    pub message: *const c_char,
+    pub _bitfields: c_uint,
+    pub _padding1: *const c_void
+}
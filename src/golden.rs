@@ -0,0 +1,501 @@
+// Locking down the encoder's output: a message is marshalled with a
+// fixed serial and byte order and compared against a hex dump kept in
+// the test, and any difference is reported by offset and by the part
+// of the message it falls in:
+//
+//     assert_golden(&msg, 1, LittleEndian, "
+//         6c 01 00 01 00 00 00 00 01 00 00 00 6d 00 00 00
+//         ...");
+//
+// Hex dumps may have offsets before a colon, as to_hex() writes them,
+// and comments after a '#'; both are ignored.
+//
+// There's no conversion trait for types to implement yet, so the
+// round-trip check works on the Values a type converts to.
+// check_roundtrip_random() checks a signature against Values from a
+// seeded ValueGen, naming the seed of any that fail so they can be
+// made again.
+
+use std::rand::{Rng, SeedableRng, XorShiftRng};
+
+use marshal::{Decoder, Endianness, LittleEndian, BigEndian};
+use message::{DBusMessage, FIXED_HEADER_LEN};
+use names::{InterfaceName, MemberName};
+use pretty::format_value;
+use value::{DBusType, Value, parse_signature};
+use value::{Byte, Boolean, Int16, UInt16, Int32, UInt32, Int64, UInt64, Double, Str};
+use value::{ObjectPath, Signature, UnixFd, Array, Struct, DictEntry, Variant};
+
+// How deep ValueGen nests the types it picks for variants.
+pub const DEFAULT_GEN_DEPTH: uint = 3;
+// The most items ValueGen puts in an array.
+static GEN_MAX_ITEMS: uint = 4;
+
+
+// `msg` marshalled as it would be sent with `serial`.
+pub fn golden_bytes(msg: &DBusMessage, serial: u32, endianness: Endianness) -> Vec<u8> {
+    let mut msg = msg.clone();
+    msg.set_serial(serial);
+    msg.marshal_with(endianness)
+}
+
+// Sixteen bytes to a line, each line starting with its offset.
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        out.push_str(format!("{:04x}:", i * 16).as_slice());
+        for byte in line.iter() {
+            out.push_str(format!(" {:02x}", *byte).as_slice());
+        }
+        out.push('\n');
+    }
+    out
+}
+
+pub fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    for (n, line) in hex.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        let line = match line.find(':') {
+            Some(colon) => line.slice_from(colon + 1),
+            None => line
+        };
+        let digits: Vec<u8> = line.chars().filter(|c| !c.is_whitespace()).filter_map(|c| {
+            c.to_digit(16).map(|d| d as u8)
+        }).collect();
+        let others = line.chars().filter(|c| !c.is_whitespace() && !c.is_digit_radix(16));
+        if others.count() > 0 || digits.len() % 2 != 0 {
+            return Err(format!("line {}: not a hex dump: {}", n + 1, line.trim()));
+        }
+        for pair in digits.chunks(2) {
+            out.push(pair[0] << 4 | pair[1]);
+        }
+    }
+    Ok(out)
+}
+
+// Ok if the bytes are the same, otherwise each run of differing bytes
+// with the field it's in, followed by both dumps.
+pub fn compare(expected: &[u8], got: &[u8]) -> Result<(), String> {
+    if expected == got {
+        return Ok(());
+    }
+    let layout = layout(expected);
+    let mut out = String::from_str("marshalled bytes differ:\n");
+    if expected.len() != got.len() {
+        out.push_str(format!("  length: expected {}, got {}\n", expected.len(), got.len())
+                     .as_slice());
+    }
+    let mut i = 0;
+    let len = if expected.len() < got.len() { expected.len() } else { got.len() };
+    while i < len {
+        if expected[i] == got[i] {
+            i += 1;
+            continue;
+        }
+        // extend the run while the bytes differ and stay in one field
+        let field = field_at(layout.as_slice(), i);
+        let start = i;
+        while i < len && expected[i] != got[i] && field_at(layout.as_slice(), i) == field {
+            i += 1;
+        }
+        out.push_str(format!("  offset {} ({}): expected{}, got{}\n", start, field,
+                             hex_run(expected.slice(start, i)),
+                             hex_run(got.slice(start, i))).as_slice());
+    }
+    out.push_str("expected:\n");
+    out.push_str(to_hex(expected).as_slice());
+    out.push_str("got:\n");
+    out.push_str(to_hex(got).as_slice());
+    Err(out)
+}
+
+// Marshals `msg` with `serial` and compares it against `expected_hex`.
+pub fn check_golden(msg: &DBusMessage, serial: u32, endianness: Endianness,
+                    expected_hex: &str) -> Result<(), String> {
+    let expected = try!(parse_hex(expected_hex));
+    compare(expected.as_slice(), golden_bytes(msg, serial, endianness).as_slice())
+}
+
+// Fails the task with the differences if they don't match.
+pub fn assert_golden(msg: &DBusMessage, serial: u32, endianness: Endianness,
+                     expected_hex: &str) {
+    match check_golden(msg, serial, endianness, expected_hex) {
+        Ok(()) => (),
+        Err(diff) => fail!("{}", diff)
+    }
+}
+
+// Puts `args` in a message body, marshals it in both byte orders and
+// reads it back, describing what went wrong if the values or their
+// signature didn't survive.  For checking the Values a type's
+// conversions produce, e.g. that an Array's items match its element
+// type.
+pub fn check_roundtrip(args: &[Value]) -> Result<(), String> {
+    let msg = DBusMessage::signal("/", InterfaceName::from_static("org.example.RoundTrip"),
+                                  MemberName::from_static("Check"))
+        .with_args(args.to_vec());
+    let signature = msg.signature();
+    for endianness in [LittleEndian, BigEndian].iter() {
+        let bytes = golden_bytes(&msg, 1, *endianness);
+        let back = match DBusMessage::from_bytes(bytes.as_slice()) {
+            Ok(back) => back,
+            Err(err) => return Err(format!("{}: reading back failed: {}\n{}", endianness, err,
+                                           to_hex(bytes.as_slice())))
+        };
+        if back.signature() != signature {
+            return Err(format!("{}: signature \"{}\" came back as \"{}\"", endianness,
+                               signature, back.signature()));
+        }
+        let mut out = String::new();
+        for (i, (sent, got)) in args.iter().zip(back.body().iter()).enumerate() {
+            if sent != got {
+                out.push_str(format!("  arg {}: sent {}, got {}\n", i, format_value(sent, 0),
+                                     format_value(got, 0)).as_slice());
+            }
+        }
+        if back.body().len() != args.len() {
+            out.push_str(format!("  sent {} args, got {}\n", args.len(), back.body().len())
+                         .as_slice());
+        }
+        if !out.is_empty() {
+            return Err(format!("{}: values changed on the way:\n{}", endianness, out));
+        }
+    }
+    Ok(())
+}
+
+// Fails the task with what went wrong if `args` don't round-trip.
+pub fn assert_roundtrip(args: &[Value]) {
+    match check_roundtrip(args) {
+        Ok(()) => (),
+        Err(diff) => fail!("{}", diff)
+    }
+}
+
+// Checks `signature` with `n` sets of arguments from ValueGens seeded
+// 1 to `n`.
+pub fn check_roundtrip_random(signature: &str, n: uint) -> Result<(), String> {
+    let types = try!(parse_signature(signature).map_err(|err| format!("{}", err)));
+    for seed in range(1, n as u32 + 1) {
+        let mut gen = ValueGen::new(seed);
+        let args: Vec<Value> = types.iter().map(|t| gen.value(t)).collect();
+        match check_roundtrip(args.as_slice()) {
+            Ok(()) => (),
+            Err(diff) => return Err(format!("\"{}\", seed {}: {}", signature, seed, diff))
+        }
+    }
+    Ok(())
+}
+
+// Fails the task with what went wrong if `signature` doesn't round-trip.
+pub fn assert_roundtrip_random(signature: &str, n: uint) {
+    match check_roundtrip_random(signature, n) {
+        Ok(()) => (),
+        Err(diff) => fail!("{}", diff)
+    }
+}
+
+
+// Makes arbitrary Values of a given type, the same ones for the same
+// seed.  Variants hold types of their own choosing, nested at most
+// `depth` containers deep; arrays deeper than that are left empty.
+pub struct ValueGen {
+    rng: XorShiftRng,
+    depth: uint
+}
+
+impl ValueGen {
+    pub fn new(seed: u32) -> ValueGen {
+        ValueGen::with_depth(seed, DEFAULT_GEN_DEPTH)
+    }
+
+    pub fn with_depth(seed: u32, depth: uint) -> ValueGen {
+        // XorShift can't start from all zeroes
+        let rng: XorShiftRng = SeedableRng::from_seed([seed, 0x9e3779b9, seed ^ 0x7f4a7c15, 1]);
+        ValueGen {
+            rng: rng,
+            depth: depth
+        }
+    }
+
+    pub fn value(&mut self, t: &DBusType) -> Value {
+        self.value_at(t, 0)
+    }
+
+    fn value_at(&mut self, t: &DBusType, depth: uint) -> Value {
+        match *t {
+            DBusType::Byte => Byte(self.rng.gen()),
+            DBusType::Boolean => Boolean(self.rng.gen()),
+            DBusType::Int16 => Int16(self.rng.gen()),
+            DBusType::UInt16 => UInt16(self.rng.gen()),
+            DBusType::Int32 => Int32(self.rng.gen()),
+            DBusType::UInt32 => UInt32(self.rng.gen()),
+            DBusType::Int64 => Int64(self.rng.gen()),
+            DBusType::UInt64 => UInt64(self.rng.gen()),
+            // NaN would never compare equal to itself coming back
+            DBusType::Double => Double(self.rng.gen_range(-1e9f64, 1e9)),
+            DBusType::Str => Str(self.string()),
+            DBusType::ObjectPath => ObjectPath(self.path()),
+            DBusType::Signature => {
+                let t = self.any_type(depth);
+                Signature(t.signature())
+            },
+            DBusType::UnixFd => UnixFd(self.rng.gen_range(0, 16)),
+            DBusType::Array(ref elem) => {
+                let len = if depth < self.depth {
+                    self.rng.gen_range(0, GEN_MAX_ITEMS + 1)
+                } else {
+                    0
+                };
+                let items = Vec::from_fn(len, |_| self.value_at(&**elem, depth + 1));
+                Array((**elem).clone(), items)
+            },
+            DBusType::Struct(ref fields) => {
+                Struct(fields.iter().map(|t| self.value_at(t, depth + 1)).collect())
+            },
+            DBusType::DictEntry(ref key, ref value) => {
+                DictEntry(box self.value_at(&**key, depth + 1),
+                          box self.value_at(&**value, depth + 1))
+            },
+            DBusType::Variant => {
+                let t = self.any_type(depth);
+                Variant(box self.value_at(&t, depth + 1))
+            }
+        }
+    }
+
+    // A complete type, containers only while above `self.depth`.
+    fn any_type(&mut self, depth: uint) -> DBusType {
+        let choices = if depth < self.depth { 16 } else { 12 };
+        match self.rng.gen_range(0u, choices) {
+            0 => DBusType::Byte,
+            1 => DBusType::Boolean,
+            2 => DBusType::Int16,
+            3 => DBusType::UInt16,
+            4 => DBusType::Int32,
+            5 => DBusType::UInt32,
+            6 => DBusType::Int64,
+            7 => DBusType::UInt64,
+            8 => DBusType::Double,
+            9 => DBusType::Str,
+            10 => DBusType::ObjectPath,
+            11 => DBusType::Signature,
+            12 => DBusType::Array(box self.any_type(depth + 1)),
+            13 => {
+                let len = self.rng.gen_range(1u, 4);
+                DBusType::Struct(Vec::from_fn(len, |_| self.any_type(depth + 1)))
+            },
+            14 => {
+                let key = self.basic_type();
+                let value = self.any_type(depth + 1);
+                DBusType::Array(box DBusType::DictEntry(box key, box value))
+            },
+            _ => DBusType::Variant
+        }
+    }
+
+    fn basic_type(&mut self) -> DBusType {
+        match self.rng.gen_range(0u, 4) {
+            0 => DBusType::Byte,
+            1 => DBusType::Int32,
+            2 => DBusType::UInt64,
+            _ => DBusType::Str
+        }
+    }
+
+    // Mixes in characters past ASCII to exercise the byte lengths.
+    fn string(&mut self) -> String {
+        let len = self.rng.gen_range(0u, 12);
+        let mut out = String::new();
+        for _ in range(0, len) {
+            out.push(match self.rng.gen_range(0u, 8) {
+                0 => 'é',
+                1 => '\U0001f980',
+                _ => self.rng.gen_range(b' ', b'~' + 1) as char
+            });
+        }
+        out
+    }
+
+    fn path(&mut self) -> String {
+        let elements = self.rng.gen_range(0u, 4);
+        if elements == 0 {
+            return String::from_str("/");
+        }
+        let mut out = String::new();
+        for _ in range(0, elements) {
+            out.push('/');
+            for _ in range(0, self.rng.gen_range(1u, 6)) {
+                out.push(match self.rng.gen_range(0u, 4) {
+                    0 => '_',
+                    1 => self.rng.gen_range(b'0', b'9' + 1) as char,
+                    _ => self.rng.gen_range(b'a', b'z' + 1) as char
+                });
+            }
+        }
+        out
+    }
+}
+
+
+fn hex_run(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for byte in bytes.iter() {
+        out.push_str(format!(" {:02x}", *byte).as_slice());
+    }
+    out
+}
+
+
+// Where each part of a message starts, in order.
+fn layout(buf: &[u8]) -> Vec<(uint, String)> {
+    let mut out = vec![(0, String::from_str("endianness")),
+                       (1, String::from_str("message type")),
+                       (2, String::from_str("flags")),
+                       (3, String::from_str("protocol version")),
+                       (4, String::from_str("body length")),
+                       (8, String::from_str("serial")),
+                       (12, String::from_str("header fields length"))];
+    let endianness = match buf.head() {
+        Some(&b'l') => LittleEndian,
+        Some(&b'B') => BigEndian,
+        _ => return out
+    };
+    if buf.len() < FIXED_HEADER_LEN {
+        return out;
+    }
+    let mut dec = Decoder::with_endianness(buf, endianness);
+    let fields_len = match dec.read_raw(12).and_then(|_| dec.read_u32()) {
+        Ok(len) => len as uint,
+        Err(_) => return out
+    };
+    let fields_end = FIXED_HEADER_LEN + fields_len;
+    let variant = DBusType::Variant;
+    // a malformed header just stops the labelling where it goes wrong
+    while dec.position() < fields_end {
+        if dec.align(8).is_err() {
+            break;
+        }
+        let start = dec.position();
+        match dec.read_byte() {
+            Ok(code) => out.push((start, format!("header field {}", field_name(code)))),
+            Err(_) => break
+        }
+        if dec.read_value(&variant).is_err() {
+            break;
+        }
+    }
+    out.push((fields_end, String::from_str("header padding")));
+    out.push(((fields_end + 7) & !7, String::from_str("body")));
+    out
+}
+
+fn field_at(layout: &[(uint, String)], offset: uint) -> &str {
+    let mut name = "";
+    for &(start, ref field) in layout.iter() {
+        if start > offset {
+            break;
+        }
+        name = field.as_slice();
+    }
+    name
+}
+
+fn field_name(code: u8) -> &'static str {
+    match code {
+        1 => "PATH",
+        2 => "INTERFACE",
+        3 => "MEMBER",
+        4 => "ERROR_NAME",
+        5 => "REPLY_SERIAL",
+        6 => "DESTINATION",
+        7 => "SENDER",
+        8 => "SIGNATURE",
+        9 => "UNIX_FDS",
+        _ => "(unknown)"
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use marshal::{LittleEndian, BigEndian};
+    use message::DBusMessage;
+    use names::{InterfaceName, MemberName};
+    use value::{DBusType, Int32, Str, Array, Struct, Variant};
+
+    #[test]
+    fn test_golden() {
+        let msg = DBusMessage::signal("/", InterfaceName::from_static("a.b"),
+                                      MemberName::from_static("C"));
+        let bytes = golden_bytes(&msg, 1, LittleEndian);
+        assert_golden(&msg, 1, LittleEndian, to_hex(bytes.as_slice()).as_slice());
+        assert_eq!(parse_hex("0000: 6c 04 # comment\n 00 01").unwrap(),
+                   vec![0x6c, 0x04, 0x00, 0x01]);
+        assert!(parse_hex("6c 0").is_err());
+        assert!(parse_hex("zz").is_err());
+
+        let diff = check_golden(&msg, 2, LittleEndian,
+                                to_hex(bytes.as_slice()).as_slice()).unwrap_err();
+        assert_eq!(diff.as_slice().lines().nth(1),
+                   Some("  offset 8 (serial): expected 01, got 02"));
+
+        let diff = compare(bytes.as_slice(),
+                           golden_bytes(&msg, 1, BigEndian).as_slice()).unwrap_err();
+        assert_eq!(diff.as_slice().lines().nth(1),
+                   Some("  offset 0 (endianness): expected 6c, got 42"));
+        assert!(diff.as_slice().contains("(header field PATH)"));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        assert_roundtrip([Int32(1), Str(String::from_str("x")),
+                          Array(DBusType::Str, vec![Str(String::from_str("y"))])].as_slice());
+        // the items don't match the element type
+        assert!(check_roundtrip([Array(DBusType::Int32,
+                                       vec![Str(String::from_str("y"))])].as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_roundtrip_random() {
+        for signature in ["y", "b", "n", "q", "i", "u", "x", "t", "d", "s", "o", "g", "h"].iter() {
+            assert_roundtrip_random(*signature, 50);
+        }
+        assert_roundtrip_random("(ius)", 50);
+        assert_roundtrip_random("a(yv)(s(dx))", 50);
+        assert_roundtrip_random("a{sv}", 50);
+        assert_roundtrip_random("a{ya{sad}}", 50);
+        assert_roundtrip_random("v", 100);
+        assert_roundtrip_random("avaav", 50);
+        assert!(check_roundtrip_random("a{vs}", 1).is_err());
+    }
+
+    #[test]
+    fn test_value_gen() {
+        // the same seed gives the same values, of the type asked for
+        let t = DBusType::Struct(vec![DBusType::Int32, DBusType::Variant]);
+        let value = ValueGen::new(7).value(&t);
+        assert_eq!(ValueGen::new(7).value(&t), value);
+        assert_eq!(value.get_type(), t);
+        match value {
+            Struct(ref fields) => match fields[1] {
+                Variant(_) => (),
+                ref other => fail!("expected a variant, got {}", other)
+            },
+            _ => fail!("expected a struct")
+        }
+
+        // no containers in variants past the depth asked for
+        let mut gen = ValueGen::with_depth(3, 0);
+        for _ in range(0u, 50) {
+            match gen.value(&DBusType::Variant) {
+                Variant(box inner) => assert!(inner.get_type().is_basic()),
+                other => fail!("expected a variant, got {}", other)
+            }
+        }
+        assert_eq!(gen.value(&DBusType::Array(box DBusType::Str)),
+                   Array(DBusType::Str, Vec::new()));
+    }
+}
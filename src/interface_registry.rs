@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+
+use super::{DBusInterface, InterfaceBuilder};
+
+
+enum Entry {
+    // Not built until someone asks for it
+    Pending(fn() -> DBusInterface),
+    Built(Arc<DBusInterface>)
+}
+
+
+// Interface definitions declared once and shared by name, built the
+// first time they're asked for.  Use global() for the process-wide
+// one, or make one per connection.
+pub struct InterfaceRegistry {
+    entries: Mutex<HashMap<String, Entry>>
+}
+
+impl InterfaceRegistry {
+    pub fn new() -> InterfaceRegistry {
+        InterfaceRegistry {
+            entries: Mutex::new(HashMap::new())
+        }
+    }
+
+    // Already holding the org.freedesktop.DBus interfaces every object
+    // implements.
+    pub fn with_standard() -> InterfaceRegistry {
+        let registry = InterfaceRegistry::new();
+        registry.register("org.freedesktop.DBus.Introspectable", introspectable);
+        registry.register("org.freedesktop.DBus.Peer", peer);
+        registry.register("org.freedesktop.DBus.Properties", properties);
+        registry
+    }
+
+    // Replaces any definition previously registered for `name`.
+    pub fn register(&self, name: &str, define: fn() -> DBusInterface) {
+        self.entries.lock().insert(String::from_str(name), Pending(define));
+    }
+
+    // Registers an interface that's already built, under its own name.
+    pub fn add(&self, iface: DBusInterface) -> Arc<DBusInterface> {
+        let iface = Arc::new(iface);
+        self.entries.lock().insert(String::from_str(iface.name()), Built(iface.clone()));
+        iface
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.entries.lock().contains_key_equiv(&name)
+    }
+
+    // The interface registered as `name`, building it if this is the
+    // first time it's been asked for.
+    pub fn get(&self, name: &str) -> Option<Arc<DBusInterface>> {
+        let mut entries = self.entries.lock();
+        let entry = match entries.find_equiv_mut(&name) {
+            Some(entry) => entry,
+            None => return None
+        };
+        let iface = match *entry {
+            Built(ref iface) => return Some(iface.clone()),
+            Pending(define) => Arc::new(define())
+        };
+        *entry = Built(iface.clone());
+        Some(iface)
+    }
+}
+
+
+static mut GLOBAL: *const InterfaceRegistry = 0 as *const InterfaceRegistry;
+static GLOBAL_INIT: Once = ONCE_INIT;
+
+// The process-wide registry, starting out with_standard().
+pub fn global() -> &'static InterfaceRegistry {
+    unsafe {
+        GLOBAL_INIT.doit(|| {
+            // never freed, like any other static
+            GLOBAL = mem::transmute(box InterfaceRegistry::with_standard());
+        });
+        &*GLOBAL
+    }
+}
+
+
+fn introspectable() -> DBusInterface {
+    InterfaceBuilder::new("org.freedesktop.DBus.Introspectable")
+        .method("Introspect").returns("s")
+        .build()
+}
+
+fn peer() -> DBusInterface {
+    InterfaceBuilder::new("org.freedesktop.DBus.Peer")
+        .method("Ping")
+        .method("GetMachineId").returns("s")
+        .build()
+}
+
+fn properties() -> DBusInterface {
+    InterfaceBuilder::new("org.freedesktop.DBus.Properties")
+        .method("Get").arg("s", "interface_name").arg("s", "property_name").returns("v")
+        .method("Set").arg("s", "interface_name").arg("s", "property_name").arg("v", "value")
+        .method("GetAll").arg("s", "interface_name").returns("a{sv}")
+        .signal("PropertiesChanged", "sa{sv}as")
+        .build()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::InterfaceBuilder;
+
+    fn frobulator() -> super::super::DBusInterface {
+        InterfaceBuilder::new("org.yasashiisyndicate.Frobulator")
+            .method("Frobulate").arg("s", "value").returns("s")
+            .build()
+    }
+
+    #[test]
+    fn test_lazy_registration() {
+        let registry = InterfaceRegistry::new();
+        assert!(registry.get("org.yasashiisyndicate.Frobulator").is_none());
+        registry.register("org.yasashiisyndicate.Frobulator", frobulator);
+        assert!(registry.is_registered("org.yasashiisyndicate.Frobulator"));
+
+        let first = registry.get("org.yasashiisyndicate.Frobulator").unwrap();
+        let second = registry.get("org.yasashiisyndicate.Frobulator").unwrap();
+        assert_eq!(first.members().len(), 1);
+        // built once, then shared
+        assert!(&*first as *const _ == &*second as *const _);
+    }
+
+    #[test]
+    fn test_global_standard_interfaces() {
+        let peer = global().get("org.freedesktop.DBus.Peer").unwrap();
+        assert_eq!(peer.name(), "org.freedesktop.DBus.Peer");
+        assert_eq!(peer.members().len(), 2);
+        assert!(global().is_registered("org.freedesktop.DBus.Properties"));
+    }
+}
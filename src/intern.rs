@@ -0,0 +1,106 @@
+// Names that turn up in most messages, kept as static strings so
+// building messages that use them doesn't allocate.  Received headers
+// aren't interned: their strings are already allocated by the time
+// we'd look them up, and unique names like ":1.42" never match.
+
+use std::str::{SendStr, Slice, Owned};
+
+
+// Sorted by length, then bytewise, so a lookup compares lengths and
+// only compares the strings of names as long as the one it's after.
+static WELL_KNOWN: &'static [&'static str] = &[
+    "Get",
+    "Set",
+    "Ping",
+    "Hello",
+    "GetAll",
+    "AddMatch",
+    "NameLost",
+    "Introspect",
+    "ReleaseName",
+    "RemoveMatch",
+    "RequestName",
+    "GetMachineId",
+    "GetNameOwner",
+    "NameAcquired",
+    "InterfacesAdded",
+    "NameOwnerChanged",
+    "GetManagedObjects",
+    "InterfacesRemoved",
+    "PropertiesChanged",
+    "org.freedesktop.DBus",
+    "/org/freedesktop/DBus",
+    "org.freedesktop.DBus.Peer",
+    "org.freedesktop.DBus.Properties",
+    "org.freedesktop.DBus.Error.Failed",
+    "org.freedesktop.DBus.ObjectManager",
+    "org.freedesktop.DBus.Introspectable",
+    "org.freedesktop.DBus.Error.InvalidArgs",
+    "org.freedesktop.DBus.Error.AccessDenied",
+    "org.freedesktop.DBus.Error.UnknownMethod",
+    "org.freedesktop.DBus.Error.UnknownObject",
+    "org.freedesktop.DBus.Error.NameHasNoOwner",
+    "org.freedesktop.DBus.Error.ServiceUnknown",
+    "org.freedesktop.DBus.Error.UnknownProperty",
+    "org.freedesktop.DBus.Error.UnknownInterface"
+];
+
+// The static copy of `s`, if it's one we know.
+pub fn interned(s: &str) -> Option<&'static str> {
+    let key = (s.len(), s);
+    let (mut lo, mut hi) = (0u, WELL_KNOWN.len());
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        let known = WELL_KNOWN[mid];
+        match (known.len(), known).cmp(&key) {
+            Less => lo = mid + 1,
+            Greater => hi = mid,
+            Equal => return Some(known)
+        }
+    }
+    None
+}
+
+pub fn intern(s: &str) -> SendStr {
+    match interned(s) {
+        Some(known) => Slice(known),
+        None => Owned(String::from_str(s))
+    }
+}
+
+// Like intern(), for a string we already own, which is dropped if a
+// static copy exists.
+pub fn intern_owned(s: String) -> SendStr {
+    match interned(s.as_slice()) {
+        Some(known) => Slice(known),
+        None => Owned(s)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::{Slice, Owned};
+
+    #[test]
+    fn test_intern() {
+        match intern("org.freedesktop.DBus") {
+            Slice(s) => assert_eq!(s, "org.freedesktop.DBus"),
+            Owned(_) => fail!("expected a static string")
+        }
+        match intern_owned(String::from_str("com.example.Foo")) {
+            Owned(s) => assert_eq!(s.as_slice(), "com.example.Foo"),
+            Slice(_) => fail!("expected an owned string")
+        }
+    }
+
+    #[test]
+    fn test_interned_finds_every_name() {
+        for known in super::WELL_KNOWN.iter() {
+            assert_eq!(interned(*known).map(|s| s.as_ptr()), Some(known.as_ptr()));
+        }
+        assert_eq!(interned("Gets"), None);
+        assert_eq!(interned(""), None);
+    }
+}
@@ -1,54 +1,91 @@
-#![feature(unsafe_destructor, globs)]
+#![feature(unsafe_destructor, globs, phase, macro_rules)]
 #![allow(non_snake_case)]
 
 extern crate libc;
-
-use libc::{c_char, c_int, c_uint, c_void};
-
-use std::os;
-use std::ptr;
-use std::c_str::CString;
+#[phase(plugin, link)]
+extern crate log;
+extern crate time;
+
+use std::cmp;
+use std::fmt;
+use std::io::{File, IoError};
+use std::time::Duration;
+use std::str::{SendStr, IntoMaybeOwned, Slice, Owned};
+
+#[cfg(not(feature = "native"))]
+pub use libdbus::DBusConnection;
+#[cfg(feature = "native")]
+pub use native::DBusConnection;
+pub use address::BusAddress;
+pub use arena::DecodeArena;
+pub use builder::{BusType, ConnectionBuilder};
+pub use credentials::Credentials;
+pub use env::{Environment, ProcessEnvironment};
+pub use error_registry::{ErrorRegistry, MappedError};
+pub use interface_registry::InterfaceRegistry;
+pub use message::{DBusMessage, MessageType, parse_message};
+pub use message_ref::{MessageRef, ValueRef};
+pub use names::{BusName, InterfaceName, MemberName};
+pub use object_tree::ObjectTree;
+pub use pool::MessagePool;
+pub use shared::SharedConnection;
+pub use stats::ConnectionStats;
+pub use value::{DBusType, Value};
+
+pub mod activation;
+pub mod address;
+pub mod arena;
+pub mod builder;
+pub mod clock;
+pub mod credentials;
+pub mod env;
+pub mod error_registry;
+pub mod golden;
+pub mod interface_registry;
+pub mod intern;
+pub mod machine_id;
+pub mod marshal;
+#[macro_escape]
+pub mod matcher;
+pub mod message;
+pub mod message_ref;
+pub mod names;
+pub mod object_tree;
+pub mod pool;
+pub mod pretty;
+pub mod shared;
+pub mod stats;
+pub mod test_bus;
+pub mod unix_fd;
+pub mod value;
+
+#[cfg(not(feature = "native"))]
+pub mod ffi;
+#[cfg(not(feature = "native"))]
+mod libdbus;
+#[cfg(feature = "native")]
+pub mod native;
 
 pub type DBusResult<T> = Result<T, DBusError>;
 
-#[allow(dead_code)]
-#[link(name = "dbus-1")]
-extern {
-    fn dbus_connection_open(address: *const u8,
-                            error: *mut DBusError
-                           ) -> *mut CDBusConnection;
-    fn dbus_connection_open_private(address: *const u8,
-                                    error: *mut DBusError
-                                   ) -> *mut CDBusConnection;
-    fn dbus_connection_unref(connection: *mut CDBusConnection);
-    fn dbus_connection_close(connection: *mut CDBusConnection);
-    fn dbus_connection_get_server_id(connection: *mut CDBusConnection) -> *const c_char;
-    fn dbus_connection_dispatch(connection: *mut CDBusConnection) -> c_int;
-
-    fn dbus_bus_register(connection: *mut CDBusConnection, error: *mut DBusError) -> u32;
-    fn dbus_bus_request_name(connection: *mut CDBusConnection, name: *const c_char,
-                             flags: c_uint, error: *mut DBusError) -> c_int;
-    fn dbus_error_is_set(error: *const DBusError) -> u32;
-    fn dbus_error_init(error: *mut DBusError);
-    fn dbus_error_free(error: *mut DBusError);
-}
-
 
 pub enum DBusInterfaceElement {
-    Method(String, String, Vec<String>, String),
-    Signal(String, String)
+    // name, argument signature, argument names, return signature
+    Method(SendStr, SendStr, Vec<SendStr>, SendStr),
+    // name, signature
+    Signal(SendStr, SendStr)
 }
 
 
 pub struct DBusInterface {
-    name: String,
+    name: SendStr,
     members: Vec<DBusInterfaceElement>
 }
 
 impl DBusInterface {
-    pub fn new(name: &str) -> DBusInterface {
+    pub fn new(name: InterfaceName) -> DBusInterface {
         DBusInterface {
-            name: String::from_str(name),
+            name: Owned(String::from_str(name.as_str())),
             members: Vec::new()
         }
     }
@@ -61,23 +98,125 @@ impl DBusInterface {
     pub fn add_method(&mut self, name: &str, argspec: &str,
                       argnames: Vec<String>, retspec: &str) {
         self.add_member(Method(
-            String::from_str(name),
-            String::from_str(argspec),
-            argnames,
-            String::from_str(retspec)
+            Owned(String::from_str(name)),
+            Owned(String::from_str(argspec)),
+            argnames.into_iter().map(|name| name.into_maybe_owned()).collect(),
+            Owned(String::from_str(retspec))
         ));
     }
 
     pub fn add_signal(&mut self, name: &str, retspec: &str) {
         self.add_member(Signal(
-            String::from_str(name),
-            String::from_str(retspec)
+            Owned(String::from_str(name)),
+            Owned(String::from_str(retspec))
         ));
     }
 
     pub fn name(&self) -> &str {
         self.name.as_slice()
     }
+
+    pub fn members(&self) -> &[DBusInterfaceElement] {
+        self.members.as_slice()
+    }
+}
+
+
+// Builds a DBusInterface without copying names given as static
+// strings, and taking ownership of those given as Strings:
+//
+//     InterfaceBuilder::new("org.example.Frobulator")
+//         .method("Frobulate").arg("s", "value").returns("s")
+//         .signal("Frobulated", "s")
+//         .build()
+pub struct InterfaceBuilder {
+    iface: DBusInterface
+}
+
+impl InterfaceBuilder {
+    pub fn new<S: IntoMaybeOwned<'static>>(name: S) -> InterfaceBuilder {
+        InterfaceBuilder {
+            iface: DBusInterface {
+                name: name.into_maybe_owned(),
+                members: Vec::new()
+            }
+        }
+    }
+
+    pub fn method<S: IntoMaybeOwned<'static>>(self, name: S) -> MethodBuilder {
+        MethodBuilder {
+            iface: self.iface,
+            name: name.into_maybe_owned(),
+            argspec: Slice(""),
+            argnames: Vec::new(),
+            retspec: Slice("")
+        }
+    }
+
+    pub fn signal<S: IntoMaybeOwned<'static>, T: IntoMaybeOwned<'static>>(
+            mut self, name: S, signature: T) -> InterfaceBuilder {
+        self.iface.add_member(Signal(name.into_maybe_owned(), signature.into_maybe_owned()));
+        self
+    }
+
+    #[inline]
+    pub fn build(self) -> DBusInterface {
+        self.iface
+    }
+}
+
+pub struct MethodBuilder {
+    iface: DBusInterface,
+    name: SendStr,
+    argspec: SendStr,
+    argnames: Vec<SendStr>,
+    retspec: SendStr
+}
+
+impl MethodBuilder {
+    // Adds an argument; the signatures of all of them make up the
+    // method's argument signature.
+    pub fn arg<S: IntoMaybeOwned<'static>>(mut self, signature: &'static str,
+                                           name: S) -> MethodBuilder {
+        self.argspec = match self.argspec {
+            // the common single-argument case stays static
+            Slice("") => Slice(signature),
+            other => {
+                let mut spec = other.into_string();
+                spec.push_str(signature);
+                Owned(spec)
+            }
+        };
+        self.argnames.push(name.into_maybe_owned());
+        self
+    }
+
+    pub fn returns<S: IntoMaybeOwned<'static>>(mut self, signature: S) -> MethodBuilder {
+        self.retspec = signature.into_maybe_owned();
+        self
+    }
+
+    fn finish(self) -> InterfaceBuilder {
+        let MethodBuilder { mut iface, name, argspec, argnames, retspec } = self;
+        iface.add_member(Method(name, argspec, argnames, retspec));
+        InterfaceBuilder {
+            iface: iface
+        }
+    }
+
+    // Finishes this method and starts the next.
+    pub fn method<S: IntoMaybeOwned<'static>>(self, name: S) -> MethodBuilder {
+        self.finish().method(name)
+    }
+
+    pub fn signal<S: IntoMaybeOwned<'static>, T: IntoMaybeOwned<'static>>(
+            self, name: S, signature: T) -> InterfaceBuilder {
+        self.finish().signal(name, signature)
+    }
+
+    pub fn build(self) -> DBusInterface {
+        self.finish().build()
+    }
 }
 
 
@@ -137,6 +276,8 @@ pub mod DBusHandlerResult {
 
 pub type DBusTimeout = self::DBusTimeout::DBusTimeout;
 pub mod DBusTimeout {
+    use super::{DBusResult, DBusError, StandardError};
+
     #[repr(C)]
     pub enum DBusTimeout {
         Default,
@@ -155,170 +296,390 @@ pub mod DBusTimeout {
     }
 
     #[inline]
-    pub fn millis(millis: i32) -> DBusTimeout {
+    pub fn millis(millis: i32) -> DBusResult<DBusTimeout> {
         if 0 <= millis && millis < 0x7FFFFFFF {
-            Milliseconds(millis)
+            Ok(Milliseconds(millis))
         } else {
-            fail!("0 <= millis < 0x7FFFFFFF");
+            Err(DBusError::standard(StandardError::InvalidArgs,
+                                    "0 <= millis < 0x7FFFFFFF"))
+        }
+    }
+}
+
+pub type StandardError = self::StandardError::StandardError;
+pub mod StandardError {
+    // from dbus-protocol.h
+    #[deriving(PartialEq, Eq, Clone, Show)]
+    pub enum StandardError {
+        Failed,
+        NoMemory,
+        ServiceUnknown,
+        NameHasNoOwner,
+        NoReply,
+        IOError,
+        BadAddress,
+        NotSupported,
+        LimitsExceeded,
+        AccessDenied,
+        AuthFailed,
+        NoServer,
+        Timeout,
+        NoNetwork,
+        AddressInUse,
+        Disconnected,
+        InvalidArgs,
+        FileNotFound,
+        FileExists,
+        UnknownMethod,
+        UnknownObject,
+        UnknownInterface,
+        UnknownProperty,
+        PropertyReadOnly,
+        TimedOut,
+        MatchRuleNotFound,
+        MatchRuleInvalid,
+        UnixProcessIdUnknown,
+        InvalidSignature,
+        InvalidFileContent,
+        SELinuxSecurityContextUnknown,
+        AdtAuditDataUnknown,
+        ObjectPathInUse,
+        InconsistentMessage,
+        InteractiveAuthorizationRequired
+    }
+
+    static ALL: &'static [StandardError] = &[
+        Failed, NoMemory, ServiceUnknown, NameHasNoOwner, NoReply, IOError,
+        BadAddress, NotSupported, LimitsExceeded, AccessDenied, AuthFailed,
+        NoServer, Timeout, NoNetwork, AddressInUse, Disconnected, InvalidArgs,
+        FileNotFound, FileExists, UnknownMethod, UnknownObject,
+        UnknownInterface, UnknownProperty, PropertyReadOnly, TimedOut,
+        MatchRuleNotFound, MatchRuleInvalid, UnixProcessIdUnknown,
+        InvalidSignature, InvalidFileContent, SELinuxSecurityContextUnknown,
+        AdtAuditDataUnknown, ObjectPathInUse, InconsistentMessage,
+        InteractiveAuthorizationRequired
+    ];
+
+    impl StandardError {
+        pub fn name(&self) -> &'static str {
+            match *self {
+                Failed => "org.freedesktop.DBus.Error.Failed",
+                NoMemory => "org.freedesktop.DBus.Error.NoMemory",
+                ServiceUnknown => "org.freedesktop.DBus.Error.ServiceUnknown",
+                NameHasNoOwner => "org.freedesktop.DBus.Error.NameHasNoOwner",
+                NoReply => "org.freedesktop.DBus.Error.NoReply",
+                IOError => "org.freedesktop.DBus.Error.IOError",
+                BadAddress => "org.freedesktop.DBus.Error.BadAddress",
+                NotSupported => "org.freedesktop.DBus.Error.NotSupported",
+                LimitsExceeded => "org.freedesktop.DBus.Error.LimitsExceeded",
+                AccessDenied => "org.freedesktop.DBus.Error.AccessDenied",
+                AuthFailed => "org.freedesktop.DBus.Error.AuthFailed",
+                NoServer => "org.freedesktop.DBus.Error.NoServer",
+                Timeout => "org.freedesktop.DBus.Error.Timeout",
+                NoNetwork => "org.freedesktop.DBus.Error.NoNetwork",
+                AddressInUse => "org.freedesktop.DBus.Error.AddressInUse",
+                Disconnected => "org.freedesktop.DBus.Error.Disconnected",
+                InvalidArgs => "org.freedesktop.DBus.Error.InvalidArgs",
+                FileNotFound => "org.freedesktop.DBus.Error.FileNotFound",
+                FileExists => "org.freedesktop.DBus.Error.FileExists",
+                UnknownMethod => "org.freedesktop.DBus.Error.UnknownMethod",
+                UnknownObject => "org.freedesktop.DBus.Error.UnknownObject",
+                UnknownInterface => "org.freedesktop.DBus.Error.UnknownInterface",
+                UnknownProperty => "org.freedesktop.DBus.Error.UnknownProperty",
+                PropertyReadOnly => "org.freedesktop.DBus.Error.PropertyReadOnly",
+                TimedOut => "org.freedesktop.DBus.Error.TimedOut",
+                MatchRuleNotFound => "org.freedesktop.DBus.Error.MatchRuleNotFound",
+                MatchRuleInvalid => "org.freedesktop.DBus.Error.MatchRuleInvalid",
+                UnixProcessIdUnknown => "org.freedesktop.DBus.Error.UnixProcessIdUnknown",
+                InvalidSignature => "org.freedesktop.DBus.Error.InvalidSignature",
+                InvalidFileContent => "org.freedesktop.DBus.Error.InvalidFileContent",
+                SELinuxSecurityContextUnknown =>
+                    "org.freedesktop.DBus.Error.SELinuxSecurityContextUnknown",
+                AdtAuditDataUnknown => "org.freedesktop.DBus.Error.AdtAuditDataUnknown",
+                ObjectPathInUse => "org.freedesktop.DBus.Error.ObjectPathInUse",
+                InconsistentMessage => "org.freedesktop.DBus.Error.InconsistentMessage",
+                InteractiveAuthorizationRequired =>
+                    "org.freedesktop.DBus.Error.InteractiveAuthorizationRequired"
+            }
         }
     }
+
+    pub fn from_name(name: &str) -> Option<StandardError> {
+        ALL.iter().find(|err| err.name() == name).map(|err| *err)
+    }
 }
 
-#[repr(C)]
-pub struct DBusError {
-    name: *const c_char,
-    message: *const c_char,
-    _bitfields: c_uint,
-    _padding1: *const c_void
+
+#[deriving(Clone)]
+pub enum DBusError {
+    // An error identified by a D-Bus error name, from libdbus or a peer
+    NamedError(String, String),
+    // A failure in the transport underneath the connection
+    IoFailure(IoError),
+    // Another error, annotated with what we were doing when it happened
+    WithContext(String, Box<DBusError>)
 }
 
 
 impl DBusError {
-    // Ensure check_safe() is true after getting this back from DBus,
-    // if we are in error.
-    fn new_unsafe() -> DBusError {
-        let mut out = DBusError {
-            name: ptr::null(),
-            message: ptr::null(),
-            _bitfields: 0,
-            _padding1: ptr::null()
-        };
-        unsafe {
-            dbus_error_init(&mut out);
-        }
-        out
+    pub fn new(name: &str, message: &str) -> DBusError {
+        NamedError(String::from_str(name), String::from_str(message))
+    }
+
+    #[inline]
+    pub fn standard(err: StandardError, message: &str) -> DBusError {
+        DBusError::new(err.name(), message)
+    }
+
+    #[inline]
+    pub fn from_io_error(err: IoError) -> DBusError {
+        IoFailure(err)
     }
 
-    fn check_safe(&self) -> bool {
-        self.name.is_not_null() && self.message.is_not_null()
+    pub fn context(self, context: &str) -> DBusError {
+        WithContext(String::from_str(context), box self)
     }
 
-    fn is_set(&self) -> bool {
-        unsafe {
-            dbus_error_is_set(self) > 0
+    // The innermost error, with all context stripped.
+    pub fn root(&self) -> &DBusError {
+        match *self {
+            WithContext(_, ref inner) => inner.root(),
+            _ => self
         }
     }
 
-    pub fn get_name(&self) -> CString {
-        unsafe {
-            CString::new(self.name, false)
+    pub fn name(&self) -> &str {
+        match *self.root() {
+            NamedError(ref name, _) => name.as_slice(),
+            IoFailure(_) => StandardError::IOError.name(),
+            WithContext(..) => unreachable!()
         }
     }
 
-    pub fn get_message(&self) -> CString {
-        unsafe {
-            CString::new(self.message, false)
+    pub fn message(&self) -> &str {
+        match *self.root() {
+            NamedError(_, ref message) => message.as_slice(),
+            IoFailure(ref err) => match err.detail {
+                Some(ref detail) => detail.as_slice(),
+                None => err.desc
+            },
+            WithContext(..) => unreachable!()
         }
     }
-}
 
-#[unsafe_destructor]
-impl Drop for DBusError {
-    fn drop(&mut self) {
-        unsafe {
-            dbus_error_free(self);
+    pub fn io_error(&self) -> Option<&IoError> {
+        match *self.root() {
+            IoFailure(ref err) => Some(err),
+            _ => None
         }
     }
-}
 
-#[repr(C)]
-struct CDBusConnection {
-    refcount: i32,
-    _extra: [u8, ..1020]
-    // ...
-}
+    #[inline]
+    pub fn is(&self, name: &str) -> bool {
+        self.name() == name
+    }
 
-#[repr(C)]
-pub struct DBusConnection {
-    ptr: *mut CDBusConnection
-}
+    #[inline]
+    pub fn is_standard(&self, err: StandardError) -> bool {
+        self.is(err.name())
+    }
 
+    pub fn as_standard(&self) -> Option<StandardError> {
+        StandardError::from_name(self.name())
+    }
+}
 
-#[unsafe_destructor]
-impl Drop for DBusConnection {
-    fn drop(&mut self) {
-        unsafe {
-            dbus_connection_unref(self.ptr);
+impl fmt::Show for DBusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NamedError(ref name, ref message) => write!(f, "{}: {}", name, message),
+            IoFailure(ref err) => write!(f, "{}: {}", self.name(), err),
+            WithContext(ref context, ref inner) => write!(f, "{}: {}", context, inner)
         }
     }
 }
 
 
-impl DBusConnection {
-    pub fn open(address: &[u8]) -> DBusResult<DBusConnection> {
-        let mut error = DBusError::new_unsafe();
-        let conn: *mut CDBusConnection = unsafe {
-            dbus_connection_open(
-                address.as_ptr(),
-                &mut error)
-        };
-        if error.is_set() {
-            assert!(error.check_safe());
-            Err(error)
-        } else {
-            Ok(DBusConnection {
-                ptr: conn
-            })
-        }
-    }
+// How dispatch_pending() reacts to DBusDispatchStatus::NeedMemory.
+// libdbus leaves the message queued when it runs out of memory, so
+// we sleep and try again rather than spinning or giving up at once.
+#[deriving(Clone, Show)]
+pub struct NeedMemoryPolicy {
+    pub max_retries: uint,
+    pub initial_backoff_ms: i64,
+    pub max_backoff_ms: i64
+}
 
-    pub fn get_server_id(&mut self) -> CString {
-        unsafe {
-            let buf = dbus_connection_get_server_id(self.ptr);
-            CString::new(buf, true)
+impl NeedMemoryPolicy {
+    pub fn new(max_retries: uint, initial_backoff_ms: i64,
+               max_backoff_ms: i64) -> NeedMemoryPolicy {
+        NeedMemoryPolicy {
+            max_retries: max_retries,
+            initial_backoff_ms: initial_backoff_ms,
+            max_backoff_ms: max_backoff_ms
         }
     }
 
-    pub fn bus_register(&mut self) -> Result<(), DBusError> {
-        let mut error = DBusError::new_unsafe();
-        unsafe {
-            dbus_bus_register(self.ptr, &mut error);
-        }
-        if error.is_set() {
-            assert!(error.check_safe());
-            Err(error)
-        } else {
-            Ok(())
-        }
+    pub fn default() -> NeedMemoryPolicy {
+        NeedMemoryPolicy::new(8, 10, 1000)
     }
 
-    pub fn bus_request_name(&mut self, name: &str, flags: u32) -> DBusResult<i32> {
-        let mut error = DBusError::new_unsafe();
-        let name_cstr = name.to_c_str();
-        let response = unsafe {
-            dbus_bus_request_name(self.ptr, name_cstr.as_ptr(), flags, &mut error)
-        };
-        if error.is_set() {
-            if error.check_safe() {
-                fail!("unsafe error after dbus_bus_request_name");
+    // Doubles from initial_backoff_ms on each attempt, capped at max_backoff_ms.
+    pub fn backoff(&self, attempt: uint) -> Duration {
+        let mut millis = self.initial_backoff_ms;
+        for _ in range(0, attempt) {
+            if millis >= self.max_backoff_ms {
+                break;
             }
-            Err(error)
-        } else {
-            assert!(response > 0);
-            Ok(response)
+            millis *= 2;
         }
+        Duration::milliseconds(cmp::min(millis, self.max_backoff_ms))
     }
+}
 
-    pub fn dispatch(&mut self) -> DBusDispatchStatus {
-        DBusDispatchStatus::from_ord(unsafe {
-            dbus_connection_dispatch(self.ptr)
-        })
+
+// DBUS_SESSION_BUS_ADDRESS, or failing that the systemd user bus in
+// XDG_RUNTIME_DIR, or the address dbus-launch saved for this machine
+// and X display.
+pub fn get_dbus_session_address() -> Option<String> {
+    get_dbus_session_address_in(&ProcessEnvironment)
+}
+
+// These and the other _in functions read `env` in place of the process
+// environment.
+pub fn get_dbus_session_address_in(env: &Environment) -> Option<String> {
+    env.var("DBUS_SESSION_BUS_ADDRESS")
+        .or_else(|| get_dbus_user_bus_address_in(env))
+        .or_else(|| get_dbus_session_file_address_in(env))
+}
+
+// unix:path=$XDG_RUNTIME_DIR/bus, if there's a socket there.
+pub fn get_dbus_user_bus_address() -> Option<String> {
+    get_dbus_user_bus_address_in(&ProcessEnvironment)
+}
+
+pub fn get_dbus_user_bus_address_in(env: &Environment) -> Option<String> {
+    match env.var("XDG_RUNTIME_DIR") {
+        Some(ref dir) if !dir.is_empty() => user_bus_address(&Path::new(dir.as_slice())),
+        _ => None
     }
 }
 
-pub fn get_dbus_session_address() -> Option<String> {
-    for &(ref key, ref value) in os::env().iter() {
-        if key.as_slice() == "DBUS_SESSION_BUS_ADDRESS" {
-            return Some(value.clone());
+fn user_bus_address(runtime_dir: &Path) -> Option<String> {
+    let path = runtime_dir.join("bus");
+    if !path.exists() {
+        return None;
+    }
+    Some(format!("unix:path={}", address::escape_value(path.as_vec())))
+}
+
+// The address in ~/.dbus/session-bus/<machine id>-<display number>,
+// which dbus-launch writes when it starts a bus for a display.
+pub fn get_dbus_session_file_address() -> Option<String> {
+    get_dbus_session_file_address_in(&ProcessEnvironment)
+}
+
+pub fn get_dbus_session_file_address_in(env: &Environment) -> Option<String> {
+    let display = match env.var("DISPLAY").as_ref().and_then(|d| display_number(d.as_slice())) {
+        Some(display) => display,
+        None => return None
+    };
+    let home = match env.var("HOME") {
+        Some(ref home) if !home.is_empty() => Path::new(home.as_slice()),
+        _ => return None
+    };
+    let machine_id = match machine_id::read_machine_id() {
+        Ok(id) => id,
+        Err(_) => return None
+    };
+    let path = home.join(".dbus").join("session-bus").join(format!("{}-{}", machine_id, display));
+    match File::open(&path).read_to_string() {
+        Ok(contents) => parse_session_file(contents.as_slice()),
+        Err(_) => None
+    }
+}
+
+// "0" for ":0.0" or "host:0".
+fn display_number(display: &str) -> Option<String> {
+    let number = match display.rfind(':') {
+        Some(colon) => display.slice_from(colon + 1),
+        None => return None
+    };
+    let number = match number.find('.') {
+        Some(dot) => number.slice_to(dot),
+        None => number
+    };
+    if number.is_empty() || !number.chars().all(|c| c.is_digit()) {
+        return None;
+    }
+    Some(String::from_str(number))
+}
+
+// Session files are shell variable assignments, with # comments.
+fn parse_session_file(contents: &str) -> Option<String> {
+    for line in contents.lines() {
+        let line = line.trim();
+        if !line.starts_with("DBUS_SESSION_BUS_ADDRESS=") {
+            continue;
+        }
+        let value = line.slice_from("DBUS_SESSION_BUS_ADDRESS=".len());
+        let value = if value.len() >= 2 && value.starts_with("'") && value.ends_with("'") {
+            value.slice(1, value.len() - 1)
+        } else {
+            value
+        };
+        if !value.is_empty() {
+            return Some(String::from_str(value));
         }
     }
     None
 }
 
+pub const DEFAULT_SYSTEM_BUS_ADDRESS: &'static str =
+    "unix:path=/var/run/dbus/system_bus_socket";
+
+// DBUS_SYSTEM_BUS_ADDRESS, or the usual place.
+pub fn get_dbus_system_address() -> String {
+    get_dbus_system_address_in(&ProcessEnvironment)
+}
+
+pub fn get_dbus_system_address_in(env: &Environment) -> String {
+    env.var("DBUS_SYSTEM_BUS_ADDRESS").unwrap_or(String::from_str(DEFAULT_SYSTEM_BUS_ADDRESS))
+}
+
+// The bus that started us, when dbus-daemon activated this process.
+pub fn get_dbus_starter_address() -> Option<String> {
+    get_dbus_starter_address_in(&ProcessEnvironment)
+}
+
+pub fn get_dbus_starter_address_in(env: &Environment) -> Option<String> {
+    env.var("DBUS_STARTER_ADDRESS")
+}
+
+// "session" or "system", when dbus-daemon activated this process.
+pub fn get_dbus_starter_bus_type() -> Option<String> {
+    get_dbus_starter_bus_type_in(&ProcessEnvironment)
+}
+
+pub fn get_dbus_starter_bus_type_in(env: &Environment) -> Option<String> {
+    env.var("DBUS_STARTER_BUS_TYPE")
+}
+
+// Whether dbus-daemon started this process to own a name.
+pub fn is_bus_activated() -> bool {
+    is_bus_activated_in(&ProcessEnvironment)
+}
+
+pub fn is_bus_activated_in(env: &Environment) -> bool {
+    get_dbus_starter_address_in(env).is_some() || get_dbus_starter_bus_type_in(env).is_some()
+}
+
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::io::{standard_error, BrokenPipe, File, TempDir};
+    use std::time::Duration;
 
     #[test]
     fn test_connection() {
@@ -330,22 +691,22 @@ mod test {
         let mut dbus_conn = match DBusConnection::open(address.as_bytes()) {
             Ok(conn) => conn,
             Err(err) => {
-                fail!("DBus Connection failure: {}: {}", err.get_name(), err.get_message());
+                fail!("DBus Connection failure: {}: {}", err.name(), err.message());
             }
         };
 
         match dbus_conn.bus_register() {
             Ok(_) => (),
             Err(err) => {
-                fail!("DBus Registration failure failure: {}: {}", err.get_name(), err.get_message());
+                fail!("DBus Registration failure failure: {}: {}", err.name(), err.message());
             }
         };
 
-        let bus_name = "org.yasashiisyndicate.dbusexample";
+        let bus_name = BusName::from_static("org.yasashiisyndicate.dbusexample");
         match dbus_conn.bus_request_name(bus_name, 0) {
             Ok(_) => (),
             Err(err) => {
-                fail!("DBus RequestName failure failure: {}: {}", err.get_name(), err.get_message());
+                fail!("DBus RequestName failure failure: {}: {}", err.name(), err.message());
             }
         };
 
@@ -356,14 +717,110 @@ mod test {
     #[test]
     fn test_dbus_interface() {
         let mut dbus_introspectable = DBusInterface::new(
-            "org.freedesktop.DBus.Introspectable");
+            InterfaceName::from_static("org.freedesktop.DBus.Introspectable"));
         dbus_introspectable.add_method("Introspect", "", vec![], "");
 
-        let mut dbus_peer = DBusInterface::new("org.freedesktop.DBus.Peer");
+        let mut dbus_peer = DBusInterface::new(
+            InterfaceName::from_static("org.freedesktop.DBus.Peer"));
         dbus_peer.add_method("Ping", "", vec![], "");
         dbus_peer.add_method("GetMachineId", "", vec![], "s");
 
-        let mut frobulator = DBusInterface::new("org.yasashiisyndicate.Frobulator");
+        let mut frobulator = DBusInterface::new(
+            InterfaceName::from_static("org.yasashiisyndicate.Frobulator"));
         frobulator.add_method("Frobulate", "s", vec![String::from_str("value")], "s");
     }
+
+    #[test]
+    fn test_interface_builder() {
+        let iface = InterfaceBuilder::new("org.yasashiisyndicate.Frobulator")
+            .method("Frobulate").arg("s", "value").arg("i", String::from_str("count")).returns("s")
+            .method("Reset")
+            .signal("Frobulated", "s")
+            .build();
+        assert_eq!(iface.name(), "org.yasashiisyndicate.Frobulator");
+        assert_eq!(iface.members().len(), 3);
+        match iface.members()[0] {
+            Method(ref name, ref argspec, ref argnames, ref retspec) => {
+                assert_eq!(name.as_slice(), "Frobulate");
+                assert_eq!(argspec.as_slice(), "si");
+                assert_eq!(argnames.len(), 2);
+                assert_eq!(argnames[1].as_slice(), "count");
+                assert_eq!(retspec.as_slice(), "s");
+            },
+            _ => fail!("expected a method")
+        }
+    }
+
+    #[test]
+    fn test_timeout_millis_range() {
+        assert!(DBusTimeout::millis(0).is_ok());
+        assert!(DBusTimeout::millis(-1).is_err());
+        assert!(DBusTimeout::millis(0x7FFFFFFF).is_err());
+    }
+
+    #[test]
+    fn test_need_memory_backoff() {
+        let policy = NeedMemoryPolicy::new(5, 10, 50);
+        assert_eq!(policy.backoff(0), Duration::milliseconds(10));
+        assert_eq!(policy.backoff(1), Duration::milliseconds(20));
+        assert_eq!(policy.backoff(2), Duration::milliseconds(40));
+        assert_eq!(policy.backoff(3), Duration::milliseconds(50));
+        assert_eq!(policy.backoff(30), Duration::milliseconds(50));
+    }
+
+    #[test]
+    fn test_error_matching() {
+        let err = DBusError::standard(StandardError::AccessDenied, "go away");
+        assert!(err.is("org.freedesktop.DBus.Error.AccessDenied"));
+        assert!(err.is_standard(StandardError::AccessDenied));
+        assert!(!err.is_standard(StandardError::Failed));
+        assert_eq!(err.as_standard(), Some(StandardError::AccessDenied));
+        assert_eq!(err.message(), "go away");
+
+        let custom = DBusError::new("com.example.Error.Busy", "busy");
+        assert_eq!(custom.as_standard(), None);
+
+        let io = DBusError::from_io_error(standard_error(BrokenPipe));
+        assert!(io.is_standard(StandardError::IOError));
+        assert!(io.io_error().is_some());
+    }
+
+    #[test]
+    fn test_session_file() {
+        assert_eq!(super::display_number(":0"), Some(String::from_str("0")));
+        assert_eq!(super::display_number("frobhost:12.1"), Some(String::from_str("12")));
+        assert_eq!(super::display_number("wayland-0"), None);
+
+        let contents = "# This file allows processes on the machine to find the\n\
+                        # bus with the below address.\n\
+                        DBUS_SESSION_BUS_ADDRESS=unix:abstract=/tmp/dbus-Frob,guid=0123\n\
+                        DBUS_SESSION_BUS_PID=12345\n\
+                        DBUS_SESSION_BUS_WINDOWID=16777217\n";
+        assert_eq!(super::parse_session_file(contents),
+                   Some(String::from_str("unix:abstract=/tmp/dbus-Frob,guid=0123")));
+        assert_eq!(super::parse_session_file("DBUS_SESSION_BUS_PID=12345\n"), None);
+    }
+
+    #[test]
+    fn test_user_bus_address() {
+        let dir = TempDir::new("dbus-runtime dir").unwrap();
+        assert_eq!(super::user_bus_address(dir.path()), None);
+        File::create(&dir.path().join("bus")).unwrap();
+        let address = super::user_bus_address(dir.path()).unwrap();
+        assert!(address.as_slice().starts_with("unix:path=/"));
+        assert!(address.as_slice().contains("dbus-runtime%20dir"));
+        assert!(address.as_slice().ends_with("/bus"));
+    }
+
+    #[test]
+    fn test_error_context() {
+        let err = DBusError::standard(StandardError::AccessDenied, "go away")
+            .context("while requesting name org.foo")
+            .context("while starting up");
+        assert!(err.is_standard(StandardError::AccessDenied));
+        assert_eq!(err.message(), "go away");
+        assert_eq!(format!("{}", err).as_slice(),
+                   "while starting up: while requesting name org.foo: \
+                    org.freedesktop.DBus.Error.AccessDenied: go away");
+    }
 }
@@ -0,0 +1,480 @@
+use libc::{c_char, c_int, c_long, c_void};
+
+use std::c_str::CString;
+use std::io::timer;
+use std::ptr;
+use std::sync::Arc;
+use time::precise_time_ns;
+
+use ffi::*;
+
+use super::{DBusResult, DBusError, NamedError, StandardError};
+use super::{DBusDispatchStatus, NeedMemoryPolicy};
+use message::BUS_PATH;
+use names::{BusName, DBUS_BUS_NAME, DBUS_INTERFACE};
+use stats::ConnectionStats;
+
+
+impl CDBusError {
+    // An empty error for a libdbus call to fill in; check it with
+    // into_result().
+    fn new_unsafe() -> CDBusError {
+        let mut out = CDBusError {
+            name: ptr::null(),
+            message: ptr::null(),
+            _bitfields: 0,
+            _padding1: ptr::null()
+        };
+        unsafe {
+            dbus_error_init(&mut out);
+        }
+        out
+    }
+
+    fn is_set(&self) -> bool {
+        unsafe {
+            dbus_error_is_set(self) > 0
+        }
+    }
+
+    // Converts an error filled in by a libdbus call into a result,
+    // replacing errors libdbus left without a name.  Both strings still
+    // belong to the error and are freed with it.
+    fn into_result<T>(self, value: T, func: &str) -> DBusResult<T> {
+        if !self.is_set() {
+            return Ok(value);
+        }
+        let (name, message) = unsafe {
+            (borrow_c_str(self.name), borrow_c_str(self.message))
+        };
+        match name {
+            Some(name) => Err(NamedError(name, message.unwrap_or(String::new()))),
+            None => Err(DBusError::standard(StandardError::Failed,
+                                            format!("unnamed error after {}", func).as_slice()))
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl Drop for CDBusError {
+    fn drop(&mut self) {
+        unsafe {
+            dbus_error_free(self);
+        }
+    }
+}
+
+// Copies a string libdbus still owns, or None for NULL.  Never frees.
+unsafe fn borrow_c_str(buf: *const c_char) -> Option<String> {
+    if buf.is_null() {
+        return None;
+    }
+    let cstr = CString::new(buf, false);
+    Some(match cstr.as_str() {
+        Some(s) => String::from_str(s),
+        None => String::from_utf8_lossy(cstr.as_bytes_no_nul()).into_string()
+    })
+}
+
+// Copies a string libdbus handed over to us, then frees it with
+// dbus_free(), the allocator it came from.
+unsafe fn take_c_str(buf: *mut c_char) -> Option<String> {
+    let copy = borrow_c_str(buf as *const c_char);
+    if buf.is_not_null() {
+        dbus_free(buf as *mut c_void);
+    }
+    copy
+}
+
+// Turns on libdbus's own locking, so one connection can be used from
+// several tasks.  Safe to call more than once.
+pub fn init_threads() -> DBusResult<()> {
+    if unsafe { dbus_threads_init_default() } == 0 {
+        Err(DBusError::standard(StandardError::NoMemory,
+                                "dbus_threads_init_default failed"))
+    } else {
+        Ok(())
+    }
+}
+
+// Closes a private connection once the last handle to it is dropped.
+// Holds a reference of its own so the connection outlives it.
+struct Closer {
+    ptr: *mut CDBusConnection
+}
+
+#[unsafe_destructor]
+impl Drop for Closer {
+    fn drop(&mut self) {
+        unsafe {
+            dbus_connection_close(self.ptr);
+            dbus_connection_unref(self.ptr);
+        }
+    }
+}
+
+pub struct DBusConnection {
+    ptr: *mut CDBusConnection,
+    // Only for private connections: shared ones belong to libdbus and
+    // must never be closed
+    closer: Option<Arc<Closer>>,
+    need_memory_policy: NeedMemoryPolicy,
+    // Fetched on first use
+    server_id: Option<String>,
+    stats: ConnectionStats
+}
+
+
+#[unsafe_destructor]
+impl Drop for DBusConnection {
+    fn drop(&mut self) {
+        unsafe {
+            dbus_connection_unref(self.ptr);
+        }
+    }
+}
+
+// Another handle to the same connection, holding its own reference.
+// A private connection is closed when the last of them is dropped.
+// Each handle keeps its own stats.
+impl Clone for DBusConnection {
+    fn clone(&self) -> DBusConnection {
+        DBusConnection {
+            ptr: unsafe { dbus_connection_ref(self.ptr) },
+            closer: self.closer.clone(),
+            need_memory_policy: self.need_memory_policy.clone(),
+            server_id: self.server_id.clone(),
+            stats: self.stats.clone()
+        }
+    }
+}
+
+
+impl DBusConnection {
+    // Opens a shared connection: libdbus hands back the same connection
+    // to everyone in the process who opens this address, and dropping
+    // it only releases our reference.
+    pub fn open(address: &[u8]) -> DBusResult<DBusConnection> {
+        DBusConnection::open_address(address, true)
+    }
+
+    // Opens a connection of our own, which is closed when dropped.
+    pub fn open_private(address: &[u8]) -> DBusResult<DBusConnection> {
+        DBusConnection::open_address(address, false)
+    }
+
+    // The process's shared session bus connection, registered already.
+    pub fn session() -> DBusResult<DBusConnection> {
+        DBusConnection::bus_get(DBUS_BUS_SESSION, true)
+    }
+
+    // The process's shared system bus connection, registered already.
+    pub fn system() -> DBusResult<DBusConnection> {
+        DBusConnection::bus_get(DBUS_BUS_SYSTEM, true)
+    }
+
+    // The bus that activated this process, from DBUS_STARTER_ADDRESS.
+    pub fn starter() -> DBusResult<DBusConnection> {
+        DBusConnection::bus_get(DBUS_BUS_STARTER, true)
+    }
+
+    pub fn session_private() -> DBusResult<DBusConnection> {
+        DBusConnection::bus_get(DBUS_BUS_SESSION, false)
+    }
+
+    pub fn system_private() -> DBusResult<DBusConnection> {
+        DBusConnection::bus_get(DBUS_BUS_SYSTEM, false)
+    }
+
+    pub fn starter_private() -> DBusResult<DBusConnection> {
+        DBusConnection::bus_get(DBUS_BUS_STARTER, false)
+    }
+
+    fn open_address(address: &[u8], shared: bool) -> DBusResult<DBusConnection> {
+        if address.contains(&0) {
+            return Err(DBusError::standard(StandardError::BadAddress,
+                                           "address contains a NUL byte"));
+        }
+        let address = address.to_c_str();
+        let mut error = CDBusError::new_unsafe();
+        let (conn, func) = unsafe {
+            if shared {
+                (dbus_connection_open(address.as_ptr(), &mut error), "dbus_connection_open")
+            } else {
+                (dbus_connection_open_private(address.as_ptr(), &mut error),
+                 "dbus_connection_open_private")
+            }
+        };
+        DBusConnection::from_raw(conn, shared, error, func)
+    }
+
+    fn bus_get(bus_type: c_int, shared: bool) -> DBusResult<DBusConnection> {
+        let mut error = CDBusError::new_unsafe();
+        let (conn, func) = unsafe {
+            if shared {
+                (dbus_bus_get(bus_type, &mut error), "dbus_bus_get")
+            } else {
+                (dbus_bus_get_private(bus_type, &mut error), "dbus_bus_get_private")
+            }
+        };
+        DBusConnection::from_raw(conn, shared, error, func)
+    }
+
+    // Takes ownership of a reference returned by `func`.
+    fn from_raw(conn: *mut CDBusConnection, shared: bool, error: CDBusError,
+                func: &str) -> DBusResult<DBusConnection> {
+        try!(error.into_result((), func));
+        // libdbus can fail to allocate without filling in the error
+        if conn.is_null() {
+            return Err(DBusError::standard(StandardError::NoMemory,
+                                           format!("{} returned NULL", func).as_slice()));
+        }
+        debug!("{} opened a {} connection", func, if shared { "shared" } else { "private" });
+        // dbus_bus_get() turns exit-on-disconnect on, so a bus daemon
+        // restart would take the whole process down with it
+        unsafe {
+            dbus_connection_set_exit_on_disconnect(conn, 0);
+        }
+        let closer = if shared {
+            None
+        } else {
+            Some(Arc::new(Closer { ptr: unsafe { dbus_connection_ref(conn) } }))
+        };
+        Ok(DBusConnection {
+            ptr: conn,
+            closer: closer,
+            need_memory_policy: NeedMemoryPolicy::default(),
+            server_id: None,
+            stats: Default::default()
+        })
+    }
+
+    #[inline]
+    pub fn is_shared(&self) -> bool {
+        self.closer.is_none()
+    }
+
+    // Empty if libdbus couldn't allocate the id, in which case the
+    // next call tries again.
+    pub fn get_server_id(&mut self) -> &str {
+        if self.server_id.is_none() {
+            self.server_id = unsafe {
+                take_c_str(dbus_connection_get_server_id(self.ptr))
+            };
+        }
+        match self.server_id {
+            Some(ref id) => id.as_slice(),
+            None => ""
+        }
+    }
+
+    // Whether libdbus calls _exit() when the connection drops.  Off for
+    // every connection we open, including the bus connections where
+    // libdbus would turn it on.  On a shared connection this affects
+    // everyone in the process using it.
+    pub fn set_exit_on_disconnect(&mut self, exit: bool) {
+        unsafe {
+            dbus_connection_set_exit_on_disconnect(self.ptr, exit as dbus_bool_t);
+        }
+    }
+
+    // libdbus drops the connection if a peer sends a longer message.
+    pub fn set_max_message_size(&mut self, size: uint) {
+        unsafe {
+            dbus_connection_set_max_message_size(self.ptr, size as c_long);
+        }
+    }
+
+    pub fn get_max_message_size(&self) -> uint {
+        unsafe {
+            dbus_connection_get_max_message_size(self.ptr) as uint
+        }
+    }
+
+    // libdbus stops reading once this many bytes of messages are
+    // queued and not yet dispatched.
+    pub fn set_max_received_size(&mut self, size: uint) {
+        unsafe {
+            dbus_connection_set_max_received_size(self.ptr, size as c_long);
+        }
+    }
+
+    pub fn get_max_received_size(&self) -> uint {
+        unsafe {
+            dbus_connection_get_max_received_size(self.ptr) as uint
+        }
+    }
+
+    // libdbus drops the connection if a peer sends a message with more
+    // unix fds than this.
+    pub fn set_max_message_unix_fds(&mut self, n: uint) {
+        unsafe {
+            dbus_connection_set_max_message_unix_fds(self.ptr, n as c_long);
+        }
+    }
+
+    pub fn get_max_message_unix_fds(&self) -> uint {
+        unsafe {
+            dbus_connection_get_max_message_unix_fds(self.ptr) as uint
+        }
+    }
+
+    // libdbus stops reading once queued messages hold this many fds.
+    pub fn set_max_received_unix_fds(&mut self, n: uint) {
+        unsafe {
+            dbus_connection_set_max_received_unix_fds(self.ptr, n as c_long);
+        }
+    }
+
+    pub fn get_max_received_unix_fds(&self) -> uint {
+        unsafe {
+            dbus_connection_get_max_received_unix_fds(self.ptr) as uint
+        }
+    }
+
+    pub fn bus_register(&mut self) -> Result<(), DBusError> {
+        let mut error = CDBusError::new_unsafe();
+        unsafe {
+            dbus_bus_register(self.ptr, &mut error);
+        }
+        error.into_result((), "dbus_bus_register")
+    }
+
+    pub fn bus_request_name(&mut self, name: BusName, flags: u32) -> DBusResult<i32> {
+        let mut error = CDBusError::new_unsafe();
+        let name_cstr = name.as_str().to_c_str();
+        let response = unsafe {
+            dbus_bus_request_name(self.ptr, name_cstr.as_ptr(), flags, &mut error)
+        };
+        let response = try!(error.into_result(response, "dbus_bus_request_name"));
+        if response > 0 {
+            Ok(response)
+        } else {
+            Err(DBusError::standard(StandardError::Failed,
+                                    format!("unexpected dbus_bus_request_name reply: {}",
+                                            response).as_slice()))
+        }
+    }
+
+    // The uid of the process owning `name`.
+    pub fn get_connection_unix_user(&mut self, name: BusName) -> DBusResult<u32> {
+        let mut error = CDBusError::new_unsafe();
+        let name_cstr = name.as_str().to_c_str();
+        let uid = unsafe {
+            dbus_bus_get_unix_user(self.ptr, name_cstr.as_ptr(), &mut error)
+        };
+        error.into_result(uid as u32, "dbus_bus_get_unix_user")
+    }
+
+    // The pid of the process owning `name`.  libdbus has no helper for
+    // this one, so we make the call ourselves.
+    pub fn get_connection_unix_process_id(&mut self, name: BusName) -> DBusResult<u32> {
+        let destination = DBUS_BUS_NAME.as_str().to_c_str();
+        let path = BUS_PATH.to_c_str();
+        let interface = DBUS_INTERFACE.as_str().to_c_str();
+        let method = "GetConnectionUnixProcessID".to_c_str();
+        let name_cstr = name.as_str().to_c_str();
+        let name_ptr = name_cstr.as_ptr();
+        let mut pid = 0u32;
+        unsafe {
+            let call = dbus_message_new_method_call(destination.as_ptr(), path.as_ptr(),
+                                                    interface.as_ptr(), method.as_ptr());
+            if call.is_null() || dbus_message_append_args(call, DBUS_TYPE_STRING,
+                                                          &name_ptr as *const *const c_char,
+                                                          DBUS_TYPE_INVALID) == 0 {
+                if call.is_not_null() {
+                    dbus_message_unref(call);
+                }
+                return Err(DBusError::standard(StandardError::NoMemory,
+                                               "couldn't build GetConnectionUnixProcessID"));
+            }
+            let mut error = CDBusError::new_unsafe();
+            let reply = dbus_connection_send_with_reply_and_block(self.ptr, call,
+                                                                  DBUS_TIMEOUT_USE_DEFAULT,
+                                                                  &mut error);
+            dbus_message_unref(call);
+            try!(error.into_result((), "dbus_connection_send_with_reply_and_block"));
+            if reply.is_null() {
+                return Err(DBusError::standard(StandardError::NoMemory,
+                                               "dbus_connection_send_with_reply_and_block \
+                                                returned NULL"));
+            }
+            let mut error = CDBusError::new_unsafe();
+            dbus_message_get_args(reply, &mut error, DBUS_TYPE_UINT32, &mut pid as *mut u32,
+                                  DBUS_TYPE_INVALID);
+            dbus_message_unref(reply);
+            error.into_result(pid, "dbus_message_get_args")
+        }
+    }
+
+    pub fn dispatch(&mut self) -> DBusDispatchStatus {
+        let start = precise_time_ns();
+        let status = DBusDispatchStatus::from_ord(unsafe {
+            dbus_connection_dispatch(self.ptr)
+        });
+        match status {
+            DBusDispatchStatus::NeedMemory => (),
+            _ => {
+                self.stats.dispatched += 1;
+                self.stats.dispatch_time_ns += precise_time_ns() - start;
+            }
+        }
+        status
+    }
+
+    // libdbus does its own reading and writing, so only the dispatch
+    // counters are kept, and they include calls that found nothing
+    // queued; the traffic counters stay at zero.
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.clone()
+    }
+
+    pub fn set_need_memory_policy(&mut self, policy: NeedMemoryPolicy) {
+        self.need_memory_policy = policy;
+    }
+
+    // Dispatches until the incoming queue is drained, backing off and
+    // retrying per the NeedMemoryPolicy when libdbus runs out of memory.
+    pub fn dispatch_pending(&mut self) -> DBusResult<()> {
+        let mut attempt = 0u;
+        loop {
+            match self.dispatch() {
+                DBusDispatchStatus::DataRemains => attempt = 0,
+                DBusDispatchStatus::Complete => return Ok(()),
+                DBusDispatchStatus::NeedMemory => {
+                    if attempt >= self.need_memory_policy.max_retries {
+                        error!("dispatch out of memory, giving up after {} retries", attempt);
+                        return Err(DBusError::standard(
+                            StandardError::NoMemory,
+                            format!("dispatch still out of memory after {} retries",
+                                    attempt).as_slice()));
+                    }
+                    warn!("dispatch out of memory, retrying (attempt {})", attempt + 1);
+                    timer::sleep(self.need_memory_policy.backoff(attempt));
+                    attempt += 1;
+                },
+                DBusDispatchStatus::Unknown(status) => {
+                    return Err(DBusError::standard(
+                        StandardError::Failed,
+                        format!("unsupported DBusDispatchStatus: {}", status).as_slice()));
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::borrow_c_str;
+    use std::ptr;
+
+    #[test]
+    fn test_borrow_c_str() {
+        unsafe {
+            assert_eq!(borrow_c_str(ptr::null()), None);
+            let cstr = "server".to_c_str();
+            assert_eq!(borrow_c_str(cstr.as_ptr()), Some(String::from_str("server")));
+        }
+    }
+}
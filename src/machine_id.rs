@@ -0,0 +1,66 @@
+// The machine id: 32 lowercase hex digits naming this installation,
+// which org.freedesktop.DBus.Peer.GetMachineId returns and X11
+// autolaunch keys its bus on.
+
+use std::io::File;
+
+use super::{DBusResult, DBusError, StandardError};
+
+
+// Where to look, in order.  dbus-daemon's own copy comes first, as
+// libdbus reads it.
+pub static MACHINE_ID_PATHS: [&'static str, ..2] = ["/var/lib/dbus/machine-id",
+                                                     "/etc/machine-id"];
+
+pub fn is_valid_machine_id(id: &str) -> bool {
+    id.len() == 32 && id.chars().all(|c| (c >= '0' && c <= '9') || (c >= 'a' && c <= 'f'))
+}
+
+// Reads and checks the id in `path`.  The trailing newline is optional.
+pub fn read_machine_id_from(path: &Path) -> DBusResult<String> {
+    let contents = try!(File::open(path).read_to_string().map_err(DBusError::from_io_error));
+    let id = contents.as_slice().trim_right_chars('\n');
+    if !is_valid_machine_id(id) {
+        return Err(DBusError::standard(StandardError::InvalidFileContent,
+                                       format!("{} does not hold a machine id",
+                                               path.display()).as_slice()));
+    }
+    Ok(String::from_str(id))
+}
+
+// This machine's id, from the first of MACHINE_ID_PATHS to hold one.
+pub fn read_machine_id() -> DBusResult<String> {
+    let mut last_err = None;
+    for path in MACHINE_ID_PATHS.iter() {
+        match read_machine_id_from(&Path::new(*path)) {
+            Ok(id) => return Ok(id),
+            Err(err) => last_err = Some(err)
+        }
+    }
+    Err(last_err.unwrap())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{File, TempDir};
+
+    use super::*;
+
+    #[test]
+    fn test_machine_id() {
+        assert!(is_valid_machine_id("0123456789abcdef0123456789abcdef"));
+        assert!(!is_valid_machine_id("0123456789ABCDEF0123456789ABCDEF"));
+        assert!(!is_valid_machine_id("0123456789abcdef"));
+        assert!(!is_valid_machine_id(""));
+
+        let dir = TempDir::new("dbus-machine-id").unwrap();
+        let path = dir.path().join("machine-id");
+        File::create(&path).write_str("0123456789abcdef0123456789abcdef\n").unwrap();
+        assert_eq!(read_machine_id_from(&path).unwrap().as_slice(),
+                   "0123456789abcdef0123456789abcdef");
+        File::create(&path).write_str("uninitialized\n").unwrap();
+        assert!(read_machine_id_from(&path).is_err());
+        assert!(read_machine_id_from(&dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,652 @@
+use std::fmt;
+use std::mem;
+use std::str;
+
+use value::{DBusType, Value, parse_single_type, is_valid_object_path};
+use value::{Byte, Boolean, Int16, UInt16, Int32, UInt32, Int64, UInt64, Double};
+use value::{Str, ObjectPath, Signature, UnixFd, Array, Struct, DictEntry, Variant};
+use super::{DBusError, StandardError};
+
+
+// from the D-Bus specification
+pub const MAX_ARRAY_LEN: uint = 1 << 26;
+// Containers, variants included, may nest at most this deep
+pub const MAX_DEPTH: uint = 64;
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum ParseError {
+    // More bytes were needed than the buffer holds, at this offset
+    UnexpectedEof(uint),
+    InvalidSignature(String),
+    // Padding at this offset was not zero
+    InvalidPadding(uint),
+    // The string at this offset was not NUL terminated
+    MissingNul(uint),
+    // The array at this offset was longer than allowed or than its contents
+    InvalidArrayLength(uint),
+    // The string at this offset was not valid UTF-8, or held a NUL
+    InvalidString(uint),
+    // The object path at this offset was malformed
+    InvalidObjectPath(uint),
+    // The boolean at this offset was neither 0 nor 1
+    InvalidBoolean(uint),
+    // The container at this offset nested deeper than MAX_DEPTH
+    TooDeep(uint),
+    // A message of this length was over the receiver's limit
+    MessageTooLong(uint),
+    InvalidHeader(String),
+    UnsupportedEndianness(u8)
+}
+
+impl ParseError {
+    pub fn to_dbus_error(&self) -> DBusError {
+        let kind = match *self {
+            MessageTooLong(_) => StandardError::LimitsExceeded,
+            _ => StandardError::InconsistentMessage
+        };
+        DBusError::standard(kind, format!("{}", self).as_slice())
+    }
+}
+
+
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Endianness {
+    LittleEndian,
+    BigEndian
+}
+
+impl Endianness {
+    // From the first byte of a message header.
+    pub fn from_marker(marker: u8) -> Option<Endianness> {
+        match marker as char {
+            'l' => Some(LittleEndian),
+            'B' => Some(BigEndian),
+            _ => None
+        }
+    }
+
+    pub fn marker(&self) -> u8 {
+        match *self {
+            LittleEndian => 'l' as u8,
+            BigEndian => 'B' as u8
+        }
+    }
+
+    // The byte shift for byte `i` of a `size` byte integer.
+    #[inline]
+    fn shift(&self, i: uint, size: uint) -> uint {
+        match *self {
+            LittleEndian => 8 * i,
+            BigEndian => 8 * (size - 1 - i)
+        }
+    }
+}
+
+
+// An array being written, returned by Encoder::open_array().
+pub struct OpenArray {
+    len_offset: uint,
+    start: uint
+}
+
+
+// Marshals values, padding each value to its alignment relative to
+// the start of the buffer.  Little-endian unless asked otherwise.
+pub struct Encoder {
+    buf: Vec<u8>,
+    endianness: Endianness
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::with_endianness(LittleEndian)
+    }
+
+    pub fn with_endianness(endianness: Endianness) -> Encoder {
+        Encoder::from_vec(Vec::new(), endianness)
+    }
+
+    // Encodes into `buf`, which is cleared first, reusing its allocation.
+    pub fn from_vec(mut buf: Vec<u8>, endianness: Endianness) -> Encoder {
+        buf.clear();
+        Encoder {
+            buf: buf,
+            endianness: endianness
+        }
+    }
+
+    #[inline]
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    #[inline]
+    pub fn len(&self) -> uint {
+        self.buf.len()
+    }
+
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        self.buf.as_slice()
+    }
+
+    #[inline]
+    pub fn unwrap(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn align(&mut self, alignment: uint) {
+        while self.buf.len() % alignment != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn put(&mut self, value: u64, size: uint) {
+        self.align(size);
+        for i in range(0, size) {
+            self.buf.push((value >> self.endianness.shift(i, size)) as u8);
+        }
+    }
+
+    // Overwrites a previously written u32, used to fill in lengths.
+    pub fn patch_u32(&mut self, offset: uint, value: u32) {
+        for i in range(0u, 4) {
+            *self.buf.get_mut(offset + i) = (value >> self.endianness.shift(i, 4)) as u8;
+        }
+    }
+
+    #[inline]
+    pub fn write_byte(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    #[inline]
+    pub fn write_bytes(&mut self, value: &[u8]) {
+        self.buf.push_all(value);
+    }
+
+    #[inline]
+    pub fn write_u16(&mut self, value: u16) {
+        self.put(value as u64, 2);
+    }
+
+    #[inline]
+    pub fn write_u32(&mut self, value: u32) {
+        self.put(value as u64, 4);
+    }
+
+    #[inline]
+    pub fn write_u64(&mut self, value: u64) {
+        self.put(value, 8);
+    }
+
+    #[inline]
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u32(if value { 1 } else { 0 });
+    }
+
+    #[inline]
+    pub fn write_f64(&mut self, value: f64) {
+        self.write_u64(unsafe { mem::transmute(value) });
+    }
+
+    pub fn write_str(&mut self, value: &str) {
+        self.write_u32(value.len() as u32);
+        self.buf.push_all(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    pub fn write_signature(&mut self, value: &str) {
+        self.write_byte(value.len() as u8);
+        self.buf.push_all(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    // Starts an array of `elem_type`, whose elements are then written
+    // directly.  The padding before the first element is not part of
+    // the array length, and is present even if the array is empty.
+    pub fn open_array(&mut self, elem_type: &DBusType) -> OpenArray {
+        self.write_u32(0);
+        let len_offset = self.buf.len() - 4;
+        self.align(elem_type.alignment());
+        OpenArray {
+            len_offset: len_offset,
+            start: self.buf.len()
+        }
+    }
+
+    // Fills in the length of an array started with open_array().
+    pub fn close_array(&mut self, array: OpenArray) {
+        let len = (self.buf.len() - array.start) as u32;
+        self.patch_u32(array.len_offset, len);
+    }
+
+    // Structs and dict entries have no header, just 8-byte alignment.
+    #[inline]
+    pub fn open_struct(&mut self) {
+        self.align(8);
+    }
+
+    pub fn write_value(&mut self, value: &Value) {
+        match *value {
+            Byte(v) => self.write_byte(v),
+            Boolean(v) => self.write_bool(v),
+            Int16(v) => self.write_u16(v as u16),
+            UInt16(v) => self.write_u16(v),
+            Int32(v) => self.write_u32(v as u32),
+            UInt32(v) => self.write_u32(v),
+            Int64(v) => self.write_u64(v as u64),
+            UInt64(v) => self.write_u64(v),
+            Double(v) => self.write_f64(v),
+            Str(ref v) | ObjectPath(ref v) => self.write_str(v.as_slice()),
+            Signature(ref v) => self.write_signature(v.as_slice()),
+            UnixFd(v) => self.write_u32(v),
+            Array(ref elem_type, ref items) => {
+                let array = self.open_array(elem_type);
+                for item in items.iter() {
+                    self.write_value(item);
+                }
+                self.close_array(array);
+            },
+            Struct(ref fields) => {
+                self.open_struct();
+                for field in fields.iter() {
+                    self.write_value(field);
+                }
+            },
+            DictEntry(ref key, ref value) => {
+                self.open_struct();
+                self.write_value(&**key);
+                self.write_value(&**value);
+            },
+            Variant(ref inner) => {
+                self.write_signature(inner.signature().as_slice());
+                self.write_value(&**inner);
+            }
+        }
+    }
+}
+
+
+// Demarshals values from a buffer, with alignment relative to the
+// start of the buffer.  Little-endian unless asked otherwise.
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: uint,
+    endianness: Endianness,
+    lossy_utf8: bool,
+    depth: uint
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Decoder<'a> {
+        Decoder::with_endianness(buf, LittleEndian)
+    }
+
+    pub fn with_endianness(buf: &'a [u8], endianness: Endianness) -> Decoder<'a> {
+        Decoder {
+            buf: buf,
+            pos: 0,
+            endianness: endianness,
+            lossy_utf8: false,
+            depth: 0
+        }
+    }
+
+    // Instead of rejecting bad strings, substitute U+FFFD for invalid
+    // UTF-8 and accept malformed object paths, for inspecting traffic
+    // from peers that don't follow the spec.
+    pub fn set_lossy_utf8(&mut self, lossy: bool) {
+        self.lossy_utf8 = lossy;
+    }
+
+    #[inline]
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    #[inline]
+    pub fn position(&self) -> uint {
+        self.pos
+    }
+
+    #[inline]
+    pub fn remaining(&self) -> uint {
+        self.buf.len() - self.pos
+    }
+
+    pub fn align(&mut self, alignment: uint) -> Result<(), ParseError> {
+        let padding = (alignment - self.pos % alignment) % alignment;
+        let start = self.pos;
+        let bytes = try!(self.take(padding));
+        if bytes.iter().any(|b| *b != 0) {
+            return Err(InvalidPadding(start));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, len: uint) -> Result<&'a [u8], ParseError> {
+        if self.remaining() < len {
+            return Err(UnexpectedEof(self.pos));
+        }
+        let out = self.buf.slice(self.pos, self.pos + len);
+        self.pos += len;
+        Ok(out)
+    }
+
+    fn get(&mut self, size: uint) -> Result<u64, ParseError> {
+        try!(self.align(size));
+        let bytes = try!(self.take(size));
+        let mut out = 0u64;
+        for (i, b) in bytes.iter().enumerate() {
+            out |= (*b as u64) << self.endianness.shift(i, size);
+        }
+        Ok(out)
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, ParseError> {
+        Ok(try!(self.take(1))[0])
+    }
+
+    #[inline]
+    pub fn read_u16(&mut self) -> Result<u16, ParseError> {
+        Ok(try!(self.get(2)) as u16)
+    }
+
+    #[inline]
+    pub fn read_u32(&mut self) -> Result<u32, ParseError> {
+        Ok(try!(self.get(4)) as u32)
+    }
+
+    #[inline]
+    pub fn read_u64(&mut self) -> Result<u64, ParseError> {
+        self.get(8)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, ParseError> {
+        try!(self.align(4));
+        let offset = self.pos;
+        match try!(self.read_u32()) {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(InvalidBoolean(offset))
+        }
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, ParseError> {
+        Ok(unsafe { mem::transmute(try!(self.read_u64())) })
+    }
+
+    // Takes `len` bytes followed by a NUL, returning the bytes.
+    fn take_nul_terminated(&mut self, len: uint) -> Result<&'a [u8], ParseError> {
+        let bytes = try!(self.take(len));
+        let nul_offset = self.pos;
+        if try!(self.read_byte()) != 0 {
+            return Err(MissingNul(nul_offset));
+        }
+        Ok(bytes)
+    }
+
+    fn check_str(bytes: &'a [u8], start: uint) -> Result<&'a str, ParseError> {
+        if bytes.contains(&0) {
+            return Err(InvalidString(start));
+        }
+        match str::from_utf8(bytes) {
+            Some(s) => Ok(s),
+            None => Err(InvalidString(start))
+        }
+    }
+
+    fn read_nul_terminated(&mut self, len: uint) -> Result<String, ParseError> {
+        let start = self.pos;
+        let bytes = try!(self.take_nul_terminated(len));
+        if self.lossy_utf8 {
+            return Ok(String::from_utf8_lossy(bytes).into_string());
+        }
+        Ok(String::from_str(try!(Decoder::check_str(bytes, start))))
+    }
+
+    pub fn read_str(&mut self) -> Result<String, ParseError> {
+        let len = try!(self.read_u32()) as uint;
+        self.read_nul_terminated(len)
+    }
+
+    pub fn read_signature(&mut self) -> Result<String, ParseError> {
+        let len = try!(self.read_byte()) as uint;
+        self.read_nul_terminated(len)
+    }
+
+    // Borrowing versions of the above, which always validate since
+    // there's nowhere to put a repaired copy.
+    pub fn read_str_ref(&mut self) -> Result<&'a str, ParseError> {
+        let len = try!(self.read_u32()) as uint;
+        let start = self.pos;
+        let bytes = try!(self.take_nul_terminated(len));
+        Decoder::check_str(bytes, start)
+    }
+
+    pub fn read_signature_ref(&mut self) -> Result<&'a str, ParseError> {
+        let len = try!(self.read_byte()) as uint;
+        let start = self.pos;
+        let bytes = try!(self.take_nul_terminated(len));
+        Decoder::check_str(bytes, start)
+    }
+
+    // The next `len` raw bytes, without alignment.
+    #[inline]
+    pub fn read_raw(&mut self, len: uint) -> Result<&'a [u8], ParseError> {
+        self.take(len)
+    }
+
+    pub fn read_value(&mut self, ty: &DBusType) -> Result<Value, ParseError> {
+        if ty.is_basic() {
+            return self.read_basic(ty);
+        }
+        // variants can nest without limit in the signature, so the
+        // depth has to be checked as we go
+        if self.depth == MAX_DEPTH {
+            return Err(TooDeep(self.pos));
+        }
+        self.depth += 1;
+        let value = self.read_container(ty);
+        self.depth -= 1;
+        value
+    }
+
+    fn read_basic(&mut self, ty: &DBusType) -> Result<Value, ParseError> {
+        Ok(match *ty {
+            DBusType::Byte => Byte(try!(self.read_byte())),
+            DBusType::Boolean => Boolean(try!(self.read_bool())),
+            DBusType::Int16 => Int16(try!(self.read_u16()) as i16),
+            DBusType::UInt16 => UInt16(try!(self.read_u16())),
+            DBusType::Int32 => Int32(try!(self.read_u32()) as i32),
+            DBusType::UInt32 => UInt32(try!(self.read_u32())),
+            DBusType::Int64 => Int64(try!(self.read_u64()) as i64),
+            DBusType::UInt64 => UInt64(try!(self.read_u64())),
+            DBusType::Double => Double(try!(self.read_f64())),
+            DBusType::Str => Str(try!(self.read_str())),
+            DBusType::ObjectPath => {
+                try!(self.align(4));
+                let start = self.pos;
+                let path = try!(self.read_str());
+                if !self.lossy_utf8 && !is_valid_object_path(path.as_slice()) {
+                    return Err(InvalidObjectPath(start));
+                }
+                ObjectPath(path)
+            },
+            DBusType::Signature => Signature(try!(self.read_signature())),
+            DBusType::UnixFd => UnixFd(try!(self.read_u32())),
+            _ => unreachable!()
+        })
+    }
+
+    fn read_container(&mut self, ty: &DBusType) -> Result<Value, ParseError> {
+        Ok(match *ty {
+            DBusType::Array(ref elem_type) => {
+                let len_offset = self.pos;
+                let len = try!(self.read_u32()) as uint;
+                if len > MAX_ARRAY_LEN {
+                    return Err(InvalidArrayLength(len_offset));
+                }
+                try!(self.align(elem_type.alignment()));
+                let end = self.pos + len;
+                if end > self.buf.len() {
+                    return Err(UnexpectedEof(self.buf.len()));
+                }
+                let mut items = Vec::new();
+                while self.pos < end {
+                    items.push(try!(self.read_value(&**elem_type)));
+                }
+                if self.pos != end {
+                    return Err(InvalidArrayLength(len_offset));
+                }
+                Array((**elem_type).clone(), items)
+            },
+            DBusType::Struct(ref field_types) => {
+                try!(self.align(8));
+                let mut fields = Vec::with_capacity(field_types.len());
+                for field_type in field_types.iter() {
+                    fields.push(try!(self.read_value(field_type)));
+                }
+                Struct(fields)
+            },
+            DBusType::DictEntry(ref key_type, ref value_type) => {
+                try!(self.align(8));
+                let key = try!(self.read_value(&**key_type));
+                let value = try!(self.read_value(&**value_type));
+                DictEntry(box key, box value)
+            },
+            DBusType::Variant => {
+                let sig = try!(self.read_signature());
+                let inner_type = try!(parse_single_type(sig.as_slice()));
+                Variant(box try!(self.read_value(&inner_type)))
+            },
+            _ => unreachable!()
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use value::{DBusType, Value, Byte, UInt16, Int32, UInt32, Str, Array, Struct, Variant};
+
+    fn round_trip(value: Value) {
+        let mut enc = Encoder::new();
+        enc.write_value(&value);
+        let buf = enc.unwrap();
+        let mut dec = Decoder::new(buf.as_slice());
+        assert_eq!(dec.read_value(&value.get_type()).unwrap(), value);
+        assert_eq!(dec.remaining(), 0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(Int32(-5));
+        round_trip(Str(String::from_str("hello")));
+        round_trip(Struct(vec![Byte(1), Variant(box Int32(7))]));
+        round_trip(Array(DBusType::Int32, vec![Int32(1), Int32(2)]));
+        round_trip(Array(DBusType::Struct(vec![DBusType::Byte]), vec![]));
+    }
+
+    fn encode(values: &[Value]) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        for value in values.iter() {
+            enc.write_value(value);
+        }
+        enc.unwrap()
+    }
+
+    #[test]
+    fn test_known_encodings() {
+        assert_eq!(encode(&[Str(String::from_str("ab"))]),
+                   vec![2u8, 0, 0, 0, 0x61, 0x62, 0]);
+        // structs start on an 8 byte boundary
+        assert_eq!(encode(&[Byte(1), Struct(vec![Byte(2), Int32(3)])]),
+                   vec![1u8, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0]);
+        // the value is aligned after the signature
+        assert_eq!(encode(&[Variant(box UInt16(7))]),
+                   vec![1u8, 0x71, 0, 0, 7, 0]);
+        // empty arrays are still padded to their element alignment
+        assert_eq!(encode(&[UInt32(5), Array(DBusType::Struct(vec![DBusType::Byte]), vec![])]),
+                   vec![5u8, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode(&[Array(DBusType::Int64, vec![])]),
+                   vec![0u8, 0, 0, 0, 0, 0, 0, 0]);
+        // a{sy}: the length excludes the padding before the first entry
+        let dict = Value::dict(DBusType::Str, DBusType::Byte,
+                               vec![(Str(String::from_str("k")), Byte(9))]);
+        assert_eq!(encode(&[dict]),
+                   vec![7u8, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0x6b, 0, 9]);
+    }
+
+    #[test]
+    fn test_open_array() {
+        let mut enc = Encoder::new();
+        let array = enc.open_array(&DBusType::UInt32);
+        enc.write_u32(1);
+        enc.write_u32(2);
+        enc.close_array(array);
+        assert_eq!(enc.unwrap(), vec![8u8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_big_endian() {
+        let value = Struct(vec![Byte(1), Int32(0x01020304)]);
+        let mut enc = Encoder::with_endianness(BigEndian);
+        enc.write_value(&value);
+        let buf = enc.unwrap();
+        assert_eq!(buf.as_slice(), [1u8, 0, 0, 0, 1, 2, 3, 4].as_slice());
+        let mut dec = Decoder::with_endianness(buf.as_slice(), BigEndian);
+        assert_eq!(dec.read_value(&value.get_type()).unwrap(), value);
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let buf = [3u8, 0, 0, 0, 0x61, 0xff, 0x62, 0];
+        let mut dec = Decoder::new(buf.as_slice());
+        assert_eq!(dec.read_str(), Err(InvalidString(4)));
+
+        let mut dec = Decoder::new(buf.as_slice());
+        dec.set_lossy_utf8(true);
+        assert_eq!(dec.read_str().unwrap().as_slice(), "a\ufffdb");
+
+        let buf = [2u8, 0, 0, 0, 0x61, 0x62, 0];
+        let mut dec = Decoder::new(buf.as_slice());
+        assert_eq!(dec.read_value(&DBusType::ObjectPath), Err(InvalidObjectPath(0)));
+    }
+
+    #[test]
+    fn test_strict_bool() {
+        let buf = [1u8, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0];
+        let mut dec = Decoder::new(buf.as_slice());
+        assert_eq!(dec.read_bool(), Ok(true));
+        assert_eq!(dec.read_bool(), Ok(false));
+        assert_eq!(dec.read_bool(), Err(InvalidBoolean(8)));
+    }
+
+    #[test]
+    fn test_nested_variants() {
+        let mut value = Byte(1);
+        for _ in range(0, MAX_DEPTH + 1) {
+            value = Variant(box value);
+        }
+        let mut enc = Encoder::new();
+        enc.write_value(&value);
+        let buf = enc.unwrap();
+        let mut dec = Decoder::new(buf.as_slice());
+        match dec.read_value(&DBusType::Variant) {
+            Err(TooDeep(_)) => (),
+            other => fail!("expected TooDeep, got {}", other)
+        }
+    }
+
+    #[test]
+    fn test_nonzero_padding() {
+        let buf = [1u8, 0xff, 0, 0, 1, 0, 0, 0];
+        let mut dec = Decoder::new(buf.as_slice());
+        dec.read_byte().unwrap();
+        assert_eq!(dec.read_u32(), Err(InvalidPadding(1)));
+    }
+}
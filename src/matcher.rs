@@ -0,0 +1,216 @@
+// Checking messages in tests, with a failure message that says which
+// fields differed rather than dumping two whole messages:
+//
+//     assert_msg!(mock.expect_sent(),
+//                 MessageMatcher::method_call("org.foo.Bar", "Baz")
+//                     .args(vec![Int32(1), Str(String::from_str("x"))]));
+//
+// Only the fields a matcher was given are checked.
+
+use message::{DBusMessage, MessageType};
+use pretty::{format_message, format_value};
+use value::Value;
+
+
+// Fails the task with the differences if `$msg` doesn't match.
+#[macro_export]
+macro_rules! assert_msg(
+    ($msg:expr, $matcher:expr) => (
+        match $matcher.check(&$msg) {
+            Ok(()) => (),
+            Err(diff) => fail!("{}", diff)
+        }
+    )
+)
+
+
+#[deriving(Clone)]
+pub struct MessageMatcher {
+    msg_type: Option<MessageType>,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    destination: Option<String>,
+    sender: Option<String>,
+    reply_serial: Option<u32>,
+    args: Option<Vec<Value>>
+}
+
+impl MessageMatcher {
+    // Matches every message.
+    pub fn any() -> MessageMatcher {
+        MessageMatcher {
+            msg_type: None,
+            path: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            destination: None,
+            sender: None,
+            reply_serial: None,
+            args: None
+        }
+    }
+
+    pub fn method_call(interface: &str, member: &str) -> MessageMatcher {
+        MessageMatcher::any().msg_type(MessageType::MethodCall)
+            .interface(interface).member(member)
+    }
+
+    pub fn signal(interface: &str, member: &str) -> MessageMatcher {
+        MessageMatcher::any().msg_type(MessageType::Signal).interface(interface).member(member)
+    }
+
+    pub fn method_return() -> MessageMatcher {
+        MessageMatcher::any().msg_type(MessageType::MethodReturn)
+    }
+
+    pub fn error(name: &str) -> MessageMatcher {
+        let mut matcher = MessageMatcher::any().msg_type(MessageType::Error);
+        matcher.error_name = Some(String::from_str(name));
+        matcher
+    }
+
+    pub fn msg_type(mut self, msg_type: MessageType) -> MessageMatcher {
+        self.msg_type = Some(msg_type);
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> MessageMatcher {
+        self.path = Some(String::from_str(path));
+        self
+    }
+
+    pub fn interface(mut self, interface: &str) -> MessageMatcher {
+        self.interface = Some(String::from_str(interface));
+        self
+    }
+
+    pub fn member(mut self, member: &str) -> MessageMatcher {
+        self.member = Some(String::from_str(member));
+        self
+    }
+
+    pub fn destination(mut self, destination: &str) -> MessageMatcher {
+        self.destination = Some(String::from_str(destination));
+        self
+    }
+
+    pub fn sender(mut self, sender: &str) -> MessageMatcher {
+        self.sender = Some(String::from_str(sender));
+        self
+    }
+
+    // Matches replies, including errors, to the message with `serial`.
+    pub fn reply_to(mut self, serial: u32) -> MessageMatcher {
+        self.reply_serial = Some(serial);
+        self
+    }
+
+    // The whole body, in order.
+    pub fn args(mut self, args: Vec<Value>) -> MessageMatcher {
+        self.args = Some(args);
+        self
+    }
+
+    pub fn matches(&self, msg: &DBusMessage) -> bool {
+        self.differences(msg).is_empty()
+    }
+
+    // Ok if `msg` matches, otherwise a description of each difference
+    // followed by the message itself.
+    pub fn check(&self, msg: &DBusMessage) -> Result<(), String> {
+        let differences = self.differences(msg);
+        if differences.is_empty() {
+            return Ok(());
+        }
+        let mut out = String::from_str("message didn't match:\n");
+        for difference in differences.iter() {
+            out.push_str("  ");
+            out.push_str(difference.as_slice());
+            out.push('\n');
+        }
+        out.push_str("the message was:\n");
+        out.push_str(format_message(msg).as_slice());
+        Err(out)
+    }
+
+    fn differences(&self, msg: &DBusMessage) -> Vec<String> {
+        let mut out = Vec::new();
+        match self.msg_type {
+            Some(msg_type) if msg_type != msg.msg_type() => {
+                out.push(format!("type: expected {}, got {}", msg_type, msg.msg_type()));
+            },
+            _ => ()
+        }
+        compare(&mut out, "path", &self.path, msg.path());
+        compare(&mut out, "interface", &self.interface, msg.interface());
+        compare(&mut out, "member", &self.member, msg.member());
+        compare(&mut out, "error name", &self.error_name, msg.error_name());
+        compare(&mut out, "destination", &self.destination, msg.destination());
+        compare(&mut out, "sender", &self.sender, msg.sender());
+        match self.reply_serial {
+            Some(serial) if Some(serial) != msg.reply_serial() => {
+                out.push(format!("reply serial: expected {}, got {}", serial,
+                                 msg.reply_serial()));
+            },
+            _ => ()
+        }
+        for args in self.args.iter() {
+            let body = msg.body();
+            if args.len() != body.len() {
+                out.push(format!("args: expected {}, got {}", args.len(), body.len()));
+            }
+            for (i, (expected, got)) in args.iter().zip(body.iter()).enumerate() {
+                if expected != got {
+                    out.push(format!("arg {}: expected {}, got {}", i,
+                                     format_value(expected, 0), format_value(got, 0)));
+                }
+            }
+        }
+        out
+    }
+}
+
+fn compare(out: &mut Vec<String>, field: &str, expected: &Option<String>, got: Option<&str>) {
+    match *expected {
+        Some(ref expected) if Some(expected.as_slice()) != got => {
+            out.push(format!("{}: expected \"{}\", got {}", field, expected, match got {
+                Some(got) => format!("\"{}\"", got),
+                None => String::from_str("nothing")
+            }));
+        },
+        _ => ()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::DBusMessage;
+    use names::{BusName, InterfaceName, MemberName};
+    use value::{Int32, Str};
+
+    #[test]
+    fn test_check() {
+        let call = DBusMessage::method_call(BusName::from_static("org.foo"), "/org/foo",
+                                            InterfaceName::from_static("org.foo.Bar"),
+                                            MemberName::from_static("Baz"))
+            .with_args(vec![Int32(1), Str(String::from_str("x"))]);
+        assert_msg!(call, MessageMatcher::method_call("org.foo.Bar", "Baz")
+                              .path("/org/foo")
+                              .args(vec![Int32(1), Str(String::from_str("x"))]));
+        assert!(MessageMatcher::any().matches(&call));
+
+        let diff = MessageMatcher::signal("org.foo.Bar", "Qux")
+            .args(vec![Int32(2), Str(String::from_str("x"))])
+            .check(&call).unwrap_err();
+        let lines: Vec<&str> = diff.as_slice().lines().collect();
+        assert_eq!(lines[1], "  type: expected Signal, got MethodCall");
+        assert_eq!(lines[2], "  member: expected \"Qux\", got \"Baz\"");
+        assert_eq!(lines[3], "  arg 0: expected int32 2, got int32 1");
+        assert_eq!(lines[4], "the message was:");
+    }
+}
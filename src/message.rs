@@ -0,0 +1,859 @@
+use marshal::{Encoder, Decoder, ParseError, InvalidHeader, UnexpectedEof};
+use marshal::{Endianness, LittleEndian, UnsupportedEndianness};
+use value::{DBusType, Value, parse_signature};
+use value::{Byte, UInt32, Str, ObjectPath, Signature, Array, Struct, Variant};
+use intern::{intern, interned};
+use names::{BusName, InterfaceName, MemberName};
+use unix_fd::OwnedFd;
+use super::{DBusResult, DBusError, StandardError};
+
+use std::str::{SendStr, Slice, Owned};
+use std::sync::Arc;
+
+
+pub const BUS_NAME: &'static str = "org.freedesktop.DBus";
+pub const BUS_PATH: &'static str = "/org/freedesktop/DBus";
+pub const BUS_INTERFACE: &'static str = "org.freedesktop.DBus";
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const LITTLE_ENDIAN: u8 = 'l' as u8;
+pub const BIG_ENDIAN: u8 = 'B' as u8;
+
+// Size of the fixed part of the header, up to the header field array's length.
+pub const FIXED_HEADER_LEN: uint = 16;
+
+// from the D-Bus specification
+pub const MAX_MESSAGE_LEN: uint = 1 << 27;
+
+// Message flags, from dbus-protocol.h
+pub const NO_REPLY_EXPECTED: u8 = 0x1;
+pub const NO_AUTO_START: u8 = 0x2;
+// The caller is prepared to wait while the service asks the user, e.g.
+// for a polkit password
+pub const ALLOW_INTERACTIVE_AUTHORIZATION: u8 = 0x4;
+
+
+pub type MessageType = self::MessageType::MessageType;
+pub mod MessageType {
+    // from dbus-protocol.h
+    pub const METHOD_CALL: u8 = 1;
+    pub const METHOD_RETURN: u8 = 2;
+    pub const ERROR: u8 = 3;
+    pub const SIGNAL: u8 = 4;
+
+    #[deriving(PartialEq, Eq, Clone, Show)]
+    pub enum MessageType {
+        MethodCall,
+        MethodReturn,
+        Error,
+        Signal
+    }
+
+    pub fn from_ord(value: u8) -> Option<MessageType> {
+        match value {
+            METHOD_CALL => Some(MethodCall),
+            METHOD_RETURN => Some(MethodReturn),
+            ERROR => Some(Error),
+            SIGNAL => Some(Signal),
+            _ => None
+        }
+    }
+
+    impl MessageType {
+        pub fn ord(&self) -> u8 {
+            match *self {
+                MethodCall => METHOD_CALL,
+                MethodReturn => METHOD_RETURN,
+                Error => ERROR,
+                Signal => SIGNAL
+            }
+        }
+    }
+}
+
+
+pub mod HeaderField {
+    // from dbus-protocol.h
+    pub const PATH: u8 = 1;
+    pub const INTERFACE: u8 = 2;
+    pub const MEMBER: u8 = 3;
+    pub const ERROR_NAME: u8 = 4;
+    pub const REPLY_SERIAL: u8 = 5;
+    pub const DESTINATION: u8 = 6;
+    pub const SENDER: u8 = 7;
+    pub const SIGNATURE: u8 = 8;
+    pub const UNIX_FDS: u8 = 9;
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct DBusMessage {
+    msg_type: MessageType,
+    flags: u8,
+    serial: u32,
+    path: Option<SendStr>,
+    interface: Option<SendStr>,
+    member: Option<SendStr>,
+    error_name: Option<SendStr>,
+    reply_serial: Option<u32>,
+    destination: Option<SendStr>,
+    sender: Option<SendStr>,
+    unix_fds: Option<u32>,
+    // The fds that came with a received message, shared by its clones
+    received_fds: Vec<Arc<OwnedFd>>,
+    // Fields we don't know, kept so they survive re-marshalling
+    unknown_fields: Vec<(u8, Value)>,
+    body: Vec<Value>
+}
+
+impl DBusMessage {
+    fn new(msg_type: MessageType) -> DBusMessage {
+        DBusMessage {
+            msg_type: msg_type,
+            flags: 0,
+            serial: 0,
+            path: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            reply_serial: None,
+            destination: None,
+            sender: None,
+            unix_fds: None,
+            received_fds: Vec::new(),
+            unknown_fields: Vec::new(),
+            body: Vec::new()
+        }
+    }
+
+    pub fn method_call(destination: BusName, path: &str, interface: InterfaceName,
+                       member: MemberName) -> DBusMessage {
+        let mut msg = DBusMessage::new(MessageType::MethodCall);
+        msg.destination = Some(intern(destination.as_str()));
+        msg.path = Some(intern(path));
+        msg.interface = Some(intern(interface.as_str()));
+        msg.member = Some(intern(member.as_str()));
+        msg
+    }
+
+    pub fn signal(path: &str, interface: InterfaceName, member: MemberName) -> DBusMessage {
+        let mut msg = DBusMessage::new(MessageType::Signal);
+        msg.path = Some(intern(path));
+        msg.interface = Some(intern(interface.as_str()));
+        msg.member = Some(intern(member.as_str()));
+        msg
+    }
+
+    pub fn method_return(call: &DBusMessage) -> DBusMessage {
+        let mut msg = DBusMessage::new(MessageType::MethodReturn);
+        msg.reply_serial = Some(call.serial);
+        msg.destination = call.sender.clone();
+        msg
+    }
+
+    pub fn error(call: &DBusMessage, name: &str, message: &str) -> DBusMessage {
+        let mut msg = DBusMessage::new(MessageType::Error);
+        msg.reply_serial = Some(call.serial);
+        msg.destination = call.sender.clone();
+        msg.error_name = Some(intern(name));
+        msg.body.push(Str(String::from_str(message)));
+        msg
+    }
+
+    pub fn error_from(call: &DBusMessage, err: &DBusError) -> DBusMessage {
+        DBusMessage::error(call, err.name(), err.message())
+    }
+
+    // Turns the message into a new signal, keeping the allocations of
+    // its strings and body.  Used by MessagePool.
+    pub fn reset_signal(&mut self, path: &str, interface: InterfaceName, member: MemberName) {
+        self.reset(MessageType::Signal);
+        self.destination = None;
+        reuse_str(&mut self.path, path);
+        reuse_str(&mut self.interface, interface.as_str());
+        reuse_str(&mut self.member, member.as_str());
+    }
+
+    // Turns the message into a new method call, like reset_signal().
+    pub fn reset_method_call(&mut self, destination: BusName, path: &str,
+                             interface: InterfaceName, member: MemberName) {
+        self.reset(MessageType::MethodCall);
+        reuse_str(&mut self.destination, destination.as_str());
+        reuse_str(&mut self.path, path);
+        reuse_str(&mut self.interface, interface.as_str());
+        reuse_str(&mut self.member, member.as_str());
+    }
+
+    fn reset(&mut self, msg_type: MessageType) {
+        self.msg_type = msg_type;
+        self.flags = 0;
+        self.serial = 0;
+        self.error_name = None;
+        self.reply_serial = None;
+        self.sender = None;
+        self.unix_fds = None;
+        self.received_fds.clear();
+        self.unknown_fields.clear();
+        self.body.clear();
+    }
+
+    pub fn append(&mut self, value: Value) {
+        self.body.push(value);
+    }
+
+    pub fn with_args(mut self, args: Vec<Value>) -> DBusMessage {
+        self.body.push_all_move(args);
+        self
+    }
+
+    #[inline]
+    pub fn msg_type(&self) -> MessageType {
+        self.msg_type
+    }
+
+    #[inline]
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    pub fn set_flag(&mut self, flag: u8, on: bool) {
+        if on {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+
+    #[inline]
+    pub fn no_reply_expected(&self) -> bool {
+        self.flags & NO_REPLY_EXPECTED != 0
+    }
+
+    // Whether the caller allows interactive authorization.  A service
+    // that would need to prompt without it should reply with
+    // InteractiveAuthorizationRequired.
+    #[inline]
+    pub fn allow_interactive_authorization(&self) -> bool {
+        self.flags & ALLOW_INTERACTIVE_AUTHORIZATION != 0
+    }
+
+    pub fn with_interactive_authorization(mut self, allow: bool) -> DBusMessage {
+        self.set_flag(ALLOW_INTERACTIVE_AUTHORIZATION, allow);
+        self
+    }
+
+    #[inline]
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    #[inline]
+    pub fn set_serial(&mut self, serial: u32) {
+        self.serial = serial;
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_ref().map(|s| s.as_slice())
+    }
+
+    pub fn interface(&self) -> Option<&str> {
+        self.interface.as_ref().map(|s| s.as_slice())
+    }
+
+    pub fn member(&self) -> Option<&str> {
+        self.member.as_ref().map(|s| s.as_slice())
+    }
+
+    // Whether the message is for `interface`.  Names we interned are
+    // told apart by address before their contents are compared.
+    pub fn has_interface(&self, interface: &str) -> bool {
+        same_str(self.interface(), interface)
+    }
+
+    // Whether the message is for `member`, compared as has_interface() does.
+    pub fn has_member(&self, member: &str) -> bool {
+        same_str(self.member(), member)
+    }
+
+    pub fn error_name(&self) -> Option<&str> {
+        self.error_name.as_ref().map(|s| s.as_slice())
+    }
+
+    #[inline]
+    pub fn reply_serial(&self) -> Option<u32> {
+        self.reply_serial
+    }
+
+    pub fn destination(&self) -> Option<&str> {
+        self.destination.as_ref().map(|s| s.as_slice())
+    }
+
+    pub fn sender(&self) -> Option<&str> {
+        self.sender.as_ref().map(|s| s.as_slice())
+    }
+
+    // Re-addresses the message, e.g. to forward it.  None sends it to
+    // no one in particular, as for a broadcast signal.
+    pub fn set_destination(&mut self, destination: Option<BusName>) {
+        match destination {
+            Some(destination) => reuse_str(&mut self.destination, destination.as_str()),
+            None => self.destination = None
+        }
+    }
+
+    pub fn with_destination(mut self, destination: BusName) -> DBusMessage {
+        self.set_destination(Some(destination));
+        self
+    }
+
+    // A bus daemon replaces whatever sender we give with our unique
+    // name, so this only matters on peer-to-peer connections.
+    pub fn set_sender(&mut self, sender: Option<BusName>) {
+        match sender {
+            Some(sender) => reuse_str(&mut self.sender, sender.as_str()),
+            None => self.sender = None
+        }
+    }
+
+    #[inline]
+    pub fn unix_fds(&self) -> Option<u32> {
+        self.unix_fds
+    }
+
+    // How many unix fds travel alongside the message.  The fds
+    // themselves are up to the transport.
+    pub fn set_unix_fds(&mut self, n: Option<u32>) {
+        self.unix_fds = n;
+    }
+
+    // Keeps the fds the transport received with the message, in the
+    // order its UnixFd values count them.
+    pub fn set_received_fds(&mut self, fds: Vec<OwnedFd>) {
+        self.received_fds = fds.into_iter().map(|fd| Arc::new(fd)).collect();
+    }
+
+    // A duplicate of the received fd a UnixFd value refers to, to be
+    // kept after the message is gone.
+    pub fn unix_fd(&self, index: u32) -> DBusResult<OwnedFd> {
+        match self.received_fds.get(index as uint) {
+            Some(fd) => fd.duplicate(),
+            None => Err(DBusError::standard(StandardError::InconsistentMessage,
+                format!("unix fd {} wasn't received with the message ({} were)",
+                        index, self.received_fds.len()).as_slice()))
+        }
+    }
+
+    // Header fields with codes newer than this crate, as (code, value).
+    pub fn unknown_header_fields(&self) -> &[(u8, Value)] {
+        self.unknown_fields.as_slice()
+    }
+
+    #[inline]
+    pub fn body(&self) -> &[Value] {
+        self.body.as_slice()
+    }
+
+    #[inline]
+    pub fn into_body(self) -> Vec<Value> {
+        self.body
+    }
+
+    pub fn signature(&self) -> String {
+        let mut out = String::new();
+        for value in self.body.iter() {
+            value.get_type().write_signature(&mut out);
+        }
+        out
+    }
+
+    pub fn is_reply_to(&self, serial: u32) -> bool {
+        match self.msg_type {
+            MessageType::MethodReturn | MessageType::Error => {
+                self.reply_serial == Some(serial)
+            },
+            _ => false
+        }
+    }
+
+    // Turns an Error message into a DBusError, using the first argument
+    // as the message if it is a string.
+    pub fn to_error(&self) -> Option<DBusError> {
+        match (self.msg_type, &self.error_name) {
+            (MessageType::Error, &Some(ref name)) => {
+                let message = match self.body.as_slice().head() {
+                    Some(&Str(ref message)) => message.as_slice(),
+                    _ => ""
+                };
+                Some(DBusError::new(name.as_slice(), message))
+            },
+            _ => None
+        }
+    }
+
+    // Writes the header field array straight from our fields, rather
+    // than building Values for it.
+    fn write_header_fields(&self, enc: &mut Encoder) {
+        let fields = enc.open_array(&header_field_type());
+        write_str_field(enc, HeaderField::PATH, "o", &self.path);
+        write_str_field(enc, HeaderField::INTERFACE, "s", &self.interface);
+        write_str_field(enc, HeaderField::MEMBER, "s", &self.member);
+        write_str_field(enc, HeaderField::ERROR_NAME, "s", &self.error_name);
+        for reply_serial in self.reply_serial.iter() {
+            write_field_start(enc, HeaderField::REPLY_SERIAL, "u");
+            enc.write_u32(*reply_serial);
+        }
+        write_str_field(enc, HeaderField::DESTINATION, "s", &self.destination);
+        write_str_field(enc, HeaderField::SENDER, "s", &self.sender);
+        if !self.body.is_empty() {
+            write_field_start(enc, HeaderField::SIGNATURE, "g");
+            enc.write_signature(self.signature().as_slice());
+        }
+        for unix_fds in self.unix_fds.iter() {
+            write_field_start(enc, HeaderField::UNIX_FDS, "u");
+            enc.write_u32(*unix_fds);
+        }
+        for &(code, ref value) in self.unknown_fields.iter() {
+            write_field_start(enc, code, value.signature().as_slice());
+            enc.write_value(value);
+        }
+        enc.close_array(fields);
+    }
+
+    // Serializes the message for the wire.  The serial must already
+    // have been assigned, normally by the connection sending it.
+    pub fn marshal(&self) -> Vec<u8> {
+        self.marshal_with(LittleEndian)
+    }
+
+    // Like marshal(), in the given byte order.
+    pub fn marshal_with(&self, endianness: Endianness) -> Vec<u8> {
+        let mut enc = Encoder::with_endianness(endianness);
+        self.encode(&mut enc);
+        enc.unwrap()
+    }
+
+    // Like marshal(), but writes into `buf`, reusing its allocation.
+    pub fn marshal_into(&self, buf: Vec<u8>) -> Vec<u8> {
+        let mut enc = Encoder::from_vec(buf, LittleEndian);
+        self.encode(&mut enc);
+        enc.unwrap()
+    }
+
+    fn encode(&self, enc: &mut Encoder) {
+        enc.write_byte(enc.endianness().marker());
+        enc.write_byte(self.msg_type.ord());
+        enc.write_byte(self.flags);
+        enc.write_byte(PROTOCOL_VERSION);
+        // the body length is filled in once the body is written
+        enc.write_u32(0);
+        enc.write_u32(self.serial);
+        self.write_header_fields(enc);
+        // the body starts 8-aligned, so its values can be written in
+        // place with the same padding they'd get in a buffer of their own
+        enc.align(8);
+        let body_start = enc.len();
+        for value in self.body.iter() {
+            enc.write_value(value);
+        }
+        let body_len = (enc.len() - body_start) as u32;
+        enc.patch_u32(4, body_len);
+    }
+
+    // How many bytes marshal() would produce, header and padding
+    // included.
+    pub fn marshalled_len(&self) -> uint {
+        let mut enc = Encoder::new();
+        self.encode(&mut enc);
+        enc.len()
+    }
+
+    // The length of the body alone, as carried in the header.  The body
+    // starts 8-aligned, so it pads the same as in a buffer of its own.
+    pub fn body_len(&self) -> uint {
+        let mut enc = Encoder::new();
+        for value in self.body.iter() {
+            enc.write_value(value);
+        }
+        enc.len()
+    }
+
+    pub fn demarshal(buf: &[u8]) -> Result<DBusMessage, ParseError> {
+        DBusMessage::demarshal_with(buf, false)
+    }
+
+    // The message in wire format, for storing or carrying over other
+    // channels.  Works the same whichever backend is in use.  Messages
+    // that were never sent have serial 0, which from_bytes() rejects;
+    // set one first if the bytes are to be read back.
+    #[inline]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.marshal()
+    }
+
+    // Reads back exactly one message written by to_bytes() or
+    // received from the wire.
+    #[inline]
+    pub fn from_bytes(buf: &[u8]) -> Result<DBusMessage, ParseError> {
+        parse_message(buf)
+    }
+
+    // Like demarshal(), but replaces invalid UTF-8 rather than
+    // rejecting the message; see Decoder::set_lossy_utf8().
+    pub fn demarshal_lossy(buf: &[u8]) -> Result<DBusMessage, ParseError> {
+        DBusMessage::demarshal_with(buf, true)
+    }
+
+    fn demarshal_with(buf: &[u8], lossy_utf8: bool) -> Result<DBusMessage, ParseError> {
+        let total_len = try!(message_length(buf));
+        if buf.len() < total_len {
+            return Err(UnexpectedEof(buf.len()));
+        }
+        // message_length() has already checked the marker
+        let endianness = Endianness::from_marker(buf[0]).unwrap();
+        let mut dec = Decoder::with_endianness(buf.slice_to(total_len), endianness);
+        dec.set_lossy_utf8(lossy_utf8);
+        try!(dec.read_byte());
+        let msg_type = match MessageType::from_ord(try!(dec.read_byte())) {
+            Some(msg_type) => msg_type,
+            None => return Err(InvalidHeader(String::from_str("unknown message type")))
+        };
+        let mut msg = DBusMessage::new(msg_type);
+        msg.flags = try!(dec.read_byte());
+        if try!(dec.read_byte()) != PROTOCOL_VERSION {
+            return Err(InvalidHeader(String::from_str("unsupported protocol version")));
+        }
+        let body_len = try!(dec.read_u32()) as uint;
+        msg.serial = try!(dec.read_u32());
+        if msg.serial == 0 {
+            return Err(InvalidHeader(String::from_str("serial must not be zero")));
+        }
+
+        let mut signature = String::new();
+        let fields = match try!(dec.read_value(&header_field_type())) {
+            Array(_, fields) => fields,
+            _ => unreachable!()
+        };
+        for field in fields.into_iter() {
+            let (code, value) = match field {
+                Struct(mut parts) => {
+                    let value = parts.pop().unwrap();
+                    let code = parts.pop().unwrap();
+                    match (code, value) {
+                        (Byte(code), Variant(value)) => (code, *value),
+                        _ => unreachable!()
+                    }
+                },
+                _ => unreachable!()
+            };
+            match (code, value) {
+                (HeaderField::PATH, ObjectPath(v)) => msg.path = Some(Owned(v)),
+                (HeaderField::INTERFACE, Str(v)) => msg.interface = Some(Owned(v)),
+                (HeaderField::MEMBER, Str(v)) => msg.member = Some(Owned(v)),
+                (HeaderField::ERROR_NAME, Str(v)) => msg.error_name = Some(Owned(v)),
+                (HeaderField::REPLY_SERIAL, UInt32(v)) => msg.reply_serial = Some(v),
+                (HeaderField::DESTINATION, Str(v)) => msg.destination = Some(Owned(v)),
+                (HeaderField::SENDER, Str(v)) => msg.sender = Some(Owned(v)),
+                (HeaderField::SIGNATURE, Signature(v)) => signature = v,
+                (HeaderField::UNIX_FDS, UInt32(v)) => msg.unix_fds = Some(v),
+                (code, value) => {
+                    if code >= HeaderField::PATH && code <= HeaderField::UNIX_FDS {
+                        return Err(InvalidHeader(format!("header field {} has the wrong type",
+                                                         code)));
+                    }
+                    // the spec says to ignore fields we don't understand
+                    msg.unknown_fields.push((code, value));
+                }
+            }
+        }
+        try!(dec.align(8));
+
+        let body_start = dec.position();
+        let body_buf = buf.slice(body_start, body_start + body_len);
+        let mut body = Decoder::with_endianness(body_buf, endianness);
+        body.set_lossy_utf8(lossy_utf8);
+        for ty in try!(parse_signature(signature.as_slice())).iter() {
+            msg.body.push(try!(body.read_value(ty)));
+        }
+        if body.remaining() != 0 {
+            return Err(InvalidHeader(String::from_str("body longer than its signature")));
+        }
+        try!(msg.check_required_fields());
+        Ok(msg)
+    }
+
+    fn check_required_fields(&self) -> Result<(), ParseError> {
+        let missing = match self.msg_type {
+            MessageType::MethodCall => {
+                self.path.is_none() || self.member.is_none()
+            },
+            MessageType::Signal => {
+                self.path.is_none() || self.interface.is_none() || self.member.is_none()
+            },
+            MessageType::Error => {
+                self.error_name.is_none() || self.reply_serial.is_none()
+            },
+            MessageType::MethodReturn => self.reply_serial.is_none()
+        };
+        if missing {
+            Err(InvalidHeader(format!("{} is missing required header fields",
+                                      self.msg_type)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+
+#[inline]
+fn same_str(field: Option<&str>, value: &str) -> bool {
+    match field {
+        Some(field) => {
+            (field.as_ptr() == value.as_ptr() && field.len() == value.len()) || field == value
+        },
+        None => false
+    }
+}
+
+// Sets `slot` to `value`, reusing the string already there if it's
+// one we own and `value` isn't interned.
+fn reuse_str(slot: &mut Option<SendStr>, value: &str) {
+    match interned(value) {
+        Some(known) => {
+            *slot = Some(Slice(known));
+            return;
+        },
+        None => ()
+    }
+    match *slot {
+        Some(Owned(ref mut s)) => {
+            s.clear();
+            s.push_str(value);
+            return;
+        },
+        _ => ()
+    }
+    *slot = Some(Owned(String::from_str(value)));
+}
+
+fn write_str_field(enc: &mut Encoder, code: u8, sig: &str, value: &Option<SendStr>) {
+    for value in value.iter() {
+        write_field_start(enc, code, sig);
+        enc.write_str(value.as_slice());
+    }
+}
+
+// The code and signature that start each (yv) header field.
+fn write_field_start(enc: &mut Encoder, code: u8, sig: &str) {
+    enc.open_struct();
+    enc.write_byte(code);
+    enc.write_signature(sig);
+}
+
+// a(yv)
+fn header_field_type() -> DBusType {
+    DBusType::Struct(vec![DBusType::Byte, DBusType::Variant])
+}
+
+// Works out the full length of a message from the start of its header,
+// which must hold at least FIXED_HEADER_LEN bytes.
+pub fn message_length(buf: &[u8]) -> Result<uint, ParseError> {
+    if buf.len() < FIXED_HEADER_LEN {
+        return Err(UnexpectedEof(buf.len()));
+    }
+    let endianness = match Endianness::from_marker(buf[0]) {
+        Some(endianness) => endianness,
+        None => return Err(UnsupportedEndianness(buf[0]))
+    };
+    let mut dec = Decoder::with_endianness(buf.slice_to(FIXED_HEADER_LEN), endianness);
+    try!(dec.read_u32());
+    let body_len = try!(dec.read_u32()) as uint;
+    try!(dec.read_u32());
+    let fields_len = try!(dec.read_u32()) as uint;
+    // checked separately so the sum can't overflow
+    if body_len > MAX_MESSAGE_LEN || fields_len > MAX_MESSAGE_LEN {
+        return Err(InvalidHeader(String::from_str("message too long")));
+    }
+    let header_len = (FIXED_HEADER_LEN + fields_len + 7) & !7;
+    if header_len + body_len > MAX_MESSAGE_LEN {
+        return Err(InvalidHeader(String::from_str("message too long")));
+    }
+    Ok(header_len + body_len)
+}
+
+// Parses exactly one message from `buf`, which must hold nothing else.
+// Never panics on malformed input, so it's safe to point a fuzzer at.
+pub fn parse_message(buf: &[u8]) -> Result<DBusMessage, ParseError> {
+    let total_len = try!(message_length(buf));
+    if buf.len() > total_len {
+        return Err(InvalidHeader(String::from_str("trailing data after message")));
+    }
+    DBusMessage::demarshal(buf)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use marshal::BigEndian;
+    use message_ref::{MessageRef, ValueRef};
+    use names::{BusName, InterfaceName, MemberName};
+    use value::{Int32, Str};
+
+    fn signal() -> DBusMessage {
+        DBusMessage::signal("/a", InterfaceName::from_static("a.b"), MemberName::from_static("C"))
+    }
+
+    fn method_call(path: &str) -> DBusMessage {
+        DBusMessage::method_call(BusName::from_static("a.b"), path,
+                                 InterfaceName::from_static("a.b"),
+                                 MemberName::from_static("C"))
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut msg = DBusMessage::method_call(
+            BusName::from_static("org.yasashiisyndicate.dbusexample"),
+            "/org/yasashiisyndicate/Frobulator",
+            InterfaceName::from_static("org.yasashiisyndicate.Frobulator"),
+            MemberName::from_static("Frobulate"));
+        msg.append(Str(String::from_str("value")));
+        msg.append(Int32(42));
+        msg.set_serial(7);
+
+        let buf = msg.marshal();
+        assert_eq!(message_length(buf.as_slice()), Ok(buf.len()));
+        assert_eq!(DBusMessage::demarshal(buf.as_slice()), Ok(msg));
+    }
+
+    #[test]
+    fn test_big_endian_message() {
+        let mut msg = signal().with_args(vec![Int32(-2)]);
+        msg.set_serial(0x0102);
+
+        let buf = msg.marshal_with(BigEndian);
+        assert_eq!(buf[0], BIG_ENDIAN);
+        assert_eq!(buf.slice(8, 12), [0u8, 0, 1, 2].as_slice());
+        assert_eq!(message_length(buf.as_slice()), Ok(buf.len()));
+        assert_eq!(DBusMessage::demarshal(buf.as_slice()), Ok(msg));
+    }
+
+    #[test]
+    fn test_unknown_header_field() {
+        let mut msg = signal();
+        msg.set_serial(1);
+        msg.unknown_fields.push((10, Str(String::from_str("container"))));
+        let buf = msg.marshal();
+
+        let parsed = DBusMessage::demarshal(buf.as_slice()).unwrap();
+        assert_eq!(parsed.unknown_header_fields(),
+                   [(10u8, Str(String::from_str("container")))].as_slice());
+        assert_eq!(parsed.marshal(), buf);
+
+        let view = MessageRef::parse(buf.as_slice()).unwrap();
+        assert_eq!(view.header_fields().last(), Some((10u8, ValueRef::Str("container"))));
+    }
+
+    #[test]
+    fn test_parse_message_garbage() {
+        let mut msg = method_call("/a")
+            .with_args(vec![Str(String::from_str("x")), Int32(1)]);
+        msg.set_serial(9);
+        let buf = msg.marshal();
+        assert_eq!(parse_message(buf.as_slice()), Ok(msg));
+
+        let mut longer = buf.clone();
+        longer.push(0);
+        assert!(parse_message(longer.as_slice()).is_err());
+
+        // every truncation and single-byte corruption must fail cleanly
+        for len in range(0, buf.len()) {
+            assert!(parse_message(buf.slice_to(len)).is_err());
+        }
+        for i in range(0, buf.len()) {
+            let mut corrupt = buf.clone();
+            *corrupt.get_mut(i) ^= 0xff;
+            let _ = parse_message(corrupt.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_interactive_authorization() {
+        let mut msg = method_call("/").with_interactive_authorization(true);
+        msg.set_serial(1);
+        assert!(msg.allow_interactive_authorization());
+        let parsed = DBusMessage::demarshal(msg.marshal().as_slice()).unwrap();
+        assert!(parsed.allow_interactive_authorization());
+        assert!(!parsed.with_interactive_authorization(false).allow_interactive_authorization());
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let mut msg = signal().with_args(vec![Int32(3)]);
+        msg.set_serial(2);
+        let bytes = msg.to_bytes();
+        let copy = DBusMessage::from_bytes(bytes.as_slice()).unwrap();
+        assert_eq!(copy, msg);
+        assert_eq!(copy.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_lengths() {
+        let mut msg = signal().with_args(vec![Str(String::from_str("ab")), Int32(3)]);
+        msg.set_serial(2);
+        // length, "ab\0", one byte of padding, then the int
+        assert_eq!(msg.body_len(), 12);
+        let buf = msg.marshal();
+        assert_eq!(msg.marshalled_len(), buf.len());
+        assert_eq!(buf[4] as uint, msg.body_len());
+
+        assert_eq!(signal().body_len(), 0);
+    }
+
+    #[test]
+    fn test_readdress() {
+        let mut msg = method_call("/");
+        msg.set_serial(5);
+        msg.set_sender(Some(BusName::from_static(":1.7")));
+        let mut forwarded = msg.clone()
+            .with_destination(BusName::from_static("com.example.Other"));
+        assert_eq!(forwarded.destination(), Some("com.example.Other"));
+        forwarded.set_sender(None);
+        let parsed = DBusMessage::demarshal(forwarded.marshal().as_slice()).unwrap();
+        assert_eq!(parsed.destination(), Some("com.example.Other"));
+        assert_eq!(parsed.sender(), None);
+        assert_eq!(parsed.body(), msg.body());
+
+        forwarded.set_destination(None);
+        assert_eq!(forwarded.destination(), None);
+    }
+
+    #[test]
+    fn test_has_interface_and_member() {
+        let peer = InterfaceName::from_static("org.freedesktop.DBus.Peer");
+        let mut msg = DBusMessage::method_call(BusName::from_static("a.b"), "/a", peer,
+                                               MemberName::from_static("Ping"));
+        assert!(msg.has_interface("org.freedesktop.DBus.Peer"));
+        assert!(msg.has_member("Ping"));
+        assert!(!msg.has_member("Pin"));
+        // received headers aren't interned, so these compare contents
+        msg.set_serial(1);
+        let received = DBusMessage::demarshal(msg.marshal().as_slice()).unwrap();
+        assert!(received.has_interface("org.freedesktop.DBus.Peer"));
+        assert!(!received.has_interface("org.freedesktop.DBus"));
+        assert!(!signal().has_member("Ping"));
+    }
+
+    #[test]
+    fn test_error_reply() {
+        let mut call = method_call("/");
+        call.set_serial(3);
+        let mut reply = DBusMessage::error(&call, "org.freedesktop.DBus.Error.Failed", "nope");
+        reply.set_serial(4);
+        assert!(reply.is_reply_to(3));
+
+        let buf = reply.marshal();
+        let err = DBusMessage::demarshal(buf.as_slice()).unwrap().to_error().unwrap();
+        assert_eq!(err.name(), "org.freedesktop.DBus.Error.Failed");
+        assert_eq!(err.message(), "nope");
+    }
+}
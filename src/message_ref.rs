@@ -0,0 +1,596 @@
+// Borrowed views of received messages.  Strings and byte arrays point
+// into the receive buffer instead of being copied out, for consumers
+// that look at a lot of traffic and keep little of it.  The native
+// DBusConnection lends them out with with_next_message().
+
+use marshal::{Decoder, Endianness, ParseError, InvalidHeader, InvalidArrayLength};
+use marshal::{InvalidObjectPath, TooDeep, UnexpectedEof, MAX_ARRAY_LEN, MAX_DEPTH};
+use message::{DBusMessage, MessageType, HeaderField, PROTOCOL_VERSION, message_length};
+use message::ALLOW_INTERACTIVE_AUTHORIZATION;
+use value::{DBusType, parse_signature, parse_single_type, is_valid_object_path};
+
+use std::slice::Chunks;
+
+pub type ValueRef<'a> = self::ValueRef::ValueRef<'a>;
+
+
+pub mod ValueRef {
+    use value::{mod, DBusType, Value};
+
+    // Like Value, but borrowing from the message buffer.  Arrays of
+    // bytes come back whole as ByteArray rather than element by element.
+    #[deriving(Clone, PartialEq, Show)]
+    pub enum ValueRef<'a> {
+        Byte(u8),
+        Boolean(bool),
+        Int16(i16),
+        UInt16(u16),
+        Int32(i32),
+        UInt32(u32),
+        Int64(i64),
+        UInt64(u64),
+        Double(f64),
+        Str(&'a str),
+        ObjectPath(&'a str),
+        Signature(&'a str),
+        UnixFd(u32),
+        ByteArray(&'a [u8]),
+        Array(DBusType, Vec<ValueRef<'a>>),
+        Struct(Vec<ValueRef<'a>>),
+        DictEntry(Box<ValueRef<'a>>, Box<ValueRef<'a>>),
+        Variant(Box<ValueRef<'a>>)
+    }
+
+    impl<'a> ValueRef<'a> {
+        pub fn as_str(&self) -> Option<&'a str> {
+            match *self {
+                Str(s) | ObjectPath(s) | Signature(s) => Some(s),
+                _ => None
+            }
+        }
+
+        pub fn as_bytes(&self) -> Option<&'a [u8]> {
+            match *self {
+                ByteArray(bytes) => Some(bytes),
+                _ => None
+            }
+        }
+
+        // Copies the value out of the buffer.
+        pub fn to_value(&self) -> Value {
+            match *self {
+                Byte(v) => value::Byte(v),
+                Boolean(v) => value::Boolean(v),
+                Int16(v) => value::Int16(v),
+                UInt16(v) => value::UInt16(v),
+                Int32(v) => value::Int32(v),
+                UInt32(v) => value::UInt32(v),
+                Int64(v) => value::Int64(v),
+                UInt64(v) => value::UInt64(v),
+                Double(v) => value::Double(v),
+                Str(v) => value::Str(String::from_str(v)),
+                ObjectPath(v) => value::ObjectPath(String::from_str(v)),
+                Signature(v) => value::Signature(String::from_str(v)),
+                UnixFd(v) => value::UnixFd(v),
+                ByteArray(v) => {
+                    value::Array(DBusType::Byte, v.iter().map(|b| value::Byte(*b)).collect())
+                },
+                Array(ref elem_type, ref items) => {
+                    value::Array(elem_type.clone(), items.iter().map(|v| v.to_value()).collect())
+                },
+                Struct(ref fields) => {
+                    value::Struct(fields.iter().map(|v| v.to_value()).collect())
+                },
+                DictEntry(ref key, ref val) => {
+                    value::DictEntry(box key.to_value(), box val.to_value())
+                },
+                Variant(ref inner) => value::Variant(box inner.to_value())
+            }
+        }
+    }
+}
+
+
+// Reads one value of type `ty`, borrowing strings from the decoder's buffer.
+pub fn read_value_ref<'a>(dec: &mut Decoder<'a>,
+                          ty: &DBusType) -> Result<ValueRef<'a>, ParseError> {
+    read_nested(dec, ty, 0)
+}
+
+fn read_nested<'a>(dec: &mut Decoder<'a>, ty: &DBusType,
+                   depth: uint) -> Result<ValueRef<'a>, ParseError> {
+    if !ty.is_basic() && depth == MAX_DEPTH {
+        return Err(TooDeep(dec.position()));
+    }
+    Ok(match *ty {
+        DBusType::Byte => ValueRef::Byte(try!(dec.read_byte())),
+        DBusType::Boolean => ValueRef::Boolean(try!(dec.read_bool())),
+        DBusType::Int16 => ValueRef::Int16(try!(dec.read_u16()) as i16),
+        DBusType::UInt16 => ValueRef::UInt16(try!(dec.read_u16())),
+        DBusType::Int32 => ValueRef::Int32(try!(dec.read_u32()) as i32),
+        DBusType::UInt32 => ValueRef::UInt32(try!(dec.read_u32())),
+        DBusType::Int64 => ValueRef::Int64(try!(dec.read_u64()) as i64),
+        DBusType::UInt64 => ValueRef::UInt64(try!(dec.read_u64())),
+        DBusType::Double => ValueRef::Double(try!(dec.read_f64())),
+        DBusType::Str => ValueRef::Str(try!(dec.read_str_ref())),
+        DBusType::ObjectPath => {
+            try!(dec.align(4));
+            let start = dec.position();
+            let path = try!(dec.read_str_ref());
+            if !is_valid_object_path(path) {
+                return Err(InvalidObjectPath(start));
+            }
+            ValueRef::ObjectPath(path)
+        },
+        DBusType::Signature => ValueRef::Signature(try!(dec.read_signature_ref())),
+        DBusType::UnixFd => ValueRef::UnixFd(try!(dec.read_u32())),
+        DBusType::Array(ref elem_type) => {
+            try!(dec.align(4));
+            let len_offset = dec.position();
+            let len = try!(dec.read_u32()) as uint;
+            if len > MAX_ARRAY_LEN {
+                return Err(InvalidArrayLength(len_offset));
+            }
+            try!(dec.align(elem_type.alignment()));
+            if **elem_type == DBusType::Byte {
+                return Ok(ValueRef::ByteArray(try!(dec.read_raw(len))));
+            }
+            if len > dec.remaining() {
+                return Err(UnexpectedEof(dec.position() + dec.remaining()));
+            }
+            let end = dec.position() + len;
+            let mut items = Vec::new();
+            while dec.position() < end {
+                items.push(try!(read_nested(dec, &**elem_type, depth + 1)));
+            }
+            if dec.position() != end {
+                return Err(InvalidArrayLength(len_offset));
+            }
+            ValueRef::Array((**elem_type).clone(), items)
+        },
+        DBusType::Struct(ref field_types) => {
+            try!(dec.align(8));
+            let mut fields = Vec::with_capacity(field_types.len());
+            for field_type in field_types.iter() {
+                fields.push(try!(read_nested(dec, field_type, depth + 1)));
+            }
+            ValueRef::Struct(fields)
+        },
+        DBusType::DictEntry(ref key_type, ref value_type) => {
+            try!(dec.align(8));
+            let key = try!(read_nested(dec, &**key_type, depth + 1));
+            let value = try!(read_nested(dec, &**value_type, depth + 1));
+            ValueRef::DictEntry(box key, box value)
+        },
+        DBusType::Variant => {
+            let sig = try!(dec.read_signature_ref());
+            let inner_type = try!(parse_single_type(sig));
+            ValueRef::Variant(box try!(read_nested(dec, &inner_type, depth + 1)))
+        }
+    })
+}
+
+
+// A received message, parsed only as far as its header.  The body is
+// decoded on request by args().
+pub struct MessageRef<'a> {
+    buf: &'a [u8],
+    endianness: Endianness,
+    msg_type: MessageType,
+    flags: u8,
+    serial: u32,
+    path: Option<&'a str>,
+    interface: Option<&'a str>,
+    member: Option<&'a str>,
+    error_name: Option<&'a str>,
+    reply_serial: Option<u32>,
+    destination: Option<&'a str>,
+    sender: Option<&'a str>,
+    signature: &'a str,
+    unix_fds: Option<u32>,
+    body_start: uint
+}
+
+impl<'a> MessageRef<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<MessageRef<'a>, ParseError> {
+        let total_len = try!(message_length(buf));
+        if buf.len() < total_len {
+            return Err(UnexpectedEof(buf.len()));
+        }
+        let buf = buf.slice_to(total_len);
+        // message_length() has already checked the marker
+        let endianness = Endianness::from_marker(buf[0]).unwrap();
+        let mut dec = Decoder::with_endianness(buf, endianness);
+        try!(dec.read_byte());
+        let msg_type = match MessageType::from_ord(try!(dec.read_byte())) {
+            Some(msg_type) => msg_type,
+            None => return Err(InvalidHeader(String::from_str("unknown message type")))
+        };
+        let flags = try!(dec.read_byte());
+        if try!(dec.read_byte()) != PROTOCOL_VERSION {
+            return Err(InvalidHeader(String::from_str("unsupported protocol version")));
+        }
+        let body_len = try!(dec.read_u32()) as uint;
+        let serial = try!(dec.read_u32());
+        if serial == 0 {
+            return Err(InvalidHeader(String::from_str("serial must not be zero")));
+        }
+
+        let mut msg = MessageRef {
+            buf: buf,
+            endianness: endianness,
+            msg_type: msg_type,
+            flags: flags,
+            serial: serial,
+            path: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            reply_serial: None,
+            destination: None,
+            sender: None,
+            signature: "",
+            unix_fds: None,
+            body_start: 0
+        };
+
+        let fields_type = DBusType::Struct(vec![DBusType::Byte, DBusType::Variant]);
+        let fields = match try!(read_value_ref(&mut dec, &DBusType::Array(box fields_type))) {
+            ValueRef::Array(_, fields) => fields,
+            _ => unreachable!()
+        };
+        for field in fields.into_iter() {
+            let (code, value) = match field {
+                ValueRef::Struct(mut parts) => {
+                    let value = parts.pop().unwrap();
+                    let code = parts.pop().unwrap();
+                    match (code, value) {
+                        (ValueRef::Byte(code), ValueRef::Variant(value)) => (code, *value),
+                        _ => unreachable!()
+                    }
+                },
+                _ => unreachable!()
+            };
+            match (code, value) {
+                (HeaderField::PATH, ValueRef::ObjectPath(v)) => msg.path = Some(v),
+                (HeaderField::INTERFACE, ValueRef::Str(v)) => msg.interface = Some(v),
+                (HeaderField::MEMBER, ValueRef::Str(v)) => msg.member = Some(v),
+                (HeaderField::ERROR_NAME, ValueRef::Str(v)) => msg.error_name = Some(v),
+                (HeaderField::REPLY_SERIAL, ValueRef::UInt32(v)) => msg.reply_serial = Some(v),
+                (HeaderField::DESTINATION, ValueRef::Str(v)) => msg.destination = Some(v),
+                (HeaderField::SENDER, ValueRef::Str(v)) => msg.sender = Some(v),
+                (HeaderField::SIGNATURE, ValueRef::Signature(v)) => msg.signature = v,
+                (HeaderField::UNIX_FDS, ValueRef::UInt32(v)) => msg.unix_fds = Some(v),
+                (code, _) => {
+                    if code >= HeaderField::PATH && code <= HeaderField::UNIX_FDS {
+                        return Err(InvalidHeader(format!("header field {} has the wrong type",
+                                                         code)));
+                    }
+                }
+            }
+        }
+        try!(dec.align(8));
+        msg.body_start = dec.position();
+        if msg.body_start + body_len != total_len {
+            return Err(InvalidHeader(String::from_str("body length does not match header")));
+        }
+        try!(msg.check_required_fields());
+        Ok(msg)
+    }
+
+    fn check_required_fields(&self) -> Result<(), ParseError> {
+        let missing = match self.msg_type {
+            MessageType::MethodCall => {
+                self.path.is_none() || self.member.is_none()
+            },
+            MessageType::Signal => {
+                self.path.is_none() || self.interface.is_none() || self.member.is_none()
+            },
+            MessageType::Error => {
+                self.error_name.is_none() || self.reply_serial.is_none()
+            },
+            MessageType::MethodReturn => self.reply_serial.is_none()
+        };
+        if missing {
+            Err(InvalidHeader(format!("{} is missing required header fields",
+                                      self.msg_type)))
+        } else {
+            Ok(())
+        }
+    }
+
+    #[inline]
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    #[inline]
+    pub fn msg_type(&self) -> MessageType {
+        self.msg_type
+    }
+
+    #[inline]
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    #[inline]
+    pub fn allow_interactive_authorization(&self) -> bool {
+        self.flags & ALLOW_INTERACTIVE_AUTHORIZATION != 0
+    }
+
+    #[inline]
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    #[inline]
+    pub fn path(&self) -> Option<&'a str> {
+        self.path
+    }
+
+    #[inline]
+    pub fn interface(&self) -> Option<&'a str> {
+        self.interface
+    }
+
+    #[inline]
+    pub fn member(&self) -> Option<&'a str> {
+        self.member
+    }
+
+    #[inline]
+    pub fn error_name(&self) -> Option<&'a str> {
+        self.error_name
+    }
+
+    #[inline]
+    pub fn reply_serial(&self) -> Option<u32> {
+        self.reply_serial
+    }
+
+    #[inline]
+    pub fn destination(&self) -> Option<&'a str> {
+        self.destination
+    }
+
+    #[inline]
+    pub fn sender(&self) -> Option<&'a str> {
+        self.sender
+    }
+
+    #[inline]
+    pub fn signature(&self) -> &'a str {
+        self.signature
+    }
+
+    #[inline]
+    pub fn unix_fds(&self) -> Option<u32> {
+        self.unix_fds
+    }
+
+    // The whole message, as received.
+    #[inline]
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    // The marshalled body, which is aligned from its own start.
+    #[inline]
+    pub fn body_bytes(&self) -> &'a [u8] {
+        self.buf.slice_from(self.body_start)
+    }
+
+    // Decodes the body.
+    pub fn args(&self) -> Result<Vec<ValueRef<'a>>, ParseError> {
+        let mut dec = Decoder::with_endianness(self.body_bytes(), self.endianness);
+        let mut out = Vec::new();
+        for ty in try!(parse_signature(self.signature)).iter() {
+            out.push(try!(read_value_ref(&mut dec, ty)));
+        }
+        if dec.remaining() != 0 {
+            return Err(InvalidHeader(String::from_str("body longer than its signature")));
+        }
+        Ok(out)
+    }
+
+    // A decoder positioned at argument `index`, and that argument's type.
+    fn arg_decoder(&self, index: uint) -> Result<(Decoder<'a>, DBusType), ParseError> {
+        let mut dec = Decoder::with_endianness(self.body_bytes(), self.endianness);
+        let mut types = try!(parse_signature(self.signature));
+        if index >= types.len() {
+            return Err(InvalidHeader(format!("message has no argument {}", index)));
+        }
+        for ty in types.slice_to(index).iter() {
+            try!(read_value_ref(&mut dec, ty));
+        }
+        Ok((dec, types.swap_remove(index).unwrap()))
+    }
+
+    // Iterates over the elements of the array argument `index`,
+    // decoding each only when it's reached.  With a message lent by
+    // DBusConnection::with_next_message(), nothing else of the array is
+    // ever decoded or copied.
+    pub fn array_iter(&self, index: uint) -> Result<ArrayIter<'a>, ParseError> {
+        let (mut dec, ty) = try!(self.arg_decoder(index));
+        let elem_type = match ty {
+            DBusType::Array(elem_type) => *elem_type,
+            _ => return Err(InvalidHeader(format!("argument {} is not an array", index)))
+        };
+        try!(dec.align(4));
+        let len_offset = dec.position();
+        let len = try!(dec.read_u32()) as uint;
+        if len > MAX_ARRAY_LEN {
+            return Err(InvalidArrayLength(len_offset));
+        }
+        try!(dec.align(elem_type.alignment()));
+        if len > dec.remaining() {
+            return Err(UnexpectedEof(dec.position() + dec.remaining()));
+        }
+        let end = dec.position() + len;
+        Ok(ArrayIter {
+            dec: dec,
+            elem_type: elem_type,
+            end: end,
+            done: false
+        })
+    }
+
+    // The byte array argument `index` in pieces of at most `chunk_len`
+    // bytes, without copying any of it: they point into the receive
+    // buffer when the message came from with_next_message().
+    // `chunk_len` must not be 0.
+    pub fn byte_chunks(&self, index: uint, chunk_len: uint) -> Result<Chunks<'a, u8>, ParseError> {
+        if chunk_len == 0 {
+            return Err(InvalidHeader(String::from_str("byte chunks must not be empty")));
+        }
+        let (mut dec, ty) = try!(self.arg_decoder(index));
+        if ty != DBusType::Array(box DBusType::Byte) {
+            return Err(InvalidHeader(format!("argument {} is not a byte array", index)));
+        }
+        match try!(read_value_ref(&mut dec, &ty)) {
+            ValueRef::ByteArray(bytes) => Ok(bytes.chunks(chunk_len)),
+            _ => unreachable!()
+        }
+    }
+
+    // The header fields in the order they were sent, as (code, value),
+    // including any with codes this crate doesn't know.
+    pub fn header_fields(&self) -> HeaderFields<'a> {
+        // parse() has already checked all of this
+        let mut dec = Decoder::with_endianness(self.buf, self.endianness);
+        dec.read_u32().unwrap();
+        dec.read_u32().unwrap();
+        dec.read_u32().unwrap();
+        let len = dec.read_u32().unwrap() as uint;
+        dec.align(8).unwrap();
+        let end = dec.position() + len;
+        HeaderFields {
+            fields: ArrayIter {
+                dec: dec,
+                elem_type: DBusType::Struct(vec![DBusType::Byte, DBusType::Variant]),
+                end: end,
+                done: false
+            }
+        }
+    }
+
+    // Copies the message out of the buffer.
+    pub fn to_message(&self) -> Result<DBusMessage, ParseError> {
+        DBusMessage::demarshal(self.buf)
+    }
+}
+
+
+// From MessageRef::header_fields().
+pub struct HeaderFields<'a> {
+    fields: ArrayIter<'a>
+}
+
+impl<'a> Iterator<(u8, ValueRef<'a>)> for HeaderFields<'a> {
+    fn next(&mut self) -> Option<(u8, ValueRef<'a>)> {
+        match self.fields.next() {
+            Some(Ok(ValueRef::Struct(mut parts))) => {
+                let value = parts.pop().unwrap();
+                let code = parts.pop().unwrap();
+                match (code, value) {
+                    (ValueRef::Byte(code), ValueRef::Variant(value)) => Some((code, *value)),
+                    _ => unreachable!()
+                }
+            },
+            None => None,
+            _ => unreachable!()
+        }
+    }
+}
+
+
+// Elements of one array argument, from MessageRef::array_iter().
+pub struct ArrayIter<'a> {
+    dec: Decoder<'a>,
+    elem_type: DBusType,
+    end: uint,
+    done: bool
+}
+
+impl<'a> Iterator<Result<ValueRef<'a>, ParseError>> for ArrayIter<'a> {
+    fn next(&mut self) -> Option<Result<ValueRef<'a>, ParseError>> {
+        if self.done {
+            return None;
+        }
+        if self.dec.position() >= self.end {
+            self.done = true;
+            if self.dec.position() != self.end {
+                return Some(Err(InvalidArrayLength(self.end)));
+            }
+            return None;
+        }
+        let result = read_value_ref(&mut self.dec, &self.elem_type);
+        if result.is_err() {
+            self.done = true;
+        }
+        Some(result)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::{DBusMessage, HeaderField};
+    use names::{BusName, InterfaceName, MemberName};
+    use value::{DBusType, Value, Byte, Str, Int32, Array};
+
+    #[test]
+    fn test_message_ref() {
+        let mut msg = DBusMessage::method_call(BusName::from_static("a.b"), "/a/b",
+                                               InterfaceName::from_static("a.b"),
+                                               MemberName::from_static("Put")).with_args(vec![
+            Str(String::from_str("name")),
+            Array(DBusType::Byte, vec![Byte(1), Byte(2), Byte(3)]),
+            Int32(-1)
+        ]);
+        msg.set_serial(5);
+        let buf = msg.marshal();
+
+        let view = MessageRef::parse(buf.as_slice()).unwrap();
+        assert_eq!(view.serial(), 5);
+        assert_eq!(view.path(), Some("/a/b"));
+        assert_eq!(view.member(), Some("Put"));
+        assert_eq!(view.signature(), "sayi");
+        let args = view.args().unwrap();
+        assert_eq!(args[0].as_str(), Some("name"));
+        assert_eq!(args[1].as_bytes(), Some([1u8, 2, 3].as_slice()));
+        assert_eq!(args[2], ValueRef::Int32(-1));
+        let owned: Vec<Value> = args.iter().map(|v| v.to_value()).collect();
+        assert_eq!(owned.as_slice(), msg.body());
+        assert_eq!(view.to_message(), Ok(msg));
+
+        let codes: Vec<u8> = view.header_fields().map(|(code, _)| code).collect();
+        assert_eq!(codes, vec![HeaderField::PATH, HeaderField::INTERFACE, HeaderField::MEMBER,
+                               HeaderField::DESTINATION, HeaderField::SIGNATURE]);
+        assert_eq!(view.header_fields().nth(2), Some((HeaderField::MEMBER, ValueRef::Str("Put"))));
+    }
+
+    #[test]
+    fn test_streaming_arrays() {
+        let data = Array(DBusType::Byte, range(0u8, 10).map(|b| Byte(b)).collect());
+        let ints = Array(DBusType::Int32, vec![Int32(1), Int32(2), Int32(3)]);
+        let mut msg = DBusMessage::signal("/a", InterfaceName::from_static("a.b"),
+                                          MemberName::from_static("C"))
+            .with_args(vec![data, ints]);
+        msg.set_serial(1);
+        let buf = msg.marshal();
+        let view = MessageRef::parse(buf.as_slice()).unwrap();
+
+        let chunks: Vec<&[u8]> = view.byte_chunks(0, 4).unwrap().collect();
+        assert_eq!(chunks, vec![[0u8, 1, 2, 3].as_slice(), [4u8, 5, 6, 7].as_slice(),
+                                [8u8, 9].as_slice()]);
+        assert!(view.byte_chunks(1, 4).is_err());
+        assert!(view.byte_chunks(0, 0).is_err());
+
+        let items: Vec<ValueRef> = view.array_iter(1).unwrap().map(|v| v.unwrap()).collect();
+        assert_eq!(items, vec![ValueRef::Int32(1), ValueRef::Int32(2), ValueRef::Int32(3)]);
+        assert!(view.array_iter(2).is_err());
+    }
+}
@@ -0,0 +1,234 @@
+// Bus, interface and member names, checked against the spec's naming
+// rules when they are made, so a bad name fails where it's written
+// rather than coming back from the bus as InvalidArgs.
+
+use std::fmt;
+
+use super::{DBusResult, DBusError, StandardError};
+
+
+// from the D-Bus specification
+pub const MAX_NAME_LEN: uint = 255;
+
+pub const DBUS_BUS_NAME: BusName<'static> = BusName { name: "org.freedesktop.DBus" };
+pub const DBUS_INTERFACE: InterfaceName<'static> =
+    InterfaceName { name: "org.freedesktop.DBus" };
+pub const DBUS_MONITORING_INTERFACE: InterfaceName<'static> =
+    InterfaceName { name: "org.freedesktop.DBus.Monitoring" };
+pub const DBUS_STATS_INTERFACE: InterfaceName<'static> =
+    InterfaceName { name: "org.freedesktop.DBus.Debug.Stats" };
+
+
+#[deriving(Clone, PartialEq, Eq, Hash)]
+pub struct BusName<'a> {
+    name: &'a str
+}
+
+impl<'a> BusName<'a> {
+    pub fn new(name: &'a str) -> DBusResult<BusName<'a>> {
+        if is_valid_bus_name(name) {
+            Ok(BusName { name: name })
+        } else {
+            Err(invalid("bus name", name))
+        }
+    }
+
+    // For names written into the program, where a bad one is a bug.
+    pub fn from_static(name: &'static str) -> BusName<'static> {
+        match BusName::new(name) {
+            Ok(name) => name,
+            Err(err) => fail!("{}", err.message())
+        }
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &'a str {
+        self.name
+    }
+
+    // Whether this is a connection's unique name, like ":1.42".
+    #[inline]
+    pub fn is_unique(&self) -> bool {
+        self.name.starts_with(":")
+    }
+}
+
+impl<'a> fmt::Show for BusName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+
+#[deriving(Clone, PartialEq, Eq, Hash)]
+pub struct InterfaceName<'a> {
+    name: &'a str
+}
+
+impl<'a> InterfaceName<'a> {
+    pub fn new(name: &'a str) -> DBusResult<InterfaceName<'a>> {
+        if is_valid_interface_name(name) {
+            Ok(InterfaceName { name: name })
+        } else {
+            Err(invalid("interface name", name))
+        }
+    }
+
+    pub fn from_static(name: &'static str) -> InterfaceName<'static> {
+        match InterfaceName::new(name) {
+            Ok(name) => name,
+            Err(err) => fail!("{}", err.message())
+        }
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &'a str {
+        self.name
+    }
+}
+
+impl<'a> fmt::Show for InterfaceName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+
+#[deriving(Clone, PartialEq, Eq, Hash)]
+pub struct MemberName<'a> {
+    name: &'a str
+}
+
+impl<'a> MemberName<'a> {
+    pub fn new(name: &'a str) -> DBusResult<MemberName<'a>> {
+        if is_valid_member_name(name) {
+            Ok(MemberName { name: name })
+        } else {
+            Err(invalid("member name", name))
+        }
+    }
+
+    pub fn from_static(name: &'static str) -> MemberName<'static> {
+        match MemberName::new(name) {
+            Ok(name) => name,
+            Err(err) => fail!("{}", err.message())
+        }
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &'a str {
+        self.name
+    }
+}
+
+impl<'a> fmt::Show for MemberName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+
+fn invalid(what: &str, name: &str) -> DBusError {
+    DBusError::standard(StandardError::InvalidArgs,
+                        format!("invalid {} {}", what, name).as_slice())
+}
+
+fn is_name_char(c: char) -> bool {
+    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') || c == '_'
+}
+
+fn is_digit(c: char) -> bool {
+    c >= '0' && c <= '9'
+}
+
+// [A-Za-z_][A-Za-z0-9_]*
+fn is_valid_element(elem: &str) -> bool {
+    match elem.chars().next() {
+        Some(c) if !is_digit(c) => elem.chars().all(is_name_char),
+        _ => false
+    }
+}
+
+// Two or more "."-separated elements of [A-Za-z0-9_-].  Elements of
+// unique names (":1.42") may start with a digit; those of well-known
+// names may not.
+pub fn is_valid_bus_name(name: &str) -> bool {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return false;
+    }
+    let (unique, rest) = if name.starts_with(":") {
+        (true, name.slice_from(1))
+    } else {
+        (false, name)
+    };
+    let mut count = 0u;
+    for elem in rest.split('.') {
+        let first = match elem.chars().next() {
+            Some(c) => c,
+            None => return false
+        };
+        if !unique && is_digit(first) {
+            return false;
+        }
+        if !elem.chars().all(|c| is_name_char(c) || c == '-') {
+            return false;
+        }
+        count += 1;
+    }
+    count >= 2
+}
+
+// Two or more "."-separated elements, none starting with a digit.
+// Error names follow the same rules.
+pub fn is_valid_interface_name(name: &str) -> bool {
+    if name.len() > MAX_NAME_LEN {
+        return false;
+    }
+    let mut count = 0u;
+    for elem in name.split('.') {
+        if !is_valid_element(elem) {
+            return false;
+        }
+        count += 1;
+    }
+    count >= 2
+}
+
+pub fn is_valid_member_name(name: &str) -> bool {
+    name.len() <= MAX_NAME_LEN && is_valid_element(name)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bus_names() {
+        assert!(is_valid_bus_name("org.freedesktop.DBus"));
+        assert!(is_valid_bus_name("com.example.my-app_2"));
+        assert!(is_valid_bus_name(":1.42"));
+        assert!(!is_valid_bus_name("org"));
+        assert!(!is_valid_bus_name("org..foo"));
+        assert!(!is_valid_bus_name("org.7zip"));
+        assert!(!is_valid_bus_name(":1"));
+        assert!(!is_valid_bus_name(""));
+        assert!(BusName::new(":1.42").unwrap().is_unique());
+        assert!(BusName::new("a.b$").is_err());
+    }
+
+    #[test]
+    fn test_interface_and_member_names() {
+        assert!(is_valid_interface_name("org.freedesktop.DBus.Peer"));
+        assert!(!is_valid_interface_name("org.free-desktop"));
+        assert!(!is_valid_interface_name("Peer"));
+        assert!(!is_valid_interface_name("org.2x"));
+        assert!(is_valid_member_name("GetMachineId"));
+        assert!(is_valid_member_name("_private"));
+        assert!(!is_valid_member_name("Get.Id"));
+        assert!(!is_valid_member_name("1st"));
+        assert!(!is_valid_member_name(""));
+        let long = String::from_char(256, 'a');
+        assert!(!is_valid_member_name(long.as_slice()));
+    }
+}
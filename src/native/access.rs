@@ -0,0 +1,83 @@
+// Who may call an exported method, declared up front instead of
+// checked by hand in every handler.  See ObjectServer::restrict_method
+// and restrict_interface.
+
+use names::BusName;
+use super::super::{DBusResult, StandardError};
+use super::connection::DBusConnection;
+
+
+// Callers running as one of `uids`, or owning one of `names`.  A new
+// policy lets nobody in.
+#[deriving(Clone, PartialEq, Show)]
+pub struct AccessPolicy {
+    uids: Vec<u32>,
+    names: Vec<String>
+}
+
+impl AccessPolicy {
+    pub fn new() -> AccessPolicy {
+        AccessPolicy {
+            uids: Vec::new(),
+            names: Vec::new()
+        }
+    }
+
+    pub fn allow_uid(mut self, uid: u32) -> AccessPolicy {
+        self.uids.push(uid);
+        self
+    }
+
+    // Lets in whichever connection currently owns `name`.
+    pub fn allow_name(mut self, name: BusName) -> AccessPolicy {
+        self.names.push(String::from_str(name.as_str()));
+        self
+    }
+
+    pub fn allows_uid(&self, uid: u32) -> bool {
+        self.uids.contains(&uid)
+    }
+
+    // Whether the connection `sender`, running as `uid` if we know, may
+    // call.  Name ownership is asked of the bus each time, since names
+    // change hands.
+    pub fn check(&self, conn: &mut DBusConnection, sender: &str,
+                 uid: Option<u32>) -> DBusResult<bool> {
+        match uid {
+            Some(uid) if self.allows_uid(uid) => return Ok(true),
+            _ => ()
+        }
+        for name in self.names.iter() {
+            if name.as_slice() == sender {
+                return Ok(true);
+            }
+            let name = try!(BusName::new(name.as_slice()));
+            match conn.get_name_owner(name) {
+                Ok(ref owner) if owner.as_slice() == sender => return Ok(true),
+                Ok(_) => (),
+                Err(ref err) if err.is_standard(StandardError::NameHasNoOwner) => (),
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(false)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use names::BusName;
+
+    #[test]
+    fn test_policy() {
+        let policy = AccessPolicy::new();
+        assert!(!policy.allows_uid(0));
+
+        let policy = policy.allow_uid(0).allow_uid(1000)
+            .allow_name(BusName::from_static("org.freedesktop.NetworkManager"));
+        assert!(policy.allows_uid(0));
+        assert!(policy.allows_uid(1000));
+        assert!(!policy.allows_uid(1001));
+    }
+}
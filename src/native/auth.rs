@@ -0,0 +1,569 @@
+// The SASL exchange that precedes the binary protocol on every D-Bus
+// connection, from both the client and the server side.
+
+use std::io::File;
+use std::os;
+use std::rand::{task_rng, Rng};
+use std::str;
+use std::time::Duration;
+use time::precise_time_ns;
+
+use libc;
+
+use super::super::{DBusResult, DBusError, StandardError};
+use super::sha1::sha1;
+
+
+// One SASL mechanism, as offered in an AUTH command.
+pub trait AuthMechanism {
+    fn name(&self) -> &'static str;
+
+    // The data sent along with AUTH, if any.
+    fn initial_response(&mut self) -> Option<Vec<u8>>;
+
+    // Answers a DATA challenge from the server.
+    fn challenge(&mut self, data: &[u8]) -> DBusResult<Vec<u8>>;
+}
+
+
+// Who we claim to be: our uid on unix, our SID string on Windows.
+#[cfg(unix)]
+fn current_identity() -> String {
+    (unsafe { libc::getuid() } as u32).to_string()
+}
+
+#[cfg(windows)]
+fn current_identity() -> String {
+    // an empty identity is rejected, letting the next mechanism try
+    super::windows::current_user_sid().unwrap_or(String::new())
+}
+
+#[cfg(unix)]
+fn current_uid() -> Option<u32> {
+    Some(unsafe { libc::getuid() } as u32)
+}
+
+#[cfg(windows)]
+fn current_uid() -> Option<u32> {
+    None
+}
+
+
+// Authenticates as our uid using the credentials the kernel passes
+// along with the socket.
+pub struct External {
+    identity: String
+}
+
+impl External {
+    pub fn new() -> External {
+        External::with_identity(current_identity().as_slice())
+    }
+
+    pub fn with_uid(uid: u32) -> External {
+        External::with_identity(uid.to_string().as_slice())
+    }
+
+    pub fn with_identity(identity: &str) -> External {
+        External {
+            identity: String::from_str(identity)
+        }
+    }
+}
+
+impl AuthMechanism for External {
+    fn name(&self) -> &'static str {
+        "EXTERNAL"
+    }
+
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        Some(self.identity.clone().into_bytes())
+    }
+
+    fn challenge(&mut self, _data: &[u8]) -> DBusResult<Vec<u8>> {
+        // the uid was already sent, so there's nothing more to say
+        Ok(Vec::new())
+    }
+}
+
+
+// Proves we can read a secret cookie from our home directory, for
+// transports (like tcp:) where the kernel can't vouch for us.
+pub struct CookieSha1 {
+    identity: String,
+    keyring_dir: Path
+}
+
+impl CookieSha1 {
+    pub fn new() -> CookieSha1 {
+        let home = os::homedir().unwrap_or(Path::new("/"));
+        CookieSha1 {
+            identity: current_identity(),
+            keyring_dir: home.join(".dbus-keyrings")
+        }
+    }
+
+    pub fn with_keyring_dir(uid: u32, keyring_dir: Path) -> CookieSha1 {
+        CookieSha1 {
+            identity: uid.to_string(),
+            keyring_dir: keyring_dir
+        }
+    }
+
+    // Looks up cookie `id` in the keyring for `context`, whose lines
+    // read "<id> <creation time> <cookie>".
+    fn find_cookie(&self, context: &str, id: &str) -> DBusResult<String> {
+        if context.is_empty() || context.starts_with(".") ||
+                context.contains_char('/') || context.contains_char('\\') {
+            return Err(auth_failed("invalid cookie context name"));
+        }
+        let path = self.keyring_dir.join(context);
+        let contents = try!(File::open(&path).read_to_string()
+                                .map_err(DBusError::from_io_error));
+        for line in contents.as_slice().lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() == 3 && fields[0] == id {
+                return Ok(String::from_str(fields[2]));
+            }
+        }
+        Err(auth_failed("cookie not found in keyring"))
+    }
+}
+
+// The hex SHA-1 of "<server challenge>:<client challenge>:<cookie>".
+pub fn cookie_response(server_challenge: &str, client_challenge: &str,
+                       cookie: &str) -> String {
+    let input = format!("{}:{}:{}", server_challenge, client_challenge, cookie);
+    hex_encode(sha1(input.as_bytes()).as_slice())
+}
+
+impl AuthMechanism for CookieSha1 {
+    fn name(&self) -> &'static str {
+        "DBUS_COOKIE_SHA1"
+    }
+
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        Some(self.identity.clone().into_bytes())
+    }
+
+    fn challenge(&mut self, data: &[u8]) -> DBusResult<Vec<u8>> {
+        let data = match str::from_utf8(data) {
+            Some(data) => data,
+            None => return Err(auth_failed("cookie challenge is not valid UTF-8"))
+        };
+        let fields: Vec<&str> = data.split(' ').collect();
+        if fields.len() != 3 {
+            return Err(auth_failed("malformed cookie challenge"));
+        }
+        let (context, id, server_challenge) = (fields[0], fields[1], fields[2]);
+        let cookie = try!(self.find_cookie(context, id));
+
+        let mut random = [0u8, ..16];
+        task_rng().fill_bytes(random.as_mut_slice());
+        let client_challenge = hex_encode(random.as_slice());
+
+        let digest = cookie_response(server_challenge, client_challenge.as_slice(),
+                                     cookie.as_slice());
+        Ok(format!("{} {}", client_challenge, digest).into_bytes())
+    }
+}
+
+
+// Doesn't identify us at all, for private peer-to-peer sockets where
+// identity doesn't matter.  The trace string is only informational.
+pub struct Anonymous {
+    trace: Option<String>
+}
+
+impl Anonymous {
+    pub fn new() -> Anonymous {
+        Anonymous {
+            trace: None
+        }
+    }
+
+    pub fn with_trace(trace: &str) -> Anonymous {
+        Anonymous {
+            trace: Some(String::from_str(trace))
+        }
+    }
+}
+
+impl AuthMechanism for Anonymous {
+    fn name(&self) -> &'static str {
+        "ANONYMOUS"
+    }
+
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        self.trace.as_ref().map(|trace| trace.clone().into_bytes())
+    }
+
+    fn challenge(&mut self, _data: &[u8]) -> DBusResult<Vec<u8>> {
+        Ok(Vec::new())
+    }
+}
+
+
+pub struct AuthResult {
+    pub server_guid: String,
+    pub unix_fd_passing: bool
+}
+
+
+pub fn hex_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() * 2);
+    for b in data.iter() {
+        out.push_str(format!("{:02x}", *b).as_slice());
+    }
+    out
+}
+
+// Works on bytes, so peer-supplied text that isn't ASCII fails to
+// decode rather than being sliced through the middle of a char.
+pub fn hex_decode(data: &str) -> Option<Vec<u8>> {
+    let digits = data.as_bytes();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(digits.len() / 2);
+    for pair in digits.chunks(2) {
+        match ((pair[0] as char).to_digit(16), (pair[1] as char).to_digit(16)) {
+            (Some(hi), Some(lo)) => out.push((hi * 16 + lo) as u8),
+            _ => return None
+        }
+    }
+    Some(out)
+}
+
+fn auth_failed(message: &str) -> DBusError {
+    DBusError::standard(StandardError::AuthFailed, message)
+}
+
+fn write_line(stream: &mut Stream, line: &str) -> DBusResult<()> {
+    try!(stream.write_str(line).map_err(DBusError::from_io_error));
+    try!(stream.write_str("\r\n").map_err(DBusError::from_io_error));
+    stream.flush().map_err(DBusError::from_io_error)
+}
+
+// Reads one CRLF-terminated line a byte at a time, so nothing past the
+// end of the SASL exchange is consumed.
+fn read_line(stream: &mut Stream) -> DBusResult<String> {
+    let mut line = Vec::new();
+    loop {
+        let b = try!(stream.read_byte().map_err(DBusError::from_io_error));
+        if b == '\n' as u8 && line.last() == Some(&('\r' as u8)) {
+            line.pop();
+            break;
+        }
+        line.push(b);
+        if line.len() > 16384 {
+            return Err(auth_failed("authentication line too long"));
+        }
+    }
+    match str::from_utf8(line.as_slice()) {
+        Some(line) => Ok(String::from_str(line)),
+        None => Err(auth_failed("authentication line is not valid UTF-8"))
+    }
+}
+
+// Splits "COMMAND args" into its command and (possibly empty) arguments.
+fn split_command<'a>(line: &'a str) -> (&'a str, &'a str) {
+    match line.find(' ') {
+        Some(pos) => (line.slice_to(pos), line.slice_from(pos + 1)),
+        None => (line, "")
+    }
+}
+
+// Tries each mechanism in turn until the server accepts one, then
+// optionally negotiates unix fd passing and sends BEGIN.
+pub fn authenticate(stream: &mut Stream, mechanisms: &mut [Box<AuthMechanism>],
+                    negotiate_unix_fd: bool) -> DBusResult<AuthResult> {
+    try!(stream.write_u8(0).map_err(DBusError::from_io_error));
+
+    let mut server_guid = None;
+    for mechanism in mechanisms.iter_mut() {
+        let auth = match mechanism.initial_response() {
+            Some(data) => format!("AUTH {} {}", mechanism.name(), hex_encode(data.as_slice())),
+            None => format!("AUTH {}", mechanism.name())
+        };
+        debug!("trying {} authentication", mechanism.name());
+        try!(write_line(stream, auth.as_slice()));
+        loop {
+            let line = try!(read_line(stream));
+            let (command, args) = split_command(line.as_slice());
+            match command {
+                "OK" => {
+                    server_guid = Some(String::from_str(args));
+                    break;
+                },
+                "REJECTED" => {
+                    debug!("server rejected {}, it offers: {}", mechanism.name(), args);
+                    break;
+                },
+                "DATA" => {
+                    let data = match hex_decode(args) {
+                        Some(data) => data,
+                        None => return Err(auth_failed("malformed DATA from server"))
+                    };
+                    match mechanism.challenge(data.as_slice()) {
+                        Ok(response) => {
+                            let reply = format!("DATA {}", hex_encode(response.as_slice()));
+                            try!(write_line(stream, reply.as_slice()));
+                        },
+                        Err(_) => try!(write_line(stream, "CANCEL"))
+                    }
+                },
+                "ERROR" => try!(write_line(stream, "CANCEL")),
+                _ => try!(write_line(stream, "ERROR \"unexpected command\""))
+            }
+        }
+        if server_guid.is_some() {
+            break;
+        }
+    }
+
+    let server_guid = match server_guid {
+        Some(guid) => guid,
+        None => return Err(auth_failed("server rejected all authentication mechanisms"))
+    };
+
+    let mut unix_fd_passing = false;
+    if negotiate_unix_fd {
+        try!(write_line(stream, "NEGOTIATE_UNIX_FD"));
+        let line = try!(read_line(stream));
+        let (command, _) = split_command(line.as_slice());
+        unix_fd_passing = command == "AGREE_UNIX_FD";
+    }
+
+    try!(write_line(stream, "BEGIN"));
+    Ok(AuthResult {
+        server_guid: server_guid,
+        unix_fd_passing: unix_fd_passing
+    })
+}
+
+
+// Which mechanisms a server accepts from connecting clients.  EXTERNAL
+// is always offered, but only works where the kernel can tell us the
+// client's uid.
+pub struct ServerAuthConfig {
+    pub allow_anonymous: bool,
+    // Refuse everything but EXTERNAL, whatever else is allowed
+    pub require_external: bool,
+    // Only accept clients running as the same uid as us
+    pub require_same_uid: bool,
+    // How long a client gets to authenticate, in all
+    pub timeout: Option<Duration>,
+    // How many times a client may be rejected before we hang up
+    pub max_failures: uint
+}
+
+// As dbus-daemon's defaults
+pub const DEFAULT_AUTH_TIMEOUT_MS: i64 = 30000;
+pub const DEFAULT_MAX_AUTH_FAILURES: uint = 6;
+
+
+impl ServerAuthConfig {
+    pub fn new() -> ServerAuthConfig {
+        ServerAuthConfig {
+            allow_anonymous: false,
+            require_external: false,
+            require_same_uid: false,
+            timeout: Some(Duration::milliseconds(DEFAULT_AUTH_TIMEOUT_MS)),
+            max_failures: DEFAULT_MAX_AUTH_FAILURES
+        }
+    }
+
+    fn offers_anonymous(&self) -> bool {
+        // anonymous clients have no uid to compare
+        self.allow_anonymous && !self.require_external && !self.require_same_uid
+    }
+
+    fn mechanisms(&self) -> String {
+        let mut out = vec!["EXTERNAL"];
+        if self.offers_anonymous() {
+            out.push("ANONYMOUS");
+        }
+        out.connect(" ")
+    }
+
+    // The uid to accept an EXTERNAL client as: the one the kernel says
+    // it has, provided that's who it claims to be and we'll take it.
+    fn external_uid(&self, peer_uid: Option<u32>, identity: &str) -> Option<u32> {
+        let uid = match peer_uid {
+            Some(uid) => uid,
+            None => return None
+        };
+        // an empty identity means whoever the kernel says
+        if !identity.is_empty() {
+            match hex_decode(identity) {
+                Some(ref claimed) if claimed.as_slice() == uid.to_string().as_bytes() => (),
+                _ => return None
+            }
+        }
+        if self.require_same_uid && Some(uid) != current_uid() {
+            return None;
+        }
+        Some(uid)
+    }
+}
+
+pub struct ServerAuthResult {
+    pub mechanism: &'static str,
+    // The client's uid, if it authenticated with EXTERNAL
+    pub uid: Option<u32>
+}
+
+pub fn generate_guid() -> String {
+    let mut random = [0u8, ..16];
+    task_rng().fill_bytes(random.as_mut_slice());
+    hex_encode(random.as_slice())
+}
+
+// Sends REJECTED, or hangs up if the client has already been rejected
+// as many times as it's allowed.
+fn reject(stream: &mut Stream, rejected: &str, failures: &mut uint,
+          max_failures: uint) -> DBusResult<()> {
+    if *failures >= max_failures {
+        info!("dropping client after {} failed authentication attempts", *failures);
+        return Err(auth_failed("too many failed authentication attempts"));
+    }
+    *failures += 1;
+    write_line(stream, rejected)
+}
+
+// Runs the server side of the exchange, returning once the client
+// has authenticated and sent BEGIN.  `peer_uid` is the client's uid as
+// the kernel reports it, if it can.
+//
+// The timeout is only checked between lines, so the caller should also
+// put a read timeout on the stream for clients that go quiet.
+pub fn serve(stream: &mut Stream, config: &ServerAuthConfig, guid: &str,
+             peer_uid: Option<u32>) -> DBusResult<ServerAuthResult> {
+    let deadline = config.timeout.map(|timeout| {
+        precise_time_ns() + timeout.num_milliseconds() as u64 * 1000000
+    });
+    if try!(stream.read_byte().map_err(DBusError::from_io_error)) != 0 {
+        return Err(auth_failed("client did not send the initial NUL byte"));
+    }
+    let rejected = format!("REJECTED {}", config.mechanisms());
+    let ok = format!("OK {}", guid);
+
+    let mut accepted = None;
+    // An EXTERNAL client that didn't send its identity with AUTH
+    let mut awaiting_identity = false;
+    let mut failures = 0u;
+    let max_failures = config.max_failures;
+    loop {
+        let line = try!(read_line(stream));
+        match deadline {
+            Some(deadline) if precise_time_ns() > deadline => {
+                info!("dropping client that took too long to authenticate");
+                return Err(auth_failed("client took too long to authenticate"));
+            },
+            _ => ()
+        }
+        let (command, args) = split_command(line.as_slice());
+        match (command, accepted) {
+            ("AUTH", None) => {
+                let (mechanism, initial) = split_command(args);
+                match mechanism {
+                    "EXTERNAL" if initial.is_empty() => {
+                        awaiting_identity = true;
+                        try!(write_line(stream, "DATA"));
+                    },
+                    "EXTERNAL" => match config.external_uid(peer_uid, initial) {
+                        Some(uid) => {
+                            accepted = Some(("EXTERNAL", Some(uid)));
+                            try!(write_line(stream, ok.as_slice()));
+                        },
+                        None => {
+                            try!(reject(stream, rejected.as_slice(), &mut failures, max_failures));
+                        }
+                    },
+                    "ANONYMOUS" if config.offers_anonymous() => {
+                        accepted = Some(("ANONYMOUS", None));
+                        try!(write_line(stream, ok.as_slice()));
+                    },
+                    _ => try!(reject(stream, rejected.as_slice(), &mut failures, max_failures))
+                }
+            },
+            ("DATA", None) if awaiting_identity => {
+                awaiting_identity = false;
+                match config.external_uid(peer_uid, args) {
+                    Some(uid) => {
+                        accepted = Some(("EXTERNAL", Some(uid)));
+                        try!(write_line(stream, ok.as_slice()));
+                    },
+                    None => try!(reject(stream, rejected.as_slice(), &mut failures, max_failures))
+                }
+            },
+            ("CANCEL", None) | ("ERROR", None) => {
+                awaiting_identity = false;
+                try!(reject(stream, rejected.as_slice(), &mut failures, max_failures));
+            },
+            ("NEGOTIATE_UNIX_FD", Some(_)) => {
+                try!(write_line(stream, "ERROR \"unix fd passing is not supported\""));
+            },
+            ("BEGIN", Some((mechanism, uid))) => {
+                debug!("client authenticated with {}", mechanism);
+                return Ok(ServerAuthResult {
+                    mechanism: mechanism,
+                    uid: uid
+                });
+            },
+            _ => try!(write_line(stream, "ERROR \"unexpected command\""))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex_encode(b"1000").as_slice(), "31303030");
+        assert_eq!(hex_decode("31303030"), Some(b"1000".to_vec()));
+        assert_eq!(hex_decode("3"), None);
+        assert_eq!(hex_decode("zz"), None);
+        assert_eq!(hex_decode("aé"), None);
+        assert_eq!(hex_decode("éé"), None);
+    }
+
+    #[test]
+    fn test_anonymous_initial_response() {
+        assert_eq!(Anonymous::new().initial_response(), None);
+        assert_eq!(Anonymous::with_trace("test").initial_response(), Some(b"test".to_vec()));
+    }
+
+    #[test]
+    fn test_cookie_response() {
+        // sha1("server:client:cookie")
+        assert_eq!(cookie_response("server", "client", "cookie").as_slice(),
+                   "da886d42e1fee942674b87d4f0d6c3d35804d5eb");
+    }
+
+    #[test]
+    fn test_server_external_uid() {
+        let mut config = ServerAuthConfig::new();
+        // "1000", hex encoded
+        assert_eq!(config.external_uid(Some(1000), "31303030"), Some(1000));
+        assert_eq!(config.external_uid(Some(1000), ""), Some(1000));
+        assert_eq!(config.external_uid(Some(1001), "31303030"), None);
+        assert_eq!(config.external_uid(None, "31303030"), None);
+
+        config.allow_anonymous = true;
+        assert!(config.offers_anonymous());
+        config.require_external = true;
+        assert!(!config.offers_anonymous());
+    }
+
+    #[test]
+    fn test_external_initial_response() {
+        let mut external = External::with_uid(1000);
+        assert_eq!(external.name(), "EXTERNAL");
+        assert_eq!(external.initial_response(), Some(b"1000".to_vec()));
+    }
+}
@@ -0,0 +1,191 @@
+// Typed wrappers for the bus daemon's own methods, so callers don't
+// have to build org.freedesktop.DBus messages by hand.
+
+use std::collections::HashMap;
+
+use credentials::Credentials;
+use message::{DBusMessage, BUS_PATH};
+use names::{BusName, InterfaceName, MemberName, DBUS_BUS_NAME, DBUS_INTERFACE};
+use names::DBUS_STATS_INTERFACE;
+use value::{Value, Array, Byte, DictEntry, Str, UInt32};
+use super::super::{DBusResult, DBusError, StandardError};
+use super::connection::DBusConnection;
+
+
+impl DBusConnection {
+    fn call_bus(&mut self, member: &'static str, args: Vec<Value>) -> DBusResult<DBusMessage> {
+        self.call_bus_interface(DBUS_INTERFACE, member, args)
+    }
+
+    fn call_bus_interface(&mut self, interface: InterfaceName<'static>, member: &'static str,
+                          args: Vec<Value>) -> DBusResult<DBusMessage> {
+        let msg = DBusMessage::method_call(DBUS_BUS_NAME, BUS_PATH, interface,
+                                           MemberName::from_static(member))
+            .with_args(args);
+        self.call(msg)
+    }
+
+    // The unique name of the connection that owns `name`.  Fails with
+    // NameHasNoOwner if nobody does.
+    pub fn get_name_owner(&mut self, name: BusName) -> DBusResult<String> {
+        let reply = try!(self.call_bus("GetNameOwner",
+                                       vec![Str(String::from_str(name.as_str()))]));
+        match reply.body().head() {
+            Some(&Str(ref owner)) => Ok(owner.clone()),
+            _ => Err(unexpected_reply("GetNameOwner"))
+        }
+    }
+
+    // Every name on the bus, unique and well-known.
+    pub fn list_names(&mut self) -> DBusResult<Vec<String>> {
+        let reply = try!(self.call_bus("ListNames", Vec::new()));
+        let names = match reply.body().head() {
+            Some(&Array(_, ref names)) => names,
+            _ => return Err(unexpected_reply("ListNames"))
+        };
+        let mut out = Vec::with_capacity(names.len());
+        for name in names.iter() {
+            match name.as_str() {
+                Some(name) => out.push(String::from_str(name)),
+                None => return Err(unexpected_reply("ListNames"))
+            }
+        }
+        Ok(out)
+    }
+
+    // The uid of the process owning `name`.
+    pub fn get_connection_unix_user(&mut self, name: BusName) -> DBusResult<u32> {
+        let reply = try!(self.call_bus("GetConnectionUnixUser",
+                                       vec![Str(String::from_str(name.as_str()))]));
+        reply_u32(&reply, "GetConnectionUnixUser")
+    }
+
+    // The pid of the process owning `name`.
+    pub fn get_connection_unix_process_id(&mut self, name: BusName) -> DBusResult<u32> {
+        let reply = try!(self.call_bus("GetConnectionUnixProcessID",
+                                       vec![Str(String::from_str(name.as_str()))]));
+        reply_u32(&reply, "GetConnectionUnixProcessID")
+    }
+
+    // Everything the bus knows about the process owning `name`.
+    pub fn get_connection_credentials(&mut self, name: BusName) -> DBusResult<Credentials> {
+        let reply = try!(self.call_bus("GetConnectionCredentials",
+                                       vec![Str(String::from_str(name.as_str()))]));
+        match reply.body().head() {
+            Some(dict) => Credentials::from_value(dict),
+            None => Err(unexpected_reply("GetConnectionCredentials"))
+        }
+    }
+
+    // The SELinux context of the process owning `name`, as the raw
+    // label bytes.  Fails with SELinuxSecurityContextUnknown on buses
+    // without SELinux.
+    pub fn get_connection_selinux_security_context(&mut self, name: BusName)
+                                                   -> DBusResult<Vec<u8>> {
+        let reply = try!(self.call_bus("GetConnectionSELinuxSecurityContext",
+                                       vec![Str(String::from_str(name.as_str()))]));
+        let bytes = match reply.body().head() {
+            Some(&Array(_, ref bytes)) => bytes,
+            _ => return Err(unexpected_reply("GetConnectionSELinuxSecurityContext"))
+        };
+        let mut label = Vec::with_capacity(bytes.len());
+        for b in bytes.iter() {
+            match *b {
+                Byte(b) => label.push(b),
+                _ => return Err(unexpected_reply("GetConnectionSELinuxSecurityContext"))
+            }
+        }
+        Ok(label)
+    }
+
+    // The LinuxSecurityLabel credential (SELinux, AppArmor or Smack,
+    // whichever the kernel uses), falling back to the SELinux-only
+    // method on buses too old to report credentials.
+    pub fn get_connection_security_label(&mut self, name: BusName) -> DBusResult<Vec<u8>> {
+        match self.get_connection_credentials(name) {
+            Ok(creds) => match creds.linux_security_label {
+                Some(label) => Ok(label),
+                None => Err(DBusError::standard(StandardError::SELinuxSecurityContextUnknown,
+                                                "the bus reported no security label"))
+            },
+            Err(ref err) if err.is_standard(StandardError::UnknownMethod) => {
+                self.get_connection_selinux_security_context(name)
+            },
+            Err(err) => Err(err)
+        }
+    }
+}
+
+
+// The Debug.Stats interface, which dbus-daemon only has when built
+// with --enable-stats.  Elsewhere these fail with UnknownInterface or
+// UnknownMethod.
+impl DBusConnection {
+    // The daemon's own counters, by name.
+    pub fn get_bus_stats(&mut self) -> DBusResult<HashMap<String, Value>> {
+        let reply = try!(self.call_bus_interface(DBUS_STATS_INTERFACE, "GetStats", Vec::new()));
+        reply_string_map(&reply, "GetStats", |v| Some(v.inner().clone()))
+    }
+
+    // The daemon's counters for the connection owning `name`.
+    pub fn get_connection_bus_stats(&mut self, name: BusName)
+                                    -> DBusResult<HashMap<String, Value>> {
+        let reply = try!(self.call_bus_interface(DBUS_STATS_INTERFACE, "GetConnectionStats",
+                                                 vec![Str(String::from_str(name.as_str()))]));
+        reply_string_map(&reply, "GetConnectionStats", |v| Some(v.inner().clone()))
+    }
+
+    // Every match rule on the bus, by the unique name that added it.
+    pub fn get_all_match_rules(&mut self) -> DBusResult<HashMap<String, Vec<String>>> {
+        let reply = try!(self.call_bus_interface(DBUS_STATS_INTERFACE, "GetAllMatchRules",
+                                                 Vec::new()));
+        reply_string_map(&reply, "GetAllMatchRules", |v| match *v {
+            Array(_, ref rules) => {
+                let mut out = Vec::with_capacity(rules.len());
+                for rule in rules.iter() {
+                    match rule.as_str() {
+                        Some(rule) => out.push(String::from_str(rule)),
+                        None => return None
+                    }
+                }
+                Some(out)
+            },
+            _ => None
+        })
+    }
+}
+
+
+// Decodes a reply holding one a{s?}, converting each value with
+// `convert`, which returns None if it has the wrong type.
+fn reply_string_map<T>(reply: &DBusMessage, member: &str,
+                       convert: |&Value| -> Option<T>) -> DBusResult<HashMap<String, T>> {
+    let entries = match reply.body().head() {
+        Some(&Array(_, ref entries)) => entries,
+        _ => return Err(unexpected_reply(member))
+    };
+    let mut out = HashMap::with_capacity(entries.len());
+    for entry in entries.iter() {
+        let (key, value) = match *entry {
+            DictEntry(ref key, ref value) => match (key.as_str(), convert(&**value)) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err(unexpected_reply(member))
+            },
+            _ => return Err(unexpected_reply(member))
+        };
+        out.insert(String::from_str(key), value);
+    }
+    Ok(out)
+}
+
+fn unexpected_reply(member: &str) -> DBusError {
+    DBusError::standard(StandardError::InconsistentMessage,
+                        format!("unexpected reply to {}", member).as_slice())
+}
+
+fn reply_u32(reply: &DBusMessage, member: &str) -> DBusResult<u32> {
+    match reply.body().head() {
+        Some(&UInt32(value)) => Ok(value),
+        _ => Err(unexpected_reply(member))
+    }
+}
@@ -0,0 +1,1215 @@
+use std::collections::{Deque, HashSet, RingBuf};
+use std::io::{IoResult, ResourceUnavailable, TimedOut};
+use std::mem;
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+use time::precise_time_ns;
+
+use address::{BusAddress, parse_addresses};
+use arena::DecodeArena;
+use clock::{Clock, SystemClock};
+use error_registry::{ErrorRegistry, MappedError};
+use message::{DBusMessage, MessageType};
+use message_ref::MessageRef;
+use pool::MessagePool;
+use stats::ConnectionStats;
+use message::BUS_PATH;
+use names::{BusName, MemberName, DBUS_BUS_NAME, DBUS_INTERFACE, DBUS_MONITORING_INTERFACE};
+use value::{DBusType, Array, Str, UInt32};
+use super::super::{DBusResult, DBusError, StandardError, DBusDispatchStatus};
+use super::super::{get_dbus_session_address_in, get_dbus_starter_address_in};
+use super::super::{get_dbus_starter_bus_type_in, get_dbus_system_address};
+use super::super::get_dbus_system_address_in;
+use super::super::env::{Environment, ProcessEnvironment};
+use super::auth::{mod, Anonymous, AuthMechanism, CookieSha1, External};
+use super::mock::memory_pipe;
+use super::policy::SecurityPolicy;
+use super::reader::MessageReader;
+use super::sender::{MessageSender, SendQueue};
+use super::trace::{Direction, Tracer};
+use super::transport::{mod, UnixSocket};
+
+
+pub use super::super::DEFAULT_SYSTEM_BUS_ADDRESS;
+
+// How much to ask the stream for at a time.
+static READ_CHUNK: uint = 4096;
+// send_batch() writes out what it has gathered past this size.
+static BATCH_WRITE_LEN: uint = 64 * 1024;
+// How long call_with_timeout() waits on the stream before looking at
+// the clock again.
+static WAIT_SLICE_MS: uint = 50;
+// libdbus's defaults for the size limits
+pub const DEFAULT_MAX_MESSAGE_SIZE: uint = 32 * 1024 * 1024;
+pub const DEFAULT_MAX_RECEIVED_SIZE: uint = 63 * 1024 * 1024;
+pub const DEFAULT_MAX_MESSAGE_UNIX_FDS: uint = 1024;
+pub const DEFAULT_MAX_RECEIVED_UNIX_FDS: uint = 4 * DEFAULT_MAX_MESSAGE_UNIX_FDS;
+
+// Where to look for the session bus when DBUS_SESSION_BUS_ADDRESS is unset.
+#[cfg(target_os = "macos")]
+fn default_session_address(_env: &Environment) -> Option<String> {
+    Some(String::from_str("launchd:env=DBUS_LAUNCHD_SESSION_BUS_SOCKET"))
+}
+
+#[cfg(windows)]
+fn default_session_address(_env: &Environment) -> Option<String> {
+    Some(String::from_str("autolaunch:"))
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn default_session_address(env: &Environment) -> Option<String> {
+    if env.var("DISPLAY").map_or(false, |display| !display.is_empty()) {
+        Some(String::from_str("autolaunch:"))
+    } else {
+        None
+    }
+}
+
+
+pub struct DBusConnection {
+    stream: Box<Stream + Send>,
+    // The socket under `stream`, when it is a unix socket, for waiting
+    // on and for the fds that come with messages
+    socket: Option<UnixSocket>,
+    server_id: String,
+    unix_fd_passing: bool,
+    // Serials, and messages queued by MessageSenders
+    queue: Arc<SendQueue>,
+    // The buffer the queue was last drained into
+    queued_buf: Vec<u8>,
+    unique_name: Option<String>,
+    reader: MessageReader,
+    // Reused for marshalling outgoing messages
+    write_buf: Vec<u8>,
+    // Messages read while waiting for a reply, with their lengths
+    incoming: RingBuf<(DBusMessage, uint)>,
+    incoming_len: uint,
+    // Unix fds declared by the messages in `incoming`
+    incoming_fds: uint,
+    max_received_size: uint,
+    max_message_unix_fds: uint,
+    max_received_unix_fds: uint,
+    stats: ConnectionStats,
+    policy: Option<SecurityPolicy>,
+    // Set by become_monitor(), after which we may only receive
+    monitor: bool,
+    tracer: Option<Box<Tracer + Send>>,
+    // Rules added with add_match() and not yet removed, once per add
+    match_rules: Vec<String>,
+    // Serials of method calls still waiting for a reply
+    awaiting_reply: HashSet<u32>,
+    // Kept empty between with_next_decoded() calls, for its memory
+    arena: Option<DecodeArena<'static>>
+}
+
+
+// Counts a received message in `stats`, matching replies against
+// `awaiting_reply`.  Apart from the connection so the borrowed receive
+// path can count while the reader is lent out.
+fn count_received_in(stats: &mut ConnectionStats, awaiting_reply: &mut HashSet<u32>,
+                     msg_type: MessageType, reply_serial: Option<u32>, routed: bool) {
+    stats.messages_received += 1;
+    match msg_type {
+        MessageType::MethodReturn | MessageType::Error => (),
+        _ => return
+    }
+    let matched = routed || match reply_serial {
+        Some(serial) => awaiting_reply.remove(&serial),
+        None => false
+    };
+    if matched {
+        stats.replies_matched += 1;
+    } else {
+        stats.replies_orphaned += 1;
+    }
+}
+
+// Lends an owned message to `f` as with_next_message() would, by way
+// of its marshalled bytes.
+fn lend<T>(msg: &DBusMessage, f: |MessageRef| -> T) -> DBusResult<T> {
+    let buf = msg.marshal();
+    let msg = try!(MessageRef::parse(buf.as_slice()).map_err(|err| err.to_dbus_error()));
+    Ok(f(msg))
+}
+
+
+// What open() offers the server, in order of preference.
+fn default_mechanisms() -> Vec<Box<AuthMechanism>> {
+    vec![box External::new() as Box<AuthMechanism>,
+         box CookieSha1::new() as Box<AuthMechanism>,
+         box Anonymous::new() as Box<AuthMechanism>]
+}
+
+
+impl DBusConnection {
+    pub fn open(address: &[u8]) -> DBusResult<DBusConnection> {
+        DBusConnection::open_with_mechanisms(address, default_mechanisms())
+    }
+
+    // Connects offering only `mechanisms`, in order.
+    pub fn open_with_mechanisms(address: &[u8], mut mechanisms: Vec<Box<AuthMechanism>>)
+                                -> DBusResult<DBusConnection> {
+        let address = match str::from_utf8(address) {
+            Some(address) => address,
+            None => return Err(DBusError::standard(StandardError::BadAddress,
+                                                   "address is not valid UTF-8"))
+        };
+        // tried in order, as the spec asks; the error names every failure
+        let addresses = try!(parse_addresses(address));
+        let mut failures = Vec::new();
+        let mut last_err = None;
+        for address in addresses.iter() {
+            match DBusConnection::connect_to(address, mechanisms.as_mut_slice()) {
+                Ok(conn) => return Ok(conn),
+                Err(err) => {
+                    debug!("couldn't connect to {}: {}", address, err);
+                    failures.push(format!("{}: {}", address, err));
+                    last_err = Some(err);
+                }
+            }
+        }
+        let last_err = last_err.unwrap();
+        if failures.len() == 1 {
+            return Err(last_err);
+        }
+        Err(DBusError::new(last_err.name(),
+                           format!("couldn't connect to any address: {}",
+                                   failures.connect("; ")).as_slice()))
+    }
+
+    fn connect_to(address: &BusAddress, mechanisms: &mut [Box<AuthMechanism>])
+                  -> DBusResult<DBusConnection> {
+        debug!("connecting to {}", address);
+        let transport::Connected { mut stream, writer, socket } =
+            try!(transport::connect(address));
+        // only unix sockets can carry fds, and we can only receive them
+        let negotiate_unix_fd = socket.as_ref().map_or(false, |socket| socket.receives_fds());
+        let result = try!(auth::authenticate(&mut *stream, mechanisms, negotiate_unix_fd));
+        debug!("authenticated to server {}", result.server_guid);
+        let guid = result.server_guid.as_slice();
+        let mut conn = match writer {
+            Some(writer) => DBusConnection::from_split_stream(stream, writer, guid),
+            None => DBusConnection::from_stream(stream, guid)
+        };
+        conn.socket = socket;
+        conn.unix_fd_passing = result.unix_fd_passing;
+        Ok(conn)
+    }
+
+    // Native connections are never shared, so this is the same as open().
+    pub fn open_private(address: &[u8]) -> DBusResult<DBusConnection> {
+        DBusConnection::open(address)
+    }
+
+    #[inline]
+    pub fn is_shared(&self) -> bool {
+        false
+    }
+
+    // Where the session bus is, from the environment or the platform's
+    // usual place.
+    pub fn session_address() -> DBusResult<String> {
+        DBusConnection::session_address_in(&ProcessEnvironment)
+    }
+
+    pub fn session_address_in(env: &Environment) -> DBusResult<String> {
+        match get_dbus_session_address_in(env).or_else(|| default_session_address(env)) {
+            Some(address) => Ok(address),
+            None => Err(DBusError::standard(StandardError::BadAddress,
+                                            "DBUS_SESSION_BUS_ADDRESS is not set, and there's \
+                                             no bus in XDG_RUNTIME_DIR"))
+        }
+    }
+
+    pub fn system_address() -> String {
+        get_dbus_system_address()
+    }
+
+    // The bus that activated this process: DBUS_STARTER_ADDRESS, or
+    // failing that the bus DBUS_STARTER_BUS_TYPE names.
+    pub fn starter_address() -> DBusResult<String> {
+        DBusConnection::starter_address_in(&ProcessEnvironment)
+    }
+
+    pub fn starter_address_in(env: &Environment) -> DBusResult<String> {
+        match get_dbus_starter_address_in(env) {
+            Some(address) => return Ok(address),
+            None => ()
+        }
+        match get_dbus_starter_bus_type_in(env) {
+            Some(ref bus) if bus.as_slice() == "session" => {
+                DBusConnection::session_address_in(env)
+            },
+            Some(ref bus) if bus.as_slice() == "system" => Ok(get_dbus_system_address_in(env)),
+            Some(bus) => Err(DBusError::standard(StandardError::BadAddress,
+                                                 format!("unknown DBUS_STARTER_BUS_TYPE {}",
+                                                         bus).as_slice())),
+            None => Err(DBusError::standard(StandardError::BadAddress,
+                                            "not activated by a bus: DBUS_STARTER_ADDRESS \
+                                             is not set"))
+        }
+    }
+
+    // Connects and registers with the bus that activated this process.
+    pub fn starter() -> DBusResult<DBusConnection> {
+        let address = try!(DBusConnection::starter_address());
+        let mut conn = try!(DBusConnection::open(address.as_bytes()));
+        try!(conn.bus_register());
+        Ok(conn)
+    }
+
+    // Connects and registers with the session bus.
+    pub fn session() -> DBusResult<DBusConnection> {
+        let address = try!(DBusConnection::session_address());
+        let mut conn = try!(DBusConnection::open(address.as_bytes()));
+        try!(conn.bus_register());
+        Ok(conn)
+    }
+
+    // Connects and registers with the system bus.
+    pub fn system() -> DBusResult<DBusConnection> {
+        let address = DBusConnection::system_address();
+        let mut conn = try!(DBusConnection::open(address.as_bytes()));
+        try!(conn.bus_register());
+        Ok(conn)
+    }
+
+    // Two connections talking directly to each other in memory, like a
+    // peer-to-peer connection with no socket or bus behind it.  Reads
+    // block until the other end sends.
+    pub fn pair() -> (DBusConnection, DBusConnection) {
+        let (a, b) = memory_pipe(true);
+        let (a_writer, b_writer) = (a.writer(), b.writer());
+        (DBusConnection::from_split_stream(box a as Box<Stream + Send>,
+                                           box a_writer as Box<Writer + Send>, "pair"),
+         DBusConnection::from_split_stream(box b as Box<Stream + Send>,
+                                           box b_writer as Box<Writer + Send>, "pair"))
+    }
+
+    // Wraps a stream on which authentication has already completed.
+    // MessageSenders' messages wait for the connection to write them.
+    pub fn from_stream(stream: Box<Stream + Send>, server_id: &str) -> DBusConnection {
+        DBusConnection::with_queue(stream, SendQueue::new(), server_id)
+    }
+
+    // Like from_stream(), with a second handle on the stream's write
+    // side, e.g. a clone of its socket.  MessageSenders write through
+    // it straight away, even while the connection is blocked reading.
+    pub fn from_split_stream(stream: Box<Stream + Send>, writer: Box<Writer + Send>,
+                             server_id: &str) -> DBusConnection {
+        DBusConnection::with_queue(stream, SendQueue::with_writer(writer), server_id)
+    }
+
+    // A connection over a unix socket that has already been through
+    // the auth exchange.  Fds that come with messages are kept for
+    // DBusMessage::unix_fd().
+    #[cfg(unix)]
+    pub fn from_unix_socket(socket: UnixSocket, server_id: &str) -> DBusConnection {
+        let stream = box socket.clone() as Box<Stream + Send>;
+        let writer = box socket.clone() as Box<Writer + Send>;
+        let mut conn = DBusConnection::from_split_stream(stream, writer, server_id);
+        conn.socket = Some(socket);
+        conn
+    }
+
+    fn with_queue(stream: Box<Stream + Send>, queue: SendQueue,
+                  server_id: &str) -> DBusConnection {
+        let mut reader = MessageReader::new();
+        reader.set_max_message_len(DEFAULT_MAX_MESSAGE_SIZE);
+        DBusConnection {
+            stream: stream,
+            socket: None,
+            server_id: String::from_str(server_id),
+            unix_fd_passing: false,
+            queue: Arc::new(queue),
+            queued_buf: Vec::new(),
+            unique_name: None,
+            reader: reader,
+            write_buf: Vec::new(),
+            incoming: RingBuf::new(),
+            incoming_len: 0,
+            incoming_fds: 0,
+            max_received_size: DEFAULT_MAX_RECEIVED_SIZE,
+            max_message_unix_fds: DEFAULT_MAX_MESSAGE_UNIX_FDS,
+            max_received_unix_fds: DEFAULT_MAX_RECEIVED_UNIX_FDS,
+            stats: Default::default(),
+            policy: None,
+            monitor: false,
+            tracer: None,
+            match_rules: Vec::new(),
+            awaiting_reply: HashSet::new(),
+            arena: None
+        }
+    }
+
+    pub fn get_server_id(&mut self) -> &str {
+        self.server_id.as_slice()
+    }
+
+    // Whether the server agreed to NEGOTIATE_UNIX_FD.  We only receive
+    // fds so far; those of messages we send don't go with them.
+    #[inline]
+    pub fn can_send_unix_fds(&self) -> bool {
+        self.unix_fd_passing
+    }
+
+    // Our unique name on the bus, once bus_register() has succeeded.
+    pub fn unique_name(&self) -> Option<&str> {
+        self.unique_name.as_ref().map(|s| s.as_slice())
+    }
+
+    // Traffic counters since the connection was opened.  Calls sent
+    // through a MessageSender count towards messages and bytes sent but
+    // not calls in flight, since we never see their serials.
+    pub fn stats(&self) -> ConnectionStats {
+        let mut stats = self.stats.clone();
+        let (messages, bytes) = self.queue.written();
+        stats.messages_sent += messages as u64;
+        stats.bytes_sent += bytes as u64;
+        stats.calls_in_flight = self.awaiting_reply.len();
+        stats
+    }
+
+    // Incoming messages longer than this are refused with
+    // LimitsExceeded, which leaves the connection unusable.
+    pub fn set_max_message_size(&mut self, size: uint) {
+        self.reader.set_max_message_len(size);
+    }
+
+    pub fn get_max_message_size(&self) -> uint {
+        self.reader.max_message_len()
+    }
+
+    // Bounds the bytes read but not yet returned: those buffered
+    // towards the next message plus those queued while call() waits
+    // for its reply.  Reading stops with LimitsExceeded once it is
+    // reached, so a peer flooding us can't grow the queue forever.
+    pub fn set_max_received_size(&mut self, size: uint) {
+        self.max_received_size = size;
+    }
+
+    pub fn get_max_received_size(&self) -> uint {
+        self.max_received_size
+    }
+
+    // Incoming messages carrying more unix fds than this are dropped,
+    // and method calls among them answered with LimitsExceeded.  The
+    // connection carries on with the next message.
+    pub fn set_max_message_unix_fds(&mut self, n: uint) {
+        self.max_message_unix_fds = n;
+    }
+
+    pub fn get_max_message_unix_fds(&self) -> uint {
+        self.max_message_unix_fds
+    }
+
+    // Bounds the unix fds held by messages queued while call() waits
+    // for its reply, as set_max_received_size() does for bytes.
+    pub fn set_max_received_unix_fds(&mut self, n: uint) {
+        self.max_received_unix_fds = n;
+    }
+
+    pub fn get_max_received_unix_fds(&self) -> uint {
+        self.max_received_unix_fds
+    }
+
+    // Drops incoming messages `policy` doesn't allow, answering method
+    // calls with AccessDenied.  Replies to our own calls always get
+    // through.  None, the default, lets everything in.
+    pub fn set_security_policy(&mut self, policy: Option<SecurityPolicy>) {
+        self.policy = policy;
+    }
+
+    pub fn get_security_policy(&self) -> Option<&SecurityPolicy> {
+        self.policy.as_ref()
+    }
+
+    // Whether the policy lets `msg` in.  Must be asked before
+    // count_received forgets which calls are awaiting replies.
+    fn is_allowed(&self, msg: &DBusMessage) -> bool {
+        let policy = match self.policy {
+            Some(ref policy) => policy,
+            None => return true
+        };
+        match msg.reply_serial() {
+            Some(serial) if self.awaiting_reply.contains(&serial) => return true,
+            _ => ()
+        }
+        policy.allows(msg)
+    }
+
+    fn reject(&mut self, msg: &DBusMessage) -> DBusResult<()> {
+        info!("security policy dropped {} {}.{} from {}", msg.msg_type(),
+              msg.interface().unwrap_or(""), msg.member().unwrap_or(""),
+              msg.sender().unwrap_or("(no sender)"));
+        self.stats.messages_rejected += 1;
+        if msg.msg_type() != MessageType::MethodCall || msg.no_reply_expected() || self.monitor {
+            return Ok(());
+        }
+        let message = format!("{}.{} is not allowed by this connection's policy",
+                              msg.interface().unwrap_or(""),
+                              msg.member().unwrap_or(""));
+        let reply = DBusMessage::error(msg, StandardError::AccessDenied.name(),
+                                       message.as_slice());
+        self.send(reply).map(|_| ())
+    }
+
+    // Drops a message carrying more than max_message_unix_fds, telling
+    // the caller why if it's a call.
+    fn refuse_fds(&mut self, msg: &DBusMessage, fds: uint) -> DBusResult<()> {
+        let message = format!("message {} carries {} unix fds, over the limit of {}",
+                              msg.serial(), fds, self.max_message_unix_fds);
+        warn!("dropped {} from {}", message, msg.sender().unwrap_or("(no sender)"));
+        self.stats.messages_rejected += 1;
+        if msg.msg_type() != MessageType::MethodCall || msg.no_reply_expected() || self.monitor {
+            return Ok(());
+        }
+        let reply = DBusMessage::error(msg, StandardError::LimitsExceeded.name(),
+                                       message.as_slice());
+        self.send(reply).map(|_| ())
+    }
+
+    // Shows `tracer` every message sent or received from now on, in
+    // place of any tracer set before.
+    pub fn set_tracer<T: Tracer + Send>(&mut self, tracer: T) {
+        self.tracer = Some(box tracer as Box<Tracer + Send>);
+    }
+
+    pub fn clear_tracer(&mut self) {
+        self.tracer = None;
+    }
+
+    fn next_serial(&mut self) -> u32 {
+        self.queue.next_serial()
+    }
+
+    // Serials count up from 1 for each connection, MessageSenders
+    // included.  This starts them over from `first`; replies to calls
+    // already in flight may then be mistaken for replies to new ones.
+    pub fn reset_serials(&mut self, first: u32) {
+        self.queue.reset_serials(first);
+    }
+
+    // A handle other tasks can send through.
+    pub fn sender(&self) -> MessageSender {
+        MessageSender::new(self.queue.clone())
+    }
+
+    fn check_can_send(&self) -> DBusResult<()> {
+        if self.monitor {
+            return Err(DBusError::standard(StandardError::NotSupported,
+                                           "monitor connections can't send messages"));
+        }
+        Ok(())
+    }
+
+    // Writes and flushes `bytes`, through the writer MessageSenders
+    // share if there is one, so our messages and theirs don't interleave.
+    fn write_out(&mut self, bytes: &[u8]) -> IoResult<()> {
+        if self.queue.has_writer() {
+            self.queue.write(bytes)
+        } else {
+            self.stream.write(bytes).and_then(|()| self.stream.flush())
+        }
+    }
+
+    // Writes out whatever MessageSenders have queued.  Monitors drop it.
+    fn write_queued(&mut self) -> DBusResult<()> {
+        let spare = mem::replace(&mut self.queued_buf, Vec::new());
+        let (queued, count) = self.queue.take(spare);
+        if self.monitor && count > 0 {
+            warn!("monitor connection dropped {} queued messages", count);
+        }
+        let result = if queued.is_empty() || self.monitor {
+            Ok(())
+        } else {
+            self.write_out(queued.as_slice())
+        };
+        if result.is_ok() {
+            self.stats.messages_sent += count as u64;
+            self.stats.bytes_sent += queued.len() as u64;
+        }
+        self.queued_buf = queued;
+        result.map_err(DBusError::from_io_error)
+    }
+
+    // Assigns the message a serial and writes it out, returning the serial.
+    pub fn send(&mut self, mut msg: DBusMessage) -> DBusResult<u32> {
+        self.send_ref(&mut msg)
+    }
+
+    // Sends the message, then hands it back to the pool for reuse.
+    pub fn send_pooled(&mut self, mut msg: DBusMessage,
+                       pool: &mut MessagePool) -> DBusResult<u32> {
+        let result = self.send_ref(&mut msg);
+        pool.recycle(msg);
+        result
+    }
+
+    // Sends the messages in order, returning their serials.  They are
+    // marshalled into one buffer and written with a single write and
+    // flush (or one of each per 64KiB for big batches), rather than one
+    // of each per message as with send() in a loop.
+    pub fn send_batch(&mut self, msgs: Vec<DBusMessage>) -> DBusResult<Vec<u32>> {
+        try!(self.check_can_send());
+        try!(self.write_queued());
+        let mut serials = Vec::with_capacity(msgs.len());
+        let mut batch = mem::replace(&mut self.write_buf, Vec::new());
+        batch.clear();
+        // each message's padding is relative to its own start, so they
+        // can't be marshalled straight into the batch
+        let mut scratch = Vec::new();
+        // what's in `batch`, counted once it has been written
+        let mut unwritten = Vec::new();
+        let mut result = Ok(());
+        for mut msg in msgs.into_iter() {
+            let serial = self.next_serial();
+            msg.set_serial(serial);
+            serials.push(serial);
+            scratch = msg.marshal_into(scratch);
+            batch.push_all(scratch.as_slice());
+            unwritten.push((msg, scratch.len()));
+            if batch.len() >= BATCH_WRITE_LEN {
+                result = self.write_out(batch.as_slice());
+                batch.clear();
+                if result.is_err() {
+                    break;
+                }
+                for (msg, len) in mem::replace(&mut unwritten, Vec::new()).into_iter() {
+                    self.count_sent(&msg, len);
+                }
+            }
+        }
+        if result.is_ok() && !batch.is_empty() {
+            result = self.write_out(batch.as_slice());
+        }
+        if result.is_ok() {
+            for (msg, len) in unwritten.into_iter() {
+                self.count_sent(&msg, len);
+            }
+        }
+        self.write_buf = batch;
+        try!(result.map_err(DBusError::from_io_error));
+        Ok(serials)
+    }
+
+    fn send_ref(&mut self, msg: &mut DBusMessage) -> DBusResult<u32> {
+        try!(self.check_can_send());
+        try!(self.write_queued());
+        let serial = self.next_serial();
+        msg.set_serial(serial);
+        let buf = msg.marshal_into(mem::replace(&mut self.write_buf, Vec::new()));
+        let result = self.write_out(buf.as_slice());
+        if result.is_ok() {
+            self.count_sent(msg, buf.len());
+        }
+        self.write_buf = buf;
+        try!(result.map_err(DBusError::from_io_error));
+        Ok(serial)
+    }
+
+    fn count_sent(&mut self, msg: &DBusMessage, len: uint) {
+        for tracer in self.tracer.iter_mut() {
+            tracer.trace(Direction::Sent, msg);
+        }
+        self.stats.messages_sent += 1;
+        self.stats.bytes_sent += len as u64;
+        if msg.msg_type() == MessageType::MethodCall && !msg.no_reply_expected() {
+            self.awaiting_reply.insert(msg.serial());
+        }
+    }
+
+    fn count_received(&mut self, msg: &DBusMessage, routed: bool) {
+        for tracer in self.tracer.iter_mut() {
+            tracer.trace(Direction::Received, msg);
+        }
+        count_received_in(&mut self.stats, &mut self.awaiting_reply, msg.msg_type(),
+                          msg.reply_serial(), routed);
+    }
+
+    // What becomes of a message just read: None if it was dealt with
+    // here, by being rejected, refused or routed to a MessageSender.
+    fn accept_received(&mut self, mut msg: DBusMessage) -> DBusResult<Option<DBusMessage>> {
+        // the fds came in order, so take this message's even if it's dropped
+        let fds = msg.unix_fds().unwrap_or(0) as uint;
+        if fds > 0 {
+            match self.socket {
+                Some(ref socket) => msg.set_received_fds(socket.take_fds(fds)),
+                None => ()
+            }
+        }
+        // replies to MessageSender::call() go to the caller
+        let route = self.queue.take_route(&msg);
+        let allowed = route.is_some() || self.is_allowed(&msg);
+        self.count_received(&msg, route.is_some());
+        if !allowed {
+            try!(self.reject(&msg));
+            return Ok(None);
+        }
+        if fds > self.max_message_unix_fds {
+            try!(self.refuse_fds(&msg, fds));
+            return Ok(None);
+        }
+        match route {
+            Some(route) => {
+                // the caller may have given up by now
+                let _ = route.send_opt(msg);
+                Ok(None)
+            },
+            None => Ok(Some(msg))
+        }
+    }
+
+    // Feeds the reader whatever the stream has next.
+    fn read_more(&mut self) -> DBusResult<()> {
+        if self.incoming_len + self.reader.buffered() >= self.max_received_size {
+            return Err(DBusError::standard(StandardError::LimitsExceeded,
+                                           "received more than max_received_size"));
+        }
+        // anything queued may be what the peer is waiting for
+        try!(self.write_queued());
+        let mut chunk = [0u8, ..READ_CHUNK];
+        let len = try!(self.stream.read(chunk).map_err(DBusError::from_io_error));
+        self.stats.bytes_received += len as u64;
+        self.reader.feed(chunk.slice_to(len));
+        Ok(())
+    }
+
+    // The next message from the stream, and how many bytes it took.
+    fn read_from_wire(&mut self) -> DBusResult<(DBusMessage, uint)> {
+        loop {
+            let buffered = self.reader.buffered();
+            match self.reader.next_message() {
+                Some(Ok(msg)) => match try!(self.accept_received(msg)) {
+                    Some(msg) => return Ok((msg, buffered - self.reader.buffered())),
+                    None => continue
+                },
+                Some(Err(err)) => return Err(err.to_dbus_error()),
+                None => try!(self.read_more())
+            }
+        }
+    }
+
+    // Hands the next incoming message to `f` as a MessageRef into the
+    // receive buffer, blocking on the wire if none are queued.  Nothing
+    // is copied out of the buffer unless `f` asks, so a monitor needn't
+    // demarshal every message it sees.  Messages a tracer, security
+    // policy or MessageSender::call() has to see, or that carry unix
+    // fds, are demarshalled as read_message() would, and so are those
+    // call() queued.  Their fds are closed once `f` returns.
+    pub fn with_next_message<T>(&mut self, f: |MessageRef| -> T) -> DBusResult<T> {
+        match self.pop_message() {
+            Some(msg) => return lend(&msg, |msg| f(msg)),
+            None => ()
+        }
+        loop {
+            let owned = match self.reader.next_message_ref() {
+                Some(Ok(msg)) => {
+                    let fds = msg.unix_fds().unwrap_or(0) as uint;
+                    let routed = msg.reply_serial().is_some() && self.queue.has_routes();
+                    if self.tracer.is_none() && self.policy.is_none() && !routed && fds == 0 {
+                        count_received_in(&mut self.stats, &mut self.awaiting_reply,
+                                          msg.msg_type(), msg.reply_serial(), false);
+                        return Ok(f(msg));
+                    }
+                    Some(try!(msg.to_message().map_err(|err| err.to_dbus_error())))
+                },
+                Some(Err(err)) => return Err(err.to_dbus_error()),
+                None => None
+            };
+            match owned {
+                Some(msg) => match try!(self.accept_received(msg)) {
+                    Some(msg) => return lend(&msg, |msg| f(msg)),
+                    None => ()
+                },
+                None => try!(self.read_more())
+            }
+        }
+    }
+
+    // Like with_next_message(), with the arguments decoded into the
+    // connection's DecodeArena for `f`.  Whatever was decoded is freed
+    // at once when `f` returns, and the arena's memory is kept for the
+    // next message.
+    pub fn with_next_decoded<T>(&mut self, f: |&MessageRef, &DecodeArena| -> T)
+                                -> DBusResult<T> {
+        let mut arena = Some(self.arena.take().unwrap_or_else(DecodeArena::new));
+        let result = self.with_next_message(|msg| {
+            let mut decoded = arena.take().unwrap().recycle();
+            let result = decoded.decode(&msg).map(|()| f(&msg, &decoded));
+            arena = Some(decoded.recycle());
+            result
+        });
+        self.arena = arena;
+        try!(result).map_err(|err| err.to_dbus_error())
+    }
+
+    // Returns the next incoming message, blocking on the wire if none
+    // are queued.
+    pub fn read_message(&mut self) -> DBusResult<DBusMessage> {
+        match self.pop_message() {
+            Some(msg) => Ok(msg),
+            None => self.read_from_wire().map(|(msg, _)| msg)
+        }
+    }
+
+    // Returns the next queued message without touching the wire.
+    pub fn pop_message(&mut self) -> Option<DBusMessage> {
+        match self.incoming.pop_front() {
+            Some((msg, len)) => {
+                self.incoming_len -= len;
+                self.incoming_fds -= msg.unix_fds().unwrap_or(0) as uint;
+                Some(msg)
+            },
+            None => None
+        }
+    }
+
+    // Queues a message read while waiting for a reply.
+    fn queue_incoming(&mut self, msg: DBusMessage, len: uint) -> DBusResult<()> {
+        let fds = msg.unix_fds().unwrap_or(0) as uint;
+        if self.incoming_fds + fds > self.max_received_unix_fds {
+            return Err(DBusError::standard(StandardError::LimitsExceeded,
+                format!("queued messages carry more than {} unix fds",
+                        self.max_received_unix_fds).as_slice()));
+        }
+        self.incoming_len += len;
+        self.incoming_fds += fds;
+        self.incoming.push_back((msg, len));
+        Ok(())
+    }
+
+    // Sends a method call and blocks until its reply arrives, queueing
+    // any other messages received in the meantime.
+    pub fn call(&mut self, msg: DBusMessage) -> DBusResult<DBusMessage> {
+        let serial = try!(self.send(msg));
+        loop {
+            let (reply, len) = try!(self.read_from_wire());
+            if reply.is_reply_to(serial) {
+                return match reply.to_error() {
+                    Some(err) => Err(err),
+                    None => Ok(reply)
+                };
+            }
+            try!(self.queue_incoming(reply, len));
+        }
+    }
+
+    // Like call(), but gives up with TimedOut if no reply comes within
+    // `timeout`.  Unix sockets are waited on directly; other streams
+    // are only checked on when they have something to read, or when
+    // a read would block, so over tcp: or unixexec: a silent peer can
+    // still keep us waiting.
+    pub fn call_with_timeout(&mut self, msg: DBusMessage,
+                             timeout: Duration) -> DBusResult<DBusMessage> {
+        self.call_with_clock(msg, timeout, &mut SystemClock)
+    }
+
+    // Like call_with_timeout(), timing the timeout by `clock`.
+    pub fn call_with_clock<C: Clock>(&mut self, msg: DBusMessage, timeout: Duration,
+                                     clock: &mut C) -> DBusResult<DBusMessage> {
+        let serial = try!(self.send(msg));
+        let expired = clock.oneshot(timeout);
+        loop {
+            let buffered = self.reader.buffered();
+            match self.reader.next_message() {
+                Some(Ok(msg)) => {
+                    let reply = match try!(self.accept_received(msg)) {
+                        Some(reply) => reply,
+                        None => continue
+                    };
+                    if reply.is_reply_to(serial) {
+                        return match reply.to_error() {
+                            Some(err) => Err(err),
+                            None => Ok(reply)
+                        };
+                    }
+                    let len = buffered - self.reader.buffered();
+                    try!(self.queue_incoming(reply, len));
+                    continue;
+                },
+                Some(Err(err)) => return Err(err.to_dbus_error()),
+                None => ()
+            }
+            if expired.try_recv().is_ok() {
+                break;
+            }
+            let socket = self.socket.as_ref().map(|socket| socket.fd());
+            match socket {
+                Some(fd) => {
+                    try!(self.write_queued());
+                    if !try!(transport::wait_readable(fd, WAIT_SLICE_MS)) {
+                        continue;
+                    }
+                },
+                None => ()
+            }
+            let would_block = match self.read_more() {
+                Ok(()) => false,
+                Err(err) => match err.io_error().map(|err| err.kind) {
+                    Some(ResourceUnavailable) | Some(TimedOut) => true,
+                    _ => return Err(err)
+                }
+            };
+            if would_block {
+                let slice = clock.oneshot(Duration::milliseconds(WAIT_SLICE_MS as i64));
+                select! {
+                    _ = expired.recv_opt() => break,
+                    _ = slice.recv_opt() => ()
+                }
+            }
+        }
+        // a late reply is an orphan, as if the call had never been made
+        self.awaiting_reply.remove(&serial);
+        Err(DBusError::standard(StandardError::TimedOut,
+                                format!("no reply within {}ms",
+                                        timeout.num_milliseconds()).as_slice()))
+    }
+
+    // Like call(), but error replies go through `errors`, so names the
+    // application registered come back as its own error type.  Other
+    // failures, and unregistered names, come back Unmapped.
+    pub fn call_mapped<E>(&mut self, msg: DBusMessage,
+                          errors: &ErrorRegistry<E>) -> Result<DBusMessage, MappedError<E>> {
+        errors.map_result(self.call(msg))
+    }
+
+    pub fn bus_register(&mut self) -> DBusResult<()> {
+        let hello = DBusMessage::method_call(DBUS_BUS_NAME, BUS_PATH, DBUS_INTERFACE,
+                                             MemberName::from_static("Hello"));
+        let reply = try!(self.call(hello));
+        match reply.body().head() {
+            Some(&Str(ref name)) => {
+                debug!("registered on the bus as {}", name);
+                self.unique_name = Some(name.clone());
+                Ok(())
+            },
+            _ => Err(DBusError::standard(StandardError::InconsistentMessage,
+                                         "unexpected reply to Hello"))
+        }
+    }
+
+    pub fn bus_request_name(&mut self, name: BusName, flags: u32) -> DBusResult<i32> {
+        let request = DBusMessage::method_call(DBUS_BUS_NAME, BUS_PATH, DBUS_INTERFACE,
+                                               MemberName::from_static("RequestName"))
+            .with_args(vec![Str(String::from_str(name.as_str())), UInt32(flags)]);
+        let reply = try!(self.call(request));
+        match reply.body().head() {
+            Some(&UInt32(response)) => Ok(response as i32),
+            _ => Err(DBusError::standard(StandardError::InconsistentMessage,
+                                         "unexpected reply to RequestName"))
+        }
+    }
+
+    // Asks the bus to route messages matching `rule` to us.
+    pub fn add_match(&mut self, rule: &str) -> DBusResult<()> {
+        let call = DBusMessage::method_call(DBUS_BUS_NAME, BUS_PATH, DBUS_INTERFACE,
+                                            MemberName::from_static("AddMatch"))
+            .with_args(vec![Str(String::from_str(rule))]);
+        try!(self.call(call));
+        self.match_rules.push(String::from_str(rule));
+        Ok(())
+    }
+
+    // Undoes one add_match() of the same rule.
+    pub fn remove_match(&mut self, rule: &str) -> DBusResult<()> {
+        let call = DBusMessage::method_call(DBUS_BUS_NAME, BUS_PATH, DBUS_INTERFACE,
+                                            MemberName::from_static("RemoveMatch"))
+            .with_args(vec![Str(String::from_str(rule))]);
+        try!(self.call(call));
+        match self.match_rules.iter().position(|r| r.as_slice() == rule) {
+            Some(i) => { self.match_rules.remove(i); },
+            None => ()
+        }
+        Ok(())
+    }
+
+    // The rules this connection has added and not removed, in the order
+    // they were added, for working out why signals do or don't arrive.
+    // A rule added twice is listed twice.
+    pub fn match_rules(&self) -> Vec<&str> {
+        self.match_rules.iter().map(|rule| rule.as_slice()).collect()
+    }
+
+    pub fn has_tracer(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    // Turns this connection into a monitor that receives a copy of
+    // every message on the bus matching any of `rules`, or all of them
+    // if there are none.  From then on it can only receive: sending
+    // fails, and nothing gets a reply.
+    pub fn become_monitor(&mut self, rules: &[&str]) -> DBusResult<()> {
+        let rules = rules.iter().map(|rule| Str(String::from_str(*rule))).collect();
+        let call = DBusMessage::method_call(DBUS_BUS_NAME, BUS_PATH, DBUS_MONITORING_INTERFACE,
+                                            MemberName::from_static("BecomeMonitor"))
+            .with_args(vec![Array(DBusType::Str, rules), UInt32(0)]);
+        try!(self.call(call));
+        debug!("became a monitor");
+        self.monitor = true;
+        self.queue.set_receive_only();
+        Ok(())
+    }
+
+    pub fn is_monitor(&self) -> bool {
+        self.monitor
+    }
+
+    // Handles one queued message.  Method calls nobody took with
+    // pop_message() are answered with UnknownMethod, as libdbus does.
+    pub fn dispatch(&mut self) -> DBusDispatchStatus {
+        let msg = match self.pop_message() {
+            Some(msg) => msg,
+            None => return DBusDispatchStatus::Complete
+        };
+        let start = precise_time_ns();
+        if msg.msg_type() == MessageType::MethodCall && !msg.no_reply_expected() &&
+                !self.monitor {
+            let message = format!("No handler for {}.{}",
+                                  msg.interface().unwrap_or(""),
+                                  msg.member().unwrap_or(""));
+            debug!("{}, replying UnknownMethod", message);
+            let reply = DBusMessage::error(&msg, StandardError::UnknownMethod.name(),
+                                           message.as_slice());
+            // the peer will see the connection drop if this fails
+            match self.send(reply) {
+                Ok(_) => (),
+                Err(err) => warn!("couldn't send UnknownMethod reply: {}", err)
+            }
+        }
+        self.stats.dispatched += 1;
+        self.stats.dispatch_time_ns += precise_time_ns() - start;
+        if self.incoming.is_empty() {
+            DBusDispatchStatus::Complete
+        } else {
+            DBusDispatchStatus::DataRemains
+        }
+    }
+
+    pub fn dispatch_pending(&mut self) -> DBusResult<()> {
+        loop {
+            match self.dispatch() {
+                DBusDispatchStatus::Complete => return Ok(()),
+                _ => ()
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::task;
+    use std::time::Duration;
+
+    use super::*;
+    use clock::ManualClock;
+    use error_registry::{ErrorRegistry, Mapped, Unmapped};
+    use message::DBusMessage;
+    use message_ref::ValueRef;
+    use names::{BusName, InterfaceName, MemberName};
+    use native::fault::FaultInjector;
+    use native::mock::MockConnection;
+    use value::{DBusType, Value, Array, Byte, Int32, Str, Variant};
+    use super::super::super::{DBusError, StandardError};
+
+    #[test]
+    fn test_open_tries_every_address() {
+        let err = DBusConnection::open(b"unix:path=/nonexistent/frob-a;\
+                                         unix:path=/nonexistent/frob-b").err().unwrap();
+        let message = err.message();
+        assert!(message.contains("/nonexistent/frob-a"));
+        assert!(message.contains("/nonexistent/frob-b"));
+    }
+
+    #[deriving(PartialEq, Show)]
+    struct Busy;
+
+    fn map_busy(_: &DBusError) -> Busy {
+        Busy
+    }
+
+    fn frob() -> DBusMessage {
+        DBusMessage::method_call(BusName::from_static("com.example.Frob"), "/frob",
+                                 InterfaceName::from_static("com.example.Frob"),
+                                 MemberName::from_static("Frob"))
+    }
+
+    fn frobbed() -> DBusMessage {
+        DBusMessage::signal("/frob", InterfaceName::from_static("com.example.Frob"),
+                            MemberName::from_static("Frobbed"))
+    }
+
+    #[test]
+    fn test_with_next_message() {
+        let mut mock = MockConnection::new();
+        mock.inject(frobbed());
+        let member = mock.conn().with_next_message(|msg| {
+            String::from_str(msg.member().unwrap())
+        }).unwrap();
+        assert_eq!(member.as_slice(), "Frobbed");
+        assert_eq!(mock.conn().stats().messages_received, 1);
+        assert!(mock.conn().with_next_message(|_| ()).is_err());
+    }
+
+    #[test]
+    fn test_with_next_message_lends_queued() {
+        // the signal arrives while call() waits, so it's queued
+        let mut mock = MockConnection::new();
+        mock.inject(frobbed());
+        let mut call = frob();
+        call.set_serial(1);
+        mock.inject(DBusMessage::method_return(&call));
+        mock.conn().call(frob()).unwrap();
+        let member = mock.conn().with_next_message(|msg| {
+            String::from_str(msg.member().unwrap())
+        }).unwrap();
+        assert_eq!(member.as_slice(), "Frobbed");
+    }
+
+    #[test]
+    fn test_stream_arrays_from_the_connection() {
+        let mut mock = MockConnection::new();
+        let contents = Vec::from_fn(10000, |i| i as u8);
+        let data = Array(DBusType::Byte, contents.iter().map(|b| Byte(*b)).collect());
+        let ints = Array(DBusType::Int32, vec![Int32(1), Int32(2)]);
+        mock.inject(frobbed().with_args(vec![data, ints]));
+        mock.conn().with_next_message(|msg| {
+            let mut received = Vec::new();
+            for chunk in msg.byte_chunks(0, 4096).unwrap() {
+                assert!(chunk.len() <= 4096);
+                received.push_all(chunk);
+            }
+            assert_eq!(received, contents);
+            let ints: Vec<ValueRef> = msg.array_iter(1).unwrap().map(|v| v.unwrap()).collect();
+            assert_eq!(ints, vec![ValueRef::Int32(1), ValueRef::Int32(2)]);
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_with_next_decoded() {
+        let mut mock = MockConnection::new();
+        let dict = Value::dict(DBusType::Str, DBusType::Variant, vec![
+            (Str(String::from_str("a")), Variant(box Int32(1)))
+        ]);
+        mock.inject(frobbed().with_args(vec![dict]));
+        mock.inject(frobbed().with_args(vec![Int32(7)]));
+
+        mock.conn().with_next_decoded(|msg, arena| {
+            assert_eq!(msg.member(), Some("Frobbed"));
+            let entry = arena.arg(0).unwrap().children().next().unwrap();
+            let key = entry.children().next().unwrap();
+            assert_eq!(key.as_leaf(), Some(&ValueRef::Str("a")));
+        }).unwrap();
+        // the arena is reused, and holds only the new message
+        let count = mock.conn().with_next_decoded(|_, arena| {
+            assert_eq!(arena.arg(0).unwrap().as_leaf(), Some(&ValueRef::Int32(7)));
+            arena.arg_count()
+        }).unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_call_mapped() {
+        let mut registry = ErrorRegistry::new();
+        registry.register("com.example.Frob.Error.Busy", map_busy);
+        let mut mock = MockConnection::new();
+
+        // replies to the connection's first and second calls
+        let mut call = frob();
+        call.set_serial(1);
+        mock.inject(DBusMessage::error(&call, "com.example.Frob.Error.Busy", "later"));
+        call.set_serial(2);
+        mock.inject(DBusMessage::error(&call, "com.example.Frob.Error.Jammed", "stuck"));
+
+        match mock.conn().call_mapped(frob(), &registry) {
+            Err(Mapped(err)) => assert_eq!(err, Busy),
+            _ => fail!("Busy was not mapped")
+        }
+        match mock.conn().call_mapped(frob(), &registry) {
+            Err(Unmapped(err)) => assert_eq!(err.name(), "com.example.Frob.Error.Jammed"),
+            _ => fail!("Jammed was mapped")
+        }
+    }
+
+    #[test]
+    fn test_call_with_clock() {
+        let mut mock = MockConnection::new();
+        let clock = ManualClock::new();
+        let timer = clock.clone();
+        spawn(proc() {
+            // once the call is waiting, let its time run out
+            while timer.pending() == 0 {
+                task::deschedule();
+            }
+            timer.advance(Duration::seconds(5));
+        });
+        let timeout = Duration::seconds(5);
+        let err = mock.conn().call_with_clock(frob(), timeout, &mut clock.clone()).err().unwrap();
+        assert!(err.is_standard(StandardError::TimedOut));
+        assert_eq!(mock.conn().stats().calls_in_flight, 0);
+
+        // a reply that is already there comes back without waiting
+        let mut call = frob();
+        call.set_serial(2);
+        mock.inject(DBusMessage::method_return(&call));
+        let reply = mock.conn().call_with_clock(frob(), timeout, &mut clock.clone()).unwrap();
+        assert!(reply.is_reply_to(2));
+    }
+
+    #[test]
+    fn test_sender_call_reply_is_routed() {
+        let mut mock = MockConnection::new();
+        let sender = mock.conn().sender();
+        let (replies, pending) = sender.call(frob()).unwrap();
+        let mut call = frob();
+        call.set_serial(pending.serial());
+        mock.inject(DBusMessage::method_return(&call));
+        mock.inject(DBusMessage::signal("/frob", InterfaceName::from_static("com.example.Frob"),
+                                        MemberName::from_static("Frobbed")));
+
+        // the reply goes to the sender, not the connection
+        let msg = mock.conn().read_message().unwrap();
+        assert_eq!(msg.member(), Some("Frobbed"));
+        assert!(replies.try_recv().unwrap().is_reply_to(pending.serial()));
+        assert_eq!(mock.conn().stats().replies_matched, 1);
+
+        // once the sender stops waiting, a late reply is the connection's
+        drop(pending);
+        mock.inject(DBusMessage::method_return(&call));
+        assert!(mock.conn().read_message().unwrap().is_reply_to(call.serial()));
+        assert!(replies.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_failed_batch_is_not_counted() {
+        let faults = FaultInjector::new();
+        let mut mock = MockConnection::with_faults(&faults);
+        faults.disconnect();
+        assert!(mock.conn().send_batch(vec![frob(), frob()]).is_err());
+        let stats = mock.conn().stats();
+        assert_eq!(stats.messages_sent, 0);
+        assert_eq!(stats.bytes_sent, 0);
+        assert_eq!(stats.calls_in_flight, 0);
+    }
+
+    #[test]
+    fn test_max_message_unix_fds() {
+        let mut mock = MockConnection::new();
+        mock.conn().set_max_message_unix_fds(1);
+        let mut greedy = frob();
+        greedy.set_unix_fds(Some(2));
+        let serial = mock.inject(greedy);
+        let mut modest = frob();
+        modest.set_unix_fds(Some(1));
+        mock.inject(modest);
+
+        let msg = mock.conn().read_message().unwrap();
+        assert_eq!(msg.unix_fds(), Some(1));
+        let refusal = mock.expect_sent();
+        assert_eq!(refusal.error_name(), Some("org.freedesktop.DBus.Error.LimitsExceeded"));
+        assert_eq!(refusal.reply_serial(), Some(serial));
+        assert_eq!(mock.conn().stats().messages_rejected, 1);
+    }
+}
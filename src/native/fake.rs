@@ -0,0 +1,206 @@
+// A stand-in for a service that client code talks to, answering
+// method calls with canned replies so tests can drive error paths
+// (AccessDenied and the like) on demand:
+//
+//     let bus = try!(TestBus::start());
+//     FakeService::new()
+//         .reply("com.example.Frob", "Version", vec![UInt32(2)])
+//         .fail("com.example.Frob", "Delete",
+//               DBusError::standard(StandardError::AccessDenied, "not yours"))
+//         .start(bus.address(), "com.example.Frob")
+//         .unwrap();
+//
+// Interface and member patterns may be "*" to match anything.  The
+// first matching declaration wins; calls nothing matches get
+// UnknownMethod.
+
+use builder::ConnectionBuilder;
+use message::{DBusMessage, MessageType};
+use names::BusName;
+use value::Value;
+use super::super::{DBusResult, DBusError, StandardError};
+use super::connection::DBusConnection;
+
+
+// DBUS_NAME_FLAG_DO_NOT_QUEUE, and the reply meaning we got the name
+const DO_NOT_QUEUE: u32 = 4;
+const PRIMARY_OWNER: i32 = 1;
+
+
+enum Response {
+    Reply(Vec<Value>),
+    Fail(DBusError)
+}
+
+struct Canned {
+    interface: String,
+    member: String,
+    response: Response,
+    // Emitted after replying, in order
+    signals: Vec<DBusMessage>
+}
+
+impl Canned {
+    fn matches(&self, call: &DBusMessage) -> bool {
+        matches(self.interface.as_slice(), call.interface())
+            && matches(self.member.as_slice(), call.member())
+    }
+}
+
+fn matches(pattern: &str, value: Option<&str>) -> bool {
+    pattern == "*" || value == Some(pattern)
+}
+
+
+pub struct FakeService {
+    canned: Vec<Canned>
+}
+
+impl FakeService {
+    pub fn new() -> FakeService {
+        FakeService {
+            canned: Vec::new()
+        }
+    }
+
+    // Answers calls matching `interface` and `member` with `args`.
+    pub fn reply(self, interface: &str, member: &str, args: Vec<Value>) -> FakeService {
+        self.add(interface, member, Reply(args))
+    }
+
+    // Answers calls matching `interface` and `member` with `err`.
+    pub fn fail(self, interface: &str, member: &str, err: DBusError) -> FakeService {
+        self.add(interface, member, Fail(err))
+    }
+
+    // Emits `signal` after answering each call the last reply() or
+    // fail() declared.
+    pub fn emit(mut self, signal: DBusMessage) -> FakeService {
+        match self.canned.last_mut() {
+            Some(canned) => canned.signals.push(signal),
+            None => fail!("emit() before any reply() or fail()")
+        }
+        self
+    }
+
+    fn add(mut self, interface: &str, member: &str, response: Response) -> FakeService {
+        self.canned.push(Canned {
+            interface: String::from_str(interface),
+            member: String::from_str(member),
+            response: response,
+            signals: Vec::new()
+        });
+        self
+    }
+
+    // Answers `msg` if it's a method call.  Returns whether it was.
+    pub fn handle(&mut self, conn: &mut DBusConnection, msg: &DBusMessage) -> DBusResult<bool> {
+        if msg.msg_type() != MessageType::MethodCall {
+            return Ok(false);
+        }
+        let (reply, signals) = match self.canned.iter().find(|c| c.matches(msg)) {
+            Some(canned) => {
+                let reply = match canned.response {
+                    Reply(ref args) => DBusMessage::method_return(msg).with_args(args.clone()),
+                    Fail(ref err) => DBusMessage::error_from(msg, err)
+                };
+                (reply, canned.signals.clone())
+            },
+            None => {
+                let err = DBusError::standard(StandardError::UnknownMethod,
+                                              format!("Nothing canned for {}.{}",
+                                                      msg.interface().unwrap_or(""),
+                                                      msg.member().unwrap_or("")).as_slice());
+                (DBusMessage::error_from(msg, &err), Vec::new())
+            }
+        };
+        if !msg.no_reply_expected() {
+            try!(conn.send(reply));
+        }
+        for signal in signals.into_iter() {
+            try!(conn.send(signal));
+        }
+        Ok(true)
+    }
+
+    // Answers calls on `conn` until it fails, which for a connection to
+    // a TestBus is when the bus goes away.
+    pub fn run(mut self, conn: &mut DBusConnection) -> DBusResult<()> {
+        loop {
+            let msg = try!(conn.read_message());
+            try!(self.handle(conn, &msg));
+        }
+    }
+
+    // Connects to the bus at `address`, takes `name` and answers calls
+    // in a task of its own.  The name is ours by the time this returns,
+    // so clients can call it straight away.
+    pub fn start(self, address: &str, name: &str) -> DBusResult<()> {
+        let name = try!(BusName::new(name));
+        let mut conn = try!(ConnectionBuilder::address(address.as_bytes())
+                                .private(true)
+                                .register(true)
+                                .connect());
+        if try!(conn.bus_request_name(name.clone(), DO_NOT_QUEUE)) != PRIMARY_OWNER {
+            return Err(DBusError::standard(StandardError::AddressInUse,
+                                           format!("{} already has an owner",
+                                                   name.as_str()).as_slice()));
+        }
+        spawn(proc() {
+            match self.run(&mut conn) {
+                Ok(()) => (),
+                Err(err) => debug!("fake service stopped: {}", err)
+            }
+        });
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::{DBusMessage, MessageType};
+    use names::{BusName, InterfaceName, MemberName};
+    use native::mock::MockConnection;
+    use value::{UInt32, Str};
+    use super::super::super::{DBusError, StandardError};
+
+    fn call(interface: &'static str, member: &'static str) -> DBusMessage {
+        DBusMessage::method_call(BusName::from_static("com.example.Frob"), "/frob",
+                                 InterfaceName::from_static(interface),
+                                 MemberName::from_static(member))
+    }
+
+    #[test]
+    fn test_canned_replies() {
+        let mut fake = FakeService::new()
+            .reply("com.example.Frob", "Version", vec![UInt32(2)])
+            .emit(DBusMessage::signal("/frob", InterfaceName::from_static("com.example.Frob"),
+                                      MemberName::from_static("Asked"))
+                  .with_args(vec![Str(String::from_str("Version"))]))
+            .fail("*", "Delete", DBusError::standard(StandardError::AccessDenied, "not yours"));
+        let mut mock = MockConnection::new();
+
+        mock.inject(call("com.example.Frob", "Version"));
+        let msg = mock.conn().read_message().unwrap();
+        assert!(fake.handle(mock.conn(), &msg).unwrap());
+        let sent = mock.sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].body(), [UInt32(2)].as_slice());
+        assert_eq!(sent[1].msg_type(), MessageType::Signal);
+        assert_eq!(sent[1].member(), Some("Asked"));
+
+        mock.inject(call("com.example.Other", "Delete"));
+        let msg = mock.conn().read_message().unwrap();
+        assert!(fake.handle(mock.conn(), &msg).unwrap());
+        assert_eq!(mock.expect_sent().error_name(),
+                   Some("org.freedesktop.DBus.Error.AccessDenied"));
+
+        mock.inject(call("com.example.Frob", "Frobulate"));
+        let msg = mock.conn().read_message().unwrap();
+        assert!(fake.handle(mock.conn(), &msg).unwrap());
+        assert_eq!(mock.expect_sent().error_name(),
+                   Some("org.freedesktop.DBus.Error.UnknownMethod"));
+    }
+}
@@ -0,0 +1,199 @@
+// Making a connection's transport misbehave on cue, so the code that
+// copes with lost connections and late replies gets exercised:
+//
+//     let faults = FaultInjector::new();
+//     let mut mock = MockConnection::with_faults(&faults);
+//     faults.disconnect_after_writes(1);
+//     assert!(mock.conn().call(msg).is_err());
+//
+// Any stream can be wrapped, so this works for real transports too:
+// DBusConnection::from_stream(box faults.wrap(stream), guid).  The
+// connection writes each message it sends in one write, so "the Nth
+// write" is normally the Nth message.
+
+use std::io::{IoError, IoErrorKind, IoResult, BrokenPipe, EndOfFile};
+use std::io::timer::sleep;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+
+struct Faults {
+    disconnected: bool,
+    // Writes left before disconnecting
+    writes_until_disconnect: Option<uint>,
+    read_delay: Option<Duration>,
+    // Bytes of the next write to let through before disconnecting
+    truncate_next_write: Option<uint>,
+    drop_every: Option<uint>,
+    writes: uint
+}
+
+// The test's handle on the faults of every stream it wrapped.
+#[deriving(Clone)]
+pub struct FaultInjector {
+    faults: Arc<Mutex<Faults>>
+}
+
+impl FaultInjector {
+    // Starts out injecting nothing.
+    pub fn new() -> FaultInjector {
+        FaultInjector {
+            faults: Arc::new(Mutex::new(Faults {
+                disconnected: false,
+                writes_until_disconnect: None,
+                read_delay: None,
+                truncate_next_write: None,
+                drop_every: None,
+                writes: 0
+            }))
+        }
+    }
+
+    pub fn wrap<S: Stream>(&self, stream: S) -> FaultyStream<S> {
+        FaultyStream {
+            inner: stream,
+            faults: self.faults.clone()
+        }
+    }
+
+    // Every read and write fails from now on, as if the peer had gone.
+    pub fn disconnect(&self) {
+        self.faults.lock().disconnected = true;
+    }
+
+    // Lets `n` more writes through, then disconnects: with n = 1, a
+    // call goes out but its reply never comes back.
+    pub fn disconnect_after_writes(&self, n: uint) {
+        if n == 0 {
+            return self.disconnect();
+        }
+        self.faults.lock().writes_until_disconnect = Some(n);
+    }
+
+    // Holds up every read by `delay`, or stops doing so if None.
+    pub fn delay_reads(&self, delay: Option<Duration>) {
+        self.faults.lock().read_delay = delay;
+    }
+
+    // Writes only the first `len` bytes of the next write, then
+    // disconnects, leaving the peer with part of a message.
+    pub fn truncate_next_write(&self, len: uint) {
+        self.faults.lock().truncate_next_write = Some(len);
+    }
+
+    // Silently throws away every `n`th write, or none if None.
+    pub fn drop_every_nth_write(&self, n: Option<uint>) {
+        self.faults.lock().drop_every = n.and_then(|n| if n == 0 { None } else { Some(n) });
+    }
+
+    // Writes attempted so far, dropped ones included.
+    pub fn writes(&self) -> uint {
+        self.faults.lock().writes
+    }
+}
+
+
+pub struct FaultyStream<S> {
+    inner: S,
+    faults: Arc<Mutex<Faults>>
+}
+
+impl<S: Reader> Reader for FaultyStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let delay = {
+            let faults = self.faults.lock();
+            if faults.disconnected {
+                return Err(disconnected(EndOfFile));
+            }
+            faults.read_delay
+        };
+        for delay in delay.iter() {
+            sleep(*delay);
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Writer> Writer for FaultyStream<S> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        let mut faults = self.faults.lock();
+        if faults.disconnected {
+            return Err(disconnected(BrokenPipe));
+        }
+        faults.writes += 1;
+        match faults.drop_every {
+            Some(n) if faults.writes % n == 0 => return Ok(()),
+            _ => ()
+        }
+        match faults.truncate_next_write.take() {
+            Some(len) => {
+                faults.disconnected = true;
+                let len = if len < buf.len() { len } else { buf.len() };
+                try!(self.inner.write(buf.slice_to(len)));
+                return Err(disconnected(BrokenPipe));
+            },
+            None => ()
+        }
+        match faults.writes_until_disconnect {
+            Some(1) => {
+                faults.writes_until_disconnect = None;
+                faults.disconnected = true;
+            },
+            Some(n) => faults.writes_until_disconnect = Some(n - 1),
+            None => ()
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+fn disconnected(kind: IoErrorKind) -> IoError {
+    IoError {
+        kind: kind,
+        desc: "disconnected by fault injection",
+        detail: None
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::DBusMessage;
+    use names::{InterfaceName, MemberName};
+    use native::mock::MockConnection;
+
+    fn signal() -> DBusMessage {
+        DBusMessage::signal("/", InterfaceName::from_static("com.example.Frob"),
+                            MemberName::from_static("Frobbed"))
+    }
+
+    #[test]
+    fn test_faults() {
+        let faults = FaultInjector::new();
+        let mut mock = MockConnection::with_faults(&faults);
+
+        faults.drop_every_nth_write(Some(2));
+        for _ in range(0u, 4) {
+            mock.conn().send(signal()).unwrap();
+        }
+        assert_eq!(mock.sent().len(), 2);
+        assert_eq!(faults.writes(), 4);
+        faults.drop_every_nth_write(None);
+
+        faults.disconnect_after_writes(1);
+        mock.conn().send(signal()).unwrap();
+        assert!(mock.conn().send(signal()).is_err());
+        assert!(mock.conn().read_message().is_err());
+        assert_eq!(mock.sent().len(), 1);
+
+        let faults = FaultInjector::new();
+        let mut mock = MockConnection::with_faults(&faults);
+        faults.truncate_next_write(10);
+        assert!(mock.conn().send(signal()).is_err());
+        assert!(mock.sent().is_empty());
+    }
+}
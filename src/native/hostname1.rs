@@ -0,0 +1,114 @@
+// Reading and setting the host name through systemd-hostnamed:
+//
+//     let info = try!(host_info(&mut conn));
+//     try!(set_static_hostname(&mut conn, "frobhost", true));
+//
+// Setting needs privileges; with `interactive`, polkit may ask the
+// user for them rather than refusing.
+
+use std::collections::HashMap;
+
+use message::DBusMessage;
+use names::{BusName, InterfaceName, MemberName};
+use value::{Value, Boolean, Str};
+use super::super::DBusResult;
+use super::connection::DBusConnection;
+use super::properties::get_all_properties;
+
+
+pub const HOSTNAME1_NAME: &'static str = "org.freedesktop.hostname1";
+pub const HOSTNAME1_PATH: &'static str = "/org/freedesktop/hostname1";
+pub const HOSTNAME1_INTERFACE: &'static str = "org.freedesktop.hostname1";
+
+
+// hostnamed's properties.  Ones it doesn't know are empty.
+#[deriving(Clone, PartialEq, Show)]
+pub struct HostInfo {
+    // The kernel's host name right now
+    pub hostname: String,
+    // The one in /etc/hostname
+    pub static_hostname: String,
+    pub pretty_hostname: String,
+    pub icon_name: String,
+    // "desktop", "laptop", "server", "vm", ...
+    pub chassis: String,
+    pub kernel_name: String,
+    pub kernel_release: String,
+    pub operating_system: String
+}
+
+impl HostInfo {
+    pub fn from_properties(properties: &HashMap<String, Value>) -> HostInfo {
+        let string = |key: &str| properties.find_equiv(&key).and_then(|v| v.as_str())
+                                           .map(|s| String::from_str(s))
+                                           .unwrap_or(String::new());
+        HostInfo {
+            hostname: string("Hostname"),
+            static_hostname: string("StaticHostname"),
+            pretty_hostname: string("PrettyHostname"),
+            icon_name: string("IconName"),
+            chassis: string("Chassis"),
+            kernel_name: string("KernelName"),
+            kernel_release: string("KernelRelease"),
+            operating_system: string("OperatingSystemPrettyName")
+        }
+    }
+}
+
+pub fn host_info(conn: &mut DBusConnection) -> DBusResult<HostInfo> {
+    let properties = try!(get_all_properties(conn, BusName::from_static(HOSTNAME1_NAME),
+                                             HOSTNAME1_PATH, HOSTNAME1_INTERFACE));
+    Ok(HostInfo::from_properties(&properties))
+}
+
+fn set_call(member: &'static str, value: &str, interactive: bool) -> DBusMessage {
+    DBusMessage::method_call(BusName::from_static(HOSTNAME1_NAME), HOSTNAME1_PATH,
+                             InterfaceName::from_static(HOSTNAME1_INTERFACE),
+                             MemberName::from_static(member))
+        .with_args(vec![Str(String::from_str(value)), Boolean(interactive)])
+        .with_interactive_authorization(interactive)
+}
+
+// Sets the transient host name, until the next boot.
+pub fn set_hostname(conn: &mut DBusConnection, name: &str,
+                    interactive: bool) -> DBusResult<()> {
+    try!(conn.call(set_call("SetHostname", name, interactive)));
+    Ok(())
+}
+
+pub fn set_static_hostname(conn: &mut DBusConnection, name: &str,
+                           interactive: bool) -> DBusResult<()> {
+    try!(conn.call(set_call("SetStaticHostname", name, interactive)));
+    Ok(())
+}
+
+pub fn set_pretty_hostname(conn: &mut DBusConnection, name: &str,
+                           interactive: bool) -> DBusResult<()> {
+    try!(conn.call(set_call("SetPrettyHostname", name, interactive)));
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use super::set_call;
+    use value::{Boolean, Str};
+
+    #[test]
+    fn test_host_info() {
+        let mut properties = HashMap::new();
+        properties.insert(String::from_str("StaticHostname"), Str(String::from_str("frobhost")));
+        properties.insert(String::from_str("Chassis"), Str(String::from_str("laptop")));
+        let info = HostInfo::from_properties(&properties);
+        assert_eq!(info.static_hostname.as_slice(), "frobhost");
+        assert_eq!(info.chassis.as_slice(), "laptop");
+        assert_eq!(info.pretty_hostname.as_slice(), "");
+
+        let call = set_call("SetHostname", "frobhost", true);
+        assert_eq!(call.body()[1], Boolean(true));
+        assert!(call.allow_interactive_authorization());
+    }
+}
@@ -0,0 +1,99 @@
+// Pinging the bus, or a peer, every so often so a hung daemon is
+// noticed even by a connection that would otherwise sit idle:
+//
+//     let keepalive = Keepalive::start(conn.sender(), None,
+//                                      Duration::seconds(30), Duration::seconds(5),
+//                                      proc(err) { println!("bus is gone: {}", err) });
+//
+// The pings go out through a MessageSender, so they don't wait for the
+// connection, but the replies are only picked up when it next reads.
+// Keep reading it, with read_message() or ObjectServer::process(), or
+// every ping will time out.
+
+use std::time::Duration;
+
+use clock::{Clock, SystemClock};
+use message::{DBusMessage, BUS_PATH};
+use names::{BusName, InterfaceName, MemberName, DBUS_BUS_NAME};
+use native::MessageSender;
+use super::super::{DBusResult, DBusError, StandardError};
+
+
+// Stops pinging when dropped.
+pub struct Keepalive {
+    // Never sent on; dropping it is the signal
+    _stop: Sender<()>
+}
+
+impl Keepalive {
+    // Pings `peer`, or the bus if None, every `interval`.  The first
+    // ping that fails or goes unanswered for `timeout` is handed to
+    // `on_failure`, and then pinging stops.
+    pub fn start(sender: MessageSender, peer: Option<String>, interval: Duration,
+                 timeout: Duration, on_failure: proc(DBusError): Send) -> Keepalive {
+        Keepalive::start_with_clock(sender, peer, interval, timeout, on_failure, SystemClock)
+    }
+
+    // Like start(), timing the interval and timeouts by `clock`.
+    pub fn start_with_clock<C: Clock>(sender: MessageSender, peer: Option<String>,
+                                      interval: Duration, timeout: Duration,
+                                      on_failure: proc(DBusError): Send,
+                                      mut clock: C) -> Keepalive {
+        let (stop_tx, stop_rx) = channel::<()>();
+        spawn(proc() {
+            let ticks = clock.periodic(interval);
+            loop {
+                select! {
+                    _ = stop_rx.recv_opt() => return,
+                    _ = ticks.recv_opt() => ()
+                }
+                let peer = peer.as_ref().map(|peer| peer.as_slice());
+                match ping_with_clock(&sender, peer, timeout, &mut clock) {
+                    Ok(()) => (),
+                    Err(err) => {
+                        warn!("keepalive ping failed: {}", err);
+                        return on_failure(err);
+                    }
+                }
+            }
+        });
+        Keepalive {
+            _stop: stop_tx
+        }
+    }
+}
+
+// Sends one org.freedesktop.DBus.Peer.Ping and waits up to `timeout`
+// for the reply, which the connection must read in the meantime.
+pub fn ping(sender: &MessageSender, peer: Option<&str>, timeout: Duration) -> DBusResult<()> {
+    ping_with_clock(sender, peer, timeout, &mut SystemClock)
+}
+
+// Like ping(), timing the timeout by `clock`.
+pub fn ping_with_clock<C: Clock>(sender: &MessageSender, peer: Option<&str>, timeout: Duration,
+                                 clock: &mut C) -> DBusResult<()> {
+    let (destination, path) = match peer {
+        Some(peer) => (try!(BusName::new(peer)), "/"),
+        None => (DBUS_BUS_NAME, BUS_PATH)
+    };
+    let call = DBusMessage::method_call(destination, path,
+                                        InterfaceName::from_static("org.freedesktop.DBus.Peer"),
+                                        MemberName::from_static("Ping"));
+    let expired = clock.oneshot(timeout);
+    // the reply stays routed to us until `_pending` goes
+    let (replies, _pending) = try!(sender.call(call));
+    select! {
+        reply = replies.recv_opt() => match reply {
+            Ok(reply) => match reply.to_error() {
+                Some(err) => Err(err),
+                None => Ok(())
+            },
+            Err(()) => Err(DBusError::standard(StandardError::Failed, "Ping reply was lost"))
+        },
+        _ = expired.recv_opt() => {
+            Err(DBusError::standard(StandardError::TimedOut,
+                                    format!("no reply to Ping within {}ms",
+                                            timeout.num_milliseconds()).as_slice()))
+        }
+    }
+}
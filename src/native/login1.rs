@@ -0,0 +1,192 @@
+// Holding off sleep, shutdown or idle through systemd-logind.  logind
+// answers Inhibit with a file descriptor and keeps the inhibitor for as
+// long as it's open:
+//
+//     let lock = try!(inhibit(&mut conn, [InhibitWhat::Sleep], "frobd",
+//                             "Saving frobs", InhibitMode::Delay));
+//     try!(save_frobs());
+//     drop(lock);
+//
+// The lock is released when the InhibitLock is dropped.  The fd comes
+// back over the system bus's unix socket, so the connection must have
+// been opened on one.
+
+use libc;
+
+use message::DBusMessage;
+use names::{BusName, InterfaceName, MemberName};
+use native::DBusConnection;
+use unix_fd::OwnedFd;
+use value::{Str, UnixFd};
+use super::super::{DBusResult, DBusError, StandardError};
+
+
+pub const LOGIN1_NAME: &'static str = "org.freedesktop.login1";
+pub const LOGIN1_PATH: &'static str = "/org/freedesktop/login1";
+pub const LOGIN1_MANAGER_INTERFACE: &'static str = "org.freedesktop.login1.Manager";
+
+
+// What to inhibit.
+pub type InhibitWhat = self::InhibitWhat::InhibitWhat;
+pub mod InhibitWhat {
+    #[deriving(PartialEq, Eq, Clone, Show)]
+    pub enum InhibitWhat {
+        Shutdown,
+        Sleep,
+        Idle,
+        HandlePowerKey,
+        HandleSuspendKey,
+        HandleHibernateKey,
+        HandleLidSwitch
+    }
+
+    impl InhibitWhat {
+        pub fn name(&self) -> &'static str {
+            match *self {
+                Shutdown => "shutdown",
+                Sleep => "sleep",
+                Idle => "idle",
+                HandlePowerKey => "handle-power-key",
+                HandleSuspendKey => "handle-suspend-key",
+                HandleHibernateKey => "handle-hibernate-key",
+                HandleLidSwitch => "handle-lid-switch"
+            }
+        }
+    }
+}
+
+pub type InhibitMode = self::InhibitMode::InhibitMode;
+pub mod InhibitMode {
+    #[deriving(PartialEq, Eq, Clone, Show)]
+    pub enum InhibitMode {
+        // Refuse outright
+        Block,
+        // Hold off for a few seconds, e.g. to save state before sleep
+        Delay
+    }
+
+    impl InhibitMode {
+        pub fn name(&self) -> &'static str {
+            match *self {
+                Block => "block",
+                Delay => "delay"
+            }
+        }
+    }
+}
+
+
+// An inhibitor, released when dropped.
+pub struct InhibitLock {
+    fd: OwnedFd
+}
+
+impl InhibitLock {
+    // Takes ownership of a descriptor Inhibit returned.
+    pub fn from_raw_fd(fd: libc::c_int) -> InhibitLock {
+        InhibitLock {
+            fd: OwnedFd::from_raw_fd(fd)
+        }
+    }
+
+    pub fn fd(&self) -> libc::c_int {
+        self.fd.fd()
+    }
+
+    // Releases the inhibitor now rather than on drop.
+    pub fn release(self) {}
+}
+
+
+// Takes an inhibitor, held until the InhibitLock is dropped.
+pub fn inhibit(conn: &mut DBusConnection, what: &[InhibitWhat], who: &str, why: &str,
+               mode: InhibitMode) -> DBusResult<InhibitLock> {
+    let reply = try!(conn.call(inhibit_call(what, who, why, mode)));
+    match reply.body().head() {
+        Some(&UnixFd(index)) => Ok(InhibitLock {
+            fd: try!(reply.unix_fd(index))
+        }),
+        _ => Err(DBusError::standard(StandardError::InvalidArgs,
+                                     "Inhibit didn't return a file descriptor"))
+    }
+}
+
+// The Inhibit call itself, for callers that get at the reply's fd some
+// other way, e.g. from a libdbus connection.
+pub fn inhibit_call(what: &[InhibitWhat], who: &str, why: &str,
+                    mode: InhibitMode) -> DBusMessage {
+    let what: Vec<&str> = what.iter().map(|w| w.name()).collect();
+    DBusMessage::method_call(BusName::from_static(LOGIN1_NAME), LOGIN1_PATH,
+                             InterfaceName::from_static(LOGIN1_MANAGER_INTERFACE),
+                             MemberName::from_static("Inhibit"))
+        .with_args(vec![Str(what.connect(":")),
+                        Str(String::from_str(who)),
+                        Str(String::from_str(why)),
+                        Str(String::from_str(mode.name()))])
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::os;
+    use libc;
+
+    use super::*;
+    use message::DBusMessage;
+    use native::{DBusConnection, MockConnection};
+    use native::transport::UnixSocket;
+    use value::{Str, UnixFd};
+
+    #[test]
+    fn test_inhibit_call() {
+        let call = inhibit_call([InhibitWhat::Sleep, InhibitWhat::Shutdown], "frobd",
+                                "Saving frobs", InhibitMode::Delay);
+        assert_eq!(call.member(), Some("Inhibit"));
+        assert_eq!(call.body()[0], Str(String::from_str("sleep:shutdown")));
+        assert_eq!(call.body()[3], Str(String::from_str("delay")));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_inhibit() {
+        let (ours, logind) = UnixSocket::pair().unwrap();
+        let mut conn = DBusConnection::from_unix_socket(ours, "logind");
+
+        // logind hands back the read end of a pipe as the lock
+        let pipe = unsafe { os::pipe().unwrap() };
+        let mut call = inhibit_call([InhibitWhat::Sleep], "frobd", "Saving frobs",
+                                    InhibitMode::Delay);
+        call.set_serial(1);
+        let mut reply = DBusMessage::method_return(&call).with_args(vec![UnixFd(0)]);
+        reply.set_serial(1);
+        reply.set_unix_fds(Some(1));
+        logind.send_with_fds(reply.marshal().as_slice(), [pipe.reader]).unwrap();
+        unsafe {
+            libc::close(pipe.reader);
+        }
+
+        let lock = inhibit(&mut conn, [InhibitWhat::Sleep], "frobd", "Saving frobs",
+                           InhibitMode::Delay).unwrap();
+        let mut byte = [0u8];
+        unsafe {
+            assert_eq!(libc::write(pipe.writer, b"z".as_ptr() as *const libc::c_void, 1), 1);
+            assert_eq!(libc::read(lock.fd(), byte.as_mut_ptr() as *mut libc::c_void, 1), 1);
+            libc::close(pipe.writer);
+        }
+        assert_eq!(byte[0], b'z');
+    }
+
+    #[test]
+    fn test_inhibit_without_fds() {
+        // nothing came with the reply, as over a stream that can't carry fds
+        let mut mock = MockConnection::new();
+        let mut call = inhibit_call([InhibitWhat::Idle], "frobd", "Frobbing",
+                                    InhibitMode::Block);
+        call.set_serial(1);
+        let mut reply = DBusMessage::method_return(&call).with_args(vec![UnixFd(0)]);
+        reply.set_unix_fds(Some(1));
+        mock.inject(reply);
+        assert!(inhibit(mock.conn(), [InhibitWhat::Idle], "frobd", "Frobbing",
+                        InhibitMode::Block).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+// Testing handlers without a bus: a MockConnection is a real
+// DBusConnection whose stream is an in-process queue, with the other
+// end left to the test to inject messages into and read replies from.
+//
+//     let mut mock = MockConnection::new();
+//     mock.inject(call);
+//     try!(server.process(mock.conn()));
+//     let reply = mock.expect_sent();
+
+use std::cmp;
+use std::comm::{Empty, Disconnected};
+use std::io::{IoError, IoResult, EndOfFile, BrokenPipe, ResourceUnavailable};
+use std::slice::bytes;
+
+use message::DBusMessage;
+use super::connection::DBusConnection;
+use super::fault::FaultInjector;
+use super::reader::MessageReader;
+
+
+// One end of an in-process byte pipe.  Blocking ends wait for the
+// other end to write; non-blocking ones fail with ResourceUnavailable
+// when there's nothing to read.  Reads fail with EndOfFile once the
+// other end is gone.
+pub struct MemoryStream {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    // What's left of the last chunk received
+    buf: Vec<u8>,
+    pos: uint,
+    blocking: bool
+}
+
+// The two ends of a new pipe.
+pub fn memory_pipe(blocking: bool) -> (MemoryStream, MemoryStream) {
+    let (a_tx, b_rx) = channel();
+    let (b_tx, a_rx) = channel();
+    (MemoryStream::new(a_tx, a_rx, blocking), MemoryStream::new(b_tx, b_rx, blocking))
+}
+
+impl MemoryStream {
+    fn new(tx: Sender<Vec<u8>>, rx: Receiver<Vec<u8>>, blocking: bool) -> MemoryStream {
+        MemoryStream {
+            tx: tx,
+            rx: rx,
+            buf: Vec::new(),
+            pos: 0,
+            blocking: blocking
+        }
+    }
+
+    // Another handle on this end's write side.
+    pub fn writer(&self) -> MemoryWriter {
+        MemoryWriter {
+            tx: self.tx.clone()
+        }
+    }
+}
+
+impl Reader for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        while self.pos == self.buf.len() {
+            let chunk = if self.blocking {
+                self.rx.recv_opt().map_err(|()| closed())
+            } else {
+                self.rx.try_recv().map_err(|err| match err {
+                    Empty => IoError {
+                        kind: ResourceUnavailable,
+                        desc: "nothing to read",
+                        detail: None
+                    },
+                    Disconnected => closed()
+                })
+            };
+            self.buf = try!(chunk);
+            self.pos = 0;
+        }
+        let n = cmp::min(buf.len(), self.buf.len() - self.pos);
+        bytes::copy_memory(buf, self.buf.slice(self.pos, self.pos + n));
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Writer for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        send_chunk(&self.tx, buf)
+    }
+}
+
+// From MemoryStream::writer().
+pub struct MemoryWriter {
+    tx: Sender<Vec<u8>>
+}
+
+impl Writer for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        send_chunk(&self.tx, buf)
+    }
+}
+
+fn send_chunk(tx: &Sender<Vec<u8>>, buf: &[u8]) -> IoResult<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    tx.send_opt(buf.to_vec()).map_err(|_| IoError {
+        kind: BrokenPipe,
+        desc: "the other end is gone",
+        detail: None
+    })
+}
+
+fn closed() -> IoError {
+    IoError {
+        kind: EndOfFile,
+        desc: "the other end is gone",
+        detail: None
+    }
+}
+
+
+pub struct MockConnection {
+    conn: DBusConnection,
+    // Our end of the connection's stream
+    wire: MemoryStream,
+    // Parses what the connection sends
+    reader: MessageReader,
+    next_serial: u32
+}
+
+impl MockConnection {
+    // MessageSenders write straight to the mock, as they do to a socket.
+    pub fn new() -> MockConnection {
+        let (ours, theirs) = memory_pipe(false);
+        let writer = theirs.writer();
+        MockConnection::from_conn(ours, DBusConnection::from_split_stream(
+            box theirs as Box<Stream + Send>, box writer as Box<Writer + Send>, "mock"))
+    }
+
+    // A mock whose connection's end of the stream misbehaves as
+    // `faults` is told to.  MessageSenders' messages wait for the
+    // connection to write them, through the faults.
+    pub fn with_faults(faults: &FaultInjector) -> MockConnection {
+        let (ours, theirs) = memory_pipe(false);
+        MockConnection::from_conn(ours, DBusConnection::from_stream(
+            box faults.wrap(theirs) as Box<Stream + Send>, "mock"))
+    }
+
+    fn from_conn(ours: MemoryStream, conn: DBusConnection) -> MockConnection {
+        MockConnection {
+            conn: conn,
+            wire: ours,
+            reader: MessageReader::new(),
+            next_serial: 1
+        }
+    }
+
+    // The connection under test, to hand to handlers.
+    pub fn conn(&mut self) -> &mut DBusConnection {
+        &mut self.conn
+    }
+
+    // Queues `msg` for the connection to receive, giving it a serial
+    // if it doesn't have one.  Returns the serial.
+    pub fn inject(&mut self, mut msg: DBusMessage) -> u32 {
+        if msg.serial() == 0 {
+            msg.set_serial(self.next_serial);
+            self.next_serial += 1;
+        }
+        // the connection holds the other end, so this can't fail
+        let _ = self.wire.write(msg.to_bytes().as_slice());
+        msg.serial()
+    }
+
+    // Starts both the connection's serials and those inject() gives
+    // out over from 1.
+    pub fn reset_serials(&mut self) {
+        self.conn.reset_serials(1);
+        self.next_serial = 1;
+    }
+
+    // Everything the connection has sent since last asked, in order.
+    pub fn sent(&mut self) -> Vec<DBusMessage> {
+        let mut chunk = [0u8, ..4096];
+        loop {
+            match self.wire.read(chunk) {
+                Ok(n) => self.reader.feed(chunk.slice_to(n)),
+                Err(_) => break
+            }
+        }
+        let mut out = Vec::new();
+        loop {
+            match self.reader.next_message() {
+                Some(Ok(msg)) => out.push(msg),
+                Some(Err(err)) => fail!("connection sent a malformed message: {}", err),
+                None => return out
+            }
+        }
+    }
+
+    // The one message the connection has sent since last asked.  Fails
+    // the test if there are none, or more than one.
+    pub fn expect_sent(&mut self) -> DBusMessage {
+        let mut sent = self.sent();
+        if sent.len() != 1 {
+            fail!("expected one message sent, got {}", sent.len());
+        }
+        sent.pop().unwrap()
+    }
+
+    // Fails the test if the connection has sent anything.
+    pub fn expect_nothing_sent(&mut self) {
+        let sent = self.sent();
+        if !sent.is_empty() {
+            fail!("expected nothing sent, got {} messages", sent.len());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::{DBusMessage, MessageType, BUS_PATH};
+    use names::{BusName, InterfaceName, MemberName, DBUS_INTERFACE};
+    use native::connection::DBusConnection;
+    use native::service::{AuditRecord, ObjectServer, PEER_INTERFACE};
+    use value::{Value, Str};
+    use super::super::super::DBusResult;
+
+    fn echo(_conn: &mut DBusConnection, call: &DBusMessage) -> DBusResult<Vec<Value>> {
+        Ok(call.body().to_vec())
+    }
+
+    fn call(member: &'static str) -> DBusMessage {
+        DBusMessage::method_call(BusName::from_static("com.example.Frob"), "/frob",
+                                 InterfaceName::from_static("com.example.Frob"),
+                                 MemberName::from_static(member))
+            .with_args(vec![Str(String::from_str("hello"))])
+    }
+
+    #[test]
+    fn test_object_server() {
+        let echo: fn(&mut DBusConnection, &DBusMessage) -> DBusResult<Vec<Value>> = echo;
+        let mut server = ObjectServer::new();
+        server.add_method("/frob", InterfaceName::from_static("com.example.Frob"),
+                          MemberName::from_static("Echo"), echo);
+        let mut mock = MockConnection::new();
+
+        let serial = mock.inject(call("Echo"));
+        assert!(server.process(mock.conn()).unwrap().is_none());
+        let reply = mock.expect_sent();
+        assert_eq!(reply.msg_type(), MessageType::MethodReturn);
+        assert_eq!(reply.reply_serial(), Some(serial));
+        assert_eq!(reply.body(), [Str(String::from_str("hello"))].as_slice());
+
+        mock.inject(call("Missing"));
+        assert!(server.process(mock.conn()).unwrap().is_none());
+        let reply = mock.expect_sent();
+        assert_eq!(reply.error_name(), Some("org.freedesktop.DBus.Error.UnknownMethod"));
+
+        // nothing left to read
+        assert!(server.process(mock.conn()).is_err());
+        mock.expect_nothing_sent();
+    }
+
+    fn ignore(_: &AuditRecord) {}
+
+    fn uid_lookups(mock: &mut MockConnection) -> uint {
+        mock.sent().iter().filter(|msg| msg.member() == Some("GetConnectionUnixUser")).count()
+    }
+
+    #[test]
+    fn test_uid_cache_forgets_departed_senders() {
+        let echo: fn(&mut DBusConnection, &DBusMessage) -> DBusResult<Vec<Value>> = echo;
+        let ignore: fn(&AuditRecord) = ignore;
+        let mut server = ObjectServer::new();
+        server.add_method("/frob", InterfaceName::from_static("com.example.Frob"),
+                          MemberName::from_static("Echo"), echo);
+        server.set_audit_hook(ignore);
+        let mut mock = MockConnection::new();
+        let mut from_peer = call("Echo");
+        from_peer.set_sender(Some(BusName::from_static(":1.5")));
+
+        // the mock bus never answers, but the lookup is cached all the same
+        mock.inject(from_peer.clone());
+        server.process(mock.conn()).unwrap();
+        assert_eq!(uid_lookups(&mut mock), 1);
+        mock.inject(from_peer.clone());
+        server.process(mock.conn()).unwrap();
+        assert_eq!(uid_lookups(&mut mock), 0);
+
+        let left = DBusMessage::signal(BUS_PATH, DBUS_INTERFACE,
+                                       MemberName::from_static("NameOwnerChanged"))
+            .with_args(vec![Str(String::from_str(":1.5")), Str(String::from_str(":1.5")),
+                            Str(String::new())]);
+        mock.inject(left);
+        assert!(server.process(mock.conn()).unwrap().is_some());
+        mock.inject(from_peer);
+        server.process(mock.conn()).unwrap();
+        assert_eq!(uid_lookups(&mut mock), 1);
+    }
+
+    #[test]
+    fn test_object_server_peer() {
+        let mut server = ObjectServer::new();
+        let mut mock = MockConnection::new();
+        // answered for any path, with nothing added
+        mock.inject(DBusMessage::method_call(BusName::from_static("com.example.Frob"), "/any",
+                                             InterfaceName::from_static(PEER_INTERFACE),
+                                             MemberName::from_static("Ping")));
+        assert!(server.process(mock.conn()).unwrap().is_none());
+        assert_eq!(mock.expect_sent().msg_type(), MessageType::MethodReturn);
+    }
+
+    #[test]
+    fn test_pair() {
+        let (mut client, mut service) = DBusConnection::pair();
+        spawn(proc() {
+            let echo: fn(&mut DBusConnection, &DBusMessage) -> DBusResult<Vec<Value>> = echo;
+            let mut server = ObjectServer::new();
+            server.add_method("/frob", InterfaceName::from_static("com.example.Frob"),
+                              MemberName::from_static("Echo"), echo);
+            server.process(&mut service).unwrap();
+        });
+        let reply = client.call(call("Echo")).unwrap();
+        assert_eq!(reply.body(), [Str(String::from_str("hello"))].as_slice());
+    }
+}
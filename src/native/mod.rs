@@ -0,0 +1,51 @@
+// A D-Bus client speaking the wire protocol itself, for builds that
+// can't or don't want to link libdbus-1.
+
+pub use self::connection::DBusConnection;
+pub use self::sender::{MessageSender, PendingReply};
+#[cfg(unix)]
+pub use self::server::DBusServer;
+pub use self::access::AccessPolicy;
+pub use self::fake::FakeService;
+pub use self::fault::FaultInjector;
+pub use self::keepalive::Keepalive;
+pub use self::mock::MockConnection;
+pub use self::policy::{Rule, SecurityPolicy};
+pub use self::record::{Recorder, Replay};
+pub use self::service::{AuditHook, AuditRecord, MethodHandler, ObjectServer};
+pub use self::trace::{Direction, Tracer};
+
+pub mod access;
+pub mod auth;
+mod bus;
+pub mod connection;
+pub mod fake;
+pub mod fault;
+pub mod hostname1;
+pub mod keepalive;
+#[cfg(unix)]
+pub mod login1;
+pub mod mock;
+pub mod monitor;
+pub mod mpris;
+pub mod notifications;
+pub mod policy;
+pub mod polkit;
+pub mod portal;
+pub mod properties;
+pub mod reader;
+pub mod record;
+pub mod screensaver;
+pub mod sender;
+#[cfg(unix)]
+pub mod server;
+pub mod service;
+pub mod sha1;
+pub mod systemd;
+pub mod timedate1;
+pub mod trace;
+pub mod transport;
+#[cfg(windows)]
+pub mod windows;
+#[cfg(all(unix, not(target_os = "macos")))]
+pub mod x11;
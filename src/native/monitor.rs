@@ -0,0 +1,121 @@
+// Watching other connections' traffic, as dbus-monitor does: with
+// BecomeMonitor where the bus has it, otherwise with eavesdropping
+// match rules, which older buses honour instead.
+
+use message_ref::MessageRef;
+use super::super::{DBusResult, StandardError};
+use super::connection::DBusConnection;
+
+
+pub type CaptureMode = self::CaptureMode::CaptureMode;
+pub mod CaptureMode {
+    #[deriving(PartialEq, Eq, Clone, Show)]
+    pub enum CaptureMode {
+        // The connection is a monitor and can no longer send
+        BecomeMonitor,
+        // The connection added eavesdrop='true' rules and works as usual
+        Eavesdrop
+    }
+}
+
+// dbus-monitor adds one rule per type, since a rule without one only
+// gets signals on some buses.
+const MESSAGE_TYPES: [&'static str, ..4] = ["signal", "method_call", "method_return", "error"];
+
+fn with_eavesdrop(rule: &str, message_type: Option<&str>) -> String {
+    let mut out = String::from_str(rule);
+    for message_type in message_type.iter() {
+        if !out.is_empty() {
+            out.push(',');
+        }
+        out.push_str(format!("type='{}'", message_type).as_slice());
+    }
+    if !out.is_empty() {
+        out.push(',');
+    }
+    out.push_str("eavesdrop='true'");
+    out
+}
+
+// `rule` with eavesdropping turned on.  Rules with a type of their own
+// keep it; the rest get one per message type.
+pub fn eavesdrop_rules(rule: &str) -> Vec<String> {
+    let rule = rule.trim();
+    if rule.starts_with("type=") || rule.contains(",type=") {
+        vec![with_eavesdrop(rule, None)]
+    } else {
+        MESSAGE_TYPES.iter().map(|t| with_eavesdrop(rule, Some(*t))).collect()
+    }
+}
+
+// Starts capturing messages matching any of `rules`, or everything if
+// there are none, preferring BecomeMonitor and falling back to
+// eavesdropping on buses that don't have it.
+pub fn start_capture(conn: &mut DBusConnection, rules: &[&str]) -> DBusResult<CaptureMode> {
+    match conn.become_monitor(rules) {
+        Ok(()) => return Ok(CaptureMode::BecomeMonitor),
+        Err(ref err) if err.is_standard(StandardError::UnknownMethod) ||
+                        err.is_standard(StandardError::UnknownInterface) => (),
+        Err(err) => return Err(err)
+    }
+    let everything = [""];
+    let rules = if rules.is_empty() { everything.as_slice() } else { rules };
+    for rule in rules.iter() {
+        for eavesdrop in eavesdrop_rules(*rule).iter() {
+            try!(conn.add_match(eavesdrop.as_slice()));
+        }
+    }
+    Ok(CaptureMode::Eavesdrop)
+}
+
+// Hands each message the connection receives to `f` as a MessageRef
+// into the receive buffer, until `f` returns false or reading fails.
+// A monitor sees every message on the bus, so nothing is copied out of
+// the buffer unless `f` does it.
+pub fn for_each_captured(conn: &mut DBusConnection, f: |MessageRef| -> bool) -> DBusResult<()> {
+    loop {
+        if !try!(conn.with_next_message(|msg| f(msg))) {
+            return Ok(());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::DBusMessage;
+    use names::{InterfaceName, MemberName};
+    use native::mock::MockConnection;
+
+    #[test]
+    fn test_eavesdrop_rules() {
+        let rules = eavesdrop_rules("");
+        assert_eq!(rules.len(), 4);
+        assert_eq!(rules[0].as_slice(), "type='signal',eavesdrop='true'");
+        assert_eq!(rules[3].as_slice(), "type='error',eavesdrop='true'");
+
+        assert_eq!(eavesdrop_rules("type='method_call',interface='com.example.Frob'"),
+                   vec![String::from_str(
+                       "type='method_call',interface='com.example.Frob',eavesdrop='true'")]);
+        assert_eq!(eavesdrop_rules("sender='com.example.Frob'")[1].as_slice(),
+                   "sender='com.example.Frob',type='method_call',eavesdrop='true'");
+    }
+
+    #[test]
+    fn test_for_each_captured() {
+        let mut mock = MockConnection::new();
+        let interface = InterfaceName::from_static("com.example.Frob");
+        for member in ["A", "B", "C"].iter() {
+            mock.inject(DBusMessage::signal("/frob", interface.clone(),
+                                            MemberName::new(*member).unwrap()));
+        }
+        let mut seen = Vec::new();
+        for_each_captured(mock.conn(), |msg| {
+            seen.push(String::from_str(msg.member().unwrap()));
+            seen.len() < 2
+        }).unwrap();
+        assert_eq!(seen, vec![String::from_str("A"), String::from_str("B")]);
+        assert_eq!(mock.conn().read_message().unwrap().member(), Some("C"));
+    }
+}
@@ -0,0 +1,247 @@
+// Controlling media players through MPRIS, the
+// org.mpris.MediaPlayer2 interfaces every desktop player exports:
+//
+//     for name in try!(list_players(&mut conn)).iter() {
+//         let player = Player::new(name.as_slice());
+//         let metadata = try!(player.metadata(&mut conn));
+//         println!("{}: {}", name, metadata.title);
+//     }
+//
+// To follow a player, add player.changes_rule() as a match rule and
+// pass incoming signals to player.changes().
+
+use std::collections::HashMap;
+
+use message::DBusMessage;
+use names::{BusName, InterfaceName, MemberName};
+use value::{Value, Array, Int64, UInt64, Str};
+use super::super::{DBusResult, DBusError, StandardError};
+use super::connection::DBusConnection;
+use super::properties::{get_property, properties_changed_rule, string_map, PropertiesChanged};
+
+
+// Every player's bus name starts with this
+pub const MPRIS_PREFIX: &'static str = "org.mpris.MediaPlayer2.";
+pub const MPRIS_PATH: &'static str = "/org/mpris/MediaPlayer2";
+pub const MPRIS_INTERFACE: &'static str = "org.mpris.MediaPlayer2";
+pub const MPRIS_PLAYER_INTERFACE: &'static str = "org.mpris.MediaPlayer2.Player";
+
+
+// The bus names of the players running now.
+pub fn list_players(conn: &mut DBusConnection) -> DBusResult<Vec<String>> {
+    let names = try!(conn.list_names());
+    Ok(names.into_iter().filter(|name| name.as_slice().starts_with(MPRIS_PREFIX)).collect())
+}
+
+
+pub type PlaybackStatus = self::PlaybackStatus::PlaybackStatus;
+pub mod PlaybackStatus {
+    #[deriving(PartialEq, Eq, Clone, Show)]
+    pub enum PlaybackStatus {
+        Playing,
+        Paused,
+        Stopped
+    }
+
+    pub fn from_str(status: &str) -> Option<PlaybackStatus> {
+        match status {
+            "Playing" => Some(Playing),
+            "Paused" => Some(Paused),
+            "Stopped" => Some(Stopped),
+            _ => None
+        }
+    }
+}
+
+
+// The current track, from the Metadata property.  Players fill in as
+// much as they know; missing fields are empty.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Metadata {
+    pub track_id: Option<String>,
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    // Microseconds
+    pub length: Option<i64>,
+    pub art_url: Option<String>,
+    pub url: Option<String>,
+    // Everything, including the fields above
+    pub all: HashMap<String, Value>
+}
+
+impl Metadata {
+    pub fn from_map(all: HashMap<String, Value>) -> Metadata {
+        let string = |key: &str| all.find_equiv(&key).and_then(|v| v.as_str())
+                                    .map(|s| String::from_str(s));
+        let artists = match all.find_equiv(&"xesam:artist") {
+            Some(&Array(_, ref artists)) => {
+                artists.iter().filter_map(|a| a.as_str()).map(|a| String::from_str(a)).collect()
+            },
+            // some players send a lone string
+            Some(&Str(ref artist)) => vec![artist.clone()],
+            _ => Vec::new()
+        };
+        let length = match all.find_equiv(&"mpris:length") {
+            Some(&Int64(length)) => Some(length),
+            Some(&UInt64(length)) => Some(length as i64),
+            _ => None
+        };
+        Metadata {
+            track_id: string("mpris:trackid"),
+            title: string("xesam:title").unwrap_or(String::new()),
+            artists: artists,
+            album: string("xesam:album").unwrap_or(String::new()),
+            length: length,
+            art_url: string("mpris:artUrl"),
+            url: string("xesam:url"),
+            all: all.clone()
+        }
+    }
+}
+
+
+pub struct Player {
+    name: String
+}
+
+impl Player {
+    // The player owning `name`, one of those list_players() returns.
+    pub fn new(name: &str) -> Player {
+        Player {
+            name: String::from_str(name)
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_slice()
+    }
+
+    fn call(&self, conn: &mut DBusConnection, member: &'static str) -> DBusResult<()> {
+        let destination = try!(BusName::new(self.name.as_slice()));
+        let call = DBusMessage::method_call(destination, MPRIS_PATH,
+                                            InterfaceName::from_static(MPRIS_PLAYER_INTERFACE),
+                                            MemberName::from_static(member));
+        try!(conn.call(call));
+        Ok(())
+    }
+
+    pub fn play(&self, conn: &mut DBusConnection) -> DBusResult<()> {
+        self.call(conn, "Play")
+    }
+
+    pub fn pause(&self, conn: &mut DBusConnection) -> DBusResult<()> {
+        self.call(conn, "Pause")
+    }
+
+    pub fn play_pause(&self, conn: &mut DBusConnection) -> DBusResult<()> {
+        self.call(conn, "PlayPause")
+    }
+
+    pub fn stop(&self, conn: &mut DBusConnection) -> DBusResult<()> {
+        self.call(conn, "Stop")
+    }
+
+    pub fn next(&self, conn: &mut DBusConnection) -> DBusResult<()> {
+        self.call(conn, "Next")
+    }
+
+    pub fn previous(&self, conn: &mut DBusConnection) -> DBusResult<()> {
+        self.call(conn, "Previous")
+    }
+
+    fn property(&self, conn: &mut DBusConnection, name: &str) -> DBusResult<Value> {
+        let destination = try!(BusName::new(self.name.as_slice()));
+        get_property(conn, destination, MPRIS_PATH, MPRIS_PLAYER_INTERFACE, name)
+    }
+
+    pub fn playback_status(&self, conn: &mut DBusConnection) -> DBusResult<PlaybackStatus> {
+        let status = try!(self.property(conn, "PlaybackStatus"));
+        match status.as_str().and_then(PlaybackStatus::from_str) {
+            Some(status) => Ok(status),
+            None => Err(bad_property("PlaybackStatus"))
+        }
+    }
+
+    pub fn metadata(&self, conn: &mut DBusConnection) -> DBusResult<Metadata> {
+        let metadata = try!(self.property(conn, "Metadata"));
+        match string_map(&metadata) {
+            Some(all) => Ok(Metadata::from_map(all)),
+            None => Err(bad_property("Metadata"))
+        }
+    }
+
+    // The match rule for this player's PropertiesChanged signals.
+    pub fn changes_rule(&self) -> String {
+        properties_changed_rule(self.name.as_slice(), MPRIS_PATH)
+    }
+
+    // The player properties `msg` says have changed, if it's a
+    // PropertiesChanged signal from this player.  The bus gives the
+    // sender as a unique name, so `owner` is that of our player's
+    // name; see get_name_owner().
+    pub fn changes(&self, msg: &DBusMessage, owner: &str) -> Option<PlayerChanges> {
+        if msg.sender() != Some(owner) && msg.sender() != Some(self.name.as_slice()) {
+            return None;
+        }
+        if msg.path() != Some(MPRIS_PATH) {
+            return None;
+        }
+        let changed = match PropertiesChanged::from_message(msg) {
+            Some(ref changed) if changed.interface.as_slice() == MPRIS_PLAYER_INTERFACE => {
+                changed.changed.clone()
+            },
+            _ => return None
+        };
+        let status = changed.find_equiv(&"PlaybackStatus").and_then(|s| s.as_str())
+                            .and_then(PlaybackStatus::from_str);
+        let metadata = changed.find_equiv(&"Metadata")
+                              .and_then(string_map)
+                              .map(Metadata::from_map);
+        Some(PlayerChanges {
+            playback_status: status,
+            metadata: metadata,
+            all: changed
+        })
+    }
+}
+
+// What a PropertiesChanged signal from a player said.
+#[deriving(Clone, PartialEq, Show)]
+pub struct PlayerChanges {
+    pub playback_status: Option<PlaybackStatus>,
+    pub metadata: Option<Metadata>,
+    pub all: HashMap<String, Value>
+}
+
+fn bad_property(name: &str) -> DBusError {
+    DBusError::standard(StandardError::InconsistentMessage,
+                        format!("player sent a malformed {}", name).as_slice())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use value::{DBusType, Array, Int64, ObjectPath, Str};
+
+    #[test]
+    fn test_metadata() {
+        let mut all = HashMap::new();
+        all.insert(String::from_str("mpris:trackid"),
+                   ObjectPath(String::from_str("/org/mpris/MediaPlayer2/Track/1")));
+        all.insert(String::from_str("mpris:length"), Int64(215000000));
+        all.insert(String::from_str("xesam:title"), Str(String::from_str("Frobnication")));
+        all.insert(String::from_str("xesam:artist"),
+                   Array(DBusType::Str, vec![Str(String::from_str("The Frobs"))]));
+        let metadata = Metadata::from_map(all);
+        assert_eq!(metadata.track_id, Some(String::from_str("/org/mpris/MediaPlayer2/Track/1")));
+        assert_eq!(metadata.title.as_slice(), "Frobnication");
+        assert_eq!(metadata.artists, vec![String::from_str("The Frobs")]);
+        assert_eq!(metadata.length, Some(215000000));
+        assert_eq!(metadata.album.as_slice(), "");
+        assert_eq!(metadata.url, None);
+    }
+}
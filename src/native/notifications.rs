@@ -0,0 +1,321 @@
+// Desktop notifications through org.freedesktop.Notifications:
+//
+//     let mut notifier = Notifier::new();
+//     let id = try!(notifier.show(&mut conn,
+//                                 &Notification::new("Download finished")
+//                                     .body("frob.tar.gz")
+//                                     .action("open", "Open"),
+//                                 on_action));
+//
+// Actions and closes come back as signals, which the application
+// hands to Notifier::handle() as it reads them.
+
+use std::collections::HashMap;
+
+use message::{DBusMessage, MessageType};
+use names::{BusName, InterfaceName, MemberName};
+use value::{DBusType, Value, Array, Byte, Int32, Str, UInt32, Variant};
+use super::super::{DBusResult, DBusError, StandardError};
+use super::connection::DBusConnection;
+
+
+pub const NOTIFICATIONS_NAME: &'static str = "org.freedesktop.Notifications";
+pub const NOTIFICATIONS_PATH: &'static str = "/org/freedesktop/Notifications";
+pub const NOTIFICATIONS_INTERFACE: &'static str = "org.freedesktop.Notifications";
+
+
+pub type Urgency = self::Urgency::Urgency;
+pub mod Urgency {
+    #[deriving(PartialEq, Eq, Clone, Show)]
+    pub enum Urgency {
+        Low,
+        Normal,
+        Critical
+    }
+}
+
+// Why a notification went away, from NotificationClosed.
+pub type CloseReason = self::CloseReason::CloseReason;
+pub mod CloseReason {
+    #[deriving(PartialEq, Eq, Clone, Show)]
+    pub enum CloseReason {
+        Expired,
+        Dismissed,
+        // By CloseNotification
+        Closed,
+        Undefined
+    }
+
+    pub fn from_u32(reason: u32) -> CloseReason {
+        match reason {
+            1 => Expired,
+            2 => Dismissed,
+            3 => Closed,
+            _ => Undefined
+        }
+    }
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct Notification {
+    app_name: String,
+    replaces_id: u32,
+    icon: String,
+    summary: String,
+    body: String,
+    // (key, label) pairs, in the order they're shown
+    actions: Vec<(String, String)>,
+    hints: Vec<(String, Value)>,
+    // Milliseconds; -1 leaves it to the server, 0 never expires
+    expire_timeout: i32
+}
+
+impl Notification {
+    pub fn new(summary: &str) -> Notification {
+        Notification {
+            app_name: String::new(),
+            replaces_id: 0,
+            icon: String::new(),
+            summary: String::from_str(summary),
+            body: String::new(),
+            actions: Vec::new(),
+            hints: Vec::new(),
+            expire_timeout: -1
+        }
+    }
+
+    pub fn app_name(mut self, app_name: &str) -> Notification {
+        self.app_name = String::from_str(app_name);
+        self
+    }
+
+    // Updates the notification with this id in place.
+    pub fn replaces(mut self, id: u32) -> Notification {
+        self.replaces_id = id;
+        self
+    }
+
+    // An icon name from the theme, or a file:// URI.
+    pub fn icon(mut self, icon: &str) -> Notification {
+        self.icon = String::from_str(icon);
+        self
+    }
+
+    pub fn body(mut self, body: &str) -> Notification {
+        self.body = String::from_str(body);
+        self
+    }
+
+    // A button labelled `label`; the ActionHandler gets `key` when
+    // it's clicked.  The key "default" is the notification itself.
+    pub fn action(mut self, key: &str, label: &str) -> Notification {
+        self.actions.push((String::from_str(key), String::from_str(label)));
+        self
+    }
+
+    // Replaces any hint of the same name.
+    pub fn hint(mut self, name: &str, value: Value) -> Notification {
+        self.hints.retain(|&(ref n, _)| n.as_slice() != name);
+        self.hints.push((String::from_str(name), value));
+        self
+    }
+
+    pub fn urgency(self, urgency: Urgency) -> Notification {
+        self.hint("urgency", Byte(match urgency {
+            Urgency::Low => 0,
+            Urgency::Normal => 1,
+            Urgency::Critical => 2
+        }))
+    }
+
+    // Milliseconds, or None to never expire.
+    pub fn expire_timeout(mut self, ms: Option<u32>) -> Notification {
+        self.expire_timeout = match ms {
+            Some(ms) => ms as i32,
+            None => 0
+        };
+        self
+    }
+
+    fn to_call(&self) -> DBusMessage {
+        let mut actions = Vec::with_capacity(self.actions.len() * 2);
+        for &(ref key, ref label) in self.actions.iter() {
+            actions.push(Str(key.clone()));
+            actions.push(Str(label.clone()));
+        }
+        let hints = self.hints.iter().map(|&(ref name, ref value)| {
+            (Str(name.clone()), Variant(box value.clone()))
+        }).collect();
+        notifications_call("Notify").with_args(vec![
+            Str(self.app_name.clone()),
+            UInt32(self.replaces_id),
+            Str(self.icon.clone()),
+            Str(self.summary.clone()),
+            Str(self.body.clone()),
+            Array(DBusType::Str, actions),
+            Value::dict(DBusType::Str, DBusType::Variant, hints),
+            Int32(self.expire_timeout)
+        ])
+    }
+}
+
+fn notifications_call(member: &'static str) -> DBusMessage {
+    DBusMessage::method_call(BusName::from_static(NOTIFICATIONS_NAME), NOTIFICATIONS_PATH,
+                             InterfaceName::from_static(NOTIFICATIONS_INTERFACE),
+                             MemberName::from_static(member))
+}
+
+// Shows `notification`, returning its id.
+pub fn notify(conn: &mut DBusConnection, notification: &Notification) -> DBusResult<u32> {
+    let reply = try!(conn.call(notification.to_call()));
+    match reply.body().head() {
+        Some(&UInt32(id)) => Ok(id),
+        _ => Err(DBusError::standard(StandardError::InconsistentMessage,
+                                     "unexpected reply to Notify"))
+    }
+}
+
+pub fn close_notification(conn: &mut DBusConnection, id: u32) -> DBusResult<()> {
+    try!(conn.call(notifications_call("CloseNotification").with_args(vec![UInt32(id)])));
+    Ok(())
+}
+
+
+// What to do when the user responds to a notification.
+pub trait ActionHandler {
+    fn invoked(&mut self, id: u32, action: &str);
+
+    fn closed(&mut self, _id: u32, _reason: CloseReason) {}
+}
+
+impl ActionHandler for fn(u32, &str) {
+    fn invoked(&mut self, id: u32, action: &str) {
+        (*self)(id, action)
+    }
+}
+
+
+// Notifications shown with handlers, until they're closed.
+pub struct Notifier {
+    handlers: HashMap<u32, Box<ActionHandler + Send>>,
+    subscribed: bool
+}
+
+impl Notifier {
+    pub fn new() -> Notifier {
+        Notifier {
+            handlers: HashMap::new(),
+            subscribed: false
+        }
+    }
+
+    // Like notify(), with `handler` told of the user's response.
+    pub fn show<H: ActionHandler + Send>(&mut self, conn: &mut DBusConnection,
+                                         notification: &Notification,
+                                         handler: H) -> DBusResult<u32> {
+        // before showing it, so a quick click isn't missed
+        if !self.subscribed {
+            for member in ["ActionInvoked", "NotificationClosed"].iter() {
+                try!(conn.add_match(format!("type='signal',interface='{}',member='{}'",
+                                            NOTIFICATIONS_INTERFACE, member).as_slice()));
+            }
+            self.subscribed = true;
+        }
+        let id = try!(notify(conn, notification));
+        self.handlers.insert(id, box handler as Box<ActionHandler + Send>);
+        Ok(id)
+    }
+
+    pub fn close(&mut self, conn: &mut DBusConnection, id: u32) -> DBusResult<()> {
+        close_notification(conn, id)
+    }
+
+    // Passes ActionInvoked and NotificationClosed signals for our
+    // notifications to their handlers.  Returns whether `msg` was one.
+    pub fn handle(&mut self, msg: &DBusMessage) -> bool {
+        if msg.msg_type() != MessageType::Signal
+            || !msg.has_interface(NOTIFICATIONS_INTERFACE) {
+            return false;
+        }
+        let body = msg.body();
+        let id = match body.head().and_then(|id| id.as_u32()) {
+            Some(id) if self.handlers.contains_key(&id) => id,
+            _ => return false
+        };
+        match (msg.member(), body.get(1)) {
+            (Some("ActionInvoked"), Some(&Str(ref action))) => {
+                self.handlers.find_mut(&id).unwrap().invoked(id, action.as_slice());
+                true
+            },
+            (Some("NotificationClosed"), Some(&UInt32(reason))) => {
+                let mut handler = self.handlers.pop(&id).unwrap();
+                handler.closed(id, CloseReason::from_u32(reason));
+                true
+            },
+            _ => false
+        }
+    }
+
+    // Notifications shown that haven't closed yet.
+    pub fn open(&self) -> uint {
+        self.handlers.len()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::DBusMessage;
+    use names::{InterfaceName, MemberName};
+    use value::{DBusType, Value, Byte, Str, UInt32, Variant};
+
+    #[test]
+    fn test_notify_call() {
+        let call = Notification::new("Done").body("frob.tar.gz").action("open", "Open")
+            .urgency(Urgency::Critical).to_call();
+        assert_eq!(call.signature().as_slice(), "susssasa{sv}i");
+        assert_eq!(call.body()[3], Str(String::from_str("Done")));
+        assert_eq!(call.body()[6],
+                   Value::dict(DBusType::Str, DBusType::Variant,
+                               vec![(Str(String::from_str("urgency")), Variant(box Byte(2)))]));
+    }
+
+    struct Clicks {
+        tx: Sender<String>
+    }
+
+    impl ActionHandler for Clicks {
+        fn invoked(&mut self, _id: u32, action: &str) {
+            self.tx.send(String::from_str(action));
+        }
+
+        fn closed(&mut self, _id: u32, reason: CloseReason) {
+            self.tx.send(format!("closed: {}", reason));
+        }
+    }
+
+    fn signal(member: &'static str, args: Vec<Value>) -> DBusMessage {
+        DBusMessage::signal(NOTIFICATIONS_PATH,
+                            InterfaceName::from_static(NOTIFICATIONS_INTERFACE),
+                            MemberName::from_static(member))
+            .with_args(args)
+    }
+
+    #[test]
+    fn test_handle() {
+        let (tx, rx) = channel();
+        let mut notifier = Notifier::new();
+        notifier.handlers.insert(7, box Clicks { tx: tx } as Box<ActionHandler + Send>);
+
+        assert!(notifier.handle(&signal("ActionInvoked",
+                                        vec![UInt32(7), Str(String::from_str("open"))])));
+        assert!(!notifier.handle(&signal("ActionInvoked",
+                                         vec![UInt32(8), Str(String::from_str("open"))])));
+        assert!(notifier.handle(&signal("NotificationClosed", vec![UInt32(7), UInt32(2)])));
+        assert_eq!(notifier.open(), 0);
+        assert_eq!(rx.recv().as_slice(), "open");
+        assert_eq!(rx.recv().as_slice(), "closed: Dismissed");
+    }
+}
@@ -0,0 +1,149 @@
+// Allow/deny rules for incoming messages, after the <allow>/<deny>
+// elements of dbus-daemon's policy files.  A bus daemon enforces its
+// own policy, but a peer-to-peer connection has nobody in between, so
+// without one of these it takes whatever the peer sends.
+//
+//     let policy = SecurityPolicy::deny_all()
+//         .rule(Rule::allow().interface(InterfaceName::from_static("com.example.Frob")))
+//         .rule(Rule::deny().member(MemberName::from_static("Reset")));
+
+use message::{DBusMessage, MessageType};
+use names::{BusName, InterfaceName, MemberName};
+
+
+// Matches messages having all of the fields given; a rule with none
+// matches everything.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Rule {
+    allow: bool,
+    msg_type: Option<MessageType>,
+    interface: Option<String>,
+    member: Option<String>,
+    sender: Option<String>
+}
+
+impl Rule {
+    fn new(allow: bool) -> Rule {
+        Rule {
+            allow: allow,
+            msg_type: None,
+            interface: None,
+            member: None,
+            sender: None
+        }
+    }
+
+    pub fn allow() -> Rule {
+        Rule::new(true)
+    }
+
+    pub fn deny() -> Rule {
+        Rule::new(false)
+    }
+
+    pub fn msg_type(mut self, msg_type: MessageType) -> Rule {
+        self.msg_type = Some(msg_type);
+        self
+    }
+
+    pub fn interface(mut self, interface: InterfaceName) -> Rule {
+        self.interface = Some(String::from_str(interface.as_str()));
+        self
+    }
+
+    pub fn member(mut self, member: MemberName) -> Rule {
+        self.member = Some(String::from_str(member.as_str()));
+        self
+    }
+
+    pub fn sender(mut self, sender: BusName) -> Rule {
+        self.sender = Some(String::from_str(sender.as_str()));
+        self
+    }
+
+    pub fn is_allow(&self) -> bool {
+        self.allow
+    }
+
+    pub fn matches(&self, msg: &DBusMessage) -> bool {
+        fn field_matches(want: &Option<String>, have: Option<&str>) -> bool {
+            match *want {
+                Some(ref want) => have == Some(want.as_slice()),
+                None => true
+            }
+        }
+        match self.msg_type {
+            Some(ref msg_type) if *msg_type != msg.msg_type() => return false,
+            _ => ()
+        }
+        field_matches(&self.interface, msg.interface()) &&
+            field_matches(&self.member, msg.member()) &&
+            field_matches(&self.sender, msg.sender())
+    }
+}
+
+
+// Rules are tried in order and the last one matching decides, as in
+// the daemon's policy files; messages no rule matches get the default.
+#[deriving(Clone, PartialEq, Show)]
+pub struct SecurityPolicy {
+    default_allow: bool,
+    rules: Vec<Rule>
+}
+
+impl SecurityPolicy {
+    pub fn allow_all() -> SecurityPolicy {
+        SecurityPolicy {
+            default_allow: true,
+            rules: Vec::new()
+        }
+    }
+
+    pub fn deny_all() -> SecurityPolicy {
+        SecurityPolicy {
+            default_allow: false,
+            rules: Vec::new()
+        }
+    }
+
+    pub fn rule(mut self, rule: Rule) -> SecurityPolicy {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn allows(&self, msg: &DBusMessage) -> bool {
+        match self.rules.iter().rev().find(|rule| rule.matches(msg)) {
+            Some(rule) => rule.allow,
+            None => self.default_allow
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::{DBusMessage, MessageType};
+    use names::{BusName, InterfaceName, MemberName};
+
+    fn call(member: &'static str) -> DBusMessage {
+        DBusMessage::method_call(BusName::from_static("com.example.Frob"), "/",
+                                 InterfaceName::from_static("com.example.Frob"),
+                                 MemberName::from_static(member))
+    }
+
+    #[test]
+    fn test_last_match_wins() {
+        let policy = SecurityPolicy::deny_all()
+            .rule(Rule::allow().interface(InterfaceName::from_static("com.example.Frob")))
+            .rule(Rule::deny().member(MemberName::from_static("Reset")));
+        assert!(policy.allows(&call("Frobulate")));
+        assert!(!policy.allows(&call("Reset")));
+
+        let signal = DBusMessage::signal("/", InterfaceName::from_static("com.example.Other"),
+                                         MemberName::from_static("Changed"));
+        assert!(!policy.allows(&signal));
+        let policy = policy.rule(Rule::allow().msg_type(MessageType::Signal));
+        assert!(policy.allows(&signal));
+    }
+}
@@ -0,0 +1,111 @@
+// Checking callers against polkit actions before running a handler, as
+// system services do for privileged operations.
+
+use message::DBusMessage;
+use names::{BusName, InterfaceName, MemberName};
+use value::{DBusType, Value, Boolean, Str, Struct, UInt32, Variant};
+use super::super::{DBusResult, DBusError, StandardError};
+use super::connection::DBusConnection;
+use super::service::MethodHandler;
+
+
+pub const AUTHORITY_NAME: &'static str = "org.freedesktop.PolicyKit1";
+pub const AUTHORITY_PATH: &'static str = "/org/freedesktop/PolicyKit1/Authority";
+pub const AUTHORITY_INTERFACE: &'static str = "org.freedesktop.PolicyKit1.Authority";
+
+// CheckAuthorizationFlags
+pub const ALLOW_USER_INTERACTION: u32 = 0x1;
+
+
+// What polkit said about a caller and an action.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Authorization {
+    pub is_authorized: bool,
+    // Authorized only if the user authenticates, which they weren't
+    // allowed to be asked to
+    pub is_challenge: bool
+}
+
+// Asks polkit whether the bus connection `sender` may perform
+// `action_id`, letting polkit prompt the user if `interactive`.
+pub fn check_authorization(conn: &mut DBusConnection, sender: &str, action_id: &str,
+                           interactive: bool) -> DBusResult<Authorization> {
+    let subject = Struct(vec![
+        Str(String::from_str("system-bus-name")),
+        Value::dict(DBusType::Str, DBusType::Variant, vec![
+            (Str(String::from_str("name")), Variant(box Str(String::from_str(sender))))
+        ])
+    ]);
+    let flags = if interactive { ALLOW_USER_INTERACTION } else { 0 };
+    let call = DBusMessage::method_call(BusName::from_static(AUTHORITY_NAME), AUTHORITY_PATH,
+                                        InterfaceName::from_static(AUTHORITY_INTERFACE),
+                                        MemberName::from_static("CheckAuthorization"))
+        .with_args(vec![
+            subject,
+            Str(String::from_str(action_id)),
+            Value::dict(DBusType::Str, DBusType::Str, Vec::new()),
+            UInt32(flags),
+            // no cancellation id
+            Str(String::new())
+        ])
+        .with_interactive_authorization(interactive);
+    let reply = try!(conn.call(call));
+    match reply.body().head() {
+        Some(&Struct(ref fields)) if fields.len() == 3 => match (&fields[0], &fields[1]) {
+            (&Boolean(is_authorized), &Boolean(is_challenge)) => Ok(Authorization {
+                is_authorized: is_authorized,
+                is_challenge: is_challenge
+            }),
+            _ => Err(unexpected_reply())
+        },
+        _ => Err(unexpected_reply())
+    }
+}
+
+fn unexpected_reply() -> DBusError {
+    DBusError::standard(StandardError::InconsistentMessage,
+                        "unexpected reply to CheckAuthorization")
+}
+
+
+// Runs `inner` only for callers polkit authorizes for the action,
+// replying AccessDenied to everyone else, or
+// InteractiveAuthorizationRequired when they could have been
+// authorized had the call allowed prompting.
+pub struct RequireAuthorization<H> {
+    action_id: String,
+    inner: H
+}
+
+impl<H: MethodHandler> RequireAuthorization<H> {
+    pub fn new(action_id: &str, inner: H) -> RequireAuthorization<H> {
+        RequireAuthorization {
+            action_id: String::from_str(action_id),
+            inner: inner
+        }
+    }
+}
+
+impl<H: MethodHandler> MethodHandler for RequireAuthorization<H> {
+    fn handle(&mut self, conn: &mut DBusConnection, call: &DBusMessage) -> DBusResult<Vec<Value>> {
+        let sender = match call.sender() {
+            Some(sender) => String::from_str(sender),
+            None => return Err(DBusError::standard(StandardError::AccessDenied,
+                                                   "caller has no bus name"))
+        };
+        let interactive = call.allow_interactive_authorization();
+        let auth = try!(check_authorization(conn, sender.as_slice(),
+                                            self.action_id.as_slice(), interactive));
+        if auth.is_authorized {
+            self.inner.handle(conn, call)
+        } else if auth.is_challenge && !interactive {
+            Err(DBusError::standard(StandardError::InteractiveAuthorizationRequired,
+                                    format!("{} needs authentication",
+                                            self.action_id).as_slice()))
+        } else {
+            Err(DBusError::standard(StandardError::AccessDenied,
+                                    format!("not authorized for {}",
+                                            self.action_id).as_slice()))
+        }
+    }
+}
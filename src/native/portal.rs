@@ -0,0 +1,239 @@
+// Calling XDG desktop portals, which is how sandboxed applications
+// open files, take screenshots and so on:
+//
+//     let mut portal = Portal::new();
+//     try!(portal.request(&mut conn, "org.freedesktop.portal.Screenshot", "Screenshot",
+//                         vec![Str(String::new())], Vec::new(), on_screenshot));
+//
+// Portal methods return at once with the path of a Request object,
+// and the result arrives later as that object's Response signal, which
+// the application hands to Portal::handle() as it reads it.  The path
+// is derived from our unique name and a token we choose, so we can
+// listen for the signal before making the call and can't miss it.
+
+use std::collections::HashMap;
+
+use std::rand::{task_rng, Rng};
+
+use message::{DBusMessage, MessageType};
+use names::{BusName, InterfaceName, MemberName};
+use value::{DBusType, Value, Str, Variant};
+use super::super::{DBusResult, DBusError, StandardError};
+use super::connection::DBusConnection;
+use super::properties::string_map;
+
+
+pub const PORTAL_NAME: &'static str = "org.freedesktop.portal.Desktop";
+pub const PORTAL_PATH: &'static str = "/org/freedesktop/portal/desktop";
+pub const REQUEST_INTERFACE: &'static str = "org.freedesktop.portal.Request";
+
+
+// How a request ended, from the Response signal.
+pub type Response = self::Response::Response;
+pub mod Response {
+    #[deriving(PartialEq, Eq, Clone, Show)]
+    pub enum Response {
+        Success,
+        // By the user
+        Cancelled,
+        // Some other way, e.g. the portal failed
+        Ended
+    }
+
+    pub fn from_u32(response: u32) -> Response {
+        match response {
+            0 => Success,
+            1 => Cancelled,
+            _ => Ended
+        }
+    }
+}
+
+
+// A fresh handle_token.  Tokens only need to be unique among our own
+// requests.
+pub fn handle_token() -> String {
+    format!("dbus_rust{}", task_rng().gen::<u32>())
+}
+
+// Where the portal will put the Request object for the given token,
+// ":1.42" and "abc" giving ".../request/1_42/abc".
+pub fn request_path(unique_name: &str, token: &str) -> String {
+    let sender = unique_name.trim_left_chars(':').replace(".", "_");
+    format!("{}/request/{}/{}", PORTAL_PATH, sender, token)
+}
+
+
+// What to do with a request's results.
+pub trait ResponseHandler {
+    fn response(&mut self, response: Response, results: HashMap<String, Value>);
+}
+
+impl ResponseHandler for fn(Response, HashMap<String, Value>) {
+    fn response(&mut self, response: Response, results: HashMap<String, Value>) {
+        (*self)(response, results)
+    }
+}
+
+
+// Portal requests made with handlers, until they're answered.
+pub struct Portal {
+    handlers: HashMap<String, Box<ResponseHandler + Send>>,
+    subscribed: bool
+}
+
+impl Portal {
+    pub fn new() -> Portal {
+        Portal {
+            handlers: HashMap::new(),
+            subscribed: false
+        }
+    }
+
+    // Calls `method` on the portal `interface` with `args`, followed
+    // by `options` as the usual trailing a{sv}, to which a
+    // handle_token is added.  Returns the Request object's path.
+    pub fn request<H: ResponseHandler + Send>(&mut self, conn: &mut DBusConnection,
+                                              interface: &str, method: &str,
+                                              args: Vec<Value>,
+                                              options: Vec<(String, Value)>,
+                                              handler: H) -> DBusResult<String> {
+        let unique_name = match conn.unique_name() {
+            Some(name) => String::from_str(name),
+            None => return Err(DBusError::standard(StandardError::Disconnected,
+                                                   "portals need a bus connection"))
+        };
+        // before calling, so a quick response isn't missed
+        if !self.subscribed {
+            let sender = unique_name.as_slice().trim_left_chars(':').replace(".", "_");
+            try!(conn.add_match(format!("type='signal',interface='{}',member='Response',\
+                                         path_namespace='{}/request/{}'",
+                                        REQUEST_INTERFACE, PORTAL_PATH, sender).as_slice()));
+            self.subscribed = true;
+        }
+
+        let token = handle_token();
+        let expected = request_path(unique_name.as_slice(), token.as_slice());
+        let call = try!(request_call(interface, method, args, options, token.as_slice()));
+        self.handlers.insert(expected.clone(), box handler as Box<ResponseHandler + Send>);
+        let reply = match conn.call(call) {
+            Ok(reply) => reply,
+            Err(err) => {
+                self.handlers.pop(&expected);
+                return Err(err);
+            }
+        };
+        let path = match reply.body().head().and_then(|path| path.as_str()) {
+            Some(path) => String::from_str(path),
+            None => {
+                self.handlers.pop(&expected);
+                return Err(DBusError::standard(StandardError::InconsistentMessage,
+                                               "portal didn't return a request path"));
+            }
+        };
+        // portals older than handle_token pick their own path
+        if path != expected {
+            let handler = self.handlers.pop(&expected).unwrap();
+            try!(conn.add_match(format!("type='signal',interface='{}',member='Response',\
+                                         path='{}'", REQUEST_INTERFACE, path).as_slice()));
+            self.handlers.insert(path.clone(), handler);
+        }
+        Ok(path)
+    }
+
+    // Passes Response signals for our requests to their handlers.
+    // Returns whether `msg` was one.
+    pub fn handle(&mut self, msg: &DBusMessage) -> bool {
+        if msg.msg_type() != MessageType::Signal
+            || !msg.has_interface(REQUEST_INTERFACE)
+            || !msg.has_member("Response") {
+            return false;
+        }
+        let path = match msg.path() {
+            Some(path) if self.handlers.contains_key_equiv(&path) => String::from_str(path),
+            _ => return false
+        };
+        let body = msg.body();
+        let response = match body.head().and_then(|r| r.as_u32()) {
+            Some(response) => Response::from_u32(response),
+            None => return false
+        };
+        let results = match body.get(1).and_then(string_map) {
+            Some(results) => results,
+            None => HashMap::new()
+        };
+        let mut handler = self.handlers.pop(&path).unwrap();
+        handler.response(response, results);
+        true
+    }
+
+    // Requests made that haven't been answered yet.
+    pub fn pending(&self) -> uint {
+        self.handlers.len()
+    }
+}
+
+fn request_call(interface: &str, method: &str, args: Vec<Value>,
+                options: Vec<(String, Value)>, token: &str) -> DBusResult<DBusMessage> {
+    let mut entries: Vec<(Value, Value)> = options.into_iter()
+        .filter(|&(ref key, _)| key.as_slice() != "handle_token")
+        .map(|(key, value)| (Str(key), Variant(box value)))
+        .collect();
+    entries.push((Str(String::from_str("handle_token")),
+                  Variant(box Str(String::from_str(token)))));
+    let mut args = args;
+    args.push(Value::dict(DBusType::Str, DBusType::Variant, entries));
+    Ok(DBusMessage::method_call(BusName::from_static(PORTAL_NAME), PORTAL_PATH,
+                                try!(InterfaceName::new(interface)),
+                                try!(MemberName::new(method)))
+        .with_args(args))
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use message::DBusMessage;
+    use names::{InterfaceName, MemberName};
+    use value::{DBusType, Value, Str, UInt32, Variant};
+
+    struct Collect(Sender<(Response, HashMap<String, Value>)>);
+
+    impl ResponseHandler for Collect {
+        fn response(&mut self, response: Response, results: HashMap<String, Value>) {
+            let Collect(ref tx) = *self;
+            tx.send((response, results));
+        }
+    }
+
+    #[test]
+    fn test_response() {
+        assert_eq!(request_path(":1.42", "abc").as_slice(),
+                   "/org/freedesktop/portal/desktop/request/1_42/abc");
+
+        let path = request_path(":1.42", "abc");
+        let (tx, rx) = channel();
+        let mut portal = Portal::new();
+        portal.handlers.insert(path.clone(), box Collect(tx) as Box<ResponseHandler + Send>);
+
+        let signal = DBusMessage::signal(path.as_slice(),
+                                         InterfaceName::from_static(REQUEST_INTERFACE),
+                                         MemberName::from_static("Response"))
+            .with_args(vec![
+                UInt32(0),
+                Value::dict(DBusType::Str, DBusType::Variant,
+                            vec![(Str(String::from_str("uri")),
+                                  Variant(box Str(String::from_str("file:///tmp/shot.png"))))])
+            ]);
+        assert!(portal.handle(&signal));
+        let (response, results) = rx.recv();
+        assert_eq!(response, Response::Success);
+        assert_eq!(results.find_equiv(&"uri"),
+                   Some(&Str(String::from_str("file:///tmp/shot.png"))));
+        assert_eq!(portal.pending(), 0);
+        // answered already
+        assert!(!portal.handle(&signal));
+    }
+}
@@ -0,0 +1,163 @@
+// Reading and writing other services' properties through
+// org.freedesktop.DBus.Properties, and decoding the PropertiesChanged
+// signals they send when they change.
+
+use std::collections::HashMap;
+
+use message::{DBusMessage, MessageType};
+use names::{BusName, InterfaceName, MemberName};
+use value::{Value, Array, DictEntry, Str, Variant};
+use super::super::{DBusResult, DBusError, StandardError};
+use super::connection::DBusConnection;
+
+
+pub const PROPERTIES_INTERFACE: &'static str = "org.freedesktop.DBus.Properties";
+
+
+fn properties_call(destination: BusName, path: &str, member: &'static str,
+                   args: Vec<Value>) -> DBusMessage {
+    DBusMessage::method_call(destination, path, InterfaceName::from_static(PROPERTIES_INTERFACE),
+                             MemberName::from_static(member))
+        .with_args(args)
+}
+
+// The property's value, without its variant wrapper.
+pub fn get_property(conn: &mut DBusConnection, destination: BusName, path: &str,
+                    interface: &str, name: &str) -> DBusResult<Value> {
+    let call = properties_call(destination, path, "Get", vec![Str(String::from_str(interface)),
+                                                               Str(String::from_str(name))]);
+    let reply = try!(conn.call(call));
+    match reply.body().head() {
+        Some(&Variant(ref value)) => Ok(value.inner().clone()),
+        _ => Err(unexpected_reply("Get"))
+    }
+}
+
+// Every property of `interface`, by name.
+pub fn get_all_properties(conn: &mut DBusConnection, destination: BusName, path: &str,
+                          interface: &str) -> DBusResult<HashMap<String, Value>> {
+    let call = properties_call(destination, path, "GetAll",
+                               vec![Str(String::from_str(interface))]);
+    let reply = try!(conn.call(call));
+    match reply.body().head().and_then(string_map) {
+        Some(properties) => Ok(properties),
+        None => Err(unexpected_reply("GetAll"))
+    }
+}
+
+pub fn set_property(conn: &mut DBusConnection, destination: BusName, path: &str,
+                    interface: &str, name: &str, value: Value) -> DBusResult<()> {
+    let call = properties_call(destination, path, "Set", vec![Str(String::from_str(interface)),
+                                                               Str(String::from_str(name)),
+                                                               Variant(box value)]);
+    try!(conn.call(call));
+    Ok(())
+}
+
+// Decodes an a{sv}, or any a{s?}, unwrapping variants.  None if
+// `value` isn't one.
+pub fn string_map(value: &Value) -> Option<HashMap<String, Value>> {
+    let entries = match *value {
+        Array(_, ref entries) => entries,
+        _ => return None
+    };
+    let mut out = HashMap::with_capacity(entries.len());
+    for entry in entries.iter() {
+        match *entry {
+            DictEntry(ref key, ref value) => match key.as_str() {
+                Some(key) => { out.insert(String::from_str(key), value.inner().clone()); },
+                None => return None
+            },
+            _ => return None
+        }
+    }
+    Some(out)
+}
+
+fn unexpected_reply(member: &str) -> DBusError {
+    DBusError::standard(StandardError::InconsistentMessage,
+                        format!("unexpected reply to {}", member).as_slice())
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct PropertiesChanged {
+    pub interface: String,
+    // New values, without their variant wrappers
+    pub changed: HashMap<String, Value>,
+    // Changed, but the new values weren't sent; Get them if needed
+    pub invalidated: Vec<String>
+}
+
+impl PropertiesChanged {
+    // None unless `msg` is a well-formed PropertiesChanged signal.
+    pub fn from_message(msg: &DBusMessage) -> Option<PropertiesChanged> {
+        if msg.msg_type() != MessageType::Signal
+            || !msg.has_interface(PROPERTIES_INTERFACE)
+            || !msg.has_member("PropertiesChanged") {
+            return None;
+        }
+        let body = msg.body();
+        if body.len() != 3 {
+            return None;
+        }
+        let interface = match body[0].as_str() {
+            Some(interface) => String::from_str(interface),
+            None => return None
+        };
+        let changed = match string_map(&body[1]) {
+            Some(changed) => changed,
+            None => return None
+        };
+        let invalidated = match body[2] {
+            Array(_, ref names) => {
+                let mut out = Vec::with_capacity(names.len());
+                for name in names.iter() {
+                    match name.as_str() {
+                        Some(name) => out.push(String::from_str(name)),
+                        None => return None
+                    }
+                }
+                out
+            },
+            _ => return None
+        };
+        Some(PropertiesChanged {
+            interface: interface,
+            changed: changed,
+            invalidated: invalidated
+        })
+    }
+}
+
+// A match rule for PropertiesChanged signals from `sender` at `path`.
+pub fn properties_changed_rule(sender: &str, path: &str) -> String {
+    format!("type='signal',sender='{}',path='{}',interface='{}',member='PropertiesChanged'",
+            sender, path, PROPERTIES_INTERFACE)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::DBusMessage;
+    use names::{InterfaceName, MemberName};
+    use value::{DBusType, Value, Array, Str, UInt32, Variant};
+
+    #[test]
+    fn test_properties_changed() {
+        let signal = DBusMessage::signal("/frob",
+                                         InterfaceName::from_static(PROPERTIES_INTERFACE),
+                                         MemberName::from_static("PropertiesChanged"))
+            .with_args(vec![
+                Str(String::from_str("com.example.Frob")),
+                Value::dict(DBusType::Str, DBusType::Variant,
+                            vec![(Str(String::from_str("Count")), Variant(box UInt32(3)))]),
+                Array(DBusType::Str, vec![Str(String::from_str("Name"))])
+            ]);
+        let changed = PropertiesChanged::from_message(&signal).unwrap();
+        assert_eq!(changed.interface.as_slice(), "com.example.Frob");
+        assert_eq!(changed.changed.find_equiv(&"Count"), Some(&UInt32(3)));
+        assert_eq!(changed.invalidated, vec![String::from_str("Name")]);
+    }
+}
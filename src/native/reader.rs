@@ -0,0 +1,196 @@
+// Splits a byte stream into messages, however the reads happen to
+// fall: a message may arrive over many reads, or many in one.
+
+use message::{DBusMessage, FIXED_HEADER_LEN, MAX_MESSAGE_LEN, message_length};
+use message_ref::MessageRef;
+use marshal::{ParseError, MessageTooLong};
+
+
+pub struct MessageReader {
+    buf: Vec<u8>,
+    // Where the first unparsed byte is
+    start: uint,
+    // The message at `start` lent out by next_message_ref(), which
+    // stays in the buffer until the next call
+    lent: uint,
+    max_message_len: uint
+}
+
+impl MessageReader {
+    pub fn new() -> MessageReader {
+        MessageReader {
+            buf: Vec::new(),
+            start: 0,
+            lent: 0,
+            max_message_len: MAX_MESSAGE_LEN
+        }
+    }
+
+    // Longer messages are refused as soon as their header arrives,
+    // before any of the body is buffered.
+    pub fn set_max_message_len(&mut self, len: uint) {
+        self.max_message_len = len;
+    }
+
+    #[inline]
+    pub fn max_message_len(&self) -> uint {
+        self.max_message_len
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.release();
+        self.buf.push_all(data);
+    }
+
+    // Bytes received but not yet returned as part of a message.
+    #[inline]
+    pub fn buffered(&self) -> uint {
+        self.buf.len() - self.start - self.lent
+    }
+
+    // The next complete message, or None if more bytes are needed.  A
+    // parse error leaves the stream unusable, since we can no longer
+    // tell where the next message starts.
+    pub fn next_message(&mut self) -> Option<Result<DBusMessage, ParseError>> {
+        self.release();
+        let total_len = match self.complete_len() {
+            Some(Ok(total_len)) => total_len,
+            Some(Err(err)) => return Some(Err(err)),
+            None => return None
+        };
+        let result = DBusMessage::demarshal(self.buf.slice(self.start, self.start + total_len));
+        self.start += total_len;
+        self.compact();
+        Some(result)
+    }
+
+    // Like next_message(), but lends the message where it lies in the
+    // buffer rather than demarshalling it.  Its bytes are kept until
+    // the next feed() or next_message*().
+    pub fn next_message_ref<'a>(&'a mut self) -> Option<Result<MessageRef<'a>, ParseError>> {
+        self.release();
+        let total_len = match self.complete_len() {
+            Some(Ok(total_len)) => total_len,
+            Some(Err(err)) => return Some(Err(err)),
+            None => return None
+        };
+        self.lent = total_len;
+        Some(MessageRef::parse(self.buf.slice(self.start, self.start + total_len)))
+    }
+
+    // The length of the message at `start`, if it has all arrived.
+    fn complete_len(&self) -> Option<Result<uint, ParseError>> {
+        let pending = self.buf.slice_from(self.start);
+        if pending.len() < FIXED_HEADER_LEN {
+            return None;
+        }
+        let total_len = match message_length(pending) {
+            Ok(total_len) => total_len,
+            Err(err) => return Some(Err(err))
+        };
+        if total_len > self.max_message_len {
+            return Some(Err(MessageTooLong(total_len)));
+        }
+        if pending.len() < total_len {
+            return None;
+        }
+        Some(Ok(total_len))
+    }
+
+    // Consumes the message next_message_ref() last lent out.
+    fn release(&mut self) {
+        if self.lent > 0 {
+            self.start += self.lent;
+            self.lent = 0;
+            self.compact();
+        }
+    }
+
+    // Drops consumed bytes once they make up most of the buffer, so
+    // it doesn't grow without bound but isn't shuffled on every read.
+    fn compact(&mut self) {
+        if self.start == self.buf.len() {
+            self.buf.clear();
+            self.start = 0;
+        } else if self.start > self.buf.len() / 2 {
+            self.buf = self.buf.slice_from(self.start).to_vec();
+            self.start = 0;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::DBusMessage;
+    use marshal::MessageTooLong;
+    use names::{InterfaceName, MemberName};
+    use value::Int32;
+
+    fn signal(serial: u32) -> DBusMessage {
+        let mut msg = DBusMessage::signal("/a", InterfaceName::from_static("a.b"),
+                                          MemberName::from_static("C"))
+            .with_args(vec![Int32(serial as i32)]);
+        msg.set_serial(serial);
+        msg
+    }
+
+    #[test]
+    fn test_split_reads() {
+        let buf = signal(1).marshal();
+        let mut reader = MessageReader::new();
+        for b in buf.iter() {
+            assert!(reader.next_message().is_none());
+            reader.feed([*b].as_slice());
+        }
+        assert_eq!(reader.next_message(), Some(Ok(signal(1))));
+        assert_eq!(reader.buffered(), 0);
+    }
+
+    #[test]
+    fn test_several_per_read() {
+        let mut buf = signal(1).marshal();
+        buf.push_all_move(signal(2).marshal());
+        let third = signal(3).marshal();
+        buf.push_all(third.slice_to(5));
+
+        let mut reader = MessageReader::new();
+        reader.feed(buf.as_slice());
+        assert_eq!(reader.next_message(), Some(Ok(signal(1))));
+        assert_eq!(reader.next_message(), Some(Ok(signal(2))));
+        assert!(reader.next_message().is_none());
+        reader.feed(third.slice_from(5));
+        assert_eq!(reader.next_message(), Some(Ok(signal(3))));
+    }
+
+    #[test]
+    fn test_next_message_ref() {
+        let mut buf = signal(1).marshal();
+        buf.push_all_move(signal(2).marshal());
+        let mut reader = MessageReader::new();
+        reader.feed(buf.as_slice());
+        match reader.next_message_ref() {
+            Some(Ok(msg)) => {
+                assert_eq!(msg.serial(), 1);
+                assert_eq!(msg.member(), Some("C"));
+            },
+            _ => fail!("no message lent")
+        }
+        // the lent message isn't counted, and is gone on the next call
+        assert_eq!(reader.buffered(), signal(2).marshal().len());
+        assert_eq!(reader.next_message(), Some(Ok(signal(2))));
+        assert!(reader.next_message_ref().is_none());
+        assert_eq!(reader.buffered(), 0);
+    }
+
+    #[test]
+    fn test_max_message_len() {
+        let buf = signal(1).marshal();
+        let mut reader = MessageReader::new();
+        reader.set_max_message_len(buf.len() - 1);
+        // the header alone is enough to refuse it
+        reader.feed(buf.slice_to(16));
+        assert_eq!(reader.next_message(), Some(Err(MessageTooLong(buf.len()))));
+    }
+}
@@ -0,0 +1,193 @@
+// Capturing a connection's traffic to a file and reading it back, so a
+// problem seen in production can be reproduced in a test.
+//
+//     let file = try!(File::create(&Path::new("capture.dbus")));
+//     conn.set_tracer(try!(Recorder::new(file)));
+//
+// Each record is the direction (0 sent, 1 received), the nanoseconds
+// since recording started and the message's length, all little-endian
+// (u8, u64, u32), followed by the message as to_bytes() wrote it.
+
+use std::io::{EndOfFile, IoError, IoResult};
+use time::precise_time_ns;
+
+use message::{DBusMessage, MAX_MESSAGE_LEN};
+use super::super::{DBusResult, DBusError, StandardError};
+use super::trace::{Direction, Tracer};
+
+
+const MAGIC: &'static [u8] = b"DBUSREC1";
+
+
+pub struct Recorder<W> {
+    writer: W,
+    start_ns: u64,
+    // The first write that failed, after which we stop writing
+    error: Option<IoError>
+}
+
+impl<W: Writer> Recorder<W> {
+    pub fn new(mut writer: W) -> IoResult<Recorder<W>> {
+        try!(writer.write(MAGIC));
+        Ok(Recorder {
+            writer: writer,
+            start_ns: precise_time_ns(),
+            error: None
+        })
+    }
+
+    pub fn record(&mut self, direction: Direction, msg: &DBusMessage) -> IoResult<()> {
+        let bytes = msg.to_bytes();
+        try!(self.writer.write_u8(match direction {
+            Direction::Sent => 0,
+            Direction::Received => 1
+        }));
+        try!(self.writer.write_le_u64(precise_time_ns() - self.start_ns));
+        try!(self.writer.write_le_u32(bytes.len() as u32));
+        self.writer.write(bytes.as_slice())
+    }
+
+    // Why recording stopped, if it did.  Tracers can't fail, so this is
+    // the only place write errors show up.
+    pub fn error(&self) -> Option<&IoError> {
+        self.error.as_ref()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Writer> Tracer for Recorder<W> {
+    fn trace(&mut self, direction: Direction, msg: &DBusMessage) {
+        if self.error.is_some() {
+            return;
+        }
+        match self.record(direction, msg) {
+            Ok(()) => (),
+            Err(err) => self.error = Some(err)
+        }
+    }
+}
+
+
+pub struct RecordedMessage {
+    pub direction: Direction,
+    // Since recording started
+    pub time_ns: u64,
+    pub msg: DBusMessage
+}
+
+// Reads back what a Recorder wrote.  Iterating stops at the end of the
+// recording or the first bad record.
+pub struct Replay<R> {
+    reader: R
+}
+
+impl<R: Reader> Replay<R> {
+    pub fn new(mut reader: R) -> DBusResult<Replay<R>> {
+        let magic = try!(reader.read_exact(MAGIC.len()).map_err(DBusError::from_io_error));
+        if magic.as_slice() != MAGIC {
+            return Err(bad_recording("not a message recording"));
+        }
+        Ok(Replay {
+            reader: reader
+        })
+    }
+
+    pub fn next_record(&mut self) -> DBusResult<Option<RecordedMessage>> {
+        let direction = match self.reader.read_u8() {
+            Ok(0) => Direction::Sent,
+            Ok(1) => Direction::Received,
+            Ok(_) => return Err(bad_recording("unknown direction")),
+            Err(ref err) if err.kind == EndOfFile => return Ok(None),
+            Err(err) => return Err(DBusError::from_io_error(err))
+        };
+        let time_ns = try!(self.reader.read_le_u64().map_err(DBusError::from_io_error));
+        let len = try!(self.reader.read_le_u32().map_err(DBusError::from_io_error));
+        // check before allocating, or a corrupt length could ask for 4GiB
+        if len as uint > MAX_MESSAGE_LEN {
+            return Err(bad_recording("record longer than the longest message"));
+        }
+        let bytes = try!(self.reader.read_exact(len as uint).map_err(DBusError::from_io_error));
+        let msg = try!(DBusMessage::from_bytes(bytes.as_slice()).map_err(|e| e.to_dbus_error()));
+        Ok(Some(RecordedMessage {
+            direction: direction,
+            time_ns: time_ns,
+            msg: msg
+        }))
+    }
+
+    // Hands each received message to `handle`, in order, as if it had
+    // just arrived.  Messages we sent are skipped.
+    pub fn replay_received(&mut self, handle: |DBusMessage| -> DBusResult<()>) -> DBusResult<()> {
+        loop {
+            match try!(self.next_record()) {
+                Some(RecordedMessage { direction: Direction::Received, msg, .. }) => {
+                    try!(handle(msg));
+                },
+                Some(_) => (),
+                None => return Ok(())
+            }
+        }
+    }
+}
+
+impl<R: Reader> Iterator<RecordedMessage> for Replay<R> {
+    fn next(&mut self) -> Option<RecordedMessage> {
+        self.next_record().unwrap_or(None)
+    }
+}
+
+fn bad_recording(why: &str) -> DBusError {
+    DBusError::standard(StandardError::InvalidArgs,
+                        format!("bad message recording: {}", why).as_slice())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{MemReader, MemWriter};
+
+    use super::*;
+    use message::DBusMessage;
+    use names::{InterfaceName, MemberName};
+    use native::trace::{Direction, Tracer};
+    use value::Str;
+
+    #[test]
+    fn test_record_replay() {
+        let mut recorder = Recorder::new(MemWriter::new()).unwrap();
+        let mut signal = DBusMessage::signal("/", InterfaceName::from_static("com.example.Frob"),
+                                             MemberName::from_static("Frobbed"))
+            .with_args(vec![Str(String::from_str("hello"))]);
+        signal.set_serial(7);
+        recorder.trace(Direction::Sent, &signal);
+        recorder.trace(Direction::Received, &signal);
+        assert!(recorder.error().is_none());
+        let bytes = recorder.into_inner().unwrap();
+
+        let records: Vec<RecordedMessage> =
+            Replay::new(MemReader::new(bytes.clone())).unwrap().collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Sent);
+        assert_eq!(records[1].direction, Direction::Received);
+        assert!(records[0].time_ns <= records[1].time_ns);
+        assert_eq!(records[1].msg.serial(), 7);
+        assert_eq!(records[1].msg.member(), Some("Frobbed"));
+
+        let mut received = Vec::new();
+        Replay::new(MemReader::new(bytes)).unwrap()
+            .replay_received(|msg| { received.push(msg); Ok(()) }).unwrap();
+        assert_eq!(received.len(), 1);
+
+        assert!(Replay::new(MemReader::new(b"not a recording".to_vec())).is_err());
+
+        // a direction, a time, then a length of 4GiB - 1
+        let mut corrupt = b"DBUSREC1\x01".to_vec();
+        corrupt.push_all([0u8, ..8]);
+        corrupt.push_all([0xffu8, 0xff, 0xff, 0xff]);
+        let mut replay = Replay::new(MemReader::new(corrupt)).unwrap();
+        assert!(replay.next_record().is_err());
+    }
+}
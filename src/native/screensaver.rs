@@ -0,0 +1,113 @@
+// Keeping the screen saver off through org.freedesktop.ScreenSaver on
+// the session bus, e.g. while playing a video:
+//
+//     let guard = try!(inhibit(&mut conn, "frobplayer", "Playing a video"));
+//     play();
+//     drop(guard);
+//
+// The screen saver also drops an inhibition when its owner leaves the
+// bus, so one can't outlive the application.
+
+use message::{DBusMessage, NO_REPLY_EXPECTED};
+use names::{BusName, InterfaceName, MemberName};
+use value::{Str, UInt32};
+use super::super::{DBusResult, DBusError, StandardError};
+use super::connection::DBusConnection;
+use super::sender::MessageSender;
+
+
+pub const SCREENSAVER_NAME: &'static str = "org.freedesktop.ScreenSaver";
+pub const SCREENSAVER_PATH: &'static str = "/org/freedesktop/ScreenSaver";
+pub const SCREENSAVER_INTERFACE: &'static str = "org.freedesktop.ScreenSaver";
+
+
+fn screensaver_call(member: &'static str) -> DBusMessage {
+    DBusMessage::method_call(BusName::from_static(SCREENSAVER_NAME), SCREENSAVER_PATH,
+                             InterfaceName::from_static(SCREENSAVER_INTERFACE),
+                             MemberName::from_static(member))
+}
+
+fn uninhibit_call(cookie: u32) -> DBusMessage {
+    screensaver_call("UnInhibit").with_args(vec![UInt32(cookie)])
+}
+
+
+// An inhibition, lifted when dropped.
+pub struct InhibitGuard {
+    sender: MessageSender,
+    cookie: u32,
+    released: bool
+}
+
+impl InhibitGuard {
+    pub fn cookie(&self) -> u32 {
+        self.cookie
+    }
+
+    // Lifts the inhibition now and waits for the screen saver to
+    // agree, rather than sending UnInhibit unchecked on drop.
+    pub fn release(mut self, conn: &mut DBusConnection) -> DBusResult<()> {
+        self.released = true;
+        try!(conn.call(uninhibit_call(self.cookie)));
+        Ok(())
+    }
+}
+
+impl Drop for InhibitGuard {
+    fn drop(&mut self) {
+        if !self.released {
+            let mut call = uninhibit_call(self.cookie);
+            call.set_flag(NO_REPLY_EXPECTED, true);
+            // nothing to be done about a failure in a destructor
+            let _ = self.sender.send(call);
+        }
+    }
+}
+
+
+// Inhibits the screen saver until the guard is dropped.
+// `application` and `reason` may be shown to the user.
+pub fn inhibit(conn: &mut DBusConnection, application: &str,
+               reason: &str) -> DBusResult<InhibitGuard> {
+    let call = screensaver_call("Inhibit").with_args(vec![Str(String::from_str(application)),
+                                                          Str(String::from_str(reason))]);
+    let reply = try!(conn.call(call));
+    match reply.body().head().and_then(|cookie| cookie.as_u32()) {
+        Some(cookie) => Ok(InhibitGuard {
+            sender: conn.sender(),
+            cookie: cookie,
+            released: false
+        }),
+        None => Err(DBusError::standard(StandardError::InconsistentMessage,
+                                        "unexpected reply to Inhibit"))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::screensaver_call;
+    use message::DBusMessage;
+    use native::mock::MockConnection;
+    use value::UInt32;
+
+    #[test]
+    fn test_guard_uninhibits_on_drop() {
+        let mut mock = MockConnection::new();
+        // the reply to the connection's first call
+        let mut call = screensaver_call("Inhibit");
+        call.set_serial(1);
+        mock.inject(DBusMessage::method_return(&call).with_args(vec![UInt32(7)]));
+
+        let guard = inhibit(mock.conn(), "frobplayer", "Playing a video").unwrap();
+        assert_eq!(guard.cookie(), 7);
+        assert_eq!(mock.expect_sent().member(), Some("Inhibit"));
+
+        drop(guard);
+        let uninhibit = mock.expect_sent();
+        assert_eq!(uninhibit.member(), Some("UnInhibit"));
+        assert_eq!(uninhibit.body()[0], UInt32(7));
+        assert!(uninhibit.no_reply_expected());
+    }
+}
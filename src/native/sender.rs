@@ -0,0 +1,280 @@
+// Sending from other tasks.  Each MessageSender marshals on its own
+// task.  Over a socket it then writes the bytes straight out, taking
+// only the lock on the stream's write side, so it isn't held up by the
+// connection blocking in a read.  Streams with no separate write side
+// get a queue instead, which the connection writes out the next time
+// it sends or reads.
+
+use std::collections::HashMap;
+use std::io::IoResult;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUint, SeqCst};
+use std::cmp;
+use std::mem;
+
+use message::DBusMessage;
+use super::super::{DBusResult, DBusError, StandardError};
+
+
+pub struct SendQueue {
+    // Serials handed out so far, shared with the connection
+    serials: AtomicUint,
+    // The stream's write side, if senders can write to it directly.
+    // The connection writes through it too, so messages never interleave.
+    writer: Option<Mutex<Box<Writer + Send>>>,
+    pending: Mutex<Vec<u8>>,
+    // Messages in `pending`, for the connection's stats
+    pending_messages: AtomicUint,
+    // Written by senders through `writer`, for the connection's stats
+    written_messages: AtomicUint,
+    written_bytes: AtomicUint,
+    // Set once the connection becomes a monitor
+    receive_only: AtomicBool,
+    // Where to hand replies to MessageSender::call(), by serial
+    routes: Mutex<HashMap<u32, Sender<DBusMessage>>>
+}
+
+impl SendQueue {
+    pub fn new() -> SendQueue {
+        SendQueue::from_writer(None)
+    }
+
+    pub fn with_writer(writer: Box<Writer + Send>) -> SendQueue {
+        SendQueue::from_writer(Some(Mutex::new(writer)))
+    }
+
+    fn from_writer(writer: Option<Mutex<Box<Writer + Send>>>) -> SendQueue {
+        SendQueue {
+            serials: AtomicUint::new(0),
+            writer: writer,
+            pending: Mutex::new(Vec::new()),
+            pending_messages: AtomicUint::new(0),
+            written_messages: AtomicUint::new(0),
+            written_bytes: AtomicUint::new(0),
+            receive_only: AtomicBool::new(false),
+            routes: Mutex::new(HashMap::new())
+        }
+    }
+
+    // The next serial, skipping zero, which is not a valid serial.
+    pub fn next_serial(&self) -> u32 {
+        let n = self.serials.fetch_add(1, SeqCst) as u64;
+        (n % 0xFFFFFFFF + 1) as u32
+    }
+
+    // Makes `first` the next serial handed out, so tests comparing
+    // bytes or recordings see the same serials every run.
+    pub fn reset_serials(&self, first: u32) {
+        self.serials.store(cmp::max(first, 1) as uint - 1, SeqCst);
+    }
+
+    #[inline]
+    pub fn has_writer(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    // Writes and flushes `bytes` under the writer's lock.  Only for
+    // queues with a writer.
+    pub fn write(&self, bytes: &[u8]) -> IoResult<()> {
+        let mut writer = self.writer.as_ref().unwrap().lock();
+        writer.write(bytes).and_then(|()| writer.flush())
+    }
+
+    pub fn push(&self, bytes: &[u8]) {
+        let mut pending = self.pending.lock();
+        pending.push_all(bytes);
+        self.pending_messages.fetch_add(1, SeqCst);
+    }
+
+    // Takes everything queued so far, and how many messages it holds,
+    // swapping in `spare` to queue into next so the lock is held only
+    // for the swap.
+    pub fn take(&self, mut spare: Vec<u8>) -> (Vec<u8>, uint) {
+        spare.clear();
+        let mut pending = self.pending.lock();
+        let messages = self.pending_messages.swap(0, SeqCst);
+        (mem::replace(&mut *pending, spare), messages)
+    }
+
+    // Messages and bytes senders have written directly.
+    pub fn written(&self) -> (uint, uint) {
+        (self.written_messages.load(SeqCst), self.written_bytes.load(SeqCst))
+    }
+
+    pub fn set_receive_only(&self) {
+        self.receive_only.store(true, SeqCst);
+    }
+
+    // Whether any MessageSender::call() is waiting for its reply.
+    pub fn has_routes(&self) -> bool {
+        !self.routes.lock().is_empty()
+    }
+
+    // Where `msg` should go instead of the connection's queue, if it's
+    // a reply to a MessageSender::call().
+    pub fn take_route(&self, msg: &DBusMessage) -> Option<Sender<DBusMessage>> {
+        let serial = match msg.reply_serial() {
+            Some(serial) => serial,
+            None => return None
+        };
+        let mut routes = self.routes.lock();
+        if routes.is_empty() {
+            return None;
+        }
+        routes.pop(&serial)
+    }
+}
+
+
+#[deriving(Clone)]
+pub struct MessageSender {
+    queue: Arc<SendQueue>
+}
+
+impl MessageSender {
+    pub fn new(queue: Arc<SendQueue>) -> MessageSender {
+        MessageSender {
+            queue: queue
+        }
+    }
+
+    // Sends the message, returning its serial.  Over a socket it's
+    // written before this returns; otherwise it goes out the next time
+    // the connection sends or reads.
+    pub fn send(&self, mut msg: DBusMessage) -> DBusResult<u32> {
+        let serial = self.queue.next_serial();
+        msg.set_serial(serial);
+        try!(self.write(&msg));
+        Ok(serial)
+    }
+
+    // Sends a method call whose reply comes back on the returned
+    // receiver rather than being queued on the connection.  The reply
+    // only arrives once the connection reads it, so something must be
+    // reading: the task that owns it, blocked in read_message() say.
+    pub fn call(&self, mut msg: DBusMessage)
+                -> DBusResult<(Receiver<DBusMessage>, PendingReply)> {
+        let serial = self.queue.next_serial();
+        msg.set_serial(serial);
+        let (tx, rx) = channel();
+        // before it's sent, so the reply can't beat us to it
+        self.queue.routes.lock().insert(serial, tx);
+        let pending = PendingReply {
+            serial: serial,
+            queue: self.queue.clone()
+        };
+        try!(self.write(&msg));
+        Ok((rx, pending))
+    }
+
+    fn write(&self, msg: &DBusMessage) -> DBusResult<()> {
+        if self.queue.receive_only.load(SeqCst) {
+            return Err(DBusError::standard(StandardError::NotSupported,
+                                           "monitor connections can't send messages"));
+        }
+        // marshal before taking the lock
+        let bytes = msg.marshal();
+        if self.queue.has_writer() {
+            try!(self.queue.write(bytes.as_slice()).map_err(DBusError::from_io_error));
+            self.queue.written_messages.fetch_add(1, SeqCst);
+            self.queue.written_bytes.fetch_add(bytes.len(), SeqCst);
+        } else {
+            self.queue.push(bytes.as_slice());
+        }
+        Ok(())
+    }
+}
+
+
+// Keeps a MessageSender::call() reply routed to its receiver.  Once
+// it's dropped, a reply that comes later is thrown away.
+pub struct PendingReply {
+    serial: u32,
+    queue: Arc<SendQueue>
+}
+
+impl PendingReply {
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        self.queue.routes.lock().remove(&self.serial);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use message::DBusMessage;
+    use names::{InterfaceName, MemberName};
+    use native::mock::memory_pipe;
+    use native::reader::MessageReader;
+
+    #[test]
+    fn test_concurrent_send() {
+        let queue = Arc::new(SendQueue::new());
+        let (tx, rx) = channel();
+        for i in range(0u, 4) {
+            let sender = MessageSender::new(queue.clone());
+            let tx = tx.clone();
+            spawn(proc() {
+                for _ in range(0u, 25) {
+                    sender.send(DBusMessage::signal("/a", InterfaceName::from_static("a.b"),
+                                                    MemberName::from_static("C"))).unwrap();
+                }
+                tx.send(i);
+            });
+        }
+        for _ in range(0u, 4) {
+            rx.recv();
+        }
+
+        let mut reader = MessageReader::new();
+        let (queued, count) = queue.take(Vec::new());
+        assert_eq!(count, 100);
+        reader.feed(queued.as_slice());
+        let mut serials = Vec::new();
+        loop {
+            match reader.next_message() {
+                Some(msg) => serials.push(msg.unwrap().serial()),
+                None => break
+            }
+        }
+        serials.sort();
+        assert_eq!(serials, range(1u32, 101).collect::<Vec<u32>>());
+
+        queue.reset_serials(1);
+        assert_eq!(queue.next_serial(), 1);
+        queue.reset_serials(0);
+        assert_eq!(queue.next_serial(), 1);
+    }
+
+    #[test]
+    fn test_direct_send() {
+        let (mut ours, theirs) = memory_pipe(true);
+        let queue = Arc::new(SendQueue::with_writer(box theirs.writer() as Box<Writer + Send>));
+        let sender = MessageSender::new(queue.clone());
+        let serial = sender.send(DBusMessage::signal("/a", InterfaceName::from_static("a.b"),
+                                                     MemberName::from_static("C"))).unwrap();
+        // nothing left for the connection to write
+        let (_, queued) = queue.take(Vec::new());
+        assert_eq!(queued, 0);
+        let (written, _) = queue.written();
+        assert_eq!(written, 1);
+
+        let mut buf = [0u8, ..4096];
+        let len = ours.read(buf).unwrap();
+        let mut reader = MessageReader::new();
+        reader.feed(buf.slice_to(len));
+        assert_eq!(reader.next_message().unwrap().unwrap().serial(), serial);
+
+        queue.set_receive_only();
+        assert!(sender.send(DBusMessage::signal("/a", InterfaceName::from_static("a.b"),
+                                                MemberName::from_static("C"))).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+// Accepting peer-to-peer connections, for services that expose a
+// private socket rather than going through a bus daemon.
+
+use std::io::pipe::PipeStream;
+use std::time::Duration;
+
+use libc;
+
+use address::{BusAddress, UnixPath};
+use super::super::{DBusResult, DBusError};
+use super::auth::{mod, ServerAuthConfig};
+use super::connection::DBusConnection;
+use super::policy::SecurityPolicy;
+use super::transport;
+
+
+pub struct DBusServer {
+    listener: libc::c_int,
+    address: String,
+    guid: String,
+    auth_config: ServerAuthConfig,
+    policy: Option<SecurityPolicy>
+}
+
+impl DBusServer {
+    pub fn listen(address: &str) -> DBusResult<DBusServer> {
+        DBusServer::listen_on(&try!(BusAddress::parse(address)))
+    }
+
+    pub fn listen_on(address: &BusAddress) -> DBusResult<DBusServer> {
+        let listener = match address.transport {
+            UnixPath(ref path) => try!(transport::listen_unix(path)),
+            _ => return Err(transport::unsupported(address))
+        };
+        Ok(DBusServer {
+            listener: listener,
+            address: address.to_address(),
+            guid: auth::generate_guid(),
+            auth_config: ServerAuthConfig::new(),
+            policy: None
+        })
+    }
+
+    // The address clients should connect to, including our guid.
+    pub fn address(&self) -> String {
+        format!("{},guid={}", self.address, self.guid)
+    }
+
+    pub fn guid(&self) -> &str {
+        self.guid.as_slice()
+    }
+
+    // Accept clients using ANONYMOUS, which identifies nobody.
+    pub fn set_allow_anonymous(&mut self, allow: bool) {
+        self.auth_config.allow_anonymous = allow;
+    }
+
+    // Accept only clients the kernel vouches for with EXTERNAL, even if
+    // anonymous ones are allowed.
+    pub fn set_require_external(&mut self, require: bool) {
+        self.auth_config.require_external = require;
+    }
+
+    // Accept only clients running as our own uid, as a control socket
+    // for a per-user service usually should.
+    pub fn set_require_same_uid(&mut self, require: bool) {
+        self.auth_config.require_same_uid = require;
+    }
+
+    // How long clients get to authenticate before we hang up, 30
+    // seconds by default.  None waits forever.
+    pub fn set_auth_timeout(&mut self, timeout: Option<Duration>) {
+        self.auth_config.timeout = timeout;
+    }
+
+    // How many rejected attempts clients get before we hang up.
+    pub fn set_max_auth_failures(&mut self, n: uint) {
+        self.auth_config.max_failures = n;
+    }
+
+    // The security policy every accepted connection starts out with.
+    pub fn set_security_policy(&mut self, policy: Option<SecurityPolicy>) {
+        self.policy = policy;
+    }
+
+    // Blocks until a client connects and authenticates.
+    pub fn accept(&mut self) -> DBusResult<DBusConnection> {
+        let fd = try!(transport::accept_unix(self.listener));
+        let peer_uid = transport::peer_uid(fd);
+        debug!("accepted a client on {} (uid {})", self.address, peer_uid);
+        let mut stream = box try!(PipeStream::open(fd).map_err(DBusError::from_io_error));
+        // so a client that stops talking can't hold us here
+        try!(transport::set_read_timeout(fd, self.auth_config.timeout));
+        try!(auth::serve(&mut *stream, &self.auth_config, self.guid.as_slice(), peer_uid));
+        try!(transport::set_read_timeout(fd, None));
+        let writer = box (*stream).clone();
+        let mut conn = DBusConnection::from_split_stream(stream as Box<Stream + Send>,
+                                                         writer as Box<Writer + Send>,
+                                                         self.guid.as_slice());
+        conn.set_security_policy(self.policy.clone());
+        Ok(conn)
+    }
+}
+
+impl Drop for DBusServer {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.listener); }
+    }
+}
@@ -0,0 +1,358 @@
+// Exporting methods: an ObjectServer routes incoming method calls to
+// the handlers registered for their path, interface and member, and
+// sends back whatever they return.
+
+use std::collections::HashMap;
+
+use machine_id::read_machine_id;
+use message::{DBusMessage, MessageType};
+use names::{BusName, InterfaceName, MemberName, DBUS_INTERFACE};
+use value::{Value, Str};
+use super::super::{DBusResult, DBusError, StandardError};
+use super::access::AccessPolicy;
+use super::connection::DBusConnection;
+
+
+pub const PEER_INTERFACE: &'static str = "org.freedesktop.DBus.Peer";
+
+
+pub trait MethodHandler {
+    // The reply's arguments, or the error to reply with.
+    fn handle(&mut self, conn: &mut DBusConnection, call: &DBusMessage) -> DBusResult<Vec<Value>>;
+}
+
+impl MethodHandler for fn(&mut DBusConnection, &DBusMessage) -> DBusResult<Vec<Value>> {
+    fn handle(&mut self, conn: &mut DBusConnection, call: &DBusMessage) -> DBusResult<Vec<Value>> {
+        (*self)(conn, call)
+    }
+}
+
+
+// How a method call dispatched by an ObjectServer turned out.
+#[deriving(Clone, PartialEq, Show)]
+pub enum AuditOutcome {
+    Succeeded,
+    // The handler failed with this error name
+    Failed(String),
+    // Nothing is exported under that name
+    NotFound
+}
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct AuditRecord {
+    pub sender: Option<String>,
+    // The sender's uid, when the bus would tell us
+    pub uid: Option<u32>,
+    pub path: String,
+    pub interface: Option<String>,
+    pub member: String,
+    pub outcome: AuditOutcome
+}
+
+// Sees every method call an ObjectServer dispatches, after it's handled.
+pub trait AuditHook {
+    fn record(&mut self, record: &AuditRecord);
+}
+
+impl AuditHook for fn(&AuditRecord) {
+    fn record(&mut self, record: &AuditRecord) {
+        (*self)(record)
+    }
+}
+
+
+// One exported method.
+struct Export {
+    path: String,
+    interface: String,
+    member: String,
+    handler: Box<MethodHandler + Send>
+}
+
+pub struct ObjectServer {
+    exports: Vec<Export>,
+    // (path, interface, member) to index in `exports`
+    index: HashMap<(String, String, String), uint>,
+    // Access rules for whole interfaces, by (path, interface), and for
+    // single methods, which take precedence
+    interface_policies: HashMap<(String, String), AccessPolicy>,
+    method_policies: HashMap<(String, String, String), AccessPolicy>,
+    audit: Option<Box<AuditHook + Send>>,
+    // Senders' uids, looked up once each for auditing and access
+    // checks.  Unique names are never reused, so these can't go stale,
+    // but they're forgotten once NameOwnerChanged says the sender has
+    // left, and all at once past MAX_CACHED_UIDS.
+    uids: HashMap<String, Option<u32>>
+}
+
+// Plenty for the callers a service sees at once.  Only services with a
+// match on NameOwnerChanged hear about callers leaving, so this keeps
+// the cache from growing forever in the others.
+static MAX_CACHED_UIDS: uint = 1024;
+
+impl ObjectServer {
+    pub fn new() -> ObjectServer {
+        ObjectServer {
+            exports: Vec::new(),
+            index: HashMap::new(),
+            interface_policies: HashMap::new(),
+            method_policies: HashMap::new(),
+            audit: None,
+            uids: HashMap::new()
+        }
+    }
+
+    // Reports every method call this server dispatches to `hook`.
+    pub fn set_audit_hook<H: AuditHook + Send>(&mut self, hook: H) {
+        self.audit = Some(box hook as Box<AuditHook + Send>);
+    }
+
+    fn audit(&mut self, conn: &mut DBusConnection, call: &DBusMessage, outcome: AuditOutcome) {
+        if self.audit.is_none() {
+            return;
+        }
+        let sender = call.sender().map(|s| String::from_str(s));
+        let uid = match sender {
+            Some(ref sender) => self.sender_uid(conn, sender.as_slice()),
+            None => None
+        };
+        let record = AuditRecord {
+            sender: sender,
+            uid: uid,
+            path: String::from_str(call.path().unwrap_or("")),
+            interface: call.interface().map(|s| String::from_str(s)),
+            member: String::from_str(call.member().unwrap_or("")),
+            outcome: outcome
+        };
+        for hook in self.audit.iter_mut() {
+            hook.record(&record);
+        }
+    }
+
+    fn sender_uid(&mut self, conn: &mut DBusConnection, sender: &str) -> Option<u32> {
+        match self.uids.find_equiv(&sender) {
+            Some(uid) => return *uid,
+            None => ()
+        }
+        // peers on a direct connection have no name the bus knows
+        let uid = match BusName::new(sender) {
+            Ok(name) => conn.get_connection_unix_user(name).ok(),
+            Err(_) => None
+        };
+        if self.uids.len() >= MAX_CACHED_UIDS {
+            self.uids.clear();
+        }
+        self.uids.insert(String::from_str(sender), uid);
+        uid
+    }
+
+    // Forgets a sender's uid once NameOwnerChanged says it has left.
+    fn forget_departed(&mut self, msg: &DBusMessage) {
+        if !msg.has_interface(DBUS_INTERFACE.as_str()) || !msg.has_member("NameOwnerChanged") {
+            return;
+        }
+        let args = msg.body();
+        if args.len() != 3 {
+            return;
+        }
+        match (&args[0], &args[2]) {
+            (&Str(ref name), &Str(ref new_owner)) if new_owner.is_empty() => {
+                self.uids.remove(name);
+            },
+            _ => ()
+        }
+    }
+
+    // Replaces any handler already registered for the same method.
+    pub fn add_method<H: MethodHandler + Send>(&mut self, path: &str, interface: InterfaceName,
+                                               member: MemberName, handler: H) {
+        let export = Export {
+            path: String::from_str(path),
+            interface: String::from_str(interface.as_str()),
+            member: String::from_str(member.as_str()),
+            handler: box handler
+        };
+        let key = (export.path.clone(), export.interface.clone(), export.member.clone());
+        match self.index.find_copy(&key) {
+            Some(i) => *self.exports.get_mut(i) = export,
+            None => {
+                self.index.insert(key, self.exports.len());
+                self.exports.push(export);
+            }
+        }
+    }
+
+    // Only callers `policy` allows may call the interface's methods at
+    // `path`; everyone else gets AccessDenied before any handler runs.
+    pub fn restrict_interface(&mut self, path: &str, interface: InterfaceName,
+                              policy: AccessPolicy) {
+        let key = (String::from_str(path), String::from_str(interface.as_str()));
+        self.interface_policies.insert(key, policy);
+    }
+
+    // Like restrict_interface, for one method, overriding any policy on
+    // its interface.
+    pub fn restrict_method(&mut self, path: &str, interface: InterfaceName,
+                           member: MemberName, policy: AccessPolicy) {
+        let key = (String::from_str(path), String::from_str(interface.as_str()),
+                   String::from_str(member.as_str()));
+        self.method_policies.insert(key, policy);
+    }
+
+    fn policy_for(&self, i: uint) -> Option<&AccessPolicy> {
+        let export = &self.exports[i];
+        let key = (export.path.clone(), export.interface.clone(), export.member.clone());
+        match self.method_policies.find(&key) {
+            Some(policy) => Some(policy),
+            None => {
+                let key = (export.path.clone(), export.interface.clone());
+                self.interface_policies.find(&key)
+            }
+        }
+    }
+
+    // Fails with AccessDenied unless the export's policy, if any,
+    // allows the caller.
+    fn check_access(&mut self, conn: &mut DBusConnection, i: uint,
+                    call: &DBusMessage) -> DBusResult<()> {
+        let policy = match self.policy_for(i) {
+            Some(policy) => policy.clone(),
+            None => return Ok(())
+        };
+        let sender = match call.sender() {
+            Some(sender) => sender,
+            None => return Err(DBusError::standard(StandardError::AccessDenied,
+                                                   "caller has no bus name"))
+        };
+        let uid = self.sender_uid(conn, sender);
+        if try!(policy.check(conn, sender, uid)) {
+            Ok(())
+        } else {
+            Err(DBusError::standard(StandardError::AccessDenied,
+                                    format!("{} may not call {}", sender,
+                                            call.member().unwrap_or("")).as_slice()))
+        }
+    }
+
+    // Every exported method, as (path, interface, member), in the order
+    // they were added.
+    pub fn methods(&self) -> Vec<(&str, &str, &str)> {
+        self.exports.iter().map(|e| {
+            (e.path.as_slice(), e.interface.as_slice(), e.member.as_slice())
+        }).collect()
+    }
+
+    // The paths with at least one exported method.
+    pub fn paths(&self) -> Vec<&str> {
+        let mut paths: Vec<&str> = Vec::new();
+        for export in self.exports.iter() {
+            if !paths.contains(&export.path.as_slice()) {
+                paths.push(export.path.as_slice());
+            }
+        }
+        paths
+    }
+
+    pub fn has_audit_hook(&self) -> bool {
+        self.audit.is_some()
+    }
+
+    // Which export a call is for.  Calls may leave out the interface,
+    // in which case any interface's method of that name will do.
+    fn find(&self, call: &DBusMessage) -> Option<uint> {
+        let (path, member) = match (call.path(), call.member()) {
+            (Some(path), Some(member)) => (path, member),
+            _ => return None
+        };
+        match call.interface() {
+            Some(interface) => {
+                let key = (String::from_str(path), String::from_str(interface),
+                           String::from_str(member));
+                self.index.find_copy(&key)
+            },
+            None => self.exports.iter().position(|e| {
+                e.path.as_slice() == path && e.member.as_slice() == member
+            })
+        }
+    }
+
+    // Handles `msg` if it's a call to one of our methods, replying
+    // unless the caller asked for no reply.  Returns whether it was.
+    pub fn handle(&mut self, conn: &mut DBusConnection, msg: &DBusMessage) -> DBusResult<bool> {
+        if msg.msg_type() == MessageType::Signal {
+            self.forget_departed(msg);
+        }
+        if msg.msg_type() != MessageType::MethodCall {
+            return Ok(false);
+        }
+        let i = match self.find(msg) {
+            Some(i) => i,
+            None => return self.handle_peer(conn, msg)
+        };
+        let result = match self.check_access(conn, i, msg) {
+            Ok(()) => self.exports.get_mut(i).handler.handle(conn, msg),
+            Err(err) => Err(err)
+        };
+        let outcome = match result {
+            Ok(_) => AuditOutcome::Succeeded,
+            Err(ref err) => {
+                debug!("{}.{} failed: {}", msg.interface().unwrap_or(""),
+                       msg.member().unwrap_or(""), err);
+                AuditOutcome::Failed(String::from_str(err.name()))
+            }
+        };
+        self.audit(conn, msg, outcome);
+        if msg.no_reply_expected() {
+            return Ok(true);
+        }
+        let reply = match result {
+            Ok(args) => DBusMessage::method_return(msg).with_args(args),
+            Err(err) => DBusMessage::error_from(msg, &err)
+        };
+        try!(conn.send(reply));
+        Ok(true)
+    }
+
+    // Answers org.freedesktop.DBus.Peer, which every object is meant
+    // to implement, unless a handler was added for it.
+    fn handle_peer(&mut self, conn: &mut DBusConnection, msg: &DBusMessage) -> DBusResult<bool> {
+        if !msg.has_interface(PEER_INTERFACE) {
+            return Ok(false);
+        }
+        let result = match msg.member() {
+            Some("Ping") => Ok(Vec::new()),
+            Some("GetMachineId") => read_machine_id().map(|id| vec![Str(id)]),
+            _ => return Ok(false)
+        };
+        if msg.no_reply_expected() {
+            return Ok(true);
+        }
+        let reply = match result {
+            Ok(args) => DBusMessage::method_return(msg).with_args(args),
+            Err(err) => DBusMessage::error_from(msg, &err)
+        };
+        try!(conn.send(reply));
+        Ok(true)
+    }
+
+    // Reads the next message and handles it.  Calls to methods we
+    // don't export get UnknownMethod; anything else is returned.
+    pub fn process(&mut self, conn: &mut DBusConnection) -> DBusResult<Option<DBusMessage>> {
+        let msg = try!(conn.read_message());
+        if try!(self.handle(conn, &msg)) {
+            return Ok(None);
+        }
+        if msg.msg_type() != MessageType::MethodCall {
+            return Ok(Some(msg));
+        }
+        self.audit(conn, &msg, AuditOutcome::NotFound);
+        if !msg.no_reply_expected() {
+            let err = DBusError::standard(StandardError::UnknownMethod,
+                                          format!("No handler for {}.{}",
+                                                  msg.interface().unwrap_or(""),
+                                                  msg.member().unwrap_or("")).as_slice());
+            try!(conn.send(DBusMessage::error_from(&msg, &err)));
+        }
+        Ok(None)
+    }
+}
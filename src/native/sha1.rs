@@ -0,0 +1,80 @@
+// SHA-1, needed only for the DBUS_COOKIE_SHA1 auth mechanism.
+
+fn rotl(x: u32, n: uint) -> u32 {
+    (x << n) | (x >> (32 - n))
+}
+
+pub fn sha1(data: &[u8]) -> [u8, ..20] {
+    let mut h: [u32, ..5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let bit_len = (data.len() as u64) * 8;
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    for i in range(0u, 8).rev() {
+        msg.push((bit_len >> (8 * i)) as u8);
+    }
+
+    for chunk in msg.as_slice().chunks(64) {
+        let mut w = [0u32, ..80];
+        for i in range(0u, 16) {
+            w[i] = (chunk[4 * i] as u32 << 24) | (chunk[4 * i + 1] as u32 << 16) |
+                   (chunk[4 * i + 2] as u32 << 8) | (chunk[4 * i + 3] as u32);
+        }
+        for i in range(16u, 80) {
+            w[i] = rotl(w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16], 1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in range(0u, 80) {
+            let (f, k) = if i < 20 {
+                ((b & c) | (!b & d), 0x5A827999u32)
+            } else if i < 40 {
+                (b ^ c ^ d, 0x6ED9EBA1u32)
+            } else if i < 60 {
+                ((b & c) | (b & d) | (c & d), 0x8F1BBCDCu32)
+            } else {
+                (b ^ c ^ d, 0xCA62C1D6u32)
+            };
+            let temp = rotl(a, 5) + f + e + k + w[i];
+            e = d;
+            d = c;
+            c = rotl(b, 30);
+            b = a;
+            a = temp;
+        }
+        h[0] += a;
+        h[1] += b;
+        h[2] += c;
+        h[3] += d;
+        h[4] += e;
+    }
+
+    let mut out = [0u8, ..20];
+    for i in range(0u, 5) {
+        for j in range(0u, 4) {
+            out[4 * i + j] = (h[i] >> (24 - 8 * j)) as u8;
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::sha1;
+    use super::super::auth::hex_encode;
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(hex_encode(sha1(b"").as_slice()).as_slice(),
+                   "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex_encode(sha1(b"abc").as_slice()).as_slice(),
+                   "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hex_encode(sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")
+                              .as_slice()).as_slice(),
+                   "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+    }
+}
@@ -0,0 +1,190 @@
+// Controlling units through systemd's org.freedesktop.systemd1.Manager
+// on the system bus (or the user manager on the session bus):
+//
+//     try!(subscribe(&mut conn));
+//     let job = try!(start_unit(&mut conn, "frobd.service", JobMode::Replace));
+//     // ... then watch for JobRemoved::from_message(&msg) with job.path
+//
+// Subscribe before starting the job: one that finishes quickly can
+// send its JobRemoved before a later match rule would catch it.
+//
+// Most of these need privileges; without them systemd asks polkit,
+// and the call fails with AccessDenied if it says no.
+
+use message::{DBusMessage, MessageType};
+use names::{BusName, InterfaceName, MemberName};
+use value::{Value, Str, UInt32};
+use super::super::{DBusResult, DBusError, StandardError};
+use super::connection::DBusConnection;
+
+
+pub const SYSTEMD_NAME: &'static str = "org.freedesktop.systemd1";
+pub const SYSTEMD_PATH: &'static str = "/org/freedesktop/systemd1";
+pub const SYSTEMD_MANAGER_INTERFACE: &'static str = "org.freedesktop.systemd1.Manager";
+
+
+// How a new job treats jobs already queued for the unit.
+pub type JobMode = self::JobMode::JobMode;
+pub mod JobMode {
+    #[deriving(PartialEq, Eq, Clone, Show)]
+    pub enum JobMode {
+        Replace,
+        Fail,
+        Isolate,
+        IgnoreDependencies,
+        IgnoreRequirements
+    }
+
+    impl JobMode {
+        pub fn name(&self) -> &'static str {
+            match *self {
+                Replace => "replace",
+                Fail => "fail",
+                Isolate => "isolate",
+                IgnoreDependencies => "ignore-dependencies",
+                IgnoreRequirements => "ignore-requirements"
+            }
+        }
+    }
+}
+
+
+// A job systemd queued, by its object path.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Job {
+    pub path: String
+}
+
+fn manager_call(member: &'static str, args: Vec<Value>) -> DBusMessage {
+    DBusMessage::method_call(BusName::from_static(SYSTEMD_NAME), SYSTEMD_PATH,
+                             InterfaceName::from_static(SYSTEMD_MANAGER_INTERFACE),
+                             MemberName::from_static(member))
+        .with_args(args)
+}
+
+// Calls a manager method taking a unit name and returning an object
+// path.
+fn call_for_path(conn: &mut DBusConnection, member: &'static str,
+                 args: Vec<Value>) -> DBusResult<String> {
+    let reply = try!(conn.call(manager_call(member, args)));
+    match reply.body().head().and_then(|path| path.as_str()) {
+        Some(path) => Ok(String::from_str(path)),
+        None => Err(DBusError::standard(StandardError::InconsistentMessage,
+                                        format!("unexpected reply to {}", member).as_slice()))
+    }
+}
+
+fn unit_job(conn: &mut DBusConnection, member: &'static str, unit: &str,
+            mode: JobMode) -> DBusResult<Job> {
+    let path = try!(call_for_path(conn, member, vec![Str(String::from_str(unit)),
+                                                     Str(String::from_str(mode.name()))]));
+    Ok(Job {
+        path: path
+    })
+}
+
+pub fn start_unit(conn: &mut DBusConnection, unit: &str, mode: JobMode) -> DBusResult<Job> {
+    unit_job(conn, "StartUnit", unit, mode)
+}
+
+pub fn stop_unit(conn: &mut DBusConnection, unit: &str, mode: JobMode) -> DBusResult<Job> {
+    unit_job(conn, "StopUnit", unit, mode)
+}
+
+pub fn restart_unit(conn: &mut DBusConnection, unit: &str, mode: JobMode) -> DBusResult<Job> {
+    unit_job(conn, "RestartUnit", unit, mode)
+}
+
+pub fn reload_unit(conn: &mut DBusConnection, unit: &str, mode: JobMode) -> DBusResult<Job> {
+    unit_job(conn, "ReloadUnit", unit, mode)
+}
+
+// The object path of a loaded unit.  Fails with
+// org.freedesktop.systemd1.NoSuchUnit if it isn't loaded.
+pub fn get_unit(conn: &mut DBusConnection, unit: &str) -> DBusResult<String> {
+    call_for_path(conn, "GetUnit", vec![Str(String::from_str(unit))])
+}
+
+// Like get_unit(), loading the unit first if need be.
+pub fn load_unit(conn: &mut DBusConnection, unit: &str) -> DBusResult<String> {
+    call_for_path(conn, "LoadUnit", vec![Str(String::from_str(unit))])
+}
+
+// Asks systemd to send job and unit signals, which it otherwise
+// doesn't, and adds a match rule for JobRemoved.
+pub fn subscribe(conn: &mut DBusConnection) -> DBusResult<()> {
+    try!(conn.call(manager_call("Subscribe", Vec::new())));
+    conn.add_match(format!("type='signal',sender='{}',path='{}',interface='{}',\
+                            member='JobRemoved'",
+                           SYSTEMD_NAME, SYSTEMD_PATH, SYSTEMD_MANAGER_INTERFACE).as_slice())
+}
+
+pub fn unsubscribe(conn: &mut DBusConnection) -> DBusResult<()> {
+    try!(conn.call(manager_call("Unsubscribe", Vec::new())));
+    Ok(())
+}
+
+
+// A job finished.
+#[deriving(Clone, PartialEq, Show)]
+pub struct JobRemoved {
+    pub id: u32,
+    pub job: Job,
+    pub unit: String,
+    // "done", "canceled", "timeout", "failed", "dependency" or "skipped"
+    pub result: String
+}
+
+impl JobRemoved {
+    pub fn from_message(msg: &DBusMessage) -> Option<JobRemoved> {
+        if msg.msg_type() != MessageType::Signal
+            || !msg.has_interface(SYSTEMD_MANAGER_INTERFACE)
+            || !msg.has_member("JobRemoved") {
+            return None;
+        }
+        let body = msg.body();
+        if body.len() != 4 {
+            return None;
+        }
+        match (&body[0], body[1].as_str(), body[2].as_str(), body[3].as_str()) {
+            (&UInt32(id), Some(path), Some(unit), Some(result)) => Some(JobRemoved {
+                id: id,
+                job: Job {
+                    path: String::from_str(path)
+                },
+                unit: String::from_str(unit),
+                result: String::from_str(result)
+            }),
+            _ => None
+        }
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.result.as_slice() == "done"
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::DBusMessage;
+    use names::{InterfaceName, MemberName};
+    use value::{ObjectPath, Str, UInt32};
+
+    #[test]
+    fn test_job_removed() {
+        let signal = DBusMessage::signal(SYSTEMD_PATH,
+                                         InterfaceName::from_static(SYSTEMD_MANAGER_INTERFACE),
+                                         MemberName::from_static("JobRemoved"))
+            .with_args(vec![UInt32(42),
+                            ObjectPath(String::from_str("/org/freedesktop/systemd1/job/42")),
+                            Str(String::from_str("frobd.service")),
+                            Str(String::from_str("failed"))]);
+        let removed = JobRemoved::from_message(&signal).unwrap();
+        assert_eq!(removed.id, 42);
+        assert_eq!(removed.job.path.as_slice(), "/org/freedesktop/systemd1/job/42");
+        assert_eq!(removed.unit.as_slice(), "frobd.service");
+        assert!(!removed.succeeded());
+    }
+}
@@ -0,0 +1,115 @@
+// Reading and setting the time zone and NTP through
+// systemd-timedated:
+//
+//     let info = try!(time_date_info(&mut conn));
+//     if info.timezone.as_slice() != "Europe/Berlin" {
+//         try!(set_timezone(&mut conn, "Europe/Berlin", true));
+//     }
+//
+// As with hostname1, setting needs privileges polkit may ask for if
+// `interactive`.
+
+use std::collections::HashMap;
+
+use message::DBusMessage;
+use names::{BusName, InterfaceName, MemberName};
+use value::{Value, Boolean, Str, UInt64};
+use super::super::DBusResult;
+use super::connection::DBusConnection;
+use super::properties::get_all_properties;
+
+
+pub const TIMEDATE1_NAME: &'static str = "org.freedesktop.timedate1";
+pub const TIMEDATE1_PATH: &'static str = "/org/freedesktop/timedate1";
+pub const TIMEDATE1_INTERFACE: &'static str = "org.freedesktop.timedate1";
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct TimeDateInfo {
+    // e.g. "Europe/Berlin"
+    pub timezone: String,
+    // Whether the RTC keeps local time rather than UTC
+    pub local_rtc: bool,
+    pub can_ntp: bool,
+    pub ntp: bool,
+    pub ntp_synchronized: bool,
+    // Microseconds since the epoch, when the properties were read
+    pub time_usec: Option<u64>,
+    pub rtc_time_usec: Option<u64>
+}
+
+impl TimeDateInfo {
+    pub fn from_properties(properties: &HashMap<String, Value>) -> TimeDateInfo {
+        let flag = |key: &str| properties.find_equiv(&key).and_then(|v| v.as_bool())
+                                         .unwrap_or(false);
+        let usec = |key: &str| match properties.find_equiv(&key) {
+            Some(&UInt64(usec)) => Some(usec),
+            _ => None
+        };
+        TimeDateInfo {
+            timezone: properties.find_equiv(&"Timezone").and_then(|v| v.as_str())
+                                .map(|s| String::from_str(s)).unwrap_or(String::new()),
+            local_rtc: flag("LocalRTC"),
+            can_ntp: flag("CanNTP"),
+            ntp: flag("NTP"),
+            ntp_synchronized: flag("NTPSynchronized"),
+            time_usec: usec("TimeUSec"),
+            rtc_time_usec: usec("RTCTimeUSec")
+        }
+    }
+}
+
+pub fn time_date_info(conn: &mut DBusConnection) -> DBusResult<TimeDateInfo> {
+    let properties = try!(get_all_properties(conn, BusName::from_static(TIMEDATE1_NAME),
+                                             TIMEDATE1_PATH, TIMEDATE1_INTERFACE));
+    Ok(TimeDateInfo::from_properties(&properties))
+}
+
+fn set_call(member: &'static str, value: Value, interactive: bool) -> DBusMessage {
+    DBusMessage::method_call(BusName::from_static(TIMEDATE1_NAME), TIMEDATE1_PATH,
+                             InterfaceName::from_static(TIMEDATE1_INTERFACE),
+                             MemberName::from_static(member))
+        .with_args(vec![value, Boolean(interactive)])
+        .with_interactive_authorization(interactive)
+}
+
+// `timezone` is a tz database name, e.g. "Europe/Berlin".
+pub fn set_timezone(conn: &mut DBusConnection, timezone: &str,
+                    interactive: bool) -> DBusResult<()> {
+    try!(conn.call(set_call("SetTimezone", Str(String::from_str(timezone)), interactive)));
+    Ok(())
+}
+
+// Turns time synchronisation on or off.
+pub fn set_ntp(conn: &mut DBusConnection, enable: bool, interactive: bool) -> DBusResult<()> {
+    try!(conn.call(set_call("SetNTP", Boolean(enable), interactive)));
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use super::set_call;
+    use value::{Boolean, Str, UInt64};
+
+    #[test]
+    fn test_time_date_info() {
+        let mut properties = HashMap::new();
+        properties.insert(String::from_str("Timezone"), Str(String::from_str("Europe/Berlin")));
+        properties.insert(String::from_str("NTP"), Boolean(true));
+        properties.insert(String::from_str("TimeUSec"), UInt64(1400000000000000));
+        let info = TimeDateInfo::from_properties(&properties);
+        assert_eq!(info.timezone.as_slice(), "Europe/Berlin");
+        assert!(info.ntp);
+        assert!(!info.local_rtc);
+        assert_eq!(info.time_usec, Some(1400000000000000));
+        assert_eq!(info.rtc_time_usec, None);
+
+        let call = set_call("SetTimezone", Str(String::from_str("UTC")), false);
+        assert_eq!(call.signature().as_slice(), "sb");
+        assert!(!call.allow_interactive_authorization());
+    }
+}
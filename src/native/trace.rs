@@ -0,0 +1,28 @@
+// Watching a connection's own traffic, for logging or mirroring it
+// without touching every call site.
+
+use message::DBusMessage;
+
+
+pub type Direction = self::Direction::Direction;
+pub mod Direction {
+    #[deriving(PartialEq, Eq, Clone, Show)]
+    pub enum Direction {
+        Sent,
+        Received
+    }
+}
+
+// Sees every message a connection sends, once it has its serial, and
+// every message it receives, as soon as it's parsed.  Messages sent
+// through a MessageSender don't pass through the connection's send
+// path, so they aren't traced.
+pub trait Tracer {
+    fn trace(&mut self, direction: Direction, msg: &DBusMessage);
+}
+
+impl Tracer for fn(Direction, &DBusMessage) {
+    fn trace(&mut self, direction: Direction, msg: &DBusMessage) {
+        (*self)(direction, msg)
+    }
+}
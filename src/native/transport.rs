@@ -0,0 +1,777 @@
+// Opening the byte stream a native connection runs over.
+
+use std::cmp;
+use std::collections::{Deque, RingBuf};
+use std::io::{File, IoError, IoResult, EndOfFile, OtherIoError, standard_error};
+use std::io::net::addrinfo::get_host_addresses;
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr};
+#[cfg(not(unix))]
+use std::io::net::pipe::UnixStream;
+use std::io::net::tcp::TcpStream;
+use std::io::process::{Command, InheritFd, Process};
+use std::mem;
+use std::os;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libc;
+
+use address::{BusAddress, TcpAddress, TcpFamily, Ipv4, Ipv6, UnixPath, UnixAbstract, Tcp};
+use address::{NonceTcp, Autolaunch, Launchd, UnixExec, parse_addresses};
+use unix_fd::OwnedFd;
+use super::super::{DBusResult, DBusError, StandardError};
+
+
+pub fn unsupported(address: &BusAddress) -> DBusError {
+    DBusError::standard(StandardError::NotSupported,
+                        format!("transport not supported by the native backend: {}",
+                                address.transport).as_slice())
+}
+
+fn family_matches(family: Option<TcpFamily>, addr: &IpAddr) -> bool {
+    match (family, *addr) {
+        (None, _) => true,
+        (Some(Ipv4), Ipv4Addr(..)) => true,
+        (Some(Ipv6), Ipv6Addr(..)) => true,
+        _ => false
+    }
+}
+
+// Tries each address the host resolves to (of the requested family,
+// if any) until one accepts the connection.
+pub fn connect_tcp(tcp: &TcpAddress) -> DBusResult<TcpStream> {
+    let addrs = try!(get_host_addresses(tcp.host.as_slice()).map_err(DBusError::from_io_error));
+    let mut last_err = None;
+    for addr in addrs.iter().filter(|addr| family_matches(tcp.family, *addr)) {
+        match TcpStream::connect(addr.to_string().as_slice(), tcp.port) {
+            Ok(mut stream) => {
+                // messages are small and latency-sensitive
+                let _ = stream.set_nodelay(true);
+                return Ok(stream);
+            },
+            Err(err) => last_err = Some(err)
+        }
+    }
+    match last_err {
+        Some(err) => Err(DBusError::from_io_error(err)),
+        None => Err(DBusError::standard(StandardError::NoNetwork,
+                                        format!("no usable address for {}",
+                                                tcp.host).as_slice()))
+    }
+}
+
+// from the D-Bus specification
+pub const NONCE_LEN: uint = 16;
+
+// Connects like tcp:, then proves we could read the server's nonce
+// file by sending its contents before the auth exchange starts.
+pub fn connect_nonce_tcp(tcp: &TcpAddress, noncefile: &Path) -> DBusResult<TcpStream> {
+    let nonce = try!(File::open(noncefile).read_to_end().map_err(DBusError::from_io_error));
+    if nonce.len() != NONCE_LEN {
+        return Err(DBusError::standard(StandardError::BadAddress,
+                                       "nonce file does not hold a 16 byte nonce"));
+    }
+    let mut stream = try!(connect_tcp(tcp));
+    try!(stream.write(nonce.as_slice()).map_err(DBusError::from_io_error));
+    Ok(stream)
+}
+
+// The most fds one read will take in, as many as Linux lets one
+// sendmsg() carry.
+static MAX_FDS_PER_READ: uint = 253;
+// Control buffer room for a cmsghdr and that many fds, in u64s to keep
+// it aligned
+static CONTROL_WORDS: uint = 130;
+
+struct SocketInner {
+    fd: libc::c_int,
+    // Fds read along with the data and not yet taken, oldest first
+    received: Mutex<RingBuf<OwnedFd>>
+}
+
+impl Drop for SocketInner {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+// A connected unix socket.  Reads keep any fds the peer sent with the
+// data, in order, for take_fds().  Clones share the socket and the
+// fds, so one can write while another reads.
+#[deriving(Clone)]
+pub struct UnixSocket {
+    inner: Arc<SocketInner>
+}
+
+impl UnixSocket {
+    // Takes ownership of `fd`, a connected unix stream socket.
+    pub fn from_raw_fd(fd: libc::c_int) -> UnixSocket {
+        UnixSocket {
+            inner: Arc::new(SocketInner {
+                fd: fd,
+                received: Mutex::new(RingBuf::new())
+            })
+        }
+    }
+
+    // Two sockets connected to each other.
+    #[cfg(unix)]
+    pub fn pair() -> DBusResult<(UnixSocket, UnixSocket)> {
+        extern {
+            fn socketpair(domain: libc::c_int, ty: libc::c_int, protocol: libc::c_int,
+                          sv: *mut libc::c_int) -> libc::c_int;
+        }
+        let mut fds = [0 as libc::c_int, ..2];
+        if unsafe { socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } < 0 {
+            return Err(DBusError::from_io_error(IoError::last_error()));
+        }
+        Ok((UnixSocket::from_raw_fd(fds[0]), UnixSocket::from_raw_fd(fds[1])))
+    }
+
+    pub fn fd(&self) -> libc::c_int {
+        self.inner.fd
+    }
+
+    // Whether fds the peer sends come through, so it's worth
+    // negotiating them.
+    #[cfg(target_os = "linux")]
+    pub fn receives_fds(&self) -> bool {
+        true
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn receives_fds(&self) -> bool {
+        false
+    }
+
+    // The next `n` fds received, or as many as have been if fewer.
+    pub fn take_fds(&self, n: uint) -> Vec<OwnedFd> {
+        let mut received = self.inner.received.lock();
+        let n = cmp::min(n, received.len());
+        Vec::from_fn(n, |_| received.pop_front().unwrap())
+    }
+
+    // Writes all of `buf`, with `fds` going along with its first byte.
+    #[cfg(target_os = "linux")]
+    pub fn send_with_fds(&self, buf: &[u8], fds: &[libc::c_int]) -> IoResult<()> {
+        if fds.is_empty() {
+            return write_all(self.inner.fd, buf);
+        }
+        if buf.is_empty() || fds.len() > MAX_FDS_PER_READ {
+            return Err(IoError {
+                kind: OtherIoError,
+                desc: "can't send that many fds, or fds without data",
+                detail: None
+            });
+        }
+        let mut control = [0u64, ..CONTROL_WORDS];
+        let cmsg_len = CMSG_HDR_LEN + fds.len() * 4;
+        unsafe {
+            let hdr = control.as_mut_ptr() as *mut cmsghdr;
+            (*hdr).cmsg_len = cmsg_len as libc::size_t;
+            (*hdr).cmsg_level = libc::SOL_SOCKET;
+            (*hdr).cmsg_type = SCM_RIGHTS;
+            let data = (control.as_mut_ptr() as *mut u8).offset(CMSG_HDR_LEN as int);
+            for (i, fd) in fds.iter().enumerate() {
+                *(data as *mut libc::c_int).offset(i as int) = *fd;
+            }
+        }
+        let mut iov = iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len() as libc::size_t
+        };
+        let msg = msghdr {
+            msg_name: 0 as *mut libc::c_void,
+            msg_namelen: 0,
+            msg_iov: &mut iov,
+            msg_iovlen: 1,
+            msg_control: control.as_mut_ptr() as *mut libc::c_void,
+            msg_controllen: cmsg_align(cmsg_len) as libc::size_t,
+            msg_flags: 0
+        };
+        let mut sent;
+        loop {
+            sent = unsafe { sendmsg(self.inner.fd, &msg, 0) };
+            if sent >= 0 {
+                break;
+            }
+            if os::errno() != libc::EINTR as uint {
+                return Err(IoError::last_error());
+            }
+        }
+        // the fds went with the first byte, so the rest can go plainly
+        write_all(self.inner.fd, buf.slice_from(sent as uint))
+    }
+}
+
+// recvmsg() and sendmsg() with SCM_RIGHTS, laid out as glibc and the
+// kernel have them.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct iovec {
+    iov_base: *mut libc::c_void,
+    iov_len: libc::size_t
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct msghdr {
+    msg_name: *mut libc::c_void,
+    msg_namelen: libc::socklen_t,
+    msg_iov: *mut iovec,
+    msg_iovlen: libc::size_t,
+    msg_control: *mut libc::c_void,
+    msg_controllen: libc::size_t,
+    msg_flags: libc::c_int
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct cmsghdr {
+    cmsg_len: libc::size_t,
+    cmsg_level: libc::c_int,
+    cmsg_type: libc::c_int
+}
+
+#[cfg(target_os = "linux")]
+extern {
+    fn recvmsg(fd: libc::c_int, msg: *mut msghdr, flags: libc::c_int) -> libc::ssize_t;
+    fn sendmsg(fd: libc::c_int, msg: *const msghdr, flags: libc::c_int) -> libc::ssize_t;
+}
+
+#[cfg(target_os = "linux")]
+static SCM_RIGHTS: libc::c_int = 1;
+#[cfg(target_os = "linux")]
+static MSG_CTRUNC: libc::c_int = 0x8;
+#[cfg(target_os = "linux")]
+static MSG_CMSG_CLOEXEC: libc::c_int = 0x40000000;
+// A cmsghdr, padded out to where its data starts
+#[cfg(all(target_os = "linux", target_word_size = "64"))]
+static CMSG_HDR_LEN: uint = 16;
+#[cfg(all(target_os = "linux", target_word_size = "32"))]
+static CMSG_HDR_LEN: uint = 12;
+
+// Control messages are padded to a size_t, as CMSG_ALIGN does.
+#[cfg(target_os = "linux")]
+fn cmsg_align(len: uint) -> uint {
+    let word = mem::size_of::<libc::size_t>();
+    (len + word - 1) & !(word - 1)
+}
+
+#[cfg(unix)]
+fn write_all(fd: libc::c_int, mut buf: &[u8]) -> IoResult<()> {
+    while !buf.is_empty() {
+        let ptr = buf.as_ptr() as *const libc::c_void;
+        match unsafe { libc::send(fd, ptr, buf.len() as libc::size_t, 0) } {
+            n if n >= 0 => buf = buf.slice_from(n as uint),
+            _ if os::errno() == libc::EINTR as uint => (),
+            _ => return Err(IoError::last_error())
+        }
+    }
+    Ok(())
+}
+
+// Reads into `buf`, queueing any fds that come with it on `received`.
+#[cfg(target_os = "linux")]
+fn recv_with_fds(fd: libc::c_int, buf: &mut [u8],
+                 received: &Mutex<RingBuf<OwnedFd>>) -> IoResult<uint> {
+    let mut control = [0u64, ..CONTROL_WORDS];
+    let mut iov = iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len() as libc::size_t
+    };
+    let mut msg = msghdr {
+        msg_name: 0 as *mut libc::c_void,
+        msg_namelen: 0,
+        msg_iov: &mut iov,
+        msg_iovlen: 1,
+        msg_control: control.as_mut_ptr() as *mut libc::c_void,
+        msg_controllen: (control.len() * 8) as libc::size_t,
+        msg_flags: 0
+    };
+    let len = match unsafe { recvmsg(fd, &mut msg, MSG_CMSG_CLOEXEC) } {
+        n if n < 0 => return Err(IoError::last_error()),
+        n => n as uint
+    };
+    // walk the control messages for the fds
+    let control_len = msg.msg_controllen as uint;
+    let base = control.as_ptr() as *const u8;
+    let mut offset = 0;
+    let mut received = received.lock();
+    while offset + CMSG_HDR_LEN <= control_len {
+        let hdr = unsafe { &*(base.offset(offset as int) as *const cmsghdr) };
+        let cmsg_len = hdr.cmsg_len as uint;
+        if cmsg_len < CMSG_HDR_LEN || offset + cmsg_len > control_len {
+            break;
+        }
+        if hdr.cmsg_level == libc::SOL_SOCKET && hdr.cmsg_type == SCM_RIGHTS {
+            let data = unsafe { base.offset((offset + CMSG_HDR_LEN) as int) };
+            for i in range(0, (cmsg_len - CMSG_HDR_LEN) / 4) {
+                let fd = unsafe { *(data as *const libc::c_int).offset(i as int) };
+                received.push_back(OwnedFd::from_raw_fd(fd));
+            }
+        }
+        offset += cmsg_align(cmsg_len);
+    }
+    if msg.msg_flags & MSG_CTRUNC != 0 {
+        // some fds were dropped, so later messages would get the wrong ones
+        return Err(IoError {
+            kind: OtherIoError,
+            desc: "unix fds were lost to a truncated read",
+            detail: None
+        });
+    }
+    Ok(len)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn recv_with_fds(fd: libc::c_int, buf: &mut [u8],
+                 _received: &Mutex<RingBuf<OwnedFd>>) -> IoResult<uint> {
+    let ptr = buf.as_mut_ptr() as *mut libc::c_void;
+    match unsafe { libc::recv(fd, ptr, buf.len() as libc::size_t, 0) } {
+        n if n < 0 => Err(IoError::last_error()),
+        n => Ok(n as uint)
+    }
+}
+
+#[cfg(unix)]
+impl Reader for UnixSocket {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        loop {
+            match recv_with_fds(self.inner.fd, buf, &self.inner.received) {
+                Ok(0) if !buf.is_empty() => return Err(standard_error(EndOfFile)),
+                Ok(len) => return Ok(len),
+                Err(_) if os::errno() == libc::EINTR as uint => continue,
+                Err(err) => return Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Writer for UnixSocket {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        write_all(self.inner.fd, buf)
+    }
+}
+
+// Connects a unix socket to the first `len` bytes of `addr`.
+#[cfg(unix)]
+fn connect_sockaddr_un(addr: &libc::sockaddr_un, len: uint) -> DBusResult<UnixSocket> {
+    unsafe {
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0);
+        if fd < 0 {
+            return Err(DBusError::from_io_error(IoError::last_error()));
+        }
+        let addr_ptr = addr as *const libc::sockaddr_un as *const libc::sockaddr;
+        if libc::connect(fd, addr_ptr, len as libc::socklen_t) < 0 {
+            let err = IoError::last_error();
+            libc::close(fd);
+            return Err(DBusError::from_io_error(err));
+        }
+        Ok(UnixSocket::from_raw_fd(fd))
+    }
+}
+
+// By hand rather than with std's UnixStream, which won't give up its fd.
+#[cfg(unix)]
+pub fn connect_unix(path: &Path) -> DBusResult<UnixSocket> {
+    unsafe {
+        let mut addr: libc::sockaddr_un = mem::zeroed();
+        let path = path.as_vec();
+        if path.len() + 1 > addr.sun_path.len() {
+            return Err(DBusError::standard(StandardError::BadAddress,
+                                           "unix socket path too long"));
+        }
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (i, b) in path.iter().enumerate() {
+            addr.sun_path[i] = *b as libc::c_char;
+        }
+        let len = mem::size_of::<libc::sa_family_t>() + path.len() + 1;
+        connect_sockaddr_un(&addr, len)
+    }
+}
+
+// Abstract sockets live in their own namespace, named by a sun_path
+// starting with NUL, which std's UnixStream can't express.
+#[cfg(target_os = "linux")]
+pub fn connect_abstract(name: &[u8]) -> DBusResult<UnixSocket> {
+    unsafe {
+        let mut addr: libc::sockaddr_un = mem::zeroed();
+        if name.len() + 1 > addr.sun_path.len() {
+            return Err(DBusError::standard(StandardError::BadAddress,
+                                           "abstract socket name too long"));
+        }
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (i, b) in name.iter().enumerate() {
+            addr.sun_path[i + 1] = *b as libc::c_char;
+        }
+        let len = mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+        connect_sockaddr_un(&addr, len)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn connect_abstract(_name: &[u8]) -> DBusResult<UnixSocket> {
+    Err(DBusError::standard(StandardError::NotSupported,
+                            "abstract unix sockets are only available on Linux"))
+}
+
+// Waits up to `timeout_ms` for `fd` to have something to read,
+// returning whether it does.  A signal cuts the wait short.
+#[cfg(unix)]
+pub fn wait_readable(fd: libc::c_int, timeout_ms: uint) -> DBusResult<bool> {
+    #[repr(C)]
+    struct pollfd {
+        fd: libc::c_int,
+        events: libc::c_short,
+        revents: libc::c_short
+    }
+    extern {
+        fn poll(fds: *mut pollfd, nfds: libc::c_ulong, timeout: libc::c_int) -> libc::c_int;
+    }
+    static POLLIN: libc::c_short = 1;
+
+    let mut fds = pollfd {
+        fd: fd,
+        events: POLLIN,
+        revents: 0
+    };
+    match unsafe { poll(&mut fds, 1, timeout_ms as libc::c_int) } {
+        n if n > 0 => Ok(true),
+        0 => Ok(false),
+        _ if os::errno() == libc::EINTR as uint => Ok(false),
+        _ => Err(DBusError::from_io_error(IoError::last_error()))
+    }
+}
+
+#[cfg(not(unix))]
+pub fn wait_readable(_fd: libc::c_int, _timeout_ms: uint) -> DBusResult<bool> {
+    Ok(true)
+}
+
+// DBusServer listens and accepts by hand rather than through std's
+// UnixListener, since it needs each client's fd to ask the kernel who
+// connected.
+#[cfg(unix)]
+pub fn listen_unix(path: &Path) -> DBusResult<libc::c_int> {
+    unsafe {
+        let mut addr: libc::sockaddr_un = mem::zeroed();
+        let path = path.as_vec();
+        if path.len() + 1 > addr.sun_path.len() {
+            return Err(DBusError::standard(StandardError::BadAddress,
+                                           "unix socket path too long"));
+        }
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (i, b) in path.iter().enumerate() {
+            addr.sun_path[i] = *b as libc::c_char;
+        }
+        let len = mem::size_of::<libc::sa_family_t>() + path.len() + 1;
+
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0);
+        if fd < 0 {
+            return Err(DBusError::from_io_error(IoError::last_error()));
+        }
+        let addr_ptr = &addr as *const libc::sockaddr_un as *const libc::sockaddr;
+        if libc::bind(fd, addr_ptr, len as libc::socklen_t) < 0 ||
+                libc::listen(fd, 128) < 0 {
+            let err = IoError::last_error();
+            libc::close(fd);
+            return Err(DBusError::from_io_error(err));
+        }
+        Ok(fd)
+    }
+}
+
+#[cfg(unix)]
+pub fn accept_unix(listener: libc::c_int) -> DBusResult<libc::c_int> {
+    loop {
+        let fd = unsafe { libc::accept(listener, 0 as *mut libc::sockaddr,
+                                       0 as *mut libc::socklen_t) };
+        if fd >= 0 {
+            return Ok(fd);
+        }
+        // retry only if a signal interrupted us
+        if os::errno() != libc::EINTR as uint {
+            return Err(DBusError::from_io_error(IoError::last_error()));
+        }
+    }
+}
+
+// Makes reads on `fd` fail once they've waited `timeout`, or never
+// if None.
+#[cfg(unix)]
+pub fn set_read_timeout(fd: libc::c_int, timeout: Option<Duration>) -> DBusResult<()> {
+    let ms = timeout.map(|t| t.num_milliseconds()).unwrap_or(0);
+    let tv = libc::timeval {
+        tv_sec: (ms / 1000) as libc::time_t,
+        tv_usec: ((ms % 1000) * 1000) as libc::suseconds_t
+    };
+    let tv_ptr = &tv as *const libc::timeval as *const libc::c_void;
+    let len = mem::size_of::<libc::timeval>() as libc::socklen_t;
+    if unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO, tv_ptr, len) } < 0 {
+        return Err(DBusError::from_io_error(IoError::last_error()));
+    }
+    Ok(())
+}
+
+// The uid of the process at the other end of a unix socket.
+#[cfg(target_os = "linux")]
+pub fn peer_uid(fd: libc::c_int) -> Option<u32> {
+    #[repr(C)]
+    struct ucred {
+        pid: libc::pid_t,
+        uid: libc::uid_t,
+        gid: libc::gid_t
+    }
+    static SO_PEERCRED: libc::c_int = 17;
+
+    unsafe {
+        let mut cred: ucred = mem::zeroed();
+        let mut len = mem::size_of::<ucred>() as libc::socklen_t;
+        let cred_ptr = &mut cred as *mut ucred as *mut libc::c_void;
+        if libc::getsockopt(fd, libc::SOL_SOCKET, SO_PEERCRED, cred_ptr, &mut len) < 0 {
+            return None;
+        }
+        Some(cred.uid as u32)
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn peer_uid(_fd: libc::c_int) -> Option<u32> {
+    None
+}
+
+// Asks launchd for the socket path it published in `env`.
+#[cfg(target_os = "macos")]
+pub fn resolve_launchd(env: &str) -> DBusResult<Path> {
+    let output = try!(Command::new("launchctl").arg("getenv").arg(env).output()
+                          .map_err(DBusError::from_io_error));
+    let path = String::from_utf8_lossy(output.output.as_slice()).into_string();
+    let path = path.as_slice().trim();
+    if !output.status.success() || path.is_empty() {
+        return Err(DBusError::standard(StandardError::BadAddress,
+                                       format!("launchd has no value for {}", env).as_slice()));
+    }
+    Ok(Path::new(path))
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn resolve_launchd(_env: &str) -> DBusResult<Path> {
+    Err(DBusError::standard(StandardError::NotSupported,
+                            "launchd: addresses are only available on macOS"))
+}
+
+// Finds the address a session bus has published for autolaunch:.
+#[cfg(windows)]
+pub fn resolve_autolaunch(scope: Option<&str>) -> DBusResult<String> {
+    super::windows::autolaunch_address(scope)
+}
+
+// On X11 there's one session bus per display, so the scope is unused.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn resolve_autolaunch(_scope: Option<&str>) -> DBusResult<String> {
+    super::x11::autolaunch_address()
+}
+
+#[cfg(target_os = "macos")]
+pub fn resolve_autolaunch(_scope: Option<&str>) -> DBusResult<String> {
+    Err(DBusError::standard(StandardError::NotSupported,
+                            "autolaunch: addresses are not available on macOS; use launchd:"))
+}
+
+// A child process's stdin and stdout, as one stream.  The child is
+// killed when it's dropped.
+pub struct ChildStream {
+    process: Process
+}
+
+impl ChildStream {
+    // Runs `path` with argv[1..] as its arguments.  Command can't set
+    // argv[0], so the child sees `path` there.
+    pub fn spawn(path: &Path, argv: &[String]) -> DBusResult<ChildStream> {
+        let mut command = Command::new(path);
+        if argv.len() > 1 {
+            command.args(argv.slice_from(1));
+        }
+        // the child's complaints are more use on our stderr than lost
+        let process = try!(command.stderr(InheritFd(libc::STDERR_FILENO)).spawn()
+                               .map_err(DBusError::from_io_error));
+        Ok(ChildStream {
+            process: process
+        })
+    }
+
+    pub fn id(&self) -> libc::pid_t {
+        self.process.id()
+    }
+}
+
+impl Reader for ChildStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        self.process.stdout.as_mut().unwrap().read(buf)
+    }
+}
+
+impl Writer for ChildStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        self.process.stdin.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.process.stdin.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for ChildStream {
+    fn drop(&mut self) {
+        // closing stdin is enough for a well-behaved child
+        drop(self.process.stdin.take());
+        let _ = self.process.signal_kill();
+    }
+}
+
+// A connected stream, with what the connection needs to know about it.
+pub struct Connected {
+    pub stream: Box<Stream + Send>,
+    // For sockets, a second handle on the stream that MessageSenders
+    // write through while the connection reads
+    pub writer: Option<Box<Writer + Send>>,
+    // The unix socket underneath, where there is one, for waiting on
+    // and for the fds that come with messages
+    pub socket: Option<UnixSocket>
+}
+
+fn split<S: Stream + Clone + Send>(stream: S) -> Connected {
+    let writer = stream.clone();
+    Connected {
+        stream: box stream as Box<Stream + Send>,
+        writer: Some(box writer as Box<Writer + Send>),
+        socket: None
+    }
+}
+
+#[cfg(unix)]
+fn split_socket(socket: UnixSocket) -> Connected {
+    Connected {
+        stream: box socket.clone() as Box<Stream + Send>,
+        writer: Some(box socket.clone() as Box<Writer + Send>),
+        socket: Some(socket)
+    }
+}
+
+#[cfg(unix)]
+fn connect_path(path: &Path) -> DBusResult<Connected> {
+    Ok(split_socket(try!(connect_unix(path))))
+}
+
+#[cfg(not(unix))]
+fn connect_path(path: &Path) -> DBusResult<Connected> {
+    Ok(split(try!(UnixStream::connect(path).map_err(DBusError::from_io_error))))
+}
+
+#[cfg(unix)]
+fn connect_abstract_name(name: &[u8]) -> DBusResult<Connected> {
+    Ok(split_socket(try!(connect_abstract(name))))
+}
+
+#[cfg(not(unix))]
+fn connect_abstract_name(_name: &[u8]) -> DBusResult<Connected> {
+    Err(DBusError::standard(StandardError::NotSupported,
+                            "abstract unix sockets are only available on Linux"))
+}
+
+// Connects to the first of `addresses` that will have us, reporting
+// every failure if none do, as DBusConnection::open() does.
+fn connect_any(addresses: &[BusAddress]) -> DBusResult<Connected> {
+    let mut failures = Vec::new();
+    let mut last_err = None;
+    for address in addresses.iter() {
+        let result = match address.transport {
+            // a daemon would never publish this, but don't loop if one did
+            Autolaunch(_) => Err(unsupported(address)),
+            _ => connect(address)
+        };
+        match result {
+            Ok(connected) => return Ok(connected),
+            Err(err) => {
+                debug!("couldn't connect to {}: {}", address, err);
+                failures.push(format!("{}: {}", address, err));
+                last_err = Some(err);
+            }
+        }
+    }
+    let last_err = match last_err {
+        Some(err) => err,
+        None => return Err(DBusError::standard(StandardError::BadAddress,
+                                               "the bus published no addresses"))
+    };
+    if failures.len() == 1 {
+        return Err(last_err);
+    }
+    Err(DBusError::new(last_err.name(),
+                       format!("couldn't connect to any address: {}",
+                               failures.connect("; ")).as_slice()))
+}
+
+pub fn connect(address: &BusAddress) -> DBusResult<Connected> {
+    match address.transport {
+        UnixPath(ref path) => connect_path(path),
+        UnixAbstract(ref name) => connect_abstract_name(name.as_slice()),
+        Tcp(ref tcp) => Ok(split(try!(connect_tcp(tcp)))),
+        NonceTcp(ref tcp, ref noncefile) => Ok(split(try!(connect_nonce_tcp(tcp, noncefile)))),
+        Launchd(ref env) => connect_path(&try!(resolve_launchd(env.as_slice()))),
+        UnixExec(ref path, ref argv) => {
+            let stream = try!(ChildStream::spawn(path, argv.as_slice()));
+            Ok(Connected {
+                stream: box stream as Box<Stream + Send>,
+                writer: None,
+                socket: None
+            })
+        },
+        Autolaunch(ref scope) => {
+            let published = try!(resolve_autolaunch(scope.as_ref().map(|s| s.as_slice())));
+            let addresses = try!(parse_addresses(published.as_slice()));
+            connect_any(addresses.as_slice())
+        },
+        _ => Err(unsupported(address))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::connect_any;
+    use address::parse_addresses;
+    use super::super::super::StandardError;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_child_stream() {
+        let mut stream = ChildStream::spawn(&Path::new("/bin/cat"),
+                                            [String::from_str("cat")]).unwrap();
+        stream.write(b"AUTH\r\n").unwrap();
+        stream.flush().unwrap();
+        let mut buf = [0u8, ..6];
+        stream.read_at_least(6, buf).unwrap();
+        assert_eq!(buf.as_slice(), b"AUTH\r\n");
+    }
+
+    #[test]
+    fn test_connect_any_reports_every_address() {
+        let addresses = parse_addresses("autolaunch:;unix:path=/nonexistent/frob-a;\
+                                         unix:path=/nonexistent/frob-b").unwrap();
+        let err = connect_any(addresses.as_slice()).err().unwrap();
+        let message = err.message();
+        assert!(message.contains("autolaunch:"));
+        assert!(message.contains("/nonexistent/frob-a"));
+        assert!(message.contains("/nonexistent/frob-b"));
+
+        let addresses = parse_addresses("autolaunch:").unwrap();
+        let err = connect_any(addresses.as_slice()).err().unwrap();
+        assert!(err.is_standard(StandardError::NotSupported));
+    }
+}
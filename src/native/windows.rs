@@ -0,0 +1,143 @@
+// Windows specifics: finding the session bus a dbus-daemon has
+// published in shared memory, and our SID for authentication.
+
+use libc::{c_char, c_void};
+
+use std::c_str::CString;
+use std::io::IoError;
+use std::io::process::Command;
+use std::io::timer;
+use std::mem;
+use std::ptr;
+use std::time::Duration;
+
+use address::escape_value;
+use super::super::{DBusResult, DBusError, StandardError};
+
+
+type HANDLE = *mut c_void;
+type BOOL = i32;
+type DWORD = u32;
+
+const FILE_MAP_READ: DWORD = 0x0004;
+const TOKEN_QUERY: DWORD = 0x0008;
+const TOKEN_USER_CLASS: u32 = 1;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn OpenFileMappingA(access: DWORD, inherit: BOOL, name: *const c_char) -> HANDLE;
+    fn MapViewOfFile(mapping: HANDLE, access: DWORD, offset_high: DWORD,
+                     offset_low: DWORD, len: uint) -> *mut c_void;
+    fn UnmapViewOfFile(view: *const c_void) -> BOOL;
+    fn CloseHandle(handle: HANDLE) -> BOOL;
+    fn GetCurrentProcess() -> HANDLE;
+    fn LocalFree(mem: *mut c_void) -> *mut c_void;
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn OpenProcessToken(process: HANDLE, access: DWORD, token: *mut HANDLE) -> BOOL;
+    fn GetTokenInformation(token: HANDLE, class: u32, info: *mut c_void,
+                           len: DWORD, ret_len: *mut DWORD) -> BOOL;
+    fn ConvertSidToStringSidA(sid: *mut c_void, out: *mut *mut c_char) -> BOOL;
+}
+
+
+// dbus-daemon publishes its address under this name, suffixed with
+// the scope when one is given.
+pub const ADDRESS_SHM_NAME: &'static str = "DBusDaemonAddressInfo";
+
+// How long to wait for a daemon we started to publish its address.
+static AUTOLAUNCH_ATTEMPTS: uint = 50;
+
+fn shm_name(scope: Option<&str>) -> String {
+    match scope {
+        Some(scope) if !scope.is_empty() => format!("{}-{}", ADDRESS_SHM_NAME, scope),
+        _ => String::from_str(ADDRESS_SHM_NAME)
+    }
+}
+
+// Reads the address a running daemon published, if there is one.
+pub fn read_published_address(scope: Option<&str>) -> Option<String> {
+    let name = shm_name(scope).to_c_str();
+    unsafe {
+        let mapping = OpenFileMappingA(FILE_MAP_READ, 0, name.as_ptr());
+        if mapping.is_null() {
+            return None;
+        }
+        let view = MapViewOfFile(mapping, FILE_MAP_READ, 0, 0, 0);
+        let out = if view.is_null() {
+            None
+        } else {
+            let address = CString::new(view as *const c_char, false);
+            let address = String::from_utf8_lossy(address.as_bytes_no_nul()).into_string();
+            UnmapViewOfFile(view as *const c_void);
+            Some(address)
+        };
+        CloseHandle(mapping);
+        out
+    }
+}
+
+// Finds the session bus address, starting a dbus-daemon if none has
+// published one yet, as libdbus does.  The daemon is told the scope,
+// so it publishes where we look.
+pub fn autolaunch_address(scope: Option<&str>) -> DBusResult<String> {
+    match read_published_address(scope) {
+        Some(address) => return Ok(address),
+        None => ()
+    }
+    let mut command = Command::new("dbus-daemon");
+    command.arg("--session");
+    match scope {
+        Some(scope) if !scope.is_empty() => {
+            command.arg(format!("--address=autolaunch:scope={}",
+                                escape_value(scope.as_bytes())));
+        },
+        _ => ()
+    }
+    let daemon = try!(command.detached().spawn().map_err(DBusError::from_io_error));
+    // dropping the handle would wait for the daemon to exit, and it's
+    // meant to outlive us
+    daemon.forget();
+    for _ in range(0, AUTOLAUNCH_ATTEMPTS) {
+        timer::sleep(Duration::milliseconds(100));
+        match read_published_address(scope) {
+            Some(address) => return Ok(address),
+            None => ()
+        }
+    }
+    Err(DBusError::standard(StandardError::NoServer,
+                            "dbus-daemon did not publish its address"))
+}
+
+// Our SID as a string, which is what Windows peers expect as the
+// identity in EXTERNAL and DBUS_COOKIE_SHA1.
+pub fn current_user_sid() -> DBusResult<String> {
+    unsafe {
+        let mut token = ptr::null_mut();
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            return Err(DBusError::from_io_error(IoError::last_error()));
+        }
+        // u64s, so the pointer TOKEN_USER starts with is aligned
+        let mut buf = [0u64, ..32];
+        let mut len = 0;
+        let ok = GetTokenInformation(token, TOKEN_USER_CLASS,
+                                     buf.as_mut_ptr() as *mut c_void,
+                                     (buf.len() * mem::size_of::<u64>()) as DWORD, &mut len);
+        CloseHandle(token);
+        if ok == 0 {
+            return Err(DBusError::from_io_error(IoError::last_error()));
+        }
+        // TOKEN_USER starts with a pointer to the SID
+        let sid = *(buf.as_ptr() as *const *mut c_void);
+        let mut sid_str = ptr::null_mut();
+        if ConvertSidToStringSidA(sid, &mut sid_str) == 0 {
+            return Err(DBusError::from_io_error(IoError::last_error()));
+        }
+        let out = CString::new(sid_str as *const c_char, false);
+        let out = String::from_utf8_lossy(out.as_bytes_no_nul()).into_string();
+        LocalFree(sid_str as *mut c_void);
+        Ok(out)
+    }
+}
@@ -0,0 +1,78 @@
+// X11 specifics: finding the session bus of the X display we're on
+// when DBUS_SESSION_BUS_ADDRESS isn't set, e.g. for applications
+// started by a window manager rather than a login shell.
+//
+// Like libdbus, we leave the work to dbus-launch, which looks for the
+// address on the root window's _DBUS_SESSION_BUS_ADDRESS property and
+// starts a daemon for the display if there isn't one.
+
+use std::io::process::Command;
+use std::os;
+
+use machine_id::read_machine_id;
+use super::super::{DBusResult, DBusError, StandardError};
+
+
+// Whether there's an X display for autolaunch: to use.
+pub fn has_display() -> bool {
+    match os::getenv("DISPLAY") {
+        Some(display) => !display.is_empty(),
+        None => false
+    }
+}
+
+// The address in dbus-launch's --binary-syntax output, which is the
+// address and a nul, followed by the daemon's pid and window id.
+pub fn parse_launch_output(output: &[u8]) -> Option<String> {
+    let end = match output.iter().position(|&b| b == 0) {
+        Some(end) => end,
+        None => return None
+    };
+    match String::from_utf8(output.slice_to(end).to_vec()) {
+        Ok(ref address) if !address.is_empty() => Some(address.clone()),
+        _ => None
+    }
+}
+
+// Finds the display's session bus address, starting a daemon for it if
+// need be.
+pub fn autolaunch_address() -> DBusResult<String> {
+    if !has_display() {
+        return Err(DBusError::standard(StandardError::NotSupported,
+                                       "autolaunch: needs an X display, and DISPLAY is not set"));
+    }
+    let machine_id = try!(read_machine_id());
+    let output = try!(Command::new("dbus-launch")
+                          .arg(format!("--autolaunch={}", machine_id))
+                          .arg("--binary-syntax")
+                          .arg("--close-stderr")
+                          .output()
+                          .map_err(DBusError::from_io_error));
+    if !output.status.success() {
+        return Err(DBusError::standard(StandardError::Failed,
+                                       format!("dbus-launch failed: {}",
+                                               output.status).as_slice()));
+    }
+    match parse_launch_output(output.output.as_slice()) {
+        Some(address) => Ok(address),
+        None => Err(DBusError::standard(StandardError::NoServer,
+                                        "dbus-launch printed no address"))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_launch_output() {
+        let mut output = b"unix:abstract=/tmp/dbus-Frob,guid=0123\0".to_vec();
+        // pid and window id, in native byte order
+        output.push_all([0x39, 0x30, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(parse_launch_output(output.as_slice()),
+                   Some(String::from_str("unix:abstract=/tmp/dbus-Frob,guid=0123")));
+        assert_eq!(parse_launch_output(b"\0"), None);
+        assert_eq!(parse_launch_output(b"unix:path=/tmp/truncated"), None);
+    }
+}
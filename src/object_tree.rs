@@ -0,0 +1,235 @@
+// Which interfaces a service exports at which paths, and the
+// introspection XML describing them.
+
+use std::collections::TreeMap;
+use std::sync::Arc;
+
+use value::parse_signature;
+use super::{DBusInterface, Method, Signal};
+
+
+pub const DOCTYPE: &'static str =
+    "<!DOCTYPE node PUBLIC \"-//freedesktop//DTD D-BUS Object Introspection 1.0//EN\"\n \
+     \"http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd\">\n";
+
+
+pub struct ObjectTree {
+    // Kept sorted so the XML comes out the same every time
+    paths: TreeMap<String, Vec<Arc<DBusInterface>>>
+}
+
+impl ObjectTree {
+    pub fn new() -> ObjectTree {
+        ObjectTree {
+            paths: TreeMap::new()
+        }
+    }
+
+    // Exports `iface` at `path`, in place of any interface of the same
+    // name already there.
+    pub fn add(&mut self, path: &str, iface: Arc<DBusInterface>) {
+        let path = String::from_str(path);
+        if !self.paths.contains_key(&path) {
+            self.paths.insert(path.clone(), Vec::new());
+        }
+        let ifaces = self.paths.find_mut(&path).unwrap();
+        match ifaces.iter().position(|i| i.name() == iface.name()) {
+            Some(i) => *ifaces.get_mut(i) = iface,
+            None => ifaces.push(iface)
+        }
+    }
+
+    pub fn remove(&mut self, path: &str) -> bool {
+        self.paths.remove(&String::from_str(path))
+    }
+
+    pub fn paths(&self) -> Vec<&str> {
+        self.paths.keys().map(|path| path.as_slice()).collect()
+    }
+
+    pub fn interfaces(&self, path: &str) -> &[Arc<DBusInterface>] {
+        match self.paths.find(&String::from_str(path)) {
+            Some(ifaces) => ifaces.as_slice(),
+            None => &[]
+        }
+    }
+
+    // What Introspect at `path` should return: its interfaces, and a
+    // node for each path one level below it.
+    pub fn introspect(&self, path: &str) -> String {
+        let mut out = String::from_str(DOCTYPE);
+        out.push_str("<node>\n");
+        for iface in self.interfaces(path).iter() {
+            write_interface(&mut out, &**iface, 1);
+        }
+        for child in self.children(path).iter() {
+            out.push_str(format!("  <node name=\"{}\"/>\n", escape(child.as_slice())).as_slice());
+        }
+        out.push_str("</node>\n");
+        out
+    }
+
+    // Every path and its interfaces in one document, for comparing a
+    // service's API against a reference copy.
+    pub fn dump_xml(&self) -> String {
+        let mut out = String::from_str(DOCTYPE);
+        out.push_str("<node>\n");
+        for (path, ifaces) in self.paths.iter() {
+            out.push_str(format!("  <node name=\"{}\">\n", escape(path.as_slice())).as_slice());
+            for iface in ifaces.iter() {
+                write_interface(&mut out, &**iface, 2);
+            }
+            out.push_str("  </node>\n");
+        }
+        out.push_str("</node>\n");
+        out
+    }
+
+    // The names of the nodes directly below `path` that lead to
+    // exported paths.
+    fn children(&self, path: &str) -> Vec<String> {
+        let prefix = if path == "/" {
+            String::from_str("/")
+        } else {
+            format!("{}/", path)
+        };
+        let mut children: Vec<String> = Vec::new();
+        for other in self.paths.keys() {
+            if !other.as_slice().starts_with(prefix.as_slice()) || other.len() == prefix.len() {
+                continue;
+            }
+            let rest = other.as_slice().slice_from(prefix.len());
+            let child = String::from_str(rest.split('/').next().unwrap());
+            if !children.contains(&child) {
+                children.push(child);
+            }
+        }
+        children
+    }
+}
+
+
+// `iface` as an <interface> element, indented two spaces per level.
+pub fn interface_xml(iface: &DBusInterface) -> String {
+    let mut out = String::new();
+    write_interface(&mut out, iface, 0);
+    out
+}
+
+fn write_interface(out: &mut String, iface: &DBusInterface, depth: uint) {
+    let indent = String::from_char(depth * 2, ' ');
+    out.push_str(format!("{}<interface name=\"{}\">\n", indent, escape(iface.name())).as_slice());
+    for member in iface.members().iter() {
+        match *member {
+            Method(ref name, ref argspec, ref argnames, ref retspec) => {
+                out.push_str(format!("{}  <method name=\"{}\">\n",
+                                     indent, escape(name.as_slice())).as_slice());
+                for (i, sig) in split_signature(argspec.as_slice()).iter().enumerate() {
+                    let name = if i < argnames.len() {
+                        Some(argnames[i].as_slice())
+                    } else {
+                        None
+                    };
+                    write_arg(out, indent.as_slice(), name, sig.as_slice(), Some("in"));
+                }
+                for sig in split_signature(retspec.as_slice()).iter() {
+                    write_arg(out, indent.as_slice(), None, sig.as_slice(), Some("out"));
+                }
+                out.push_str(format!("{}  </method>\n", indent).as_slice());
+            },
+            Signal(ref name, ref signature) => {
+                out.push_str(format!("{}  <signal name=\"{}\">\n",
+                                     indent, escape(name.as_slice())).as_slice());
+                for sig in split_signature(signature.as_slice()).iter() {
+                    write_arg(out, indent.as_slice(), None, sig.as_slice(), None);
+                }
+                out.push_str(format!("{}  </signal>\n", indent).as_slice());
+            }
+        }
+    }
+    out.push_str(format!("{}</interface>\n", indent).as_slice());
+}
+
+fn write_arg(out: &mut String, indent: &str, name: Option<&str>, signature: &str,
+             direction: Option<&str>) {
+    out.push_str(indent);
+    out.push_str("    <arg");
+    for name in name.iter() {
+        out.push_str(format!(" name=\"{}\"", escape(*name)).as_slice());
+    }
+    out.push_str(format!(" type=\"{}\"", escape(signature)).as_slice());
+    for direction in direction.iter() {
+        out.push_str(format!(" direction=\"{}\"", direction).as_slice());
+    }
+    out.push_str("/>\n");
+}
+
+// One signature per argument.  A signature that doesn't parse is left
+// whole rather than dropped.
+fn split_signature(signature: &str) -> Vec<String> {
+    if signature.is_empty() {
+        return Vec::new();
+    }
+    match parse_signature(signature) {
+        Ok(types) => types.iter().map(|t| t.signature()).collect(),
+        Err(_) => vec![String::from_str(signature)]
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::InterfaceBuilder;
+
+    #[test]
+    fn test_dump_xml() {
+        let frob = Arc::new(InterfaceBuilder::new("com.example.Frob")
+            .method("Frobulate").arg("s", "value").arg("u", "count").returns("as")
+            .signal("Frobbed", "s")
+            .build());
+        let mut tree = ObjectTree::new();
+        tree.add("/com/example/Frob/b", frob.clone());
+        tree.add("/com/example/Frob/a", frob.clone());
+        tree.add("/com/example/Frob/a/deeper", frob.clone());
+        assert_eq!(tree.paths(), vec!["/com/example/Frob/a", "/com/example/Frob/a/deeper",
+                                      "/com/example/Frob/b"]);
+
+        let xml = tree.dump_xml();
+        assert!(xml.as_slice().starts_with(DOCTYPE));
+        assert_eq!(xml.as_slice().slice_from(DOCTYPE.len()).lines().nth(1),
+                   Some("  <node name=\"/com/example/Frob/a\">"));
+        assert_eq!(interface_xml(&*frob).as_slice(),
+                   "<interface name=\"com.example.Frob\">\n\
+                   \x20 <method name=\"Frobulate\">\n\
+                   \x20   <arg name=\"value\" type=\"s\" direction=\"in\"/>\n\
+                   \x20   <arg name=\"count\" type=\"u\" direction=\"in\"/>\n\
+                   \x20   <arg type=\"as\" direction=\"out\"/>\n\
+                   \x20 </method>\n\
+                   \x20 <signal name=\"Frobbed\">\n\
+                   \x20   <arg type=\"s\"/>\n\
+                   \x20 </signal>\n\
+                   </interface>\n");
+
+        let root = tree.introspect("/com/example/Frob");
+        assert!(root.as_slice().contains("<node name=\"a\"/>"));
+        assert!(root.as_slice().contains("<node name=\"b\"/>"));
+        assert!(!root.as_slice().contains("deeper"));
+    }
+}
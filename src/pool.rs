@@ -0,0 +1,109 @@
+// Reuses message allocations, for senders emitting many small messages
+// where the allocator would otherwise show up in profiles.
+
+use message::DBusMessage;
+use names::{BusName, InterfaceName, MemberName};
+
+
+pub struct MessagePool {
+    messages: Vec<DBusMessage>,
+    buffers: Vec<Vec<u8>>,
+    // How many of each to keep around
+    capacity: uint
+}
+
+impl MessagePool {
+    pub fn new(capacity: uint) -> MessagePool {
+        MessagePool {
+            messages: Vec::with_capacity(capacity),
+            buffers: Vec::with_capacity(capacity),
+            capacity: capacity
+        }
+    }
+
+    pub fn signal(&mut self, path: &str, interface: InterfaceName,
+                  member: MemberName) -> DBusMessage {
+        match self.messages.pop() {
+            Some(mut msg) => {
+                msg.reset_signal(path, interface, member);
+                msg
+            },
+            None => DBusMessage::signal(path, interface, member)
+        }
+    }
+
+    pub fn method_call(&mut self, destination: BusName, path: &str,
+                       interface: InterfaceName, member: MemberName) -> DBusMessage {
+        match self.messages.pop() {
+            Some(mut msg) => {
+                msg.reset_method_call(destination, path, interface, member);
+                msg
+            },
+            None => DBusMessage::method_call(destination, path, interface, member)
+        }
+    }
+
+    // Returns a message for reuse once it's been sent.
+    pub fn recycle(&mut self, msg: DBusMessage) {
+        if self.messages.len() < self.capacity {
+            self.messages.push(msg);
+        }
+    }
+
+    // A buffer to marshal into, see DBusMessage::marshal_into().
+    pub fn buffer(&mut self) -> Vec<u8> {
+        match self.buffers.pop() {
+            Some(buf) => buf,
+            None => Vec::new()
+        }
+    }
+
+    pub fn recycle_buffer(&mut self, buf: Vec<u8>) {
+        if self.buffers.len() < self.capacity {
+            self.buffers.push(buf);
+        }
+    }
+
+    // How many messages are waiting to be reused.
+    #[inline]
+    pub fn available(&self) -> uint {
+        self.messages.len()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::DBusMessage;
+    use names::{BusName, InterfaceName, MemberName};
+    use value::Int32;
+
+    #[test]
+    fn test_pool_reuse() {
+        let mut pool = MessagePool::new(1);
+        let mut msg = pool.signal("/a", InterfaceName::from_static("a.b"),
+                                  MemberName::from_static("C"));
+        msg.append(Int32(1));
+        msg.set_serial(4);
+        pool.recycle(msg);
+        // beyond capacity, so dropped
+        pool.recycle(DBusMessage::signal("/", InterfaceName::from_static("a.b"),
+                                         MemberName::from_static("D")));
+        assert_eq!(pool.available(), 1);
+
+        let msg = pool.method_call(BusName::from_static("x.y"), "/b",
+                                   InterfaceName::from_static("x.y"),
+                                   MemberName::from_static("Z"));
+        assert_eq!(pool.available(), 0);
+        assert_eq!(msg.serial(), 0);
+        assert_eq!(msg.body().len(), 0);
+        assert_eq!(msg.destination(), Some("x.y"));
+        assert_eq!(msg.path(), Some("/b"));
+        assert_eq!(msg.member(), Some("Z"));
+
+        let buf = msg.marshal_into(pool.buffer());
+        assert_eq!(buf, msg.marshal());
+        pool.recycle_buffer(buf);
+    }
+}
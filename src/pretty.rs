@@ -0,0 +1,144 @@
+// Renders messages the way dbus-monitor does, for debug logs:
+//
+//     signal sender=org.freedesktop.DBus -> destination=:1.7 serial=2
+//        path=/org/freedesktop/DBus; interface=org.freedesktop.DBus; member=NameAcquired
+//        string ":1.7"
+//
+// except that the header is all on one line.
+
+use message::{DBusMessage, MessageType};
+use value::{Value, Byte, Boolean, Int16, UInt16, Int32, UInt32, Int64, UInt64, Double};
+use value::{Str, ObjectPath, Signature, UnixFd, Array, Struct, DictEntry, Variant};
+
+
+// The header line, then one line per body value, nested values
+// indented three spaces further.  No trailing newline.
+pub fn format_message(msg: &DBusMessage) -> String {
+    let mut out = String::new();
+    out.push_str(match msg.msg_type() {
+        MessageType::MethodCall => "method call",
+        MessageType::MethodReturn => "method return",
+        MessageType::Error => "error",
+        MessageType::Signal => "signal"
+    });
+    out.push_str(format!(" sender={} -> destination={}",
+                         msg.sender().unwrap_or("(null sender)"),
+                         msg.destination().unwrap_or("(null destination)")).as_slice());
+    match msg.msg_type() {
+        MessageType::MethodCall | MessageType::Signal => {
+            out.push_str(format!(" serial={} path={}; interface={}; member={}", msg.serial(),
+                                 msg.path().unwrap_or(""), msg.interface().unwrap_or(""),
+                                 msg.member().unwrap_or("")).as_slice());
+        },
+        MessageType::MethodReturn => {
+            out.push_str(format!(" serial={} reply_serial={}", msg.serial(),
+                                 msg.reply_serial().unwrap_or(0)).as_slice());
+        },
+        MessageType::Error => {
+            out.push_str(format!(" error_name={} reply_serial={}",
+                                 msg.error_name().unwrap_or(""),
+                                 msg.reply_serial().unwrap_or(0)).as_slice());
+        }
+    }
+    for value in msg.body().iter() {
+        out.push('\n');
+        write_value(&mut out, value, 1);
+    }
+    out
+}
+
+// One value as dbus-monitor prints it, starting `depth` levels in.
+pub fn format_value(value: &Value, depth: uint) -> String {
+    let mut out = String::new();
+    write_value(&mut out, value, depth);
+    out
+}
+
+fn indent(out: &mut String, depth: uint) {
+    for _ in range(0, depth * 3) {
+        out.push(' ');
+    }
+}
+
+fn write_value(out: &mut String, value: &Value, depth: uint) {
+    indent(out, depth);
+    write_inline(out, value, depth);
+}
+
+// Writes `value` assuming the line is already indented.
+fn write_inline(out: &mut String, value: &Value, depth: uint) {
+    let line = match *value {
+        Byte(v) => format!("byte {}", v),
+        Boolean(v) => format!("boolean {}", v),
+        Int16(v) => format!("int16 {}", v),
+        UInt16(v) => format!("uint16 {}", v),
+        Int32(v) => format!("int32 {}", v),
+        UInt32(v) => format!("uint32 {}", v),
+        Int64(v) => format!("int64 {}", v),
+        UInt64(v) => format!("uint64 {}", v),
+        Double(v) => format!("double {}", v),
+        Str(ref v) => format!("string \"{}\"", v),
+        ObjectPath(ref v) => format!("object path \"{}\"", v),
+        Signature(ref v) => format!("signature \"{}\"", v),
+        UnixFd(v) => format!("file descriptor {}", v),
+        Variant(ref inner) => {
+            out.push_str("variant ");
+            return write_inline(out, &**inner, depth);
+        },
+        Array(_, ref items) => return write_nested(out, "array [", items.as_slice(), "]", depth),
+        Struct(ref fields) => return write_nested(out, "struct {", fields.as_slice(), "}", depth),
+        DictEntry(ref key, ref value) => {
+            out.push_str("dict entry(\n");
+            write_value(out, &**key, depth + 1);
+            out.push('\n');
+            write_value(out, &**value, depth + 1);
+            out.push('\n');
+            indent(out, depth);
+            out.push(')');
+            return;
+        }
+    };
+    out.push_str(line.as_slice());
+}
+
+fn write_nested(out: &mut String, open: &str, items: &[Value], close: &str, depth: uint) {
+    out.push_str(open);
+    for item in items.iter() {
+        out.push('\n');
+        write_value(out, item, depth + 1);
+    }
+    out.push('\n');
+    indent(out, depth);
+    out.push_str(close);
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::DBusMessage;
+    use names::{BusName, InterfaceName, MemberName};
+    use value::{DBusType, Array, Str, UInt32, Variant};
+
+    #[test]
+    fn test_format_message() {
+        let msg = DBusMessage::method_call(BusName::from_static("org.freedesktop.DBus"),
+                                           "/org/freedesktop/DBus",
+                                           InterfaceName::from_static("org.freedesktop.DBus"),
+                                           MemberName::from_static("RequestName"))
+            .with_args(vec![
+                Str(String::from_str("com.example.Frob")),
+                Array(DBusType::UInt32, vec![UInt32(4)]),
+                Variant(box UInt32(7))
+            ]);
+        assert_eq!(format_message(&msg).as_slice(),
+                   "method call sender=(null sender) -> destination=org.freedesktop.DBus \
+                    serial=0 path=/org/freedesktop/DBus; interface=org.freedesktop.DBus; \
+                    member=RequestName\n   \
+                    string \"com.example.Frob\"\n   \
+                    array [\n      \
+                    uint32 4\n   \
+                    ]\n   \
+                    variant uint32 7");
+    }
+}
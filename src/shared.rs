@@ -0,0 +1,56 @@
+// A connection any number of tasks can hold.  Each use takes a lock,
+// so a task blocked in a call holds up the others; with the native
+// backend, tasks that only send should use a MessageSender instead.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use builder::ConnectionBuilder;
+use super::{DBusConnection, DBusResult};
+
+
+#[deriving(Clone)]
+pub struct SharedConnection {
+    inner: Arc<Mutex<DBusConnection>>
+}
+
+impl SharedConnection {
+    // Opens the connection `builder` describes, with libdbus's thread
+    // support turned on first, as it must be before the connection
+    // exists.
+    pub fn connect(builder: ConnectionBuilder) -> DBusResult<SharedConnection> {
+        try!(init_threads());
+        Ok(SharedConnection::new(try!(builder.connect())))
+    }
+
+    // Shares a connection that's already open.  With libdbus, call
+    // init_threads() before opening it, or use connect().
+    pub fn new(conn: DBusConnection) -> SharedConnection {
+        SharedConnection {
+            inner: Arc::new(Mutex::new(conn))
+        }
+    }
+
+    // Holds the connection for as long as the guard lives.
+    pub fn lock<'a>(&'a self) -> MutexGuard<'a, DBusConnection> {
+        self.inner.lock()
+    }
+
+    pub fn with<T>(&self, f: |&mut DBusConnection| -> T) -> T {
+        let mut conn = self.inner.lock();
+        f(&mut *conn)
+    }
+}
+
+
+// Turns on libdbus's thread support.  It must happen before the
+// process makes any other libdbus call.  The native backend needs
+// nothing.
+#[cfg(not(feature = "native"))]
+pub fn init_threads() -> DBusResult<()> {
+    super::libdbus::init_threads()
+}
+
+#[cfg(feature = "native")]
+pub fn init_threads() -> DBusResult<()> {
+    Ok(())
+}
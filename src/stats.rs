@@ -0,0 +1,20 @@
+// Counters kept by each connection, returned by DBusConnection::stats().
+
+#[deriving(Clone, PartialEq, Show, Default)]
+pub struct ConnectionStats {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    // Received messages the connection's security policy dropped
+    pub messages_rejected: u64,
+    // Method calls sent that are still waiting for a reply
+    pub calls_in_flight: uint,
+    // Replies to calls we made
+    pub replies_matched: u64,
+    // Replies to calls we never made, or that already had one
+    pub replies_orphaned: u64,
+    // Calls to dispatch() that handled a message, and the time spent in them
+    pub dispatched: u64,
+    pub dispatch_time_ns: u64
+}
@@ -0,0 +1,79 @@
+// A private dbus-daemon for integration tests, so they don't depend on
+// (or disturb) the session bus of whoever runs them:
+//
+//     let bus = try!(TestBus::start());
+//     let mut conn = try!(bus.connect());
+//
+// The daemon and its socket go away when the TestBus is dropped.
+
+use std::io::{BufferedReader, TempDir};
+use std::io::process::{Command, Ignored, Process};
+
+use builder::ConnectionBuilder;
+use super::{DBusConnection, DBusResult, DBusError, StandardError};
+
+
+pub struct TestBus {
+    process: Process,
+    address: String,
+    // Holds the socket; removed on drop
+    _dir: TempDir
+}
+
+impl TestBus {
+    // Starts `dbus-daemon` from the PATH with the session bus
+    // configuration, listening on a socket in a new temporary directory.
+    pub fn start() -> DBusResult<TestBus> {
+        TestBus::start_daemon("dbus-daemon")
+    }
+
+    pub fn start_daemon(program: &str) -> DBusResult<TestBus> {
+        let dir = try!(TempDir::new("dbus-test").map_err(DBusError::from_io_error));
+        let listen = format!("--address=unix:path={}", dir.path().join("bus").display());
+        let mut process = try!(Command::new(program)
+                                   .arg("--session")
+                                   .arg("--nofork")
+                                   .arg("--print-address")
+                                   .arg(listen)
+                                   // nobody reads it, and a full pipe would stall the daemon
+                                   .stderr(Ignored)
+                                   .spawn()
+                                   .map_err(DBusError::from_io_error));
+        // the daemon prints its address once it's listening
+        let line = match process.stdout.take() {
+            Some(stdout) => BufferedReader::new(stdout).read_line(),
+            None => unreachable!()
+        };
+        let address = match line {
+            Ok(ref line) if !line.as_slice().trim().is_empty() => {
+                String::from_str(line.as_slice().trim())
+            },
+            _ => {
+                let _ = process.signal_kill();
+                return Err(DBusError::standard(StandardError::Failed,
+                                               "dbus-daemon exited without an address"));
+            }
+        };
+        Ok(TestBus {
+            process: process,
+            address: address,
+            _dir: dir
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        self.address.as_slice()
+    }
+
+    // A new connection to this bus, registered with it.
+    pub fn connect(&self) -> DBusResult<DBusConnection> {
+        ConnectionBuilder::address(self.address.as_bytes()).private(true).register(true).connect()
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = self.process.signal_kill();
+        let _ = self.process.wait();
+    }
+}
@@ -0,0 +1,55 @@
+// File descriptors passed alongside messages.  A received message's
+// UnixFd values index into the fds that came with it, and
+// DBusMessage::unix_fd() hands out a duplicate of one, so the message
+// and its clones can go without closing it under whoever kept it.
+
+use std::io::IoError;
+use std::mem;
+use libc;
+
+use super::{DBusResult, DBusError};
+
+
+// A descriptor, closed when dropped.
+#[deriving(PartialEq, Eq, Show)]
+pub struct OwnedFd {
+    fd: libc::c_int
+}
+
+impl OwnedFd {
+    // Takes ownership of `fd`.
+    pub fn from_raw_fd(fd: libc::c_int) -> OwnedFd {
+        OwnedFd {
+            fd: fd
+        }
+    }
+
+    pub fn fd(&self) -> libc::c_int {
+        self.fd
+    }
+
+    // Gives the descriptor up without closing it.
+    pub fn into_raw_fd(self) -> libc::c_int {
+        let fd = self.fd;
+        unsafe {
+            mem::forget(self);
+        }
+        fd
+    }
+
+    // Another descriptor for the same file, with its own lifetime.
+    pub fn duplicate(&self) -> DBusResult<OwnedFd> {
+        match unsafe { libc::dup(self.fd) } {
+            fd if fd < 0 => Err(DBusError::from_io_error(IoError::last_error())),
+            fd => Ok(OwnedFd::from_raw_fd(fd))
+        }
+    }
+}
+
+impl Drop for OwnedFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use marshal::{ParseError, InvalidSignature};
+
+pub type DBusType = self::DBusType::DBusType;
+
+
+// from the D-Bus specification
+pub const MAX_SIGNATURE_LEN: uint = 255;
+pub const MAX_ARRAY_DEPTH: uint = 32;
+pub const MAX_STRUCT_DEPTH: uint = 32;
+
+
+pub mod DBusType {
+    #[deriving(PartialEq, Eq, Clone, Show)]
+    pub enum DBusType {
+        Byte,
+        Boolean,
+        Int16,
+        UInt16,
+        Int32,
+        UInt32,
+        Int64,
+        UInt64,
+        Double,
+        Str,
+        ObjectPath,
+        Signature,
+        UnixFd,
+        Array(Box<DBusType>),
+        Struct(Vec<DBusType>),
+        DictEntry(Box<DBusType>, Box<DBusType>),
+        Variant
+    }
+
+    impl DBusType {
+        pub fn alignment(&self) -> uint {
+            match *self {
+                Byte | Signature | Variant => 1,
+                Int16 | UInt16 => 2,
+                Boolean | Int32 | UInt32 | Str | ObjectPath | UnixFd | Array(_) => 4,
+                Int64 | UInt64 | Double | Struct(_) | DictEntry(..) => 8
+            }
+        }
+
+        pub fn is_basic(&self) -> bool {
+            match *self {
+                Array(_) | Struct(_) | DictEntry(..) | Variant => false,
+                _ => true
+            }
+        }
+
+        pub fn signature(&self) -> String {
+            let mut out = String::new();
+            self.write_signature(&mut out);
+            out
+        }
+
+        pub fn write_signature(&self, out: &mut String) {
+            match *self {
+                Byte => out.push_str("y"),
+                Boolean => out.push_str("b"),
+                Int16 => out.push_str("n"),
+                UInt16 => out.push_str("q"),
+                Int32 => out.push_str("i"),
+                UInt32 => out.push_str("u"),
+                Int64 => out.push_str("x"),
+                UInt64 => out.push_str("t"),
+                Double => out.push_str("d"),
+                Str => out.push_str("s"),
+                ObjectPath => out.push_str("o"),
+                Signature => out.push_str("g"),
+                UnixFd => out.push_str("h"),
+                Variant => out.push_str("v"),
+                Array(ref elem) => {
+                    out.push_str("a");
+                    elem.write_signature(out);
+                },
+                Struct(ref fields) => {
+                    out.push_str("(");
+                    for field in fields.iter() {
+                        field.write_signature(out);
+                    }
+                    out.push_str(")");
+                },
+                DictEntry(ref key, ref value) => {
+                    out.push_str("{");
+                    key.write_signature(out);
+                    value.write_signature(out);
+                    out.push_str("}");
+                }
+            }
+        }
+    }
+}
+
+
+// Parses a signature into its sequence of complete types.
+pub fn parse_signature(sig: &str) -> Result<Vec<DBusType>, ParseError> {
+    let bytes = sig.as_bytes();
+    if bytes.len() > MAX_SIGNATURE_LEN {
+        return Err(invalid_signature(sig, "too long"));
+    }
+    let mut pos = 0u;
+    let mut out = Vec::new();
+    while pos < bytes.len() {
+        out.push(try!(parse_one(sig, &mut pos, 0, 0, false)));
+    }
+    Ok(out)
+}
+
+// Parses a signature that must hold exactly one complete type, as in a variant.
+pub fn parse_single_type(sig: &str) -> Result<DBusType, ParseError> {
+    let mut types = try!(parse_signature(sig));
+    if types.len() != 1 {
+        return Err(invalid_signature(sig, "expected a single complete type"));
+    }
+    Ok(types.pop().unwrap())
+}
+
+fn invalid_signature(sig: &str, why: &str) -> ParseError {
+    InvalidSignature(format!("\"{}\": {}", sig, why))
+}
+
+fn parse_one(sig: &str, pos: &mut uint, arrays: uint, structs: uint,
+             in_array: bool) -> Result<DBusType, ParseError> {
+    let bytes = sig.as_bytes();
+    if *pos >= bytes.len() {
+        return Err(invalid_signature(sig, "incomplete type"));
+    }
+    let code = bytes[*pos];
+    *pos += 1;
+    Ok(match code as char {
+        'y' => DBusType::Byte,
+        'b' => DBusType::Boolean,
+        'n' => DBusType::Int16,
+        'q' => DBusType::UInt16,
+        'i' => DBusType::Int32,
+        'u' => DBusType::UInt32,
+        'x' => DBusType::Int64,
+        't' => DBusType::UInt64,
+        'd' => DBusType::Double,
+        's' => DBusType::Str,
+        'o' => DBusType::ObjectPath,
+        'g' => DBusType::Signature,
+        'h' => DBusType::UnixFd,
+        'v' => DBusType::Variant,
+        'a' => {
+            if arrays + 1 > MAX_ARRAY_DEPTH {
+                return Err(invalid_signature(sig, "arrays nested too deeply"));
+            }
+            let elem = try!(parse_one(sig, pos, arrays + 1, structs, true));
+            DBusType::Array(box elem)
+        },
+        '(' => {
+            if structs + 1 > MAX_STRUCT_DEPTH {
+                return Err(invalid_signature(sig, "structs nested too deeply"));
+            }
+            let mut fields = Vec::new();
+            loop {
+                if *pos >= bytes.len() {
+                    return Err(invalid_signature(sig, "unterminated struct"));
+                }
+                if bytes[*pos] == ')' as u8 {
+                    *pos += 1;
+                    break;
+                }
+                fields.push(try!(parse_one(sig, pos, arrays, structs + 1, false)));
+            }
+            if fields.is_empty() {
+                return Err(invalid_signature(sig, "empty struct"));
+            }
+            DBusType::Struct(fields)
+        },
+        '{' => {
+            if !in_array {
+                return Err(invalid_signature(sig, "dict entry outside of an array"));
+            }
+            let key = try!(parse_one(sig, pos, arrays, structs + 1, false));
+            if !key.is_basic() {
+                return Err(invalid_signature(sig, "dict entry key must be a basic type"));
+            }
+            let value = try!(parse_one(sig, pos, arrays, structs + 1, false));
+            if *pos >= bytes.len() || bytes[*pos] != '}' as u8 {
+                return Err(invalid_signature(sig, "dict entry must hold exactly two types"));
+            }
+            *pos += 1;
+            DBusType::DictEntry(box key, box value)
+        },
+        _ => return Err(invalid_signature(sig, "unknown type code"))
+    })
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum Value {
+    Byte(u8),
+    Boolean(bool),
+    Int16(i16),
+    UInt16(u16),
+    Int32(i32),
+    UInt32(u32),
+    Int64(i64),
+    UInt64(u64),
+    Double(f64),
+    Str(String),
+    ObjectPath(String),
+    Signature(String),
+    // An index into the message's out-of-band unix fd array
+    UnixFd(u32),
+    // The element type is kept so empty arrays still have a signature
+    Array(DBusType, Vec<Value>),
+    Struct(Vec<Value>),
+    DictEntry(Box<Value>, Box<Value>),
+    Variant(Box<Value>)
+}
+
+impl Value {
+    pub fn get_type(&self) -> DBusType {
+        match *self {
+            Byte(_) => DBusType::Byte,
+            Boolean(_) => DBusType::Boolean,
+            Int16(_) => DBusType::Int16,
+            UInt16(_) => DBusType::UInt16,
+            Int32(_) => DBusType::Int32,
+            UInt32(_) => DBusType::UInt32,
+            Int64(_) => DBusType::Int64,
+            UInt64(_) => DBusType::UInt64,
+            Double(_) => DBusType::Double,
+            Str(_) => DBusType::Str,
+            ObjectPath(_) => DBusType::ObjectPath,
+            Signature(_) => DBusType::Signature,
+            UnixFd(_) => DBusType::UnixFd,
+            Array(ref elem, _) => DBusType::Array(box elem.clone()),
+            Struct(ref fields) => {
+                DBusType::Struct(fields.iter().map(|f| f.get_type()).collect())
+            },
+            DictEntry(ref key, ref value) => {
+                DBusType::DictEntry(box key.get_type(), box value.get_type())
+            },
+            Variant(_) => DBusType::Variant
+        }
+    }
+
+    #[inline]
+    pub fn signature(&self) -> String {
+        self.get_type().signature()
+    }
+
+    // Convenience constructor for a{kv} dictionaries.
+    pub fn dict(key_type: DBusType, value_type: DBusType,
+                entries: Vec<(Value, Value)>) -> Value {
+        Array(DBusType::DictEntry(box key_type, box value_type),
+              entries.into_iter().map(|(k, v)| DictEntry(box k, box v)).collect())
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Str(ref s) | ObjectPath(ref s) | Signature(ref s) => Some(s.as_slice()),
+            _ => None
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match *self {
+            UInt32(v) => Some(v),
+            _ => None
+        }
+    }
+
+    pub fn as_i32(&self) -> Option<i32> {
+        match *self {
+            Int32(v) => Some(v),
+            _ => None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Boolean(v) => Some(v),
+            _ => None
+        }
+    }
+
+    // Looks through any number of variant wrappers.
+    pub fn inner(&self) -> &Value {
+        match *self {
+            Variant(ref inner) => inner.inner(),
+            _ => self
+        }
+    }
+}
+
+
+// "/" or "/"-separated, non-empty elements of [A-Za-z0-9_], with no
+// trailing slash.
+pub fn is_valid_object_path(path: &str) -> bool {
+    if path == "/" {
+        return true;
+    }
+    if !path.starts_with("/") || path.ends_with("/") {
+        return false;
+    }
+    path.slice_from(1).split('/').all(|elem| {
+        !elem.is_empty() && elem.chars().all(|c| {
+            (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') ||
+                (c >= '0' && c <= '9') || c == '_'
+        })
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_signature() {
+        let types = parse_signature("ya{sv}(ii)").unwrap();
+        assert_eq!(types.len(), 3);
+        assert_eq!(*types.get(1), DBusType::Array(box DBusType::DictEntry(
+            box DBusType::Str, box DBusType::Variant)));
+        assert_eq!(types.get(2).signature().as_slice(), "(ii)");
+
+        assert!(parse_signature("a").is_err());
+        assert!(parse_signature("()").is_err());
+        assert!(parse_signature("{sv}").is_err());
+        assert!(parse_signature("a{vs}").is_err());
+        assert!(parse_signature("a{sss}").is_err());
+        assert!(parse_signature("(ii").is_err());
+    }
+
+    #[test]
+    fn test_value_signature() {
+        let dict = Value::dict(DBusType::Str, DBusType::Variant, vec![
+            (Str(String::from_str("x")), Variant(box Int32(1)))
+        ]);
+        assert_eq!(dict.signature().as_slice(), "a{sv}");
+        let empty = Array(DBusType::Struct(vec![DBusType::Byte, DBusType::Variant]), vec![]);
+        assert_eq!(empty.signature().as_slice(), "a(yv)");
+    }
+
+    #[test]
+    fn test_object_path() {
+        assert!(is_valid_object_path("/"));
+        assert!(is_valid_object_path("/org/freedesktop/DBus_1"));
+        assert!(!is_valid_object_path(""));
+        assert!(!is_valid_object_path("org"));
+        assert!(!is_valid_object_path("/org/"));
+        assert!(!is_valid_object_path("/org//x"));
+        assert!(!is_valid_object_path("/org/free-desktop"));
+    }
+}